@@ -0,0 +1,52 @@
+//! Steps per second through a tight loop with and without the decode cache.
+//! Run with `cargo bench --bench decode_cache`.
+
+use std::time::Instant;
+
+use riscv_emu::cpu::Machine;
+use riscv_emu::cpu::decode_cache::DecodeCache;
+
+/// Count a0 down from `ITERATIONS`, summing it into a1:
+/// loop: add a1, a1, a0; addi a0, a0, -1; xor t0, a1, a0; bnez a0, loop
+const PROGRAM: [u32; 5] = [
+    0x00a5_85b3, // add a1, a1, a0
+    0xfff5_0513, // addi a0, a0, -1
+    0x00a5_c2b3, // xor t0, a1, a0
+    0xfe05_1ae3, // bne a0, zero, loop
+    0x0000_006f, // j .
+];
+const ITERATIONS: u64 = 2_000_000;
+
+fn run(cached: bool) -> f64 {
+    let mut m = Machine::new(0x1000);
+    for (i, inst) in PROGRAM.iter().enumerate() {
+        m.mem
+            .write_u32_phys(0x8000_0000 + 4 * i as u64, *inst)
+            .unwrap();
+    }
+    m.cpu.pc = 0x8000_0000;
+    m.cpu.set_reg(10, ITERATIONS);
+    if cached {
+        m.decode_cache = Some(DecodeCache::default());
+    }
+
+    let steps = 4 * ITERATIONS;
+    let start = Instant::now();
+    for _ in 0..steps {
+        m.step().unwrap();
+    }
+    let secs = start.elapsed().as_secs_f64();
+    assert_eq!(m.cpu.a1(), ITERATIONS * (ITERATIONS + 1) / 2);
+    steps as f64 / secs
+}
+
+fn main() {
+    let uncached = run(false);
+    let cached = run(true);
+    println!("uncached: {:>12.0} steps/s", uncached);
+    println!(
+        "cached:   {:>12.0} steps/s ({:.2}x)",
+        cached,
+        cached / uncached
+    );
+}
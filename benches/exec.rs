@@ -0,0 +1,83 @@
+//! Steady-state instructions per second on a loop mixing loads, stores, ALU
+//! ops, a multiply and a branch: single steps, then `run` with the decode and
+//! block caches. Run with `cargo bench --bench exec`.
+
+use std::time::Instant;
+
+use riscv_emu::cpu::Machine;
+use riscv_emu::cpu::block_cache::BlockCache;
+use riscv_emu::cpu::decode_cache::DecodeCache;
+
+/// Count a0 down from `ITERATIONS`, accumulating into memory at sp and a1
+const PROGRAM: [u32; 8] = [
+    0x0001_3303, // ld t1, 0(sp)
+    0x00a3_0333, // add t1, t1, a0
+    0x0061_3023, // sd t1, 0(sp)
+    0x02a3_03b3, // mul t2, t1, a0
+    0x0075_c5b3, // xor a1, a1, t2
+    0xfff5_0513, // addi a0, a0, -1
+    0xfe05_14e3, // bne a0, zero, loop
+    0x0000_006f, // j .
+];
+const ITERATIONS: u64 = 1_000_000;
+
+#[derive(Clone, Copy)]
+enum Mode {
+    Step,
+    DecodeCache,
+    Blocks,
+}
+
+fn measure(mode: Mode) -> f64 {
+    let mut m = Machine::new(0x2000);
+    for (i, inst) in PROGRAM.iter().enumerate() {
+        m.mem
+            .write_u32_phys(0x8000_0000 + 4 * i as u64, *inst)
+            .unwrap();
+    }
+    m.cpu.pc = 0x8000_0000;
+    m.cpu.set_reg(2, 0x8000_1000);
+    m.cpu.set_reg(10, ITERATIONS);
+    match mode {
+        Mode::Step => {}
+        Mode::DecodeCache => m.decode_cache = Some(DecodeCache::default()),
+        Mode::Blocks => m.block_cache = Some(BlockCache::new()),
+    }
+
+    // Warm up the TLB and caches before timing
+    let warmup = 7 * 1000;
+    let steps = 7 * ITERATIONS - warmup;
+    m.run(warmup);
+    let start = Instant::now();
+    match mode {
+        Mode::Step => {
+            for _ in 0..steps {
+                m.step().unwrap();
+            }
+        }
+        Mode::DecodeCache | Mode::Blocks => {
+            m.run(steps);
+        }
+    }
+    let secs = start.elapsed().as_secs_f64();
+    assert_eq!(m.cpu.a0(), 0);
+    assert_eq!(m.cpu.pc, 0x8000_001c);
+    steps as f64 / secs
+}
+
+fn main() {
+    let base = measure(Mode::Step);
+    for (name, mode) in [
+        ("step", Mode::Step),
+        ("decode cache", Mode::DecodeCache),
+        ("blocks", Mode::Blocks),
+    ] {
+        let ips = measure(mode);
+        println!(
+            "{:<14}{:>12.0} instructions/s ({:.2}x)",
+            format!("{}:", name),
+            ips,
+            ips / base
+        );
+    }
+}
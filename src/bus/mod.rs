@@ -0,0 +1,214 @@
+use thiserror::Error;
+
+use crate::mem::MemError;
+use crate::mem::ram::Ram;
+
+/// A memory-mapped device. Offsets are relative to the base of the window the
+/// device is attached at, and `size` is the access width in bytes (1, 2, 4 or 8).
+pub trait Device {
+    fn read(&self, offset: u64, size: u64) -> u64;
+    fn write(&mut self, offset: u64, size: u64, value: u64);
+
+    /// PLIC source the device's interrupt line is wired to, if it has one.
+    fn irq_line(&self) -> Option<u32> {
+        None
+    }
+
+    fn irq_pending(&self) -> bool {
+        false
+    }
+
+    /// Carry out work that register writes only requested, such as DMA to and
+    /// from guest RAM. `Machine::step` calls this once per step.
+    fn service(&mut self, _ram: &mut GuestRam) {}
+}
+
+/// Guest RAM as seen by a bus-mastering device: physical addresses, no
+/// translation, PMP or watchpoints.
+pub struct GuestRam<'a> {
+    base: u64,
+    data: &'a mut Ram,
+}
+
+impl<'a> GuestRam<'a> {
+    pub fn new(base: u64, data: &'a mut Ram) -> Self {
+        Self { base, data }
+    }
+
+    fn range(&self, paddr: u64, len: usize) -> Result<std::ops::Range<usize>, MemError> {
+        let start = paddr.checked_sub(self.base).ok_or(MemError::Oob(paddr))?;
+        let end = start
+            .checked_add(len as u64)
+            .filter(|&end| end <= self.data.len() as u64)
+            .ok_or(MemError::Oob(paddr))?;
+        Ok(start as usize..end as usize)
+    }
+
+    pub fn read(&self, paddr: u64, buf: &mut [u8]) -> Result<(), MemError> {
+        let range = self.range(paddr, buf.len())?;
+        self.data.read(range.start, buf);
+        Ok(())
+    }
+
+    pub fn write(&mut self, paddr: u64, bytes: &[u8]) -> Result<(), MemError> {
+        let range = self.range(paddr, bytes.len())?;
+        self.data.write(range.start, bytes);
+        Ok(())
+    }
+}
+
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BusError {
+    #[error("device window at 0x{base:x} is empty or runs past the end of the address space")]
+    BadWindow { base: u64, size: u64 },
+    #[error("device window [0x{base:x}, 0x{end:x}) overlaps another mapping")]
+    Overlap { base: u64, end: u64 },
+}
+
+/// One attached device and the physical range `[base, base + size)` it decodes
+struct Window {
+    base: u64,
+    size: u64,
+    device: Box<dyn Device>,
+}
+
+/// Devices beyond the built-in CLINT, PLIC, UART and syscon, kept sorted by
+/// base address so a lookup is a binary search.
+#[derive(Default)]
+pub struct Bus {
+    windows: Vec<Window>,
+}
+
+impl Bus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Map `device` at `[base, base + size)`. Fails if the window is empty,
+    /// wraps, or overlaps one already on the bus.
+    pub fn attach(
+        &mut self,
+        base: u64,
+        size: u64,
+        device: Box<dyn Device>,
+    ) -> Result<(), BusError> {
+        let end = match base.checked_add(size) {
+            Some(end) if size != 0 => end,
+            _ => return Err(BusError::BadWindow { base, size }),
+        };
+        if self.overlaps(base, end) {
+            return Err(BusError::Overlap { base, end });
+        }
+        let at = self.windows.partition_point(|w| w.base < base);
+        self.windows.insert(at, Window { base, size, device });
+        Ok(())
+    }
+
+    /// Whether any attached window intersects `[start, end)`.
+    pub fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.ranges()
+            .any(|(base, size)| base < end && start < base + size)
+    }
+
+    /// `(base, size)` of every attached window, in address order.
+    pub fn ranges(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        self.windows.iter().map(|w| (w.base, w.size))
+    }
+
+    fn index_of(&self, paddr: u64) -> Option<usize> {
+        let i = self
+            .windows
+            .partition_point(|w| w.base <= paddr)
+            .checked_sub(1)?;
+        (paddr - self.windows[i].base < self.windows[i].size).then_some(i)
+    }
+
+    /// The device decoding `paddr`, with the offset of `paddr` into its window.
+    pub fn find(&self, paddr: u64) -> Option<(&dyn Device, u64)> {
+        let w = &self.windows[self.index_of(paddr)?];
+        Some((w.device.as_ref(), paddr - w.base))
+    }
+
+    /// Let every device run its deferred work against `ram`.
+    pub fn service(&mut self, ram: &mut GuestRam) {
+        for w in &mut self.windows {
+            w.device.service(ram);
+        }
+    }
+
+    /// `(source, level)` of every device interrupt line.
+    pub fn irqs(&self) -> impl Iterator<Item = (u32, bool)> + '_ {
+        self.windows
+            .iter()
+            .filter_map(|w| Some((w.device.irq_line()?, w.device.irq_pending())))
+    }
+
+    pub fn find_mut(&mut self, paddr: u64) -> Option<(&mut dyn Device, u64)> {
+        let i = self.index_of(paddr)?;
+        let w = &mut self.windows[i];
+        Some((w.device.as_mut(), paddr - w.base))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Remembers the last write; reads return it plus the offset
+    #[derive(Default)]
+    struct Latch(u64);
+
+    impl Device for Latch {
+        fn read(&self, offset: u64, _size: u64) -> u64 {
+            self.0 + offset
+        }
+
+        fn write(&mut self, _offset: u64, _size: u64, value: u64) {
+            self.0 = value;
+        }
+    }
+
+    #[test]
+    fn test_find_dispatches_by_window() {
+        let mut bus = Bus::new();
+        bus.attach(0x3000, 0x100, Box::new(Latch(0x300))).unwrap();
+        bus.attach(0x1000, 0x100, Box::new(Latch(0x100))).unwrap();
+        assert_eq!(
+            bus.ranges().collect::<Vec<_>>(),
+            [(0x1000, 0x100), (0x3000, 0x100)]
+        );
+
+        let (dev, off) = bus.find(0x3010).unwrap();
+        assert_eq!((off, dev.read(off, 4)), (0x10, 0x310));
+        let (dev, off) = bus.find_mut(0x10ff).unwrap();
+        dev.write(off, 8, 7);
+        assert_eq!(bus.find(0x1000).unwrap().0.read(0, 8), 7);
+
+        assert!(bus.find(0xfff).is_none());
+        assert!(bus.find(0x1100).is_none());
+        assert!(bus.find(0x2000).is_none());
+    }
+
+    #[test]
+    fn test_attach_rejects_overlaps_and_bad_windows() {
+        let mut bus = Bus::new();
+        bus.attach(0x1000, 0x100, Box::<Latch>::default()).unwrap();
+        assert_eq!(
+            bus.attach(0x10f0, 0x100, Box::<Latch>::default()),
+            Err(BusError::Overlap {
+                base: 0x10f0,
+                end: 0x11f0
+            })
+        );
+        assert!(matches!(
+            bus.attach(0x2000, 0, Box::<Latch>::default()),
+            Err(BusError::BadWindow { .. })
+        ));
+        assert!(matches!(
+            bus.attach(u64::MAX, 2, Box::<Latch>::default()),
+            Err(BusError::BadWindow { .. })
+        ));
+        // Adjacent windows are fine
+        bus.attach(0x1100, 0x100, Box::<Latch>::default()).unwrap();
+    }
+}
@@ -0,0 +1,174 @@
+use crate::bus::Device;
+use crate::snapshot::{Reader, SnapshotError, Writer};
+
+/// Core-Local Interruptor (CLINT) at the QEMU virt base address.
+///
+/// Register layout (single hart):
+///   0x0000  msip      (32-bit, bit 0 drives mip.MSIP)
+///   0x4000  mtimecmp  (64-bit)
+///   0xBFF8  mtime     (64-bit, free-running)
+///
+/// mtime advances one tick per `Machine::step` (and by the time slept while
+/// idling with `Machine::sleep_on_wfi`), and the `time` CSR reads it.
+pub const CLINT_BASE: u64 = 0x0200_0000;
+pub const CLINT_SIZE: u64 = 0x1_0000;
+
+/// Nominal mtime frequency to advertise to guests (QEMU virt's 10 MHz). As
+/// mtime ticks once per step, guests calibrating delays against it see each
+/// instruction take 100 ns.
+pub const TIMEBASE_HZ: u64 = 10_000_000;
+
+const MSIP: u64 = 0x0000;
+const MTIMECMP: u64 = 0x4000;
+const MTIME: u64 = 0xBFF8;
+
+#[derive(Clone)]
+pub struct Clint {
+    pub msip: u32,
+    pub mtimecmp: u64,
+    pub mtime: u64,
+}
+
+impl Default for Clint {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clint {
+    pub fn new() -> Self {
+        Self {
+            msip: 0,
+            // Reset to the max so the timer doesn't fire before software programs it
+            mtimecmp: u64::MAX,
+            mtime: 0,
+        }
+    }
+
+    pub fn contains(paddr: u64) -> bool {
+        paddr >= CLINT_BASE && paddr - CLINT_BASE < CLINT_SIZE
+    }
+
+    /// Read `size` bytes at `offset` from the CLINT base. Unmapped offsets read as zero.
+    pub fn read(&self, offset: u64, size: u64) -> u64 {
+        match offset {
+            MSIP..=0x3 => read_field(self.msip as u64, offset - MSIP, size),
+            MTIMECMP..=0x4007 => read_field(self.mtimecmp, offset - MTIMECMP, size),
+            MTIME..=0xBFFF => read_field(self.mtime, offset - MTIME, size),
+            _ => 0,
+        }
+    }
+
+    /// Write `size` bytes at `offset` from the CLINT base. Unmapped offsets are ignored.
+    pub fn write(&mut self, offset: u64, size: u64, value: u64) {
+        match offset {
+            MSIP..=0x3 => {
+                // Only bit 0 of msip is implemented
+                let msip = write_field(self.msip as u64, offset - MSIP, size, value);
+                self.msip = (msip & 1) as u32;
+            }
+            MTIMECMP..=0x4007 => {
+                self.mtimecmp = write_field(self.mtimecmp, offset - MTIMECMP, size, value);
+            }
+            MTIME..=0xBFFF => {
+                self.mtime = write_field(self.mtime, offset - MTIME, size, value);
+            }
+            _ => {}
+        }
+    }
+
+    /// Advance mtime by one tick.
+    pub fn tick(&mut self) {
+        self.mtime = self.mtime.wrapping_add(1);
+    }
+
+    /// MTIP level: asserted while mtime >= mtimecmp
+    pub fn timer_pending(&self) -> bool {
+        self.mtime >= self.mtimecmp
+    }
+
+    /// MSIP level: asserted while msip bit 0 is set
+    pub fn software_pending(&self) -> bool {
+        self.msip & 1 != 0
+    }
+
+    pub(crate) fn save(&self, w: &mut Writer) {
+        w.u32(self.msip);
+        w.u64(self.mtimecmp);
+        w.u64(self.mtime);
+    }
+
+    pub(crate) fn load(r: &mut Reader) -> Result<Self, SnapshotError> {
+        Ok(Self {
+            msip: r.u32()?,
+            mtimecmp: r.u64()?,
+            mtime: r.u64()?,
+        })
+    }
+}
+
+impl Device for Clint {
+    fn read(&self, offset: u64, size: u64) -> u64 {
+        Clint::read(self, offset, size)
+    }
+
+    fn write(&mut self, offset: u64, size: u64, value: u64) {
+        Clint::write(self, offset, size, value)
+    }
+}
+
+/// Extract `size` bytes starting `byte_off` bytes into a register.
+fn read_field(reg: u64, byte_off: u64, size: u64) -> u64 {
+    let shift = byte_off * 8;
+    let mask = if size >= 8 {
+        u64::MAX
+    } else {
+        (1u64 << (size * 8)) - 1
+    };
+    (reg >> shift) & mask
+}
+
+/// Replace `size` bytes starting `byte_off` bytes into a register.
+fn write_field(reg: u64, byte_off: u64, size: u64, value: u64) -> u64 {
+    let shift = byte_off * 8;
+    let mask = if size >= 8 {
+        u64::MAX
+    } else {
+        (1u64 << (size * 8)) - 1
+    };
+    (reg & !(mask << shift)) | ((value & mask) << shift)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mtimecmp_split_32_bit_writes() {
+        let mut clint = Clint::new();
+
+        clint.write(MTIMECMP, 4, 0x2000);
+        clint.write(MTIMECMP + 4, 4, 0);
+        assert_eq!(clint.mtimecmp, 0x2000);
+        assert_eq!(clint.read(MTIMECMP, 8), 0x2000);
+
+        clint.mtime = 0x1fff;
+        assert!(!clint.timer_pending());
+        clint.tick();
+        assert!(clint.timer_pending());
+        assert_eq!(clint.read(MTIME, 4), 0x2000);
+        assert_eq!(clint.read(MTIME + 4, 4), 0);
+    }
+
+    #[test]
+    fn test_msip_only_bit_zero_is_writable() {
+        let mut clint = Clint::new();
+
+        clint.write(MSIP, 4, 0xffff_ffff);
+        assert_eq!(clint.read(MSIP, 4), 1);
+        assert!(clint.software_pending());
+
+        clint.write(MSIP, 4, 0);
+        assert!(!clint.software_pending());
+    }
+}
@@ -0,0 +1,115 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::cpu::decode::Instr;
+use crate::csr::PrivMode;
+
+/// A straight-line run of decoded instructions with their lengths, ending at
+/// the first control transfer or system instruction. A block never spans a
+/// page, so one translation covers all of it.
+pub struct Block {
+    pub instrs: Vec<(Instr, u64)>,
+}
+
+/// Blocks by start pc, tagged with satp and the privilege mode like
+/// `DecodeCache` entries and flushed on the same events.
+#[derive(Default)]
+pub struct BlockCache {
+    blocks: HashMap<(u64, u64, PrivMode), Rc<Block>>,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl BlockCache {
+    /// Longest block decoded, in instructions
+    pub const MAX_LEN: usize = 64;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&mut self, pc: u64, satp: u64, priv_mode: PrivMode) -> Option<Rc<Block>> {
+        let block = self.blocks.get(&(pc, satp, priv_mode)).cloned();
+        if block.is_some() {
+            self.hits += 1;
+        } else {
+            self.misses += 1;
+        }
+        block
+    }
+
+    pub fn insert(&mut self, pc: u64, satp: u64, priv_mode: PrivMode, block: Rc<Block>) {
+        self.blocks.insert((pc, satp, priv_mode), block);
+    }
+
+    pub fn flush(&mut self) {
+        self.blocks.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::{Machine, StepOutcome};
+
+    /// Sum 1..=10 into a1, then spin on `j .`
+    const SUM: [u32; 5] = [
+        0x00a0_0513, // addi a0, zero, 10
+        0x00a5_85b3, // add a1, a1, a0
+        0xfff5_0513, // addi a0, a0, -1
+        0xfe05_1ce3, // bne a0, zero, -8
+        0x0000_006f, // j .
+    ];
+
+    fn load(m: &mut Machine, program: &[u32]) {
+        for (i, inst) in program.iter().enumerate() {
+            m.mem
+                .write_u32_phys(0x8000_0000 + 4 * i as u64, *inst)
+                .unwrap();
+        }
+        m.cpu.pc = 0x8000_0000;
+    }
+
+    #[test]
+    fn test_blocks_match_single_stepping() {
+        let mut stepped = Machine::new(0x1000);
+        load(&mut stepped, &SUM);
+        let mut blocked = Machine::new(0x1000);
+        load(&mut blocked, &SUM);
+        blocked.block_cache = Some(BlockCache::new());
+
+        // 1 + 3 * 10 instructions reach the final jump
+        for m in [&mut stepped, &mut blocked] {
+            assert_eq!(m.run(31), StepOutcome::Continued);
+        }
+        assert_eq!(blocked.cpu.a1(), 55);
+        assert_eq!(blocked.cpu.pc, 0x8000_0010);
+        assert_eq!(blocked.cpu.regs, stepped.cpu.regs);
+        assert_eq!(blocked.executed, stepped.executed);
+        assert_eq!(blocked.cpu.csr.instret, stepped.cpu.csr.instret);
+        assert_eq!(blocked.mem.clint.mtime, stepped.mem.clint.mtime);
+
+        // Blocks at the entry and the loop head, then the loop body reused
+        let cache = blocked.block_cache.as_ref().unwrap();
+        assert_eq!((cache.hits, cache.misses), (8, 2));
+    }
+
+    #[test]
+    fn test_store_into_block_cuts_it_short() {
+        // sw t1, 8(t0) overwrites the addi right behind it in the same block
+        let program = [
+            0x0062_a423, // sw t1, 8(t0)
+            0x0010_0513, // addi a0, zero, 1
+            0x0010_0513, // addi a0, zero, 1 (becomes addi a0, zero, 42)
+            0x0000_006f, // j .
+        ];
+        let mut m = Machine::new(0x1000);
+        load(&mut m, &program);
+        m.block_cache = Some(BlockCache::new());
+        m.cpu.set_reg(5, 0x8000_0000);
+        m.cpu.set_reg(6, 0x02a0_0513);
+
+        assert_eq!(m.run(3), StepOutcome::Continued);
+        assert_eq!(m.cpu.a0(), 42);
+    }
+}
@@ -0,0 +1,264 @@
+use thiserror::Error;
+
+use crate::clint::{CLINT_BASE, CLINT_SIZE};
+use crate::cpu::{Cpu, Machine, MachineConfig};
+use crate::csr::PrivMode;
+use crate::mem::{Devices, Memory};
+use crate::mmu::{Mmu, PteAdMode};
+use crate::plic::{PLIC_BASE, PLIC_SIZE};
+use crate::syscon::{SYSCON_BASE, SYSCON_SIZE};
+use crate::uart::{UART_BASE, UART_SIZE};
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum ConfigError {
+    #[error("RAM of 0x{size:x} bytes at 0x{base:x} runs past the end of the address space")]
+    RamOutOfRange { base: u64, size: u64 },
+    #[error("RAM at [0x{ram_start:x}, 0x{ram_end:x}) overlaps the {device} at 0x{device_base:x}")]
+    Overlap {
+        device: &'static str,
+        ram_start: u64,
+        ram_end: u64,
+        device_base: u64,
+    },
+}
+
+/// Builds a `Machine` with a non-default memory map or initial state.
+///
+/// Defaults match `Machine::new`: 128 MiB of RAM at 0x8000_0000, the CLINT,
+/// PLIC, UART and syscon attached at their QEMU virt addresses, and the hart starting
+/// in M-mode at the base of RAM.
+///
+/// `build` rejects RAM that runs past the top of the address space or overlaps
+/// the window of an attached device:
+///   CLINT  [0x0200_0000, 0x0201_0000)
+///   PLIC   [0x0c00_0000, 0x1000_0000)
+///   UART   [0x1000_0000, 0x1000_0100)
+///   SYSCON [0x0010_0000, 0x0010_1000)
+/// Detach a device to put RAM over its window. The reset vector isn't checked;
+/// one outside RAM simply faults on the first fetch.
+pub struct MachineBuilder {
+    ram_base: u64,
+    ram_size: usize,
+    reset_vector: Option<u64>,
+    priv_mode: PrivMode,
+    devices: Devices,
+    config: MachineConfig,
+}
+
+impl Default for MachineBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MachineBuilder {
+    pub fn new() -> Self {
+        Self {
+            ram_base: 0x8000_0000,
+            ram_size: 128 * 1024 * 1024,
+            reset_vector: None,
+            priv_mode: PrivMode::Machine,
+            devices: Devices::default(),
+            config: MachineConfig::default(),
+        }
+    }
+
+    pub fn ram_base(mut self, base: u64) -> Self {
+        self.ram_base = base;
+        self
+    }
+
+    pub fn ram_size(mut self, bytes: usize) -> Self {
+        self.ram_size = bytes;
+        self
+    }
+
+    /// Initial pc, also restored by `Machine::reset`. Defaults to the RAM base.
+    pub fn reset_vector(mut self, pc: u64) -> Self {
+        self.reset_vector = Some(pc);
+        self
+    }
+
+    /// Privilege mode the hart starts (and resets) in.
+    pub fn privilege(mut self, mode: PrivMode) -> Self {
+        self.priv_mode = mode;
+        self
+    }
+
+    /// Extensions the hart implements, as returned by `csr::parse_isa`.
+    /// Defaults to `CsrFile::MISA` (RV64GC).
+    pub fn isa(mut self, misa: u64) -> Self {
+        self.config.isa = misa;
+        self
+    }
+
+    /// Accept Sv48 in satp as well as Sv39. Off by default, so the hart looks
+    /// like an Sv39-only implementation.
+    pub fn sv48(mut self, enabled: bool) -> Self {
+        self.config.sv48 = enabled;
+        self
+    }
+
+    /// Whether page walks set clear A/D bits themselves (the default) or
+    /// raise a page fault for the guest to set them.
+    pub fn pte_ad_mode(mut self, mode: PteAdMode) -> Self {
+        self.config.ad_mode = mode;
+        self
+    }
+
+    pub fn clint(mut self, attached: bool) -> Self {
+        self.devices.clint = attached;
+        self
+    }
+
+    pub fn plic(mut self, attached: bool) -> Self {
+        self.devices.plic = attached;
+        self
+    }
+
+    pub fn uart(mut self, attached: bool) -> Self {
+        self.devices.uart = attached;
+        self
+    }
+
+    pub fn syscon(mut self, attached: bool) -> Self {
+        self.devices.syscon = attached;
+        self
+    }
+
+    pub fn build(self) -> Result<Machine, ConfigError> {
+        let size = self.ram_size as u64;
+        let ram_end = self
+            .ram_base
+            .checked_add(size)
+            .ok_or(ConfigError::RamOutOfRange {
+                base: self.ram_base,
+                size,
+            })?;
+
+        let windows = [
+            ("CLINT", self.devices.clint, CLINT_BASE, CLINT_SIZE),
+            ("PLIC", self.devices.plic, PLIC_BASE, PLIC_SIZE),
+            ("UART", self.devices.uart, UART_BASE, UART_SIZE),
+            ("SYSCON", self.devices.syscon, SYSCON_BASE, SYSCON_SIZE),
+        ];
+        for (device, attached, device_base, device_size) in windows {
+            if attached && self.ram_base < device_base + device_size && device_base < ram_end {
+                return Err(ConfigError::Overlap {
+                    device,
+                    ram_start: self.ram_base,
+                    ram_end,
+                    device_base,
+                });
+            }
+        }
+
+        let mut mem = Memory::with_base(self.ram_size, self.ram_base);
+        mem.devices = self.devices;
+        let mut machine = Machine {
+            cpu: Cpu::default(),
+            mem,
+            mmu: Mmu::new(),
+            config: self.config,
+            host_exit_addr: None,
+            max_insns: 0,
+            executed: 0,
+            reset_vector: self.reset_vector.unwrap_or(self.ram_base),
+            reset_priv: self.priv_mode,
+            reset_sp: None,
+            profile: None,
+            trap_stats: None,
+            sleep_on_wfi: false,
+            deadlock_after: 0,
+            self_jumps: 0,
+            decode_cache: None,
+            block_cache: None,
+            proxy: None,
+            injected_irqs: 0,
+            halt_on_trap: 0,
+            held_trap: None,
+        };
+        machine.reset();
+        Ok(machine)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ram_overlapping_an_attached_device_is_rejected() {
+        let err = MachineBuilder::new()
+            .ram_base(0)
+            .ram_size(0x1000_0000)
+            .build()
+            .err();
+        assert_eq!(
+            err,
+            Some(ConfigError::Overlap {
+                device: "CLINT",
+                ram_start: 0,
+                ram_end: 0x1000_0000,
+                device_base: CLINT_BASE,
+            })
+        );
+
+        let err = MachineBuilder::new()
+            .ram_base(u64::MAX - 0xfff)
+            .ram_size(0x2000)
+            .build()
+            .err();
+        assert!(matches!(err, Some(ConfigError::RamOutOfRange { .. })));
+    }
+
+    #[test]
+    fn test_detached_devices_free_their_window_for_ram() {
+        // RAM right up to the UART, which stays attached
+        let mut m = MachineBuilder::new()
+            .ram_base(0)
+            .ram_size(0x1000_0000)
+            .clint(false)
+            .plic(false)
+            .syscon(false)
+            .reset_vector(0x0200_0000)
+            .privilege(PrivMode::Supervisor)
+            .build()
+            .expect("no attached device overlaps");
+
+        assert_eq!(m.cpu.pc, 0x0200_0000);
+        assert_eq!(m.cpu.csr.priv_mode, PrivMode::Supervisor);
+
+        // What was the CLINT's msip register is now plain RAM
+        m.mem.write_u32_phys(0x0200_0000, 0x0050_0513).unwrap(); // li a0, 5
+        m.step().unwrap();
+        assert_eq!(m.cpu.a0(), 5);
+        assert_eq!(m.cpu.pc, 0x0200_0004);
+        assert_eq!(m.mem.clint.msip, 0);
+
+        m.reset();
+        assert_eq!(m.cpu.pc, 0x0200_0000);
+        assert_eq!(m.cpu.csr.priv_mode, PrivMode::Supervisor);
+    }
+
+    #[test]
+    fn test_pte_ad_mode_survives_reset() {
+        let mut m = MachineBuilder::new()
+            .ram_size(0x10000)
+            .pte_ad_mode(PteAdMode::Fault)
+            .build()
+            .unwrap();
+        assert_eq!(m.mmu.ad_mode, PteAdMode::Fault);
+        m.reset();
+        assert_eq!(m.mmu.ad_mode, PteAdMode::Fault);
+        assert_eq!(
+            MachineBuilder::new()
+                .ram_size(0x10000)
+                .build()
+                .unwrap()
+                .mmu
+                .ad_mode,
+            PteAdMode::Update
+        );
+    }
+}
@@ -2,8 +2,17 @@ use std::fmt;
 
 #[derive(Debug, Clone, Copy)]
 pub enum DecodeError {
-    InvalidOpcode { inst: u32 },
-    InvalidFunct { inst: u32 },
+    InvalidOpcode {
+        inst: u32,
+    },
+    InvalidFunct {
+        inst: u32,
+    },
+    /// A reserved encoding, such as a compressed instruction with a zero
+    /// immediate that must be non-zero
+    Reserved {
+        inst: u32,
+    },
 }
 
 impl fmt::Display for DecodeError {
@@ -11,10 +20,28 @@ impl fmt::Display for DecodeError {
         match self {
             DecodeError::InvalidOpcode { inst } => write!(f, "invalid opcode: 0x{:08x}", inst),
             DecodeError::InvalidFunct { inst } => write!(f, "invalid function: 0x{:08x}", inst),
+            DecodeError::Reserved { inst } => write!(f, "reserved encoding: 0x{:08x}", inst),
         }
     }
 }
 
+/// Read-modify-write operation of an AMO instruction, keyed by funct5.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AmoOp {
+    Swap,
+    Add,
+    Xor,
+    And,
+    Or,
+    Min,
+    Max,
+    Minu,
+    Maxu,
+}
+
+// Kept one variant per line: the five-operand R4-type variants are over
+// rustfmt's struct_variant_width, which would otherwise expand the whole enum
+#[rustfmt::skip]
 #[derive(Clone, Copy, Debug)]
 pub enum Instr {
     // ** RISC-V 32 & 64 Base Instructions **
@@ -75,7 +102,6 @@ pub enum Instr {
     // I-type environment
     Ecall,  // 0b1110011 with funct3=0 and imm=0
     Ebreak, // 0b1110011 with funct3=0 and imm=1
-    // slt, slti, blt, bge require explicit signedness
 
     // ** RISC-V 64 Base Instructions **
     Addiw { rd: u8, rs1: u8, imm: i64 },
@@ -106,10 +132,469 @@ pub enum Instr {
     Csrrci { rd: u8, csr: u16, uimm: u8 },
     Mret,
     Sret,
-    Sfence,
+    SfenceVma { rs1: u8, rs2: u8 },
     Wfi,
+    // A extension (0b0101111)
+    LrW { rd: u8, rs1: u8 },
+    ScW { rd: u8, rs1: u8, rs2: u8 },
+    LrD { rd: u8, rs1: u8 },
+    ScD { rd: u8, rs1: u8, rs2: u8 },
+    AmoW { op: AmoOp, rd: u8, rs1: u8, rs2: u8 },
+    AmoD { op: AmoOp, rd: u8, rs1: u8, rs2: u8 },
+    // F extension; rm is the raw rounding-mode field (0b111 = dynamic)
+    Flw { rd: u8, rs1: u8, off: i64 },          // 0b0000111
+    Fsw { rs1: u8, rs2: u8, off: i64 },         // 0b0100111
+    FaddS { rd: u8, rs1: u8, rs2: u8, rm: u8 }, // 0b1010011
+    FsubS { rd: u8, rs1: u8, rs2: u8, rm: u8 },
+    FmulS { rd: u8, rs1: u8, rs2: u8, rm: u8 },
+    FdivS { rd: u8, rs1: u8, rs2: u8, rm: u8 },
+    FsqrtS { rd: u8, rs1: u8, rm: u8 },
+    FcvtWS { rd: u8, rs1: u8, rm: u8 },
+    FcvtWuS { rd: u8, rs1: u8, rm: u8 },
+    FcvtLS { rd: u8, rs1: u8, rm: u8 },
+    FcvtLuS { rd: u8, rs1: u8, rm: u8 },
+    FcvtSW { rd: u8, rs1: u8, rm: u8 },
+    FcvtSWu { rd: u8, rs1: u8, rm: u8 },
+    FcvtSL { rd: u8, rs1: u8, rm: u8 },
+    FcvtSLu { rd: u8, rs1: u8, rm: u8 },
+    // D extension
+    Fld { rd: u8, rs1: u8, off: i64 },
+    Fsd { rs1: u8, rs2: u8, off: i64 },
+    FaddD { rd: u8, rs1: u8, rs2: u8, rm: u8 },
+    FsubD { rd: u8, rs1: u8, rs2: u8, rm: u8 },
+    FmulD { rd: u8, rs1: u8, rs2: u8, rm: u8 },
+    FdivD { rd: u8, rs1: u8, rs2: u8, rm: u8 },
+    FsqrtD { rd: u8, rs1: u8, rm: u8 },
+    FcvtSD { rd: u8, rs1: u8, rm: u8 },
+    FcvtDS { rd: u8, rs1: u8, rm: u8 },
+    FcvtWD { rd: u8, rs1: u8, rm: u8 },
+    FcvtWuD { rd: u8, rs1: u8, rm: u8 },
+    FcvtLD { rd: u8, rs1: u8, rm: u8 },
+    FcvtLuD { rd: u8, rs1: u8, rm: u8 },
+    FcvtDW { rd: u8, rs1: u8, rm: u8 },
+    FcvtDWu { rd: u8, rs1: u8, rm: u8 },
+    FcvtDL { rd: u8, rs1: u8, rm: u8 },
+    FcvtDLu { rd: u8, rs1: u8, rm: u8 },
+    FmvXD { rd: u8, rs1: u8 },
+    FmvDX { rd: u8, rs1: u8 },
+    // Comparisons write 0/1 to an integer rd
+    FeqS { rd: u8, rs1: u8, rs2: u8 },
+    FltS { rd: u8, rs1: u8, rs2: u8 },
+    FleS { rd: u8, rs1: u8, rs2: u8 },
+    FeqD { rd: u8, rs1: u8, rs2: u8 },
+    FltD { rd: u8, rs1: u8, rs2: u8 },
+    FleD { rd: u8, rs1: u8, rs2: u8 },
+    FclassS { rd: u8, rs1: u8 },
+    FclassD { rd: u8, rs1: u8 },
+    // Sign injection and min/max; exact, so no rounding mode
+    FsgnjS { rd: u8, rs1: u8, rs2: u8 },
+    FsgnjnS { rd: u8, rs1: u8, rs2: u8 },
+    FsgnjxS { rd: u8, rs1: u8, rs2: u8 },
+    FsgnjD { rd: u8, rs1: u8, rs2: u8 },
+    FsgnjnD { rd: u8, rs1: u8, rs2: u8 },
+    FsgnjxD { rd: u8, rs1: u8, rs2: u8 },
+    FminS { rd: u8, rs1: u8, rs2: u8 },
+    FmaxS { rd: u8, rs1: u8, rs2: u8 },
+    FminD { rd: u8, rs1: u8, rs2: u8 },
+    FmaxD { rd: u8, rs1: u8, rs2: u8 },
+    // Fused multiply-add (R4-type); rs3 is the addend
+    FmaddS { rd: u8, rs1: u8, rs2: u8, rs3: u8, rm: u8 }, // 0b1000011
+    FmsubS { rd: u8, rs1: u8, rs2: u8, rs3: u8, rm: u8 }, // 0b1000111
+    FnmsubS { rd: u8, rs1: u8, rs2: u8, rs3: u8, rm: u8 }, // 0b1001011
+    FnmaddS { rd: u8, rs1: u8, rs2: u8, rs3: u8, rm: u8 }, // 0b1001111
+    FmaddD { rd: u8, rs1: u8, rs2: u8, rs3: u8, rm: u8 },
+    FmsubD { rd: u8, rs1: u8, rs2: u8, rs3: u8, rm: u8 },
+    FnmsubD { rd: u8, rs1: u8, rs2: u8, rs3: u8, rm: u8 },
+    FnmaddD { rd: u8, rs1: u8, rs2: u8, rs3: u8, rm: u8 },
     // Atomic/Memory instructions
-    Fence, // 0b0001111 - No-op for now
+    Fence,  // 0b0001111 - No-op for now
+    FenceI, // 0b0001111 funct3=1
+}
+
+impl Instr {
+    /// Number of distinct `kind()` values
+    pub const KINDS: usize = 142;
+
+    /// Mnemonic for each `kind()`; the AMOs share one per width
+    pub const KIND_NAMES: [&'static str; Self::KINDS] = [
+        "add",
+        "sub",
+        "xor",
+        "or",
+        "and",
+        "sll",
+        "srl",
+        "sra",
+        "slt",
+        "sltu",
+        "mul",
+        "mulh",
+        "mulhsu",
+        "mulhu",
+        "div",
+        "divu",
+        "rem",
+        "remu",
+        "addi",
+        "xori",
+        "ori",
+        "andi",
+        "slli",
+        "srli",
+        "srai",
+        "slti",
+        "sltiu",
+        "lb",
+        "lbu",
+        "lh",
+        "lhu",
+        "lw",
+        "sb",
+        "sh",
+        "sw",
+        "beq",
+        "bne",
+        "blt",
+        "bge",
+        "bltu",
+        "bgeu",
+        "jal",
+        "jalr",
+        "lui",
+        "auipc",
+        "ecall",
+        "ebreak",
+        "addiw",
+        "slliw",
+        "srliw",
+        "sraiw",
+        "addw",
+        "subw",
+        "sllw",
+        "srlw",
+        "sraw",
+        "mulw",
+        "divw",
+        "divuw",
+        "remw",
+        "remuw",
+        "lwu",
+        "ld",
+        "sd",
+        "csrrw",
+        "csrrs",
+        "csrrc",
+        "csrrwi",
+        "csrrsi",
+        "csrrci",
+        "mret",
+        "sret",
+        "sfence.vma",
+        "wfi",
+        "lr.w",
+        "sc.w",
+        "lr.d",
+        "sc.d",
+        "amo*.w",
+        "amo*.d",
+        "flw",
+        "fsw",
+        "fadd.s",
+        "fsub.s",
+        "fmul.s",
+        "fdiv.s",
+        "fsqrt.s",
+        "fld",
+        "fsd",
+        "fadd.d",
+        "fsub.d",
+        "fmul.d",
+        "fdiv.d",
+        "fsqrt.d",
+        "fcvt.s.d",
+        "fcvt.d.s",
+        "fcvt.w.d",
+        "fcvt.wu.d",
+        "fcvt.l.d",
+        "fcvt.lu.d",
+        "fcvt.d.w",
+        "fcvt.d.wu",
+        "fcvt.d.l",
+        "fcvt.d.lu",
+        "fmv.x.d",
+        "fmv.d.x",
+        "feq.s",
+        "flt.s",
+        "fle.s",
+        "feq.d",
+        "flt.d",
+        "fle.d",
+        "fclass.s",
+        "fclass.d",
+        "fmadd.s",
+        "fmsub.s",
+        "fnmsub.s",
+        "fnmadd.s",
+        "fmadd.d",
+        "fmsub.d",
+        "fnmsub.d",
+        "fnmadd.d",
+        "fence",
+        "fence.i",
+        "fcvt.w.s",
+        "fcvt.wu.s",
+        "fcvt.l.s",
+        "fcvt.lu.s",
+        "fcvt.s.w",
+        "fcvt.s.wu",
+        "fcvt.s.l",
+        "fcvt.s.lu",
+        "fsgnj.s",
+        "fsgnjn.s",
+        "fsgnjx.s",
+        "fsgnj.d",
+        "fsgnjn.d",
+        "fsgnjx.d",
+        "fmin.s",
+        "fmax.s",
+        "fmin.d",
+        "fmax.d",
+    ];
+    /// Letter of the misa extension the instruction belongs to: b'I' for the
+    /// base ISA (including Zicsr and Zifencei), else M, A, F or D.
+    pub fn extension(&self) -> u8 {
+        match self {
+            Instr::Mul { .. }
+            | Instr::Mulh { .. }
+            | Instr::Mulhsu { .. }
+            | Instr::Mulhu { .. }
+            | Instr::Div { .. }
+            | Instr::Divu { .. }
+            | Instr::Rem { .. }
+            | Instr::Remu { .. }
+            | Instr::Mulw { .. }
+            | Instr::Divw { .. }
+            | Instr::Divuw { .. }
+            | Instr::Remw { .. }
+            | Instr::Remuw { .. } => b'M',
+            Instr::LrW { .. }
+            | Instr::ScW { .. }
+            | Instr::LrD { .. }
+            | Instr::ScD { .. }
+            | Instr::AmoW { .. }
+            | Instr::AmoD { .. } => b'A',
+            Instr::Flw { .. }
+            | Instr::Fsw { .. }
+            | Instr::FaddS { .. }
+            | Instr::FsubS { .. }
+            | Instr::FmulS { .. }
+            | Instr::FdivS { .. }
+            | Instr::FsqrtS { .. }
+            | Instr::FeqS { .. }
+            | Instr::FltS { .. }
+            | Instr::FleS { .. }
+            | Instr::FclassS { .. }
+            | Instr::FmaddS { .. }
+            | Instr::FmsubS { .. }
+            | Instr::FnmsubS { .. }
+            | Instr::FnmaddS { .. }
+            | Instr::FcvtWS { .. }
+            | Instr::FcvtWuS { .. }
+            | Instr::FcvtLS { .. }
+            | Instr::FcvtLuS { .. }
+            | Instr::FcvtSW { .. }
+            | Instr::FcvtSWu { .. }
+            | Instr::FcvtSL { .. }
+            | Instr::FcvtSLu { .. }
+            | Instr::FsgnjS { .. }
+            | Instr::FsgnjnS { .. }
+            | Instr::FsgnjxS { .. }
+            | Instr::FminS { .. }
+            | Instr::FmaxS { .. } => b'F',
+            Instr::Fld { .. }
+            | Instr::Fsd { .. }
+            | Instr::FaddD { .. }
+            | Instr::FsubD { .. }
+            | Instr::FmulD { .. }
+            | Instr::FdivD { .. }
+            | Instr::FsqrtD { .. }
+            | Instr::FcvtSD { .. }
+            | Instr::FcvtDS { .. }
+            | Instr::FcvtWD { .. }
+            | Instr::FcvtWuD { .. }
+            | Instr::FcvtLD { .. }
+            | Instr::FcvtLuD { .. }
+            | Instr::FcvtDW { .. }
+            | Instr::FcvtDWu { .. }
+            | Instr::FcvtDL { .. }
+            | Instr::FcvtDLu { .. }
+            | Instr::FmvXD { .. }
+            | Instr::FmvDX { .. }
+            | Instr::FeqD { .. }
+            | Instr::FltD { .. }
+            | Instr::FleD { .. }
+            | Instr::FclassD { .. }
+            | Instr::FmaddD { .. }
+            | Instr::FmsubD { .. }
+            | Instr::FnmsubD { .. }
+            | Instr::FnmaddD { .. }
+            | Instr::FsgnjD { .. }
+            | Instr::FsgnjnD { .. }
+            | Instr::FsgnjxD { .. }
+            | Instr::FminD { .. }
+            | Instr::FmaxD { .. } => b'D',
+            _ => b'I',
+        }
+    }
+
+    /// Dense index of the variant, for per-instruction tables such as the
+    /// profiler's counters.
+    pub fn kind(&self) -> usize {
+        match self {
+            Instr::Add { .. } => 0,
+            Instr::Sub { .. } => 1,
+            Instr::Xor { .. } => 2,
+            Instr::Or { .. } => 3,
+            Instr::And { .. } => 4,
+            Instr::Sll { .. } => 5,
+            Instr::Srl { .. } => 6,
+            Instr::Sra { .. } => 7,
+            Instr::Slt { .. } => 8,
+            Instr::Sltu { .. } => 9,
+            Instr::Mul { .. } => 10,
+            Instr::Mulh { .. } => 11,
+            Instr::Mulhsu { .. } => 12,
+            Instr::Mulhu { .. } => 13,
+            Instr::Div { .. } => 14,
+            Instr::Divu { .. } => 15,
+            Instr::Rem { .. } => 16,
+            Instr::Remu { .. } => 17,
+            Instr::Addi { .. } => 18,
+            Instr::Xori { .. } => 19,
+            Instr::Ori { .. } => 20,
+            Instr::Andi { .. } => 21,
+            Instr::Slli { .. } => 22,
+            Instr::Srli { .. } => 23,
+            Instr::Srai { .. } => 24,
+            Instr::Slti { .. } => 25,
+            Instr::Sltiu { .. } => 26,
+            Instr::LB { .. } => 27,
+            Instr::LBU { .. } => 28,
+            Instr::LH { .. } => 29,
+            Instr::LHU { .. } => 30,
+            Instr::LW { .. } => 31,
+            Instr::SB { .. } => 32,
+            Instr::SH { .. } => 33,
+            Instr::SW { .. } => 34,
+            Instr::Beq { .. } => 35,
+            Instr::Bne { .. } => 36,
+            Instr::Blt { .. } => 37,
+            Instr::Bge { .. } => 38,
+            Instr::Bltu { .. } => 39,
+            Instr::Bgeu { .. } => 40,
+            Instr::Jal { .. } => 41,
+            Instr::Jalr { .. } => 42,
+            Instr::Lui { .. } => 43,
+            Instr::Auipc { .. } => 44,
+            Instr::Ecall => 45,
+            Instr::Ebreak => 46,
+            Instr::Addiw { .. } => 47,
+            Instr::Slliw { .. } => 48,
+            Instr::Srliw { .. } => 49,
+            Instr::Sraiw { .. } => 50,
+            Instr::Addw { .. } => 51,
+            Instr::Subw { .. } => 52,
+            Instr::Sllw { .. } => 53,
+            Instr::Srlw { .. } => 54,
+            Instr::Sraw { .. } => 55,
+            Instr::Mulw { .. } => 56,
+            Instr::Divw { .. } => 57,
+            Instr::Divuw { .. } => 58,
+            Instr::Remw { .. } => 59,
+            Instr::Remuw { .. } => 60,
+            Instr::LWU { .. } => 61,
+            Instr::LD { .. } => 62,
+            Instr::SD { .. } => 63,
+            Instr::Csrrw { .. } => 64,
+            Instr::Csrrs { .. } => 65,
+            Instr::Csrrc { .. } => 66,
+            Instr::Csrrwi { .. } => 67,
+            Instr::Csrrsi { .. } => 68,
+            Instr::Csrrci { .. } => 69,
+            Instr::Mret => 70,
+            Instr::Sret => 71,
+            Instr::SfenceVma { .. } => 72,
+            Instr::Wfi => 73,
+            Instr::LrW { .. } => 74,
+            Instr::ScW { .. } => 75,
+            Instr::LrD { .. } => 76,
+            Instr::ScD { .. } => 77,
+            Instr::AmoW { .. } => 78,
+            Instr::AmoD { .. } => 79,
+            Instr::Flw { .. } => 80,
+            Instr::Fsw { .. } => 81,
+            Instr::FaddS { .. } => 82,
+            Instr::FsubS { .. } => 83,
+            Instr::FmulS { .. } => 84,
+            Instr::FdivS { .. } => 85,
+            Instr::FsqrtS { .. } => 86,
+            Instr::Fld { .. } => 87,
+            Instr::Fsd { .. } => 88,
+            Instr::FaddD { .. } => 89,
+            Instr::FsubD { .. } => 90,
+            Instr::FmulD { .. } => 91,
+            Instr::FdivD { .. } => 92,
+            Instr::FsqrtD { .. } => 93,
+            Instr::FcvtSD { .. } => 94,
+            Instr::FcvtDS { .. } => 95,
+            Instr::FcvtWD { .. } => 96,
+            Instr::FcvtWuD { .. } => 97,
+            Instr::FcvtLD { .. } => 98,
+            Instr::FcvtLuD { .. } => 99,
+            Instr::FcvtDW { .. } => 100,
+            Instr::FcvtDWu { .. } => 101,
+            Instr::FcvtDL { .. } => 102,
+            Instr::FcvtDLu { .. } => 103,
+            Instr::FmvXD { .. } => 104,
+            Instr::FmvDX { .. } => 105,
+            Instr::FeqS { .. } => 106,
+            Instr::FltS { .. } => 107,
+            Instr::FleS { .. } => 108,
+            Instr::FeqD { .. } => 109,
+            Instr::FltD { .. } => 110,
+            Instr::FleD { .. } => 111,
+            Instr::FclassS { .. } => 112,
+            Instr::FclassD { .. } => 113,
+            Instr::FmaddS { .. } => 114,
+            Instr::FmsubS { .. } => 115,
+            Instr::FnmsubS { .. } => 116,
+            Instr::FnmaddS { .. } => 117,
+            Instr::FmaddD { .. } => 118,
+            Instr::FmsubD { .. } => 119,
+            Instr::FnmsubD { .. } => 120,
+            Instr::FnmaddD { .. } => 121,
+            Instr::Fence => 122,
+            Instr::FenceI => 123,
+            Instr::FcvtWS { .. } => 124,
+            Instr::FcvtWuS { .. } => 125,
+            Instr::FcvtLS { .. } => 126,
+            Instr::FcvtLuS { .. } => 127,
+            Instr::FcvtSW { .. } => 128,
+            Instr::FcvtSWu { .. } => 129,
+            Instr::FcvtSL { .. } => 130,
+            Instr::FcvtSLu { .. } => 131,
+            Instr::FsgnjS { .. } => 132,
+            Instr::FsgnjnS { .. } => 133,
+            Instr::FsgnjxS { .. } => 134,
+            Instr::FsgnjD { .. } => 135,
+            Instr::FsgnjnD { .. } => 136,
+            Instr::FsgnjxD { .. } => 137,
+            Instr::FminS { .. } => 138,
+            Instr::FmaxS { .. } => 139,
+            Instr::FminD { .. } => 140,
+            Instr::FmaxD { .. } => 141,
+        }
+    }
 }
 
 fn sign_extend(value: i64, bits: u32) -> i64 {
@@ -296,10 +781,14 @@ pub fn decode(_pc: u64, inst: u32) -> Result<Instr, DecodeError> {
                         0x302 => Ok(Instr::Mret),
                         0x105 => Ok(Instr::Wfi),
                         _ => {
-                            // Check for SFENCE.VMA
+                            // SFENCE.VMA rs1, rs2
                             let funct7 = (inst >> 25) & 0x7f;
-                            if funct7 == 0x9 {
-                                Ok(Instr::Sfence)
+                            let rd = (inst >> 7) & 0x1f;
+                            if funct7 == 0x9 && rd == 0 {
+                                Ok(Instr::SfenceVma {
+                                    rs1: ((inst >> 15) & 0x1f) as u8,
+                                    rs2: ((inst >> 20) & 0x1f) as u8,
+                                })
                             } else {
                                 Err(DecodeError::InvalidOpcode { inst })
                             }
@@ -393,8 +882,1176 @@ pub fn decode(_pc: u64, inst: u32) -> Result<Instr, DecodeError> {
                 _ => Err(DecodeError::InvalidOpcode { inst }),
             }
         }
-        // Fence instruction (0b0001111)
-        0b0001111 => Ok(Instr::Fence),
+        // float load
+        0b0000111 => {
+            let rd = ((inst >> 7) & 0x1f) as u8;
+            let funct3 = ((inst >> 12) & 0x7) as u8;
+            let rs1 = ((inst >> 15) & 0x1f) as u8;
+            let imm = sign_extend((inst >> 20) as i64, 12);
+            match funct3 {
+                0x2 => Ok(Instr::Flw { rd, rs1, off: imm }),
+                0x3 => Ok(Instr::Fld { rd, rs1, off: imm }),
+                _ => Err(DecodeError::InvalidOpcode { inst }),
+            }
+        }
+        // float store
+        0b0100111 => {
+            let funct3 = ((inst >> 12) & 0x7) as u8;
+            let rs1 = ((inst >> 15) & 0x1f) as u8;
+            let rs2 = ((inst >> 20) & 0x1f) as u8;
+            let imm = {
+                let imm4_0 = (inst >> 7) & 0x1f;
+                let imm11_5 = (inst >> 25) & 0x7f;
+                sign_extend(((imm11_5 << 5) | imm4_0) as i64, 12)
+            };
+            match funct3 {
+                0x2 => Ok(Instr::Fsw { rs1, rs2, off: imm }),
+                0x3 => Ok(Instr::Fsd { rs1, rs2, off: imm }),
+                _ => Err(DecodeError::InvalidOpcode { inst }),
+            }
+        }
+        // OP-FP; funct3 holds the rounding mode
+        0b1010011 => {
+            let rd = ((inst >> 7) & 0x1f) as u8;
+            let rm = ((inst >> 12) & 0x7) as u8;
+            let rs1 = ((inst >> 15) & 0x1f) as u8;
+            let rs2 = ((inst >> 20) & 0x1f) as u8;
+            let funct7 = ((inst >> 25) & 0x7f) as u8;
+            match funct7 {
+                0x00 => Ok(Instr::FaddS { rd, rs1, rs2, rm }),
+                0x04 => Ok(Instr::FsubS { rd, rs1, rs2, rm }),
+                0x08 => Ok(Instr::FmulS { rd, rs1, rs2, rm }),
+                0x0c => Ok(Instr::FdivS { rd, rs1, rs2, rm }),
+                0x2c if rs2 == 0 => Ok(Instr::FsqrtS { rd, rs1, rm }),
+                0x01 => Ok(Instr::FaddD { rd, rs1, rs2, rm }),
+                0x05 => Ok(Instr::FsubD { rd, rs1, rs2, rm }),
+                0x09 => Ok(Instr::FmulD { rd, rs1, rs2, rm }),
+                0x0d => Ok(Instr::FdivD { rd, rs1, rs2, rm }),
+                0x2d if rs2 == 0 => Ok(Instr::FsqrtD { rd, rs1, rm }),
+                // rs2 names the source format/integer type for conversions
+                0x20 if rs2 == 1 => Ok(Instr::FcvtSD { rd, rs1, rm }),
+                0x21 if rs2 == 0 => Ok(Instr::FcvtDS { rd, rs1, rm }),
+                0x60 => match rs2 {
+                    0 => Ok(Instr::FcvtWS { rd, rs1, rm }),
+                    1 => Ok(Instr::FcvtWuS { rd, rs1, rm }),
+                    2 => Ok(Instr::FcvtLS { rd, rs1, rm }),
+                    3 => Ok(Instr::FcvtLuS { rd, rs1, rm }),
+                    _ => Err(DecodeError::InvalidFunct { inst }),
+                },
+                0x68 => match rs2 {
+                    0 => Ok(Instr::FcvtSW { rd, rs1, rm }),
+                    1 => Ok(Instr::FcvtSWu { rd, rs1, rm }),
+                    2 => Ok(Instr::FcvtSL { rd, rs1, rm }),
+                    3 => Ok(Instr::FcvtSLu { rd, rs1, rm }),
+                    _ => Err(DecodeError::InvalidFunct { inst }),
+                },
+                0x61 => match rs2 {
+                    0 => Ok(Instr::FcvtWD { rd, rs1, rm }),
+                    1 => Ok(Instr::FcvtWuD { rd, rs1, rm }),
+                    2 => Ok(Instr::FcvtLD { rd, rs1, rm }),
+                    3 => Ok(Instr::FcvtLuD { rd, rs1, rm }),
+                    _ => Err(DecodeError::InvalidFunct { inst }),
+                },
+                0x69 => match rs2 {
+                    0 => Ok(Instr::FcvtDW { rd, rs1, rm }),
+                    1 => Ok(Instr::FcvtDWu { rd, rs1, rm }),
+                    2 => Ok(Instr::FcvtDL { rd, rs1, rm }),
+                    3 => Ok(Instr::FcvtDLu { rd, rs1, rm }),
+                    _ => Err(DecodeError::InvalidFunct { inst }),
+                },
+                // funct3 selects the sign source or min/max
+                0x10 | 0x11 => match (funct7, rm) {
+                    (0x10, 0x0) => Ok(Instr::FsgnjS { rd, rs1, rs2 }),
+                    (0x10, 0x1) => Ok(Instr::FsgnjnS { rd, rs1, rs2 }),
+                    (0x10, 0x2) => Ok(Instr::FsgnjxS { rd, rs1, rs2 }),
+                    (0x11, 0x0) => Ok(Instr::FsgnjD { rd, rs1, rs2 }),
+                    (0x11, 0x1) => Ok(Instr::FsgnjnD { rd, rs1, rs2 }),
+                    (0x11, 0x2) => Ok(Instr::FsgnjxD { rd, rs1, rs2 }),
+                    _ => Err(DecodeError::InvalidFunct { inst }),
+                },
+                0x14 | 0x15 => match (funct7, rm) {
+                    (0x14, 0x0) => Ok(Instr::FminS { rd, rs1, rs2 }),
+                    (0x14, 0x1) => Ok(Instr::FmaxS { rd, rs1, rs2 }),
+                    (0x15, 0x0) => Ok(Instr::FminD { rd, rs1, rs2 }),
+                    (0x15, 0x1) => Ok(Instr::FmaxD { rd, rs1, rs2 }),
+                    _ => Err(DecodeError::InvalidFunct { inst }),
+                },
+                // funct3 selects the comparison
+                0x50 | 0x51 => match (funct7, rm) {
+                    (0x50, 0x2) => Ok(Instr::FeqS { rd, rs1, rs2 }),
+                    (0x50, 0x1) => Ok(Instr::FltS { rd, rs1, rs2 }),
+                    (0x50, 0x0) => Ok(Instr::FleS { rd, rs1, rs2 }),
+                    (0x51, 0x2) => Ok(Instr::FeqD { rd, rs1, rs2 }),
+                    (0x51, 0x1) => Ok(Instr::FltD { rd, rs1, rs2 }),
+                    (0x51, 0x0) => Ok(Instr::FleD { rd, rs1, rs2 }),
+                    _ => Err(DecodeError::InvalidFunct { inst }),
+                },
+                0x70 if rs2 == 0 && rm == 1 => Ok(Instr::FclassS { rd, rs1 }),
+                0x71 if rs2 == 0 && rm == 1 => Ok(Instr::FclassD { rd, rs1 }),
+                0x71 if rs2 == 0 && rm == 0 => Ok(Instr::FmvXD { rd, rs1 }),
+                0x79 if rs2 == 0 && rm == 0 => Ok(Instr::FmvDX { rd, rs1 }),
+                _ => Err(DecodeError::InvalidFunct { inst }),
+            }
+        }
+        // FMADD/FMSUB/FNMSUB/FNMADD; fmt in bits 26:25 picks single or double
+        0b1000011 | 0b1000111 | 0b1001011 | 0b1001111 => {
+            let rd = ((inst >> 7) & 0x1f) as u8;
+            let rm = ((inst >> 12) & 0x7) as u8;
+            let rs1 = ((inst >> 15) & 0x1f) as u8;
+            let rs2 = ((inst >> 20) & 0x1f) as u8;
+            let fmt = (inst >> 25) & 0x3;
+            let rs3 = ((inst >> 27) & 0x1f) as u8;
+            match (opcode, fmt) {
+                (0b1000011, 0) => Ok(Instr::FmaddS {
+                    rd,
+                    rs1,
+                    rs2,
+                    rs3,
+                    rm,
+                }),
+                (0b1000111, 0) => Ok(Instr::FmsubS {
+                    rd,
+                    rs1,
+                    rs2,
+                    rs3,
+                    rm,
+                }),
+                (0b1001011, 0) => Ok(Instr::FnmsubS {
+                    rd,
+                    rs1,
+                    rs2,
+                    rs3,
+                    rm,
+                }),
+                (0b1001111, 0) => Ok(Instr::FnmaddS {
+                    rd,
+                    rs1,
+                    rs2,
+                    rs3,
+                    rm,
+                }),
+                (0b1000011, 1) => Ok(Instr::FmaddD {
+                    rd,
+                    rs1,
+                    rs2,
+                    rs3,
+                    rm,
+                }),
+                (0b1000111, 1) => Ok(Instr::FmsubD {
+                    rd,
+                    rs1,
+                    rs2,
+                    rs3,
+                    rm,
+                }),
+                (0b1001011, 1) => Ok(Instr::FnmsubD {
+                    rd,
+                    rs1,
+                    rs2,
+                    rs3,
+                    rm,
+                }),
+                (0b1001111, 1) => Ok(Instr::FnmaddD {
+                    rd,
+                    rs1,
+                    rs2,
+                    rs3,
+                    rm,
+                }),
+                _ => Err(DecodeError::InvalidFunct { inst }),
+            }
+        }
+        // atomics; the aq/rl ordering bits are irrelevant on a single in-order hart
+        0b0101111 => {
+            let rd = ((inst >> 7) & 0x1f) as u8;
+            let funct3 = ((inst >> 12) & 0x7) as u8;
+            let rs1 = ((inst >> 15) & 0x1f) as u8;
+            let rs2 = ((inst >> 20) & 0x1f) as u8;
+            let funct5 = ((inst >> 27) & 0x1f) as u8;
+            match (funct5, funct3) {
+                (0b00010, 0x2) if rs2 == 0 => Ok(Instr::LrW { rd, rs1 }),
+                (0b00011, 0x2) => Ok(Instr::ScW { rd, rs1, rs2 }),
+                (0b00010, 0x3) if rs2 == 0 => Ok(Instr::LrD { rd, rs1 }),
+                (0b00011, 0x3) => Ok(Instr::ScD { rd, rs1, rs2 }),
+                (_, 0x2 | 0x3) => {
+                    let op = match funct5 {
+                        0b00001 => AmoOp::Swap,
+                        0b00000 => AmoOp::Add,
+                        0b00100 => AmoOp::Xor,
+                        0b01100 => AmoOp::And,
+                        0b01000 => AmoOp::Or,
+                        0b10000 => AmoOp::Min,
+                        0b10100 => AmoOp::Max,
+                        0b11000 => AmoOp::Minu,
+                        0b11100 => AmoOp::Maxu,
+                        _ => return Err(DecodeError::InvalidOpcode { inst }),
+                    };
+                    if funct3 == 0x2 {
+                        Ok(Instr::AmoW { op, rd, rs1, rs2 })
+                    } else {
+                        Ok(Instr::AmoD { op, rd, rs1, rs2 })
+                    }
+                }
+                _ => Err(DecodeError::InvalidOpcode { inst }),
+            }
+        }
+        // MISC-MEM: FENCE (funct3=0) and FENCE.I (funct3=1)
+        0b0001111 => match (inst >> 12) & 0x7 {
+            0x0 => Ok(Instr::Fence),
+            0x1 => Ok(Instr::FenceI),
+            _ => Err(DecodeError::InvalidFunct { inst }),
+        },
+        _ => Err(DecodeError::InvalidOpcode { inst }),
+    }
+}
+
+/// Expand a 16-bit RVC instruction into the equivalent base `Instr`.
+/// Register fields written `rd'`/`rs1'`/`rs2'` in the spec address x8..x15.
+/// Reserved encodings, the all-zero halfword among them, are errors rather
+/// than decoding as whatever their fields spell.
+pub fn decode_compressed(_pc: u64, inst: u16) -> Result<Instr, DecodeError> {
+    let inst = inst as u32;
+    // inst[hi:lo], shifted down to bit 0
+    let bits = |hi: u32, lo: u32| (inst >> lo) & ((1 << (hi - lo + 1)) - 1);
+    let funct3 = bits(15, 13);
+    let rd = bits(11, 7) as u8;
+    let rs2 = bits(6, 2) as u8;
+    let rd_p = bits(4, 2) as u8 + 8;
+    let rs1_p = bits(9, 7) as u8 + 8;
+    // The 6-bit immediate shared by C.ADDI, C.LI, C.ANDI and friends
+    let imm6 = sign_extend(((bits(12, 12) << 5) | bits(6, 2)) as i64, 6);
+    let shamt = ((bits(12, 12) << 5) | bits(6, 2)) as u8;
+    // C.LDSP/C.FLDSP: uimm[5] in 12, uimm[4:3|8:6] in 6:2
+    let ldsp_off = ((bits(12, 12) << 5) | (bits(6, 5) << 3) | (bits(4, 2) << 6)) as i64;
+    // C.SDSP/C.FSDSP: uimm[5:3|8:6] in 12:7
+    let sdsp_off = ((bits(12, 10) << 3) | (bits(9, 7) << 6)) as i64;
+
+    match (inst & 0b11, funct3) {
+        // Quadrant 0
+        (0b00, 0b000) => {
+            // C.ADDI4SPN: nzuimm[5:4|9:6|2|3]
+            let imm =
+                (bits(12, 11) << 4) | (bits(10, 7) << 6) | (bits(6, 6) << 2) | (bits(5, 5) << 3);
+            if imm == 0 {
+                return Err(DecodeError::Reserved { inst });
+            }
+            Ok(Instr::Addi {
+                rd: rd_p,
+                rs1: 2,
+                imm: imm as i64,
+            })
+        }
+        (0b00, 0b001) => {
+            // C.FLD: laid out like C.LD
+            let off = (bits(12, 10) << 3) | (bits(6, 5) << 6);
+            Ok(Instr::Fld {
+                rd: rd_p,
+                rs1: rs1_p,
+                off: off as i64,
+            })
+        }
+        (0b00, 0b010) => {
+            // C.LW: uimm[5:3] in 12:10, uimm[2|6] in 6:5
+            let off = (bits(12, 10) << 3) | (bits(6, 6) << 2) | (bits(5, 5) << 6);
+            Ok(Instr::LW {
+                rd: rd_p,
+                rs1: rs1_p,
+                off: off as i64,
+            })
+        }
+        (0b00, 0b011) => {
+            // C.LD: uimm[5:3] in 12:10, uimm[7:6] in 6:5
+            let off = (bits(12, 10) << 3) | (bits(6, 5) << 6);
+            Ok(Instr::LD {
+                rd: rd_p,
+                rs1: rs1_p,
+                off: off as i64,
+            })
+        }
+        (0b00, 0b101) => {
+            // C.FSD: laid out like C.SD
+            let off = (bits(12, 10) << 3) | (bits(6, 5) << 6);
+            Ok(Instr::Fsd {
+                rs1: rs1_p,
+                rs2: rd_p,
+                off: off as i64,
+            })
+        }
+        (0b00, 0b110) => {
+            let off = (bits(12, 10) << 3) | (bits(6, 6) << 2) | (bits(5, 5) << 6);
+            Ok(Instr::SW {
+                rs1: rs1_p,
+                rs2: rd_p,
+                off: off as i64,
+            })
+        }
+        (0b00, 0b111) => {
+            let off = (bits(12, 10) << 3) | (bits(6, 5) << 6);
+            Ok(Instr::SD {
+                rs1: rs1_p,
+                rs2: rd_p,
+                off: off as i64,
+            })
+        }
+        // Quadrant 1
+        (0b01, 0b000) => Ok(Instr::Addi {
+            rd,
+            rs1: rd,
+            imm: imm6,
+        }),
+        (0b01, 0b001) if rd == 0 => Err(DecodeError::Reserved { inst }),
+        (0b01, 0b001) => Ok(Instr::Addiw {
+            rd,
+            rs1: rd,
+            imm: imm6,
+        }),
+        (0b01, 0b010) => Ok(Instr::Addi {
+            rd,
+            rs1: 0,
+            imm: imm6,
+        }),
+        (0b01, 0b011) if rd == 2 => {
+            // C.ADDI16SP: nzimm[9] in 12, nzimm[4|6|8:7|5] in 6:2
+            let imm = (bits(12, 12) << 9)
+                | (bits(6, 6) << 4)
+                | (bits(5, 5) << 6)
+                | (bits(4, 3) << 7)
+                | (bits(2, 2) << 5);
+            if imm == 0 {
+                return Err(DecodeError::Reserved { inst });
+            }
+            Ok(Instr::Addi {
+                rd: 2,
+                rs1: 2,
+                imm: sign_extend(imm as i64, 10),
+            })
+        }
+        (0b01, 0b011) if imm6 == 0 => Err(DecodeError::Reserved { inst }),
+        (0b01, 0b011) => Ok(Instr::Lui {
+            rd,
+            imm: imm6 << 12,
+        }),
+        (0b01, 0b100) => match (bits(11, 10), bits(12, 12), bits(6, 5)) {
+            (0b00, _, _) => Ok(Instr::Srli {
+                rd: rs1_p,
+                rs1: rs1_p,
+                shamt,
+            }),
+            (0b01, _, _) => Ok(Instr::Srai {
+                rd: rs1_p,
+                rs1: rs1_p,
+                shamt,
+            }),
+            (0b10, _, _) => Ok(Instr::Andi {
+                rd: rs1_p,
+                rs1: rs1_p,
+                imm: imm6,
+            }),
+            (0b11, 0, 0b00) => Ok(Instr::Sub {
+                rd: rs1_p,
+                rs1: rs1_p,
+                rs2: rd_p,
+            }),
+            (0b11, 0, 0b01) => Ok(Instr::Xor {
+                rd: rs1_p,
+                rs1: rs1_p,
+                rs2: rd_p,
+            }),
+            (0b11, 0, 0b10) => Ok(Instr::Or {
+                rd: rs1_p,
+                rs1: rs1_p,
+                rs2: rd_p,
+            }),
+            (0b11, 0, 0b11) => Ok(Instr::And {
+                rd: rs1_p,
+                rs1: rs1_p,
+                rs2: rd_p,
+            }),
+            (0b11, 1, 0b00) => Ok(Instr::Subw {
+                rd: rs1_p,
+                rs1: rs1_p,
+                rs2: rd_p,
+            }),
+            (0b11, 1, 0b01) => Ok(Instr::Addw {
+                rd: rs1_p,
+                rs1: rs1_p,
+                rs2: rd_p,
+            }),
+            _ => Err(DecodeError::InvalidFunct { inst }),
+        },
+        (0b01, 0b101) => {
+            // C.J: offset[11|4|9:8|10|6|7|3:1|5]
+            let off = (bits(12, 12) << 11)
+                | (bits(11, 11) << 4)
+                | (bits(10, 9) << 8)
+                | (bits(8, 8) << 10)
+                | (bits(7, 7) << 6)
+                | (bits(6, 6) << 7)
+                | (bits(5, 3) << 1)
+                | (bits(2, 2) << 5);
+            Ok(Instr::Jal {
+                rd: 0,
+                off: sign_extend(off as i64, 12),
+            })
+        }
+        (0b01, 0b110 | 0b111) => {
+            // C.BEQZ/C.BNEZ: offset[8|4:3] in 12:10, offset[7:6|2:1|5] in 6:2
+            let off = (bits(12, 12) << 8)
+                | (bits(11, 10) << 3)
+                | (bits(6, 5) << 6)
+                | (bits(4, 3) << 1)
+                | (bits(2, 2) << 5);
+            let off = sign_extend(off as i64, 9);
+            if funct3 == 0b110 {
+                Ok(Instr::Beq {
+                    rs1: rs1_p,
+                    rs2: 0,
+                    off,
+                })
+            } else {
+                Ok(Instr::Bne {
+                    rs1: rs1_p,
+                    rs2: 0,
+                    off,
+                })
+            }
+        }
+        // Quadrant 2
+        (0b10, 0b000) => Ok(Instr::Slli { rd, rs1: rd, shamt }),
+        // C.LWSP/C.LDSP/C.FLDSP and the stores below are relative to sp. Unlike
+        // the register forms, the immediate is split around bit 12, with its
+        // high bits at the low end of the instruction.
+        (0b10, 0b001) => Ok(Instr::Fld {
+            rd,
+            rs1: 2,
+            off: ldsp_off,
+        }),
+        (0b10, 0b010 | 0b011) if rd == 0 => Err(DecodeError::Reserved { inst }),
+        (0b10, 0b010) => {
+            // C.LWSP: uimm[5] in 12, uimm[4:2|7:6] in 6:2
+            let off = (bits(12, 12) << 5) | (bits(6, 4) << 2) | (bits(3, 2) << 6);
+            Ok(Instr::LW {
+                rd,
+                rs1: 2,
+                off: off as i64,
+            })
+        }
+        (0b10, 0b011) => Ok(Instr::LD {
+            rd,
+            rs1: 2,
+            off: ldsp_off,
+        }),
+        (0b10, 0b100) => match (bits(12, 12), rd, rs2) {
+            (0, 0, 0) => Err(DecodeError::Reserved { inst }),
+            (0, _, 0) => Ok(Instr::Jalr {
+                rd: 0,
+                rs1: rd,
+                off: 0,
+            }),
+            (0, _, _) => Ok(Instr::Add { rd, rs1: 0, rs2 }),
+            (_, 0, 0) => Ok(Instr::Ebreak),
+            (_, _, 0) => Ok(Instr::Jalr {
+                rd: 1,
+                rs1: rd,
+                off: 0,
+            }),
+            (_, _, _) => Ok(Instr::Add { rd, rs1: rd, rs2 }),
+        },
+        (0b10, 0b101) => Ok(Instr::Fsd {
+            rs1: 2,
+            rs2,
+            off: sdsp_off,
+        }),
+        (0b10, 0b110) => {
+            // C.SWSP: uimm[5:2|7:6] in 12:7
+            let off = (bits(12, 9) << 2) | (bits(8, 7) << 6);
+            Ok(Instr::SW {
+                rs1: 2,
+                rs2,
+                off: off as i64,
+            })
+        }
+        (0b10, 0b111) => Ok(Instr::SD {
+            rs1: 2,
+            rs2,
+            off: sdsp_off,
+        }),
         _ => Err(DecodeError::InvalidOpcode { inst }),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encode an OP-IMM instruction with the raw 12-bit immediate field.
+    fn op_imm(funct3: u32, imm12: u32) -> u32 {
+        (imm12 << 20) | (5 << 15) | (funct3 << 12) | (7 << 7) | 0b0010011
+    }
+
+    /// Encode an R-type instruction `rd=x7, rs1=x5, rs2=x6`.
+    fn op_reg(opcode: u32, funct7: u32, funct3: u32) -> u32 {
+        (funct7 << 25) | (6 << 20) | (5 << 15) | (funct3 << 12) | (7 << 7) | opcode
+    }
+
+    #[test]
+    fn test_shift_immediates_use_six_bit_shamt() {
+        match decode(0, op_imm(0x1, 63)) {
+            Ok(Instr::Slli { shamt: 63, .. }) => {}
+            other => panic!("expected slli 63, got {:?}", other),
+        }
+        match decode(0, op_imm(0x5, 0x400 | 63)) {
+            Ok(Instr::Srai { shamt: 63, .. }) => {}
+            other => panic!("expected srai 63, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_shift_immediates_reject_bit_26() {
+        // imm[6] (instruction bit 26) would be shamt[6], which RV64 does not have
+        for funct3 in [0x1, 0x5] {
+            assert!(decode(0, op_imm(funct3, 1 << 6)).is_err());
+        }
+        assert!(decode(0, op_imm(0x5, 0x400 | (1 << 6))).is_err());
+    }
+
+    #[test]
+    fn test_word_shift_immediates_reject_shamt_bit_5() {
+        // OP-IMM-32 only has a 5-bit shamt; bit 25 set is reserved
+        let op_imm_32 = |funct3: u32, imm12: u32| (op_imm(funct3, imm12) & !0x7f) | 0b0011011;
+        for funct3 in [0x1, 0x5] {
+            assert!(decode(0, op_imm_32(funct3, 31)).is_ok());
+            assert!(decode(0, op_imm_32(funct3, 32)).is_err());
+        }
+        assert!(decode(0, op_imm_32(0x5, 0x400 | 31)).is_ok());
+        assert!(decode(0, op_imm_32(0x5, 0x400 | 32)).is_err());
+    }
+
+    #[test]
+    fn test_sfence_vma_decodes_operands() {
+        // sfence.vma a0, a1
+        match decode(0, 0x12b5_0073) {
+            Ok(Instr::SfenceVma { rs1: 10, rs2: 11 }) => {}
+            other => panic!("expected sfence.vma a0, a1, got {:?}", other),
+        }
+        // rd must be zero
+        assert!(decode(0, 0x12b5_00f3).is_err());
+    }
+
+    #[test]
+    fn test_fence_and_fence_i_decode() {
+        assert!(matches!(decode(0, 0x0ff0_000f), Ok(Instr::Fence))); // fence
+        assert!(matches!(decode(0, 0x0330_000f), Ok(Instr::Fence))); // fence rw, rw
+        assert!(matches!(decode(0, 0x0000_100f), Ok(Instr::FenceI))); // fence.i
+        assert!(decode(0, 0x0000_200f).is_err());
+    }
+
+    #[test]
+    fn test_m_extension_multiply_funct3_mapping() {
+        let op = |funct3| op_reg(0b0110011, 0x01, funct3);
+        match decode(0, op(0x0)) {
+            Ok(Instr::Mul {
+                rd: 7,
+                rs1: 5,
+                rs2: 6,
+            }) => {}
+            other => panic!("expected mul x7, x5, x6, got {:?}", other),
+        }
+        assert!(matches!(decode(0, op(0x1)), Ok(Instr::Mulh { .. })));
+        assert!(matches!(decode(0, op(0x2)), Ok(Instr::Mulhsu { .. })));
+        assert!(matches!(decode(0, op(0x3)), Ok(Instr::Mulhu { .. })));
+    }
+
+    #[test]
+    fn test_m_extension_divide_funct3_mapping() {
+        let op = |funct3| op_reg(0b0110011, 0x01, funct3);
+        assert!(matches!(decode(0, op(0x4)), Ok(Instr::Div { .. })));
+        assert!(matches!(decode(0, op(0x5)), Ok(Instr::Divu { .. })));
+        assert!(matches!(decode(0, op(0x6)), Ok(Instr::Rem { .. })));
+        assert!(matches!(decode(0, op(0x7)), Ok(Instr::Remu { .. })));
+    }
+
+    #[test]
+    fn test_m_extension_word_ops_decode() {
+        let op = |funct3| op_reg(0b0111011, 0x01, funct3);
+        assert!(matches!(decode(0, op(0x0)), Ok(Instr::Mulw { .. })));
+        assert!(matches!(decode(0, op(0x4)), Ok(Instr::Divw { .. })));
+        assert!(matches!(decode(0, op(0x5)), Ok(Instr::Divuw { .. })));
+        assert!(matches!(decode(0, op(0x6)), Ok(Instr::Remw { .. })));
+        assert!(matches!(decode(0, op(0x7)), Ok(Instr::Remuw { .. })));
+        // RV64M has no word-width MULH variants
+        for funct3 in [0x1, 0x2, 0x3] {
+            assert!(decode(0, op(funct3)).is_err());
+        }
+    }
+
+    #[test]
+    fn test_lr_sc_decode() {
+        let amo = |funct5: u32, funct3| op_reg(0b0101111, funct5 << 2, funct3);
+        let (lr, sc) = (0b00010, 0b00011);
+        assert!(matches!(
+            decode(0, amo(sc, 0x2)),
+            Ok(Instr::ScW { rs2: 6, .. })
+        ));
+        assert!(matches!(
+            decode(0, amo(sc, 0x3)),
+            Ok(Instr::ScD { rs2: 6, .. })
+        ));
+        // LR takes no rs2; op_reg always encodes x6 there
+        assert!(decode(0, amo(lr, 0x2)).is_err());
+        let lr_d = amo(lr, 0x3) & !(0x1f << 20);
+        assert!(matches!(decode(0, lr_d), Ok(Instr::LrD { rd: 7, rs1: 5 })));
+        // aq/rl bits don't change the decode
+        let lr_d_aqrl = lr_d | (0b11 << 25);
+        assert!(matches!(decode(0, lr_d_aqrl), Ok(Instr::LrD { .. })));
+    }
+
+    #[test]
+    fn test_amo_decode() {
+        let amo = |funct5: u32, funct3| op_reg(0b0101111, funct5 << 2, funct3);
+        let cases = [
+            (0b00001, AmoOp::Swap),
+            (0b00000, AmoOp::Add),
+            (0b00100, AmoOp::Xor),
+            (0b01100, AmoOp::And),
+            (0b01000, AmoOp::Or),
+            (0b10000, AmoOp::Min),
+            (0b10100, AmoOp::Max),
+            (0b11000, AmoOp::Minu),
+            (0b11100, AmoOp::Maxu),
+        ];
+        for (funct5, expected) in cases {
+            match decode(0, amo(funct5, 0x2)) {
+                Ok(Instr::AmoW {
+                    op,
+                    rd: 7,
+                    rs1: 5,
+                    rs2: 6,
+                }) => assert_eq!(op, expected),
+                other => panic!("funct5 {funct5:#07b} W: {other:?}"),
+            }
+            match decode(0, amo(funct5, 0x3)) {
+                Ok(Instr::AmoD {
+                    op,
+                    rd: 7,
+                    rs1: 5,
+                    rs2: 6,
+                }) => assert_eq!(op, expected),
+                other => panic!("funct5 {funct5:#07b} D: {other:?}"),
+            }
+        }
+        // Unassigned funct5 and widths other than W/D are rejected
+        assert!(decode(0, amo(0b00101, 0x2)).is_err());
+        assert!(decode(0, amo(0b00000, 0x1)).is_err());
+    }
+
+    #[test]
+    fn test_reserved_compressed_encodings_are_illegal() {
+        use crate::cpu::trap::{Trap, WithPc};
+
+        for inst in [
+            0x0000, // all zero, which is also c.addi4spn with nzuimm=0
+            0x0004, // c.addi4spn s1, sp, 0
+            0x2005, // c.addiw zero, 1
+            0x6101, // c.addi16sp sp, 0
+            0x6501, // c.lui a0, 0
+            0x4012, // c.lwsp zero, 4(sp)
+            0x6012, // c.ldsp zero, 8(sp)
+            0x8002, // c.jr zero
+        ] {
+            assert!(
+                matches!(
+                    decode_compressed(0, inst),
+                    Err(DecodeError::Reserved { .. })
+                ),
+                "0x{:04x} is reserved",
+                inst
+            );
+            assert_eq!(
+                decode_compressed(0, inst).with_pc(0x8000_0000).unwrap_err(),
+                Trap::IllegalInstruction {
+                    pc: 0x8000_0000,
+                    inst: inst as u32
+                }
+            );
+        }
+
+        // Their neighbours, and the zero-immediate HINTs, still decode
+        for inst in [
+            0x0040, // c.addi4spn s0, sp, 4
+            0x2085, // c.addiw ra, 1
+            0x6105, // c.addi16sp sp, 32
+            0x6005, // c.lui zero, 1 (a HINT)
+            0x4082, // c.lwsp ra, 0(sp)
+            0x8082, // c.jr ra
+            0x0001, // c.nop
+        ] {
+            assert!(decode_compressed(0, inst).is_ok(), "0x{:04x}", inst);
+        }
+    }
+
+    #[test]
+    fn test_compressed_sp_relative_loads_and_stores() {
+        // A compiled prologue and epilogue
+        assert!(matches!(
+            decode_compressed(0, 0xf406), // sd ra, 40(sp)
+            Ok(Instr::SD {
+                rs1: 2,
+                rs2: 1,
+                off: 40
+            })
+        ));
+        assert!(matches!(
+            decode_compressed(0, 0xf022), // sd s0, 32(sp)
+            Ok(Instr::SD {
+                rs1: 2,
+                rs2: 8,
+                off: 32
+            })
+        ));
+        assert!(matches!(
+            decode_compressed(0, 0xac22), // fsd fs0, 24(sp)
+            Ok(Instr::Fsd {
+                rs1: 2,
+                rs2: 8,
+                off: 24
+            })
+        ));
+        assert!(matches!(
+            decode_compressed(0, 0xc62a), // sw a0, 12(sp)
+            Ok(Instr::SW {
+                rs1: 2,
+                rs2: 10,
+                off: 12
+            })
+        ));
+        assert!(matches!(
+            decode_compressed(0, 0x70a2), // ld ra, 40(sp)
+            Ok(Instr::LD {
+                rd: 1,
+                rs1: 2,
+                off: 40
+            })
+        ));
+        assert!(matches!(
+            decode_compressed(0, 0x4532), // lw a0, 12(sp)
+            Ok(Instr::LW {
+                rd: 10,
+                rs1: 2,
+                off: 12
+            })
+        ));
+        assert!(matches!(
+            decode_compressed(0, 0x2462), // fld fs0, 24(sp)
+            Ok(Instr::Fld {
+                rd: 8,
+                rs1: 2,
+                off: 24
+            })
+        ));
+
+        // Every offset bit set, so a misplaced one shows up
+        let largest = [
+            (
+                0x74fe,
+                Instr::LD {
+                    rd: 9,
+                    rs1: 2,
+                    off: 504,
+                },
+            ),
+            (
+                0xffa6,
+                Instr::SD {
+                    rs1: 2,
+                    rs2: 9,
+                    off: 504,
+                },
+            ),
+            (
+                0x57fe,
+                Instr::LW {
+                    rd: 15,
+                    rs1: 2,
+                    off: 252,
+                },
+            ),
+            (
+                0xdfbe,
+                Instr::SW {
+                    rs1: 2,
+                    rs2: 15,
+                    off: 252,
+                },
+            ),
+            (
+                0x30fe,
+                Instr::Fld {
+                    rd: 1,
+                    rs1: 2,
+                    off: 504,
+                },
+            ),
+            (
+                0xbf86,
+                Instr::Fsd {
+                    rs1: 2,
+                    rs2: 1,
+                    off: 504,
+                },
+            ),
+        ];
+        for (inst, expected) in largest {
+            assert_eq!(
+                format!("{:?}", decode_compressed(0, inst).unwrap()),
+                format!("{:?}", expected),
+                "0x{:04x}",
+                inst
+            );
+        }
+    }
+
+    #[test]
+    fn test_compressed_register_form_fp_loads_and_stores() {
+        // Every offset bit set, so a misplaced one shows up
+        assert!(matches!(
+            decode_compressed(0, 0x3de8), // fld fa0, 248(a1)
+            Ok(Instr::Fld {
+                rd: 10,
+                rs1: 11,
+                off: 248
+            })
+        ));
+        assert!(matches!(
+            decode_compressed(0, 0xbde8), // fsd fa0, 248(a1)
+            Ok(Instr::Fsd {
+                rs1: 11,
+                rs2: 10,
+                off: 248
+            })
+        ));
+    }
+
+    #[test]
+    fn test_compressed_decode() {
+        // c.addi4spn x8, sp, 16
+        assert!(matches!(
+            decode_compressed(0, 0x0800),
+            Ok(Instr::Addi {
+                rd: 8,
+                rs1: 2,
+                imm: 16
+            })
+        ));
+        // c.lw a0, 4(a1) / c.sd s0, 8(s1)
+        assert!(matches!(
+            decode_compressed(0, 0x41c8),
+            Ok(Instr::LW {
+                rd: 10,
+                rs1: 11,
+                off: 4
+            })
+        ));
+        assert!(matches!(
+            decode_compressed(0, 0xe480),
+            Ok(Instr::SD {
+                rs1: 9,
+                rs2: 8,
+                off: 8
+            })
+        ));
+        // c.addi a0, -1 / c.li a5, 31 / c.lui a0, 0xfffff
+        assert!(matches!(
+            decode_compressed(0, 0x157d),
+            Ok(Instr::Addi {
+                rd: 10,
+                rs1: 10,
+                imm: -1
+            })
+        ));
+        assert!(matches!(
+            decode_compressed(0, 0x47fd),
+            Ok(Instr::Addi {
+                rd: 15,
+                rs1: 0,
+                imm: 31
+            })
+        ));
+        assert!(matches!(
+            decode_compressed(0, 0x757d),
+            Ok(Instr::Lui { rd: 10, imm: -4096 })
+        ));
+        // c.addi16sp sp, -64
+        assert!(matches!(
+            decode_compressed(0, 0x7139),
+            Ok(Instr::Addi {
+                rd: 2,
+                rs1: 2,
+                imm: -64
+            })
+        ));
+        // c.j -2 / c.bnez a0, -4
+        assert!(matches!(
+            decode_compressed(0, 0xbffd),
+            Ok(Instr::Jal { rd: 0, off: -2 })
+        ));
+        assert!(matches!(
+            decode_compressed(0, 0xfd75),
+            Ok(Instr::Bne {
+                rs1: 10,
+                rs2: 0,
+                off: -4
+            })
+        ));
+        // c.srai a0, 63 / c.subw a0, a1
+        assert!(matches!(
+            decode_compressed(0, 0x957d),
+            Ok(Instr::Srai {
+                rd: 10,
+                rs1: 10,
+                shamt: 63
+            })
+        ));
+        assert!(matches!(
+            decode_compressed(0, 0x9d0d),
+            Ok(Instr::Subw {
+                rd: 10,
+                rs1: 10,
+                rs2: 11
+            })
+        ));
+        // c.jr ra / c.jalr a0 / c.mv a0, a1 / c.add a0, a1 / c.ebreak
+        assert!(matches!(
+            decode_compressed(0, 0x8082),
+            Ok(Instr::Jalr {
+                rd: 0,
+                rs1: 1,
+                off: 0
+            })
+        ));
+        assert!(matches!(
+            decode_compressed(0, 0x9502),
+            Ok(Instr::Jalr {
+                rd: 1,
+                rs1: 10,
+                off: 0
+            })
+        ));
+        assert!(matches!(
+            decode_compressed(0, 0x852e),
+            Ok(Instr::Add {
+                rd: 10,
+                rs1: 0,
+                rs2: 11
+            })
+        ));
+        assert!(matches!(
+            decode_compressed(0, 0x952e),
+            Ok(Instr::Add {
+                rd: 10,
+                rs1: 10,
+                rs2: 11
+            })
+        ));
+        assert!(matches!(decode_compressed(0, 0x9002), Ok(Instr::Ebreak)));
+    }
+
+    #[test]
+    fn test_f_extension_decode() {
+        // flw f1, 8(x5) / fsw f6, -4(x5)
+        let flw = (8 << 20) | (5 << 15) | (0x2 << 12) | (1 << 7) | 0b0000111;
+        assert!(matches!(
+            decode(0, flw),
+            Ok(Instr::Flw {
+                rd: 1,
+                rs1: 5,
+                off: 8
+            })
+        ));
+        let fsw = (0x7f << 25) | (6 << 20) | (5 << 15) | (0x2 << 12) | (0x1c << 7) | 0b0100111;
+        assert!(matches!(
+            decode(0, fsw),
+            Ok(Instr::Fsw {
+                rs1: 5,
+                rs2: 6,
+                off: -4
+            })
+        ));
+
+        // op_reg puts the rounding mode in funct3
+        let op_fp = |funct7, rm| op_reg(0b1010011, funct7, rm);
+        assert!(matches!(
+            decode(0, op_fp(0x00, 0b111)),
+            Ok(Instr::FaddS {
+                rd: 7,
+                rs1: 5,
+                rs2: 6,
+                rm: 0b111
+            })
+        ));
+        assert!(matches!(
+            decode(0, op_fp(0x0c, 0b001)),
+            Ok(Instr::FdivS { rm: 0b001, .. })
+        ));
+        // fsqrt.s requires rs2 = 0
+        assert!(decode(0, op_fp(0x2c, 0)).is_err());
+        let fsqrt = op_fp(0x2c, 0) & !(0x1f << 20);
+        assert!(matches!(
+            decode(0, fsqrt),
+            Ok(Instr::FsqrtS {
+                rd: 7,
+                rs1: 5,
+                rm: 0
+            })
+        ));
+    }
+
+    #[test]
+    fn test_d_extension_decode() {
+        let op_fp =
+            |funct7, rs2: u32, rm| op_reg(0b1010011, funct7, rm) & !(0x1f << 20) | (rs2 << 20);
+        assert!(matches!(
+            decode(0, op_fp(0x01, 6, 0)),
+            Ok(Instr::FaddD {
+                rd: 7,
+                rs1: 5,
+                rs2: 6,
+                rm: 0
+            })
+        ));
+        assert!(matches!(
+            decode(0, op_fp(0x2d, 0, 0)),
+            Ok(Instr::FsqrtD { .. })
+        ));
+        assert!(matches!(
+            decode(0, op_fp(0x20, 1, 0)),
+            Ok(Instr::FcvtSD { .. })
+        ));
+        assert!(matches!(
+            decode(0, op_fp(0x21, 0, 0)),
+            Ok(Instr::FcvtDS { .. })
+        ));
+        assert!(matches!(
+            decode(0, op_fp(0x61, 3, 1)),
+            Ok(Instr::FcvtLuD {
+                rd: 7,
+                rs1: 5,
+                rm: 1
+            })
+        ));
+        assert!(matches!(
+            decode(0, op_fp(0x69, 1, 0)),
+            Ok(Instr::FcvtDWu { .. })
+        ));
+        assert!(matches!(
+            decode(0, op_fp(0x71, 0, 0)),
+            Ok(Instr::FmvXD { rd: 7, rs1: 5 })
+        ));
+        assert!(matches!(
+            decode(0, op_fp(0x79, 0, 0)),
+            Ok(Instr::FmvDX { rd: 7, rs1: 5 })
+        ));
+        // fmv takes no rounding mode, and fcvt.s.d names its source as rs2 = 1
+        assert!(decode(0, op_fp(0x79, 0, 1)).is_err());
+        assert!(decode(0, op_fp(0x20, 0, 0)).is_err());
+
+        let fld = (16 << 20) | (2 << 15) | (0x3 << 12) | (8 << 7) | 0b0000111;
+        assert!(matches!(
+            decode(0, fld),
+            Ok(Instr::Fld {
+                rd: 8,
+                rs1: 2,
+                off: 16
+            })
+        ));
+    }
+
+    #[test]
+    fn test_fp_compare_and_classify_decode() {
+        let op_fp = |funct7, funct3| op_reg(0b1010011, funct7, funct3);
+        assert!(matches!(
+            decode(0, op_fp(0x50, 0x2)),
+            Ok(Instr::FeqS {
+                rd: 7,
+                rs1: 5,
+                rs2: 6
+            })
+        ));
+        assert!(matches!(
+            decode(0, op_fp(0x50, 0x1)),
+            Ok(Instr::FltS { .. })
+        ));
+        assert!(matches!(
+            decode(0, op_fp(0x51, 0x0)),
+            Ok(Instr::FleD { .. })
+        ));
+        assert!(decode(0, op_fp(0x51, 0x3)).is_err());
+
+        let no_rs2 = |funct7, funct3| op_fp(funct7, funct3) & !(0x1f << 20);
+        assert!(matches!(
+            decode(0, no_rs2(0x70, 0x1)),
+            Ok(Instr::FclassS { rd: 7, rs1: 5 })
+        ));
+        assert!(matches!(
+            decode(0, no_rs2(0x71, 0x1)),
+            Ok(Instr::FclassD { .. })
+        ));
+        assert!(matches!(
+            decode(0, no_rs2(0x71, 0x0)),
+            Ok(Instr::FmvXD { .. })
+        ));
+    }
+
+    #[test]
+    fn test_sign_injection_and_min_max_decode() {
+        let op_fp = |funct7, funct3| op_reg(0b1010011, funct7, funct3);
+        assert!(matches!(
+            decode(0, op_fp(0x10, 0x1)),
+            Ok(Instr::FsgnjnS {
+                rd: 7,
+                rs1: 5,
+                rs2: 6
+            })
+        ));
+        assert!(matches!(
+            decode(0, op_fp(0x11, 0x2)),
+            Ok(Instr::FsgnjxD { .. })
+        ));
+        assert!(matches!(
+            decode(0, op_fp(0x15, 0x1)),
+            Ok(Instr::FmaxD { .. })
+        ));
+        assert!(decode(0, op_fp(0x10, 0x3)).is_err());
+        assert!(decode(0, op_fp(0x14, 0x2)).is_err());
+    }
+
+    #[test]
+    fn test_fused_multiply_add_decode() {
+        // Encodings from llvm-mc: f7 = f5 * f6 +/- f8
+        assert!(matches!(
+            decode(0, 0x4062_83c3), // fmadd.s ft7, ft5, ft6, fs0, rne
+            Ok(Instr::FmaddS {
+                rd: 7,
+                rs1: 5,
+                rs2: 6,
+                rs3: 8,
+                rm: 0
+            })
+        ));
+        assert!(matches!(
+            decode(0, 0x4062_93c7), // fmsub.s ..., rtz
+            Ok(Instr::FmsubS { rm: 1, .. })
+        ));
+        assert!(matches!(
+            decode(0, 0x4262_f3cb), // fnmsub.d ..., dyn
+            Ok(Instr::FnmsubD { rs3: 8, rm: 7, .. })
+        ));
+        assert!(matches!(
+            decode(0, 0x4262_a3cf), // fnmadd.d ..., rdn
+            Ok(Instr::FnmaddD { rm: 2, .. })
+        ));
+        // fmt = 0b10 (half) and 0b11 (quad) aren't supported
+        assert!(decode(0, 0x4462_83c3).is_err());
+        assert!(decode(0, 0x4662_83c3).is_err());
+    }
+}
@@ -0,0 +1,125 @@
+use crate::cpu::decode::Instr;
+use crate::csr::PrivMode;
+
+/// One decoded instruction and the translation context it was fetched under
+#[derive(Clone, Copy)]
+struct Entry {
+    pc: u64,
+    satp: u64,
+    priv_mode: PrivMode,
+    instr: Instr,
+    len: u64,
+}
+
+/// Direct-mapped cache from virtual pc to decoded instruction, so a hot loop
+/// is fetched and decoded once. Entries are tagged with satp and the
+/// privilege mode; `Machine` flushes the whole cache on FENCE.I, SFENCE.VMA,
+/// misa and PMP writes, and stores to a page holding cached code. Device DMA
+/// into RAM isn't tracked; as on hardware, code loaded that way needs a
+/// FENCE.I before it runs.
+pub struct DecodeCache {
+    entries: Vec<Option<Entry>>,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl DecodeCache {
+    /// Default number of entries
+    pub const DEFAULT_ENTRIES: usize = 4096;
+
+    /// A cache of `entries` slots, rounded up to a power of two.
+    pub fn new(entries: usize) -> Self {
+        Self {
+            entries: vec![None; entries.max(1).next_power_of_two()],
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    fn slot(&self, pc: u64) -> usize {
+        // pc is at least 2-byte aligned
+        (pc >> 1) as usize & (self.entries.len() - 1)
+    }
+
+    /// The instruction cached for `pc` and its length, if fetched under the
+    /// same satp and privilege mode.
+    pub fn get(&mut self, pc: u64, satp: u64, priv_mode: PrivMode) -> Option<(Instr, u64)> {
+        match self.entries[self.slot(pc)] {
+            Some(e) if e.pc == pc && e.satp == satp && e.priv_mode == priv_mode => {
+                self.hits += 1;
+                Some((e.instr, e.len))
+            }
+            _ => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    pub fn insert(&mut self, pc: u64, satp: u64, priv_mode: PrivMode, instr: Instr, len: u64) {
+        let slot = self.slot(pc);
+        self.entries[slot] = Some(Entry {
+            pc,
+            satp,
+            priv_mode,
+            instr,
+            len,
+        });
+    }
+
+    pub fn flush(&mut self) {
+        self.entries.fill(None);
+    }
+}
+
+impl Default for DecodeCache {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_ENTRIES)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::Machine;
+
+    const NOP: Instr = Instr::Addi {
+        rd: 0,
+        rs1: 0,
+        imm: 0,
+    };
+
+    #[test]
+    fn test_lookup_is_tagged_by_pc_satp_and_mode() {
+        let mut cache = DecodeCache::new(16);
+        cache.insert(0x8000_0000, 0, PrivMode::Machine, NOP, 4);
+        assert!(cache.get(0x8000_0000, 0, PrivMode::Machine).is_some());
+        assert!(cache.get(0x8000_0000, 0, PrivMode::Supervisor).is_none());
+        assert!(cache.get(0x8000_0000, 8 << 60, PrivMode::Machine).is_none());
+        // Same slot, different pc
+        assert!(cache.get(0x8000_0020, 0, PrivMode::Machine).is_none());
+        assert_eq!((cache.hits, cache.misses), (1, 3));
+
+        cache.flush();
+        assert!(cache.get(0x8000_0000, 0, PrivMode::Machine).is_none());
+    }
+
+    #[test]
+    fn test_store_to_cached_code_invalidates_it() {
+        // loop: addi a0, a0, 1; sw t1, 0(t0); j loop. The first store
+        // rewrites the addi into addi a0, a0, 2 with no FENCE.I.
+        let mut m = Machine::new(0x1000);
+        m.decode_cache = Some(DecodeCache::default());
+        m.mem.write_u32_phys(0x8000_0000, 0x0015_0513).unwrap();
+        m.mem.write_u32_phys(0x8000_0004, 0x0062_a023).unwrap();
+        m.mem.write_u32_phys(0x8000_0008, 0xff9f_f06f).unwrap();
+        m.cpu.set_reg(5, 0x8000_0000);
+        m.cpu.set_reg(6, 0x0025_0513);
+        m.cpu.pc = 0x8000_0000;
+
+        for _ in 0..6 {
+            m.step().unwrap();
+        }
+        assert_eq!(m.cpu.a0(), 1 + 2);
+    }
+}
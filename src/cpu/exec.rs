@@ -1,205 +1,250 @@
 use super::IntoCpuResult;
-use super::decode::Instr;
+use super::decode::{AmoOp, Instr};
+use super::fpu::{self, RoundingMode};
 use super::trap::{Trap, WithPc};
 use crate::cpu::{Cpu, CpuStepResult};
 use crate::mem::Memory;
 use crate::mmu::Mmu;
+use std::io::Write;
 
+/// Side effects of a successful CSR write: a satp write flushes the TLB, and a
+/// misa write that clears C is dropped entirely, restoring `old_misa`, if the
+/// next instruction is only 2-byte aligned, as it would then be unreachable.
+fn csr_written(cpu: &mut Cpu, mmu: &mut Mmu, csr: u16, next_pc: u64, old_misa: u64) {
+    match csr {
+        0x180 => mmu.flush_tlb(None, None),
+        0x301 if !cpu.csr.ext_enabled(b'C') && !next_pc.is_multiple_of(4) => {
+            cpu.csr.misa = old_misa;
+        }
+        _ => {}
+    }
+}
+
+/// Per-instruction state shared by the `execute_*` helpers
+#[derive(Clone, Copy)]
+struct Ctx {
+    pc: u64,
+    next_pc: u64,
+    satp: u64,
+    mstatus: u64,
+    /// Privilege for loads and stores, adjusted for MPRV
+    priv_mode: crate::csr::PrivMode,
+}
+
+fn sign_extend(val: i64, bits: u32) -> i64 {
+    let shift = 64 - bits;
+    (val << shift) >> shift
+}
+
+/// Execute a full-width (4-byte) instruction.
 pub fn execute(
     cpu: &mut Cpu,
     mem: &mut Memory,
     mmu: &mut Mmu,
     instr: Instr,
     host_exit_addr: Option<u64>,
+) -> Result<(), CpuStepResult> {
+    execute_with_len(cpu, mem, mmu, instr, 4, host_exit_addr)
+}
+
+/// Execute an instruction that was encoded in `len` bytes (2 for RVC, else 4).
+/// The length sets the fall-through pc and the link address of jumps.
+pub fn execute_with_len(
+    cpu: &mut Cpu,
+    mem: &mut Memory,
+    mmu: &mut Mmu,
+    instr: Instr,
+    len: u64,
+    host_exit_addr: Option<u64>,
 ) -> Result<(), CpuStepResult> {
     let pc = cpu.pc;
+    let next_pc = pc.wrapping_add(len);
     let satp = cpu.csr.satp;
-    let priv_mode = cpu.csr.priv_mode;
-
-    let r = |cpu: &Cpu, idx: u8| -> u64 { cpu.regs[idx as usize] };
-    let w = |cpu: &mut Cpu, idx: u8, val: u64| {
-        if idx != 0 {
-            cpu.regs[idx as usize] = val;
-        } // x0 hardwired
-    };
+    let mstatus = cpu.csr.mstatus;
+    // Loads and stores use the MPRV-adjusted privilege
+    let priv_mode = cpu.csr.data_priv_mode();
 
-    let sign_extend = |val: i64, bits: u32| -> i64 {
-        let shift = 64 - bits;
-        (val << shift) >> shift
+    let ctx = Ctx {
+        pc,
+        next_pc,
+        satp,
+        mstatus,
+        priv_mode,
     };
 
     match instr {
-        Instr::Addi { rd, rs1, imm } => {
-            w(cpu, rd, r(cpu, rs1).wrapping_add(imm as u64));
-            cpu.pc = pc.wrapping_add(4);
-        }
-        Instr::Add { rd, rs1, rs2 } => {
-            w(cpu, rd, r(cpu, rs1).wrapping_add(r(cpu, rs2)));
-            cpu.pc = pc.wrapping_add(4);
-        }
-        Instr::Sub { rd, rs1, rs2 } => {
-            w(cpu, rd, r(cpu, rs1).wrapping_sub(r(cpu, rs2)));
-            cpu.pc = pc.wrapping_add(4);
-        }
-        Instr::Beq { rs1, rs2, off } => {
-            cpu.pc = if r(cpu, rs1) == r(cpu, rs2) {
-                pc.wrapping_add(off as u64)
-            } else {
-                pc.wrapping_add(4)
-            };
-        }
-        Instr::Bne { rs1, rs2, off } => {
-            cpu.pc = if r(cpu, rs1) != r(cpu, rs2) {
-                pc.wrapping_add(off as u64)
-            } else {
-                pc.wrapping_add(4)
-            };
-        }
+        // Integer computation
         Instr::Lui { rd, imm } => {
-            w(cpu, rd, imm as u64);
-            cpu.pc = pc.wrapping_add(4);
-        }
-        Instr::Jal { rd, off } => {
-            w(cpu, rd, pc.wrapping_add(4));
-            cpu.pc = pc.wrapping_add(off as u64);
+            cpu.set_reg(rd, imm as u64);
+            cpu.pc = next_pc;
         }
-        Instr::LB { rd, rs1, off } => {
-            let addr = r(cpu, rs1).wrapping_add(off as u64);
-            let byte = mem
-                .read_u8(addr, satp, priv_mode, mmu)
-                .with_pc(pc)
-                .into_cpu_result()?;
-            let value = sign_extend(byte as i64, 8) as u64;
-            w(cpu, rd, value);
-            cpu.pc = pc.wrapping_add(4);
+        Instr::Auipc { rd, imm } => {
+            cpu.set_reg(rd, pc.wrapping_add(imm as u64));
+            cpu.pc = next_pc;
         }
-        Instr::LBU { rd, rs1, off } => {
-            let addr = r(cpu, rs1).wrapping_add(off as u64);
-            let byte = mem
-                .read_u8(addr, satp, priv_mode, mmu)
-                .with_pc(pc)
-                .into_cpu_result()?;
-            let value = byte as u64; // Zero-extend from 8 to 64 bits
-            w(cpu, rd, value);
-            cpu.pc = pc.wrapping_add(4);
+        Instr::Addi { rd, rs1, imm } => {
+            cpu.set_reg(rd, cpu.reg(rs1).wrapping_add(imm as u64));
+            cpu.pc = next_pc;
         }
-        Instr::LH { rd, rs1, off } => {
-            let addr = r(cpu, rs1).wrapping_add(off as u64);
-            let half = mem
-                .read_u16(addr, satp, priv_mode, mmu)
-                .with_pc(pc)
-                .into_cpu_result()?;
-            let value = sign_extend(half as i64, 16) as u64;
-            w(cpu, rd, value);
-            cpu.pc = pc.wrapping_add(4);
+        Instr::Slti { rd, rs1, imm } => {
+            cpu.set_reg(rd, if (cpu.reg(rs1) as i64) < imm { 1 } else { 0 });
+            cpu.pc = next_pc;
         }
-        Instr::LHU { rd, rs1, off } => {
-            let addr = r(cpu, rs1).wrapping_add(off as u64);
-            let half = mem
-                .read_u16(addr, satp, priv_mode, mmu)
-                .with_pc(pc)
-                .into_cpu_result()?;
-            let value = half as u64;
-            w(cpu, rd, value);
-            cpu.pc = pc.wrapping_add(4);
+        Instr::Sltiu { rd, rs1, imm } => {
+            cpu.set_reg(rd, if cpu.reg(rs1) < (imm as u64) { 1 } else { 0 });
+            cpu.pc = next_pc;
         }
-        Instr::LD { rd, rs1, off } => {
-            let addr = r(cpu, rs1).wrapping_add(off as u64);
-            let word = mem
-                .read_u64(addr, satp, priv_mode, mmu)
-                .with_pc(pc)
-                .into_cpu_result()?;
-            w(cpu, rd, word);
-            cpu.pc = pc.wrapping_add(4);
+        Instr::Xori { rd, rs1, imm } => {
+            cpu.set_reg(rd, cpu.reg(rs1) ^ (imm as u64));
+            cpu.pc = next_pc;
         }
-        Instr::SB { rs1, rs2, off } => {
-            let addr = r(cpu, rs1).wrapping_add(off as u64);
-            let byte = (r(cpu, rs2) & 0xff) as u8;
-            mem.write_u8(addr, byte, satp, priv_mode, mmu)
-                .with_pc(pc)
-                .into_cpu_result()?;
-            cpu.pc = pc.wrapping_add(4);
+        Instr::Ori { rd, rs1, imm } => {
+            cpu.set_reg(rd, cpu.reg(rs1) | (imm as u64));
+            cpu.pc = next_pc;
         }
-        Instr::Xor { rd, rs1, rs2 } => {
-            w(cpu, rd, r(cpu, rs1) ^ r(cpu, rs2));
-            cpu.pc = pc.wrapping_add(4);
+        Instr::Andi { rd, rs1, imm } => {
+            cpu.set_reg(rd, cpu.reg(rs1) & (imm as u64));
+            cpu.pc = next_pc;
         }
-        Instr::Or { rd, rs1, rs2 } => {
-            w(cpu, rd, r(cpu, rs1) | r(cpu, rs2));
-            cpu.pc = pc.wrapping_add(4);
+        Instr::Slli { rd, rs1, shamt } => {
+            cpu.set_reg(rd, cpu.reg(rs1).wrapping_shl((shamt & 0x3f) as u32));
+            cpu.pc = next_pc;
         }
-        Instr::And { rd, rs1, rs2 } => {
-            w(cpu, rd, r(cpu, rs1) & r(cpu, rs2));
-            cpu.pc = pc.wrapping_add(4);
+        Instr::Srli { rd, rs1, shamt } => {
+            cpu.set_reg(rd, cpu.reg(rs1).wrapping_shr((shamt & 0x3f) as u32));
+            cpu.pc = next_pc;
         }
-        Instr::Sll { rd, rs1, rs2 } => {
-            w(
-                cpu,
+        Instr::Srai { rd, rs1, shamt } => {
+            cpu.set_reg(
                 rd,
-                r(cpu, rs1).wrapping_shl((r(cpu, rs2) & 0x3f) as u32),
+                ((cpu.reg(rs1) as i64) >> ((shamt & 0x3f) as u32)) as u64,
             );
-            cpu.pc = pc.wrapping_add(4);
+            cpu.pc = next_pc;
         }
-        Instr::Srl { rd, rs1, rs2 } => {
-            w(
-                cpu,
-                rd,
-                r(cpu, rs1).wrapping_shr((r(cpu, rs2) & 0x3f) as u32),
-            );
-            cpu.pc = pc.wrapping_add(4);
+        Instr::Add { rd, rs1, rs2 } => {
+            cpu.set_reg(rd, cpu.reg(rs1).wrapping_add(cpu.reg(rs2)));
+            cpu.pc = next_pc;
         }
-        Instr::Sra { rd, rs1, rs2 } => {
-            w(
-                cpu,
-                rd,
-                ((r(cpu, rs1) as i64) >> ((r(cpu, rs2) & 0x3f) as u32)) as u64,
-            );
-            cpu.pc = pc.wrapping_add(4);
+        Instr::Sub { rd, rs1, rs2 } => {
+            cpu.set_reg(rd, cpu.reg(rs1).wrapping_sub(cpu.reg(rs2)));
+            cpu.pc = next_pc;
+        }
+        Instr::Sll { rd, rs1, rs2 } => {
+            cpu.set_reg(rd, cpu.reg(rs1).wrapping_shl((cpu.reg(rs2) & 0x3f) as u32));
+            cpu.pc = next_pc;
         }
         Instr::Slt { rd, rs1, rs2 } => {
-            w(
-                cpu,
+            cpu.set_reg(
                 rd,
-                if (r(cpu, rs1) as i64) < (r(cpu, rs2) as i64) {
+                if (cpu.reg(rs1) as i64) < (cpu.reg(rs2) as i64) {
                     1
                 } else {
                     0
                 },
             );
-            cpu.pc = pc.wrapping_add(4);
+            cpu.pc = next_pc;
         }
         Instr::Sltu { rd, rs1, rs2 } => {
-            w(cpu, rd, if r(cpu, rs1) < r(cpu, rs2) { 1 } else { 0 });
-            cpu.pc = pc.wrapping_add(4);
+            cpu.set_reg(rd, if cpu.reg(rs1) < cpu.reg(rs2) { 1 } else { 0 });
+            cpu.pc = next_pc;
+        }
+        Instr::Xor { rd, rs1, rs2 } => {
+            cpu.set_reg(rd, cpu.reg(rs1) ^ cpu.reg(rs2));
+            cpu.pc = next_pc;
+        }
+        Instr::Srl { rd, rs1, rs2 } => {
+            cpu.set_reg(rd, cpu.reg(rs1).wrapping_shr((cpu.reg(rs2) & 0x3f) as u32));
+            cpu.pc = next_pc;
+        }
+        Instr::Sra { rd, rs1, rs2 } => {
+            cpu.set_reg(
+                rd,
+                ((cpu.reg(rs1) as i64) >> ((cpu.reg(rs2) & 0x3f) as u32)) as u64,
+            );
+            cpu.pc = next_pc;
+        }
+        Instr::Or { rd, rs1, rs2 } => {
+            cpu.set_reg(rd, cpu.reg(rs1) | cpu.reg(rs2));
+            cpu.pc = next_pc;
+        }
+        Instr::And { rd, rs1, rs2 } => {
+            cpu.set_reg(rd, cpu.reg(rs1) & cpu.reg(rs2));
+            cpu.pc = next_pc;
+        }
+        Instr::Addiw { rd, rs1, imm } => {
+            let result = (cpu.reg(rs1) as i64).wrapping_add(imm);
+            cpu.set_reg(rd, sign_extend(result, 32) as u64);
+            cpu.pc = next_pc;
+        }
+        Instr::Slliw { rd, rs1, shamt } => {
+            let result = (cpu.reg(rs1) & 0xffff_ffff).wrapping_shl((shamt & 0x1f) as u32);
+            cpu.set_reg(rd, sign_extend(result as i64, 32) as u64);
+            cpu.pc = next_pc;
+        }
+        Instr::Srliw { rd, rs1, shamt } => {
+            let result = (cpu.reg(rs1) & 0xffff_ffff).wrapping_shr((shamt & 0x1f) as u32);
+            cpu.set_reg(rd, sign_extend(result as i64, 32) as u64);
+            cpu.pc = next_pc;
+        }
+        Instr::Sraiw { rd, rs1, shamt } => {
+            let result =
+                ((cpu.reg(rs1) & 0xffff_ffff) as i32).wrapping_shr((shamt & 0x1f) as u32) as u32;
+            cpu.set_reg(rd, sign_extend(result as i64, 32) as u64);
+            cpu.pc = next_pc;
+        }
+        Instr::Addw { rd, rs1, rs2 } => {
+            let result = (cpu.reg(rs1) as i32).wrapping_add(cpu.reg(rs2) as i32);
+            cpu.set_reg(rd, sign_extend(result as i64, 32) as u64);
+            cpu.pc = next_pc;
+        }
+        Instr::Subw { rd, rs1, rs2 } => {
+            let result = (cpu.reg(rs1) as i32).wrapping_sub(cpu.reg(rs2) as i32);
+            cpu.set_reg(rd, sign_extend(result as i64, 32) as u64);
+            cpu.pc = next_pc;
+        }
+        Instr::Sllw { rd, rs1, rs2 } => {
+            let result = (cpu.reg(rs1) as u32).wrapping_shl((cpu.reg(rs2) & 0x1f) as u32);
+            cpu.set_reg(rd, sign_extend(result as i64, 32) as u64);
+            cpu.pc = next_pc;
+        }
+        Instr::Srlw { rd, rs1, rs2 } => {
+            let result = (cpu.reg(rs1) as u32).wrapping_shr((cpu.reg(rs2) & 0x1f) as u32);
+            cpu.set_reg(rd, sign_extend(result as i64, 32) as u64);
+            cpu.pc = next_pc;
+        }
+        Instr::Sraw { rd, rs1, rs2 } => {
+            let result = (cpu.reg(rs1) as i32).wrapping_shr((cpu.reg(rs2) & 0x1f) as u32);
+            cpu.set_reg(rd, sign_extend(result as i64, 32) as u64);
+            cpu.pc = next_pc;
         }
         Instr::Mul { rd, rs1, rs2 } => {
-            w(cpu, rd, r(cpu, rs1).wrapping_mul(r(cpu, rs2)));
-            cpu.pc = pc.wrapping_add(4);
+            cpu.set_reg(rd, cpu.reg(rs1).wrapping_mul(cpu.reg(rs2)));
+            cpu.pc = next_pc;
         }
         Instr::Mulh { rd, rs1, rs2 } => {
-            let lhs = r(cpu, rs1) as i64 as i128;
-            let rhs = r(cpu, rs2) as i64 as i128;
+            let lhs = cpu.reg(rs1) as i64 as i128;
+            let rhs = cpu.reg(rs2) as i64 as i128;
             let hi = (lhs.wrapping_mul(rhs) >> 64) as i64 as u64;
-            w(cpu, rd, hi);
-            cpu.pc = pc.wrapping_add(4);
+            cpu.set_reg(rd, hi);
+            cpu.pc = next_pc;
         }
         Instr::Mulhsu { rd, rs1, rs2 } => {
-            let lhs = r(cpu, rs1) as i64 as i128;
-            let rhs = r(cpu, rs2) as i128;
+            let lhs = cpu.reg(rs1) as i64 as i128;
+            let rhs = cpu.reg(rs2) as i128;
             let hi = (lhs.wrapping_mul(rhs) >> 64) as i64 as u64;
-            w(cpu, rd, hi);
-            cpu.pc = pc.wrapping_add(4);
+            cpu.set_reg(rd, hi);
+            cpu.pc = next_pc;
         }
         Instr::Mulhu { rd, rs1, rs2 } => {
-            let lhs = r(cpu, rs1) as u128;
-            let rhs = r(cpu, rs2) as u128;
+            let lhs = cpu.reg(rs1) as u128;
+            let rhs = cpu.reg(rs2) as u128;
             let hi = (lhs.wrapping_mul(rhs) >> 64) as u64;
-            w(cpu, rd, hi);
-            cpu.pc = pc.wrapping_add(4);
+            cpu.set_reg(rd, hi);
+            cpu.pc = next_pc;
         }
         Instr::Div { rd, rs1, rs2 } => {
-            let dividend = r(cpu, rs1) as i64;
-            let divisor = r(cpu, rs2) as i64;
+            let dividend = cpu.reg(rs1) as i64;
+            let divisor = cpu.reg(rs2) as i64;
             let result = if divisor == 0 {
                 -1i64
             } else if dividend == i64::MIN && divisor == -1 {
@@ -207,23 +252,23 @@ pub fn execute(
             } else {
                 dividend.wrapping_div(divisor)
             };
-            w(cpu, rd, result as u64);
-            cpu.pc = pc.wrapping_add(4);
+            cpu.set_reg(rd, result as u64);
+            cpu.pc = next_pc;
         }
         Instr::Divu { rd, rs1, rs2 } => {
-            let dividend = r(cpu, rs1);
-            let divisor = r(cpu, rs2);
+            let dividend = cpu.reg(rs1);
+            let divisor = cpu.reg(rs2);
             let result = if divisor == 0 {
                 u64::MAX
             } else {
                 dividend.wrapping_div(divisor)
             };
-            w(cpu, rd, result);
-            cpu.pc = pc.wrapping_add(4);
+            cpu.set_reg(rd, result);
+            cpu.pc = next_pc;
         }
         Instr::Rem { rd, rs1, rs2 } => {
-            let dividend = r(cpu, rs1) as i64;
-            let divisor = r(cpu, rs2) as i64;
+            let dividend = cpu.reg(rs1) as i64;
+            let divisor = cpu.reg(rs2) as i64;
             let result = if divisor == 0 {
                 dividend
             } else if dividend == i64::MIN && divisor == -1 {
@@ -231,223 +276,28 @@ pub fn execute(
             } else {
                 dividend.wrapping_rem(divisor)
             };
-            w(cpu, rd, result as u64);
-            cpu.pc = pc.wrapping_add(4);
+            cpu.set_reg(rd, result as u64);
+            cpu.pc = next_pc;
         }
         Instr::Remu { rd, rs1, rs2 } => {
-            let dividend = r(cpu, rs1);
-            let divisor = r(cpu, rs2);
+            let dividend = cpu.reg(rs1);
+            let divisor = cpu.reg(rs2);
             let result = if divisor == 0 {
                 dividend
             } else {
                 dividend.wrapping_rem(divisor)
             };
-            w(cpu, rd, result);
-            cpu.pc = pc.wrapping_add(4);
-        }
-        Instr::Xori { rd, rs1, imm } => {
-            w(cpu, rd, r(cpu, rs1) ^ (imm as u64));
-            cpu.pc = pc.wrapping_add(4);
-        }
-        Instr::Ori { rd, rs1, imm } => {
-            w(cpu, rd, r(cpu, rs1) | (imm as u64));
-            cpu.pc = pc.wrapping_add(4);
-        }
-        Instr::Andi { rd, rs1, imm } => {
-            w(cpu, rd, r(cpu, rs1) & (imm as u64));
-            cpu.pc = pc.wrapping_add(4);
-        }
-        Instr::Slli { rd, rs1, shamt } => {
-            w(cpu, rd, r(cpu, rs1).wrapping_shl((shamt & 0x3f) as u32));
-            cpu.pc = pc.wrapping_add(4);
-        }
-        Instr::Srli { rd, rs1, shamt } => {
-            w(cpu, rd, r(cpu, rs1).wrapping_shr((shamt & 0x3f) as u32));
-            cpu.pc = pc.wrapping_add(4);
-        }
-        Instr::Srai { rd, rs1, shamt } => {
-            w(
-                cpu,
-                rd,
-                ((r(cpu, rs1) as i64) >> ((shamt & 0x3f) as u32)) as u64,
-            );
-            cpu.pc = pc.wrapping_add(4);
-        }
-        Instr::Slti { rd, rs1, imm } => {
-            w(
-                cpu,
-                rd,
-                if (r(cpu, rs1) as i64) < (imm as i64) {
-                    1
-                } else {
-                    0
-                },
-            );
-            cpu.pc = pc.wrapping_add(4);
-        }
-        Instr::Sltiu { rd, rs1, imm } => {
-            w(cpu, rd, if r(cpu, rs1) < (imm as u64) { 1 } else { 0 });
-            cpu.pc = pc.wrapping_add(4);
-        }
-        Instr::LW { rd, rs1, off } => {
-            let addr = r(cpu, rs1).wrapping_add(off as u64);
-            let word = mem
-                .read_u32(addr, satp, priv_mode, mmu)
-                .with_pc(pc)
-                .into_cpu_result()?;
-            let value = sign_extend(word as i64, 32) as u64;
-            w(cpu, rd, value);
-            cpu.pc = pc.wrapping_add(4);
-        }
-        Instr::SH { rs1, rs2, off } => {
-            let addr = r(cpu, rs1).wrapping_add(off as u64);
-            let half = (r(cpu, rs2) & 0xffff) as u16;
-            mem.write_u16(addr, half, satp, priv_mode, mmu)
-                .with_pc(pc)
-                .into_cpu_result()?;
-            cpu.pc = pc.wrapping_add(4);
-        }
-        Instr::SW { rs1, rs2, off } => {
-            let addr = r(cpu, rs1).wrapping_add(off as u64);
-            let word = (r(cpu, rs2) & 0xffff_ffff) as u32;
-            let paddr = mem
-                .translate_addr(addr, satp, false, true, priv_mode, mmu)
-                .with_pc(pc)
-                .into_cpu_result()?;
-
-            // Handle HTIF-like tohost writes using physical address so it works
-            // for both direct and virtual mappings.
-            if let Some(exit_addr) = host_exit_addr {
-                if paddr == exit_addr {
-                    let value = word as u64;
-                    let device = (value >> 56) & 0xff;
-                    let cmd = (value >> 48) & 0xff;
-                    if device == 0 && cmd == 0 && value != 0 {
-                        let gp = r(cpu, 3); // x3 is gp (global pointer)
-                        return Err(CpuStepResult::Halt(super::HaltReason::HostExit {
-                            code: value,
-                            gp,
-                        }));
-                    }
-
-                    // Non-exit host packet: consume immediately so guest polling
-                    // loops on tohost can make progress.
-                    mem.write_u64_phys(paddr, 0)
-                        .with_pc(pc)
-                        .into_cpu_result()?;
-                    cpu.pc = pc.wrapping_add(4);
-                    return Ok(());
-                }
-            }
-
-            mem.write_u32_phys(paddr, word)
-                .with_pc(pc)
-                .into_cpu_result()?;
-            cpu.pc = pc.wrapping_add(4);
-        }
-        Instr::Blt { rs1, rs2, off } => {
-            cpu.pc = if (r(cpu, rs1) as i64) < (r(cpu, rs2) as i64) {
-                pc.wrapping_add(off as u64)
-            } else {
-                pc.wrapping_add(4)
-            };
-        }
-        Instr::Bge { rs1, rs2, off } => {
-            cpu.pc = if (r(cpu, rs1) as i64) >= (r(cpu, rs2) as i64) {
-                pc.wrapping_add(off as u64)
-            } else {
-                pc.wrapping_add(4)
-            };
-        }
-        Instr::Bltu { rs1, rs2, off } => {
-            cpu.pc = if r(cpu, rs1) < r(cpu, rs2) {
-                pc.wrapping_add(off as u64)
-            } else {
-                pc.wrapping_add(4)
-            };
-        }
-        Instr::Bgeu { rs1, rs2, off } => {
-            cpu.pc = if r(cpu, rs1) >= r(cpu, rs2) {
-                pc.wrapping_add(off as u64)
-            } else {
-                pc.wrapping_add(4)
-            };
-        }
-        Instr::Jalr { rd, rs1, off } => {
-            let target = r(cpu, rs1).wrapping_add(off as u64) & !1;
-            w(cpu, rd, pc.wrapping_add(4));
-            cpu.pc = target;
-        }
-        Instr::Auipc { rd, imm } => {
-            w(cpu, rd, pc.wrapping_add(imm as u64));
-            cpu.pc = pc.wrapping_add(4);
-        }
-        Instr::Ecall => {
-            use crate::csr::PrivMode;
-            let trap = match cpu.csr.priv_mode {
-                PrivMode::User => Trap::Ecall { pc },
-                PrivMode::Supervisor => Trap::EcallFromS { pc },
-                PrivMode::Machine => Trap::EcallFromM { pc },
-            };
-            return Err(CpuStepResult::Trapped(trap));
-        }
-        Instr::Ebreak => {
-            return Err(CpuStepResult::Trapped(Trap::Breakpoint { pc }));
-        }
-        Instr::Addiw { rd, rs1, imm } => {
-            let result = (r(cpu, rs1) as i64).wrapping_add(imm as i64);
-            w(cpu, rd, sign_extend(result, 32) as u64);
-            cpu.pc = pc.wrapping_add(4);
-        }
-        Instr::Slliw { rd, rs1, shamt } => {
-            let result = (r(cpu, rs1) & 0xffff_ffff).wrapping_shl((shamt & 0x1f) as u32);
-            w(cpu, rd, sign_extend(result as i64, 32) as u64);
-            cpu.pc = pc.wrapping_add(4);
-        }
-        Instr::Srliw { rd, rs1, shamt } => {
-            let result = (r(cpu, rs1) & 0xffff_ffff).wrapping_shr((shamt & 0x1f) as u32);
-            w(cpu, rd, sign_extend(result as i64, 32) as u64);
-            cpu.pc = pc.wrapping_add(4);
-        }
-        Instr::Sraiw { rd, rs1, shamt } => {
-            let result =
-                ((r(cpu, rs1) & 0xffff_ffff) as i32).wrapping_shr((shamt & 0x1f) as u32) as u32;
-            w(cpu, rd, sign_extend(result as i64, 32) as u64);
-            cpu.pc = pc.wrapping_add(4);
-        }
-        Instr::Addw { rd, rs1, rs2 } => {
-            let result = (r(cpu, rs1) as i32).wrapping_add(r(cpu, rs2) as i32);
-            w(cpu, rd, sign_extend(result as i64, 32) as u64);
-            cpu.pc = pc.wrapping_add(4);
-        }
-        Instr::Subw { rd, rs1, rs2 } => {
-            let result = (r(cpu, rs1) as i32).wrapping_sub(r(cpu, rs2) as i32);
-            w(cpu, rd, sign_extend(result as i64, 32) as u64);
-            cpu.pc = pc.wrapping_add(4);
-        }
-        Instr::Sllw { rd, rs1, rs2 } => {
-            let result = (r(cpu, rs1) as u32).wrapping_shl((r(cpu, rs2) & 0x1f) as u32);
-            w(cpu, rd, sign_extend(result as i64, 32) as u64);
-            cpu.pc = pc.wrapping_add(4);
-        }
-        Instr::Srlw { rd, rs1, rs2 } => {
-            let result = (r(cpu, rs1) as u32).wrapping_shr((r(cpu, rs2) & 0x1f) as u32);
-            w(cpu, rd, sign_extend(result as i64, 32) as u64);
-            cpu.pc = pc.wrapping_add(4);
-        }
-        Instr::Sraw { rd, rs1, rs2 } => {
-            let result = ((r(cpu, rs1) as i32).wrapping_shr((r(cpu, rs2) & 0x1f) as u32)) as i32;
-            w(cpu, rd, sign_extend(result as i64, 32) as u64);
-            cpu.pc = pc.wrapping_add(4);
+            cpu.set_reg(rd, result);
+            cpu.pc = next_pc;
         }
         Instr::Mulw { rd, rs1, rs2 } => {
-            let result = (r(cpu, rs1) as u32).wrapping_mul(r(cpu, rs2) as u32);
-            w(cpu, rd, sign_extend(result as i64, 32) as u64);
-            cpu.pc = pc.wrapping_add(4);
+            let result = (cpu.reg(rs1) as u32).wrapping_mul(cpu.reg(rs2) as u32);
+            cpu.set_reg(rd, sign_extend(result as i64, 32) as u64);
+            cpu.pc = next_pc;
         }
         Instr::Divw { rd, rs1, rs2 } => {
-            let dividend = r(cpu, rs1) as i32;
-            let divisor = r(cpu, rs2) as i32;
+            let dividend = cpu.reg(rs1) as i32;
+            let divisor = cpu.reg(rs2) as i32;
             let result = if divisor == 0 {
                 -1i32
             } else if dividend == i32::MIN && divisor == -1 {
@@ -455,23 +305,23 @@ pub fn execute(
             } else {
                 dividend.wrapping_div(divisor)
             };
-            w(cpu, rd, sign_extend(result as i64, 32) as u64);
-            cpu.pc = pc.wrapping_add(4);
+            cpu.set_reg(rd, sign_extend(result as i64, 32) as u64);
+            cpu.pc = next_pc;
         }
         Instr::Divuw { rd, rs1, rs2 } => {
-            let dividend = r(cpu, rs1) as u32;
-            let divisor = r(cpu, rs2) as u32;
+            let dividend = cpu.reg(rs1) as u32;
+            let divisor = cpu.reg(rs2) as u32;
             let result = if divisor == 0 {
                 u32::MAX
             } else {
                 dividend.wrapping_div(divisor)
             };
-            w(cpu, rd, sign_extend(result as i64, 32) as u64);
-            cpu.pc = pc.wrapping_add(4);
+            cpu.set_reg(rd, sign_extend(result as i64, 32) as u64);
+            cpu.pc = next_pc;
         }
         Instr::Remw { rd, rs1, rs2 } => {
-            let dividend = r(cpu, rs1) as i32;
-            let divisor = r(cpu, rs2) as i32;
+            let dividend = cpu.reg(rs1) as i32;
+            let divisor = cpu.reg(rs2) as i32;
             let result = if divisor == 0 {
                 dividend
             } else if dividend == i32::MIN && divisor == -1 {
@@ -479,137 +329,543 @@ pub fn execute(
             } else {
                 dividend.wrapping_rem(divisor)
             };
-            w(cpu, rd, sign_extend(result as i64, 32) as u64);
-            cpu.pc = pc.wrapping_add(4);
+            cpu.set_reg(rd, sign_extend(result as i64, 32) as u64);
+            cpu.pc = next_pc;
         }
         Instr::Remuw { rd, rs1, rs2 } => {
-            let dividend = r(cpu, rs1) as u32;
-            let divisor = r(cpu, rs2) as u32;
+            let dividend = cpu.reg(rs1) as u32;
+            let divisor = cpu.reg(rs2) as u32;
             let result = if divisor == 0 {
                 dividend
             } else {
                 dividend.wrapping_rem(divisor)
             };
-            w(cpu, rd, sign_extend(result as i64, 32) as u64);
-            cpu.pc = pc.wrapping_add(4);
+            cpu.set_reg(rd, sign_extend(result as i64, 32) as u64);
+            cpu.pc = next_pc;
         }
-        Instr::LWU { rd, rs1, off } => {
-            let addr = r(cpu, rs1).wrapping_add(off as u64);
-            let word = mem
-                .read_u32(addr, satp, priv_mode, mmu)
+        // Control transfer
+        Instr::Jal { rd, off } => {
+            let target = jump_target(cpu, pc, pc.wrapping_add(off as u64))?;
+            cpu.set_reg(rd, next_pc);
+            cpu.pc = target;
+        }
+        Instr::Jalr { rd, rs1, off } => {
+            let target = cpu.reg(rs1).wrapping_add(off as u64) & !1;
+            let target = jump_target(cpu, pc, target)?;
+            cpu.set_reg(rd, next_pc);
+            cpu.pc = target;
+        }
+        Instr::Beq { rs1, rs2, off } => {
+            cpu.pc = if cpu.reg(rs1) == cpu.reg(rs2) {
+                jump_target(cpu, pc, pc.wrapping_add(off as u64))?
+            } else {
+                next_pc
+            };
+        }
+        Instr::Bne { rs1, rs2, off } => {
+            cpu.pc = if cpu.reg(rs1) != cpu.reg(rs2) {
+                jump_target(cpu, pc, pc.wrapping_add(off as u64))?
+            } else {
+                next_pc
+            };
+        }
+        Instr::Blt { rs1, rs2, off } => {
+            cpu.pc = if (cpu.reg(rs1) as i64) < (cpu.reg(rs2) as i64) {
+                jump_target(cpu, pc, pc.wrapping_add(off as u64))?
+            } else {
+                next_pc
+            };
+        }
+        Instr::Bge { rs1, rs2, off } => {
+            cpu.pc = if (cpu.reg(rs1) as i64) >= (cpu.reg(rs2) as i64) {
+                jump_target(cpu, pc, pc.wrapping_add(off as u64))?
+            } else {
+                next_pc
+            };
+        }
+        Instr::Bltu { rs1, rs2, off } => {
+            cpu.pc = if cpu.reg(rs1) < cpu.reg(rs2) {
+                jump_target(cpu, pc, pc.wrapping_add(off as u64))?
+            } else {
+                next_pc
+            };
+        }
+        Instr::Bgeu { rs1, rs2, off } => {
+            cpu.pc = if cpu.reg(rs1) >= cpu.reg(rs2) {
+                jump_target(cpu, pc, pc.wrapping_add(off as u64))?
+            } else {
+                next_pc
+            };
+        }
+        // Loads and stores
+        Instr::LB { rd, rs1, off } => {
+            let addr = cpu.reg(rs1).wrapping_add(off as u64);
+            let byte = mem
+                .read_u8(addr, satp, priv_mode, mstatus, mmu)
                 .with_pc(pc)
                 .into_cpu_result()?;
-            let value = word as u64;
-            w(cpu, rd, value);
-            cpu.pc = pc.wrapping_add(4);
+            let value = sign_extend(byte as i64, 8) as u64;
+            cpu.set_reg(rd, value);
+            cpu.pc = next_pc;
         }
-        Instr::SD { rs1, rs2, off } => {
-            let addr = r(cpu, rs1).wrapping_add(off as u64);
-            let value = r(cpu, rs2);
-            let paddr = mem
-                .translate_addr(addr, satp, false, true, priv_mode, mmu)
+        Instr::LH { rd, rs1, off } => {
+            let addr = cpu.reg(rs1).wrapping_add(off as u64);
+            let half = mem
+                .read_u16(addr, satp, priv_mode, mstatus, mmu)
                 .with_pc(pc)
                 .into_cpu_result()?;
-
-            if let Some(exit_addr) = host_exit_addr {
-                if paddr == exit_addr {
-                    let device = (value >> 56) & 0xff;
-                    let cmd = (value >> 48) & 0xff;
-                    if device == 0 && cmd == 0 && value != 0 {
-                        let gp = r(cpu, 3); // x3 is gp (global pointer)
-                        return Err(CpuStepResult::Halt(super::HaltReason::HostExit {
-                            code: value,
-                            gp,
-                        }));
-                    }
-
-                    mem.write_u64_phys(paddr, 0)
-                        .with_pc(pc)
-                        .into_cpu_result()?;
-                    cpu.pc = pc.wrapping_add(4);
-                    return Ok(());
-                }
-            }
-
-            mem.write_u64_phys(paddr, value)
+            let value = sign_extend(half as i64, 16) as u64;
+            cpu.set_reg(rd, value);
+            cpu.pc = next_pc;
+        }
+        Instr::LW { rd, rs1, off } => {
+            let addr = cpu.reg(rs1).wrapping_add(off as u64);
+            let word = mem
+                .read_u32(addr, satp, priv_mode, mstatus, mmu)
+                .with_pc(pc)
+                .into_cpu_result()?;
+            let value = sign_extend(word as i64, 32) as u64;
+            cpu.set_reg(rd, value);
+            cpu.pc = next_pc;
+        }
+        Instr::LD { rd, rs1, off } => {
+            let addr = cpu.reg(rs1).wrapping_add(off as u64);
+            let word = mem
+                .read_u64(addr, satp, priv_mode, mstatus, mmu)
+                .with_pc(pc)
+                .into_cpu_result()?;
+            cpu.set_reg(rd, word);
+            cpu.pc = next_pc;
+        }
+        Instr::LBU { rd, rs1, off } => {
+            let addr = cpu.reg(rs1).wrapping_add(off as u64);
+            let byte = mem
+                .read_u8(addr, satp, priv_mode, mstatus, mmu)
+                .with_pc(pc)
+                .into_cpu_result()?;
+            let value = byte as u64; // Zero-extend from 8 to 64 bits
+            cpu.set_reg(rd, value);
+            cpu.pc = next_pc;
+        }
+        Instr::LHU { rd, rs1, off } => {
+            let addr = cpu.reg(rs1).wrapping_add(off as u64);
+            let half = mem
+                .read_u16(addr, satp, priv_mode, mstatus, mmu)
+                .with_pc(pc)
+                .into_cpu_result()?;
+            let value = half as u64;
+            cpu.set_reg(rd, value);
+            cpu.pc = next_pc;
+        }
+        Instr::LWU { rd, rs1, off } => {
+            let addr = cpu.reg(rs1).wrapping_add(off as u64);
+            let word = mem
+                .read_u32(addr, satp, priv_mode, mstatus, mmu)
+                .with_pc(pc)
+                .into_cpu_result()?;
+            let value = word as u64;
+            cpu.set_reg(rd, value);
+            cpu.pc = next_pc;
+        }
+        Instr::SB { rs1, rs2, off } => {
+            let addr = cpu.reg(rs1).wrapping_add(off as u64);
+            let byte = (cpu.reg(rs2) & 0xff) as u8;
+            mem.write_u8(addr, byte, satp, priv_mode, mstatus, mmu)
+                .with_pc(pc)
+                .into_cpu_result()?;
+            invalidate_reservation(cpu, addr, 1);
+            cpu.pc = next_pc;
+        }
+        Instr::SH { rs1, rs2, off } => {
+            let addr = cpu.reg(rs1).wrapping_add(off as u64);
+            let half = (cpu.reg(rs2) & 0xffff) as u16;
+            mem.write_u16(addr, half, satp, priv_mode, mstatus, mmu)
+                .with_pc(pc)
+                .into_cpu_result()?;
+            invalidate_reservation(cpu, addr, 2);
+            cpu.pc = next_pc;
+        }
+        Instr::SW { rs1, rs2, off } => {
+            let addr = cpu.reg(rs1).wrapping_add(off as u64);
+            let word = (cpu.reg(rs2) & 0xffff_ffff) as u32;
+            mem.check_alignment(addr, 4, true)
+                .with_pc(pc)
+                .into_cpu_result()?;
+            let paddr = mem
+                .translate_addr(addr, satp, false, true, priv_mode, mstatus, mmu)
                 .with_pc(pc)
                 .into_cpu_result()?;
-            cpu.pc = pc.wrapping_add(4);
+
+            // Handle HTIF tohost writes using physical address so it works
+            // for both direct and virtual mappings.
+            if host_exit_addr == Some(paddr) {
+                htif_tohost(mem, pc, paddr, word as u64, cpu.reg(3))?;
+                cpu.pc = next_pc;
+                return Ok(());
+            }
+
+            // A misaligned store split across pages translates each part
+            if crate::mem::crosses_page(addr, 4) {
+                mem.write_u32(addr, word, satp, priv_mode, mstatus, mmu)
+            } else {
+                mem.check_pmp(paddr, 4, false, true, priv_mode, addr)
+                    .and_then(|()| mem.write_u32_phys(paddr, data_order(priv_mode, mstatus, word)))
+                    .map_err(|err| err.into_access_fault(addr, false, true))
+            }
+            .with_pc(pc)
+            .into_cpu_result()?;
+            invalidate_reservation(cpu, addr, 4);
+            cpu.pc = next_pc;
         }
+        Instr::SD { rs1, rs2, off } => {
+            let addr = cpu.reg(rs1).wrapping_add(off as u64);
+            let value = cpu.reg(rs2);
+            mem.check_alignment(addr, 8, true)
+                .with_pc(pc)
+                .into_cpu_result()?;
+            let paddr = mem
+                .translate_addr(addr, satp, false, true, priv_mode, mstatus, mmu)
+                .with_pc(pc)
+                .into_cpu_result()?;
+
+            if host_exit_addr == Some(paddr) {
+                htif_tohost(mem, pc, paddr, value, cpu.reg(3))?;
+                cpu.pc = next_pc;
+                return Ok(());
+            }
+
+            // A misaligned store split across pages translates each part
+            if crate::mem::crosses_page(addr, 8) {
+                mem.write_u64(addr, value, satp, priv_mode, mstatus, mmu)
+            } else {
+                mem.check_pmp(paddr, 8, false, true, priv_mode, addr)
+                    .and_then(|()| mem.write_u64_phys(paddr, data_order(priv_mode, mstatus, value)))
+                    .map_err(|err| err.into_access_fault(addr, false, true))
+            }
+            .with_pc(pc)
+            .into_cpu_result()?;
+            invalidate_reservation(cpu, addr, 8);
+            cpu.pc = next_pc;
+        }
+        // Everything else is rarer; keep it out of the hot arms
+        Instr::LrW { .. }
+        | Instr::LrD { .. }
+        | Instr::ScW { .. }
+        | Instr::ScD { .. }
+        | Instr::AmoW { .. }
+        | Instr::AmoD { .. } => return execute_atomic(cpu, mem, mmu, instr, &ctx),
+        Instr::Ecall
+        | Instr::Ebreak
+        | Instr::Csrrw { .. }
+        | Instr::Csrrs { .. }
+        | Instr::Csrrc { .. }
+        | Instr::Csrrwi { .. }
+        | Instr::Csrrsi { .. }
+        | Instr::Csrrci { .. }
+        | Instr::Mret
+        | Instr::Sret
+        | Instr::SfenceVma { .. }
+        | Instr::Wfi
+        | Instr::Fence
+        | Instr::FenceI => return execute_system(cpu, mmu, instr, &ctx),
+        Instr::Flw { .. }
+        | Instr::Fsw { .. }
+        | Instr::FaddS { .. }
+        | Instr::FsubS { .. }
+        | Instr::FmulS { .. }
+        | Instr::FdivS { .. }
+        | Instr::FsqrtS { .. }
+        | Instr::FcvtWS { .. }
+        | Instr::FcvtWuS { .. }
+        | Instr::FcvtLS { .. }
+        | Instr::FcvtLuS { .. }
+        | Instr::FcvtSW { .. }
+        | Instr::FcvtSWu { .. }
+        | Instr::FcvtSL { .. }
+        | Instr::FcvtSLu { .. }
+        | Instr::Fld { .. }
+        | Instr::Fsd { .. }
+        | Instr::FaddD { .. }
+        | Instr::FsubD { .. }
+        | Instr::FmulD { .. }
+        | Instr::FdivD { .. }
+        | Instr::FsqrtD { .. }
+        | Instr::FcvtSD { .. }
+        | Instr::FcvtDS { .. }
+        | Instr::FcvtWD { .. }
+        | Instr::FcvtWuD { .. }
+        | Instr::FcvtLD { .. }
+        | Instr::FcvtLuD { .. }
+        | Instr::FcvtDW { .. }
+        | Instr::FcvtDWu { .. }
+        | Instr::FcvtDL { .. }
+        | Instr::FcvtDLu { .. }
+        | Instr::FmvXD { .. }
+        | Instr::FmvDX { .. }
+        | Instr::FeqS { .. }
+        | Instr::FltS { .. }
+        | Instr::FleS { .. }
+        | Instr::FeqD { .. }
+        | Instr::FltD { .. }
+        | Instr::FleD { .. }
+        | Instr::FclassS { .. }
+        | Instr::FclassD { .. }
+        | Instr::FsgnjS { .. }
+        | Instr::FsgnjnS { .. }
+        | Instr::FsgnjxS { .. }
+        | Instr::FsgnjD { .. }
+        | Instr::FsgnjnD { .. }
+        | Instr::FsgnjxD { .. }
+        | Instr::FminS { .. }
+        | Instr::FmaxS { .. }
+        | Instr::FminD { .. }
+        | Instr::FmaxD { .. }
+        | Instr::FmaddS { .. }
+        | Instr::FmsubS { .. }
+        | Instr::FnmsubS { .. }
+        | Instr::FnmaddS { .. }
+        | Instr::FmaddD { .. }
+        | Instr::FmsubD { .. }
+        | Instr::FnmsubD { .. }
+        | Instr::FnmaddD { .. } => return execute_fp(cpu, mem, mmu, instr, &ctx),
+    }
+
+    Ok(())
+}
+
+/// LR/SC and AMOs
+fn execute_atomic(
+    cpu: &mut Cpu,
+    mem: &mut Memory,
+    mmu: &mut Mmu,
+    instr: Instr,
+    ctx: &Ctx,
+) -> Result<(), CpuStepResult> {
+    let Ctx {
+        pc,
+        next_pc,
+        satp,
+        mstatus,
+        priv_mode,
+    } = *ctx;
+
+    match instr {
         // TODO: atomicity later
+        Instr::LrW { rd, rs1 } => {
+            let addr = cpu.reg(rs1);
+            check_atomic_alignment(pc, addr, 4, false)?;
+            let word = mem
+                .read_u32(addr, satp, priv_mode, mstatus, mmu)
+                .with_pc(pc)
+                .into_cpu_result()?;
+            cpu.set_reg(rd, sign_extend(word as i64, 32) as u64);
+            cpu.reservation = Some(addr);
+            cpu.pc = next_pc;
+        }
+        Instr::LrD { rd, rs1 } => {
+            let addr = cpu.reg(rs1);
+            check_atomic_alignment(pc, addr, 8, false)?;
+            let value = mem
+                .read_u64(addr, satp, priv_mode, mstatus, mmu)
+                .with_pc(pc)
+                .into_cpu_result()?;
+            cpu.set_reg(rd, value);
+            cpu.reservation = Some(addr);
+            cpu.pc = next_pc;
+        }
+        Instr::ScW { rd, rs1, rs2 } => {
+            let addr = cpu.reg(rs1);
+            check_atomic_alignment(pc, addr, 4, true)?;
+            // SC always gives up the reservation, whether or not it stores
+            let held = cpu.reservation.take() == Some(addr);
+            if held {
+                let word = cpu.reg(rs2) as u32;
+                mem.write_u32(addr, word, satp, priv_mode, mstatus, mmu)
+                    .with_pc(pc)
+                    .into_cpu_result()?;
+            }
+            cpu.set_reg(rd, if held { 0 } else { 1 });
+            cpu.pc = next_pc;
+        }
+        Instr::ScD { rd, rs1, rs2 } => {
+            let addr = cpu.reg(rs1);
+            check_atomic_alignment(pc, addr, 8, true)?;
+            let held = cpu.reservation.take() == Some(addr);
+            if held {
+                let value = cpu.reg(rs2);
+                mem.write_u64(addr, value, satp, priv_mode, mstatus, mmu)
+                    .with_pc(pc)
+                    .into_cpu_result()?;
+            }
+            cpu.set_reg(rd, if held { 0 } else { 1 });
+            cpu.pc = next_pc;
+        }
+        Instr::AmoW { op, rd, rs1, rs2 } => {
+            let addr = cpu.reg(rs1);
+            check_atomic_alignment(pc, addr, 4, true)?;
+            // AMOs need write permission and report faults as store/AMO faults
+            let paddr = mem
+                .translate_addr(addr, satp, false, true, priv_mode, mstatus, mmu)
+                .with_pc(pc)
+                .into_cpu_result()?;
+            check_amo_pmp(mem, paddr, 4, priv_mode, addr)
+                .with_pc(pc)
+                .into_cpu_result()?;
+            let old = mem
+                .read_u32_phys(paddr)
+                .map_err(|err| err.into_access_fault(addr, false, true))
+                .with_pc(pc)
+                .into_cpu_result()?;
+            let old = data_order(priv_mode, mstatus, old);
+            let new = amo_result(op, old as i32 as i64 as u64, cpu.reg(rs2), 32) as u32;
+            mem.write_u32_phys(paddr, data_order(priv_mode, mstatus, new))
+                .map_err(|err| err.into_access_fault(addr, false, true))
+                .with_pc(pc)
+                .into_cpu_result()?;
+            invalidate_reservation(cpu, addr, 4);
+            cpu.set_reg(rd, old as i32 as i64 as u64);
+            cpu.pc = next_pc;
+        }
+        Instr::AmoD { op, rd, rs1, rs2 } => {
+            let addr = cpu.reg(rs1);
+            check_atomic_alignment(pc, addr, 8, true)?;
+            let paddr = mem
+                .translate_addr(addr, satp, false, true, priv_mode, mstatus, mmu)
+                .with_pc(pc)
+                .into_cpu_result()?;
+            check_amo_pmp(mem, paddr, 8, priv_mode, addr)
+                .with_pc(pc)
+                .into_cpu_result()?;
+            let old = mem
+                .read_u64_phys(paddr)
+                .map_err(|err| err.into_access_fault(addr, false, true))
+                .with_pc(pc)
+                .into_cpu_result()?;
+            let old = data_order(priv_mode, mstatus, old);
+            let new = amo_result(op, old, cpu.reg(rs2), 64);
+            mem.write_u64_phys(paddr, data_order(priv_mode, mstatus, new))
+                .map_err(|err| err.into_access_fault(addr, false, true))
+                .with_pc(pc)
+                .into_cpu_result()?;
+            invalidate_reservation(cpu, addr, 8);
+            cpu.set_reg(rd, old);
+            cpu.pc = next_pc;
+        }
+        _ => unreachable!("not an atomic: {:?}", instr),
+    }
+
+    Ok(())
+}
+
+/// CSR accesses, trap returns, fences and other privileged instructions
+fn execute_system(
+    cpu: &mut Cpu,
+    mmu: &mut Mmu,
+    instr: Instr,
+    ctx: &Ctx,
+) -> Result<(), CpuStepResult> {
+    let Ctx { pc, next_pc, .. } = *ctx;
+
+    match instr {
+        Instr::Ecall => {
+            use crate::csr::PrivMode;
+            let trap = match cpu.csr.priv_mode {
+                PrivMode::User => Trap::Ecall { pc },
+                PrivMode::Supervisor => Trap::EcallFromS { pc },
+                PrivMode::Machine => Trap::EcallFromM { pc },
+            };
+            return Err(CpuStepResult::Trapped(trap));
+        }
+        Instr::Ebreak => {
+            return Err(CpuStepResult::Trapped(Trap::Breakpoint { pc }));
+        }
         Instr::Csrrw { rd, csr, rs1 } => {
             // CSR ops use the original x[rs1] value even when rd == rs1.
-            let rs1_value = r(cpu, rs1);
-            let csr_value = cpu.csr.read(csr).with_pc(pc).into_cpu_result()?;
-            w(cpu, rd, csr_value);
+            let rs1_value = cpu.reg(rs1);
+            // With rd == x0 the CSR is not read, so read side effects don't happen.
+            let csr_value = if rd != 0 {
+                cpu.csr.read(csr).with_pc(pc).into_cpu_result()?
+            } else {
+                0
+            };
+            let old_misa = cpu.csr.misa;
             cpu.csr
                 .write(csr, rs1_value)
                 .with_pc(pc)
                 .into_cpu_result()?;
-            // Flush TLB if writing to satp (0x180)
-            if csr == 0x180 {
-                mmu.flush_tlb(None);
-            }
-            cpu.pc = pc.wrapping_add(4);
+            cpu.set_reg(rd, csr_value);
+            csr_written(cpu, mmu, csr, next_pc, old_misa);
+            cpu.pc = next_pc;
         }
         Instr::Csrrs { rd, csr, rs1 } => {
-            let rs1_value = r(cpu, rs1);
+            let rs1_value = cpu.reg(rs1);
             let csr_value = cpu.csr.read(csr).with_pc(pc).into_cpu_result()?;
-            w(cpu, rd, csr_value);
+            // With rs1 == x0 the CSR is not written, so read-only CSRs don't trap.
             if rs1 != 0 {
+                let old_misa = cpu.csr.misa;
                 cpu.csr
                     .set_bits(csr, rs1_value)
                     .with_pc(pc)
                     .into_cpu_result()?;
+                csr_written(cpu, mmu, csr, next_pc, old_misa);
             }
-            cpu.pc = pc.wrapping_add(4);
+            cpu.set_reg(rd, csr_value);
+            cpu.pc = next_pc;
         }
         Instr::Csrrc { rd, csr, rs1 } => {
-            let rs1_value = r(cpu, rs1);
+            let rs1_value = cpu.reg(rs1);
             let csr_value = cpu.csr.read(csr).with_pc(pc).into_cpu_result()?;
-            w(cpu, rd, csr_value);
             if rs1 != 0 {
+                let old_misa = cpu.csr.misa;
                 cpu.csr
                     .clear_bits(csr, rs1_value)
                     .with_pc(pc)
                     .into_cpu_result()?;
+                csr_written(cpu, mmu, csr, next_pc, old_misa);
             }
-            cpu.pc = pc.wrapping_add(4);
+            cpu.set_reg(rd, csr_value);
+            cpu.pc = next_pc;
         }
         Instr::Csrrwi { rd, csr, uimm } => {
-            let csr_value = cpu.csr.read(csr).with_pc(pc).into_cpu_result()?;
-            w(cpu, rd, csr_value);
+            let csr_value = if rd != 0 {
+                cpu.csr.read(csr).with_pc(pc).into_cpu_result()?
+            } else {
+                0
+            };
+            let old_misa = cpu.csr.misa;
             cpu.csr
                 .write(csr, uimm as u64)
                 .with_pc(pc)
                 .into_cpu_result()?;
-            // Flush TLB if writing to satp (0x180)
-            if csr == 0x180 {
-                mmu.flush_tlb(None);
-            }
-            cpu.pc = pc.wrapping_add(4);
+            cpu.set_reg(rd, csr_value);
+            csr_written(cpu, mmu, csr, next_pc, old_misa);
+            cpu.pc = next_pc;
         }
         Instr::Csrrsi { rd, csr, uimm } => {
             let csr_value = cpu.csr.read(csr).with_pc(pc).into_cpu_result()?;
-            w(cpu, rd, csr_value);
             if uimm != 0 {
+                let old_misa = cpu.csr.misa;
                 cpu.csr
                     .set_bits(csr, uimm as u64)
                     .with_pc(pc)
                     .into_cpu_result()?;
+                csr_written(cpu, mmu, csr, next_pc, old_misa);
             }
-            cpu.pc = pc.wrapping_add(4);
+            cpu.set_reg(rd, csr_value);
+            cpu.pc = next_pc;
         }
         Instr::Csrrci { rd, csr, uimm } => {
             let csr_value = cpu.csr.read(csr).with_pc(pc).into_cpu_result()?;
-            w(cpu, rd, csr_value);
             if uimm != 0 {
+                let old_misa = cpu.csr.misa;
                 cpu.csr
                     .clear_bits(csr, uimm as u64)
                     .with_pc(pc)
                     .into_cpu_result()?;
+                csr_written(cpu, mmu, csr, next_pc, old_misa);
             }
-            cpu.pc = pc.wrapping_add(4);
+            cpu.set_reg(rd, csr_value);
+            cpu.pc = next_pc;
         }
         Instr::Mret => {
             use crate::csr::PrivMode;
@@ -638,13 +894,23 @@ pub fn execute(
             // Set MPIE to 1
             cpu.csr.mstatus |= 1 << 7;
 
+            // Returning below M-mode clears MPRV
+            if mpp != PrivMode::Machine {
+                cpu.csr.mstatus &= !(1 << 17);
+            }
+
+            cpu.reservation = None;
             cpu.pc = mepc;
         }
         Instr::Sret => {
-            use crate::csr::PrivMode;
+            use crate::csr::{CsrFile, PrivMode};
 
-            // SRET is only valid in S-mode or higher
-            if cpu.csr.priv_mode == PrivMode::User {
+            // SRET is only valid in S-mode or higher, and in S-mode only
+            // while mstatus.TSR doesn't trap it
+            let tsr = cpu.csr.mstatus & CsrFile::MSTATUS_TSR != 0;
+            if cpu.csr.priv_mode == PrivMode::User
+                || (cpu.csr.priv_mode == PrivMode::Supervisor && tsr)
+            {
                 return Err(CpuStepResult::Trapped(Trap::IllegalInstruction {
                     pc,
                     inst: 0x10200073, // SRET opcode
@@ -667,40 +933,744 @@ pub fn execute(
             // Set SPIE to 1
             cpu.csr.mstatus |= 1 << 5;
 
+            // SRET never returns to M-mode, so MPRV is always cleared
+            cpu.csr.mstatus &= !(1 << 17);
+
+            cpu.reservation = None;
             cpu.pc = sepc;
         }
-        Instr::Sfence => {
-            // SFENCE.VMA - decode and flush TLB
-            // For now, we flush all entries since we don't have the rs1/rs2 available here
-            // In a full implementation, we'd check if rs1==0 && rs2==0 for full flush
-            // or use rs1 as VPN for selective flush
-            mmu.flush_tlb(None);
-            cpu.pc = pc.wrapping_add(4);
+        Instr::SfenceVma { rs1, rs2 } => {
+            use crate::csr::{CsrFile, PrivMode};
+
+            // Illegal in U-mode, and in S-mode when mstatus.TVM traps VM management
+            let tvm = cpu.csr.mstatus & CsrFile::MSTATUS_TVM != 0;
+            if cpu.csr.priv_mode == PrivMode::User
+                || (cpu.csr.priv_mode == PrivMode::Supervisor && tvm)
+            {
+                return Err(CpuStepResult::Trapped(Trap::IllegalInstruction {
+                    pc,
+                    inst: 0x12000073 | ((rs2 as u32) << 20) | ((rs1 as u32) << 15),
+                }));
+            }
+
+            // rs1=x0 covers all addresses, rs2=x0 covers all address spaces
+            let vaddr = (rs1 != 0).then(|| cpu.reg(rs1));
+            let asid = (rs2 != 0).then(|| cpu.reg(rs2));
+            mmu.flush_tlb(vaddr, asid);
+            cpu.pc = next_pc;
         }
         Instr::Wfi => {
-            // WFI - wait for interrupt
-            // For now, just treat as no-op
-            // In future, could pause execution until interrupt pending
-            cpu.pc = pc.wrapping_add(4);
+            use crate::csr::{CsrFile, PrivMode};
+
+            // Illegal in U-mode, and in S-mode when mstatus.TW is set
+            let tw = cpu.csr.mstatus & CsrFile::MSTATUS_TW != 0;
+            if cpu.csr.priv_mode == PrivMode::User
+                || (cpu.csr.priv_mode == PrivMode::Supervisor && tw)
+            {
+                return Err(CpuStepResult::Trapped(Trap::IllegalInstruction {
+                    pc,
+                    inst: 0x10500073,
+                }));
+            }
+
+            // With nothing pending, Machine::step stalls until something is. The
+            // pc moves on either way, so a wakeup interrupt's epc is the next instruction.
+            cpu.wfi = !cpu.csr.interrupt_pending_locally();
+            cpu.pc = next_pc;
         }
         Instr::Fence => {
             // Memory fence - for in-order execution, this is a no-op
-            cpu.pc = pc.wrapping_add(4);
+            cpu.pc = next_pc;
             // TODO: once multiple harts, implement proper fencing
         }
+        Instr::FenceI => {
+            // Instruction fence - fetch reads memory directly, and
+            // Machine::on_fence_i drops the decode caches
+            cpu.pc = next_pc;
+        }
+        _ => unreachable!("not a system instruction: {:?}", instr),
+    }
+
+    Ok(())
+}
+
+/// F and D extension instructions
+fn execute_fp(
+    cpu: &mut Cpu,
+    mem: &mut Memory,
+    mmu: &mut Mmu,
+    instr: Instr,
+    ctx: &Ctx,
+) -> Result<(), CpuStepResult> {
+    let Ctx {
+        pc,
+        next_pc,
+        satp,
+        mstatus,
+        priv_mode,
+    } = *ctx;
+
+    match instr {
+        Instr::Flw { rd, rs1, off } => {
+            check_fpu(cpu, pc)?;
+            let addr = cpu.reg(rs1).wrapping_add(off as u64);
+            let word = mem
+                .read_u32(addr, satp, priv_mode, mstatus, mmu)
+                .with_pc(pc)
+                .into_cpu_result()?;
+            cpu.set_f(rd, fpu::box_f32(f32::from_bits(word)));
+            cpu.pc = next_pc;
+        }
+        Instr::Fsw { rs1, rs2, off } => {
+            check_fpu(cpu, pc)?;
+            let addr = cpu.reg(rs1).wrapping_add(off as u64);
+            // Stores the raw low word, boxed or not
+            let word = cpu.f[rs2 as usize] as u32;
+            mem.write_u32(addr, word, satp, priv_mode, mstatus, mmu)
+                .with_pc(pc)
+                .into_cpu_result()?;
+            invalidate_reservation(cpu, addr, 4);
+            cpu.pc = next_pc;
+        }
+        Instr::FaddS { rd, rs1, rs2, rm } => {
+            let rm = rounding_mode(cpu, pc, rm)?;
+            let (v, flags) = fpu::add_f32(cpu.f32_reg(rs1), cpu.f32_reg(rs2), rm);
+            cpu.csr.set_fflags(flags);
+            cpu.set_f(rd, fpu::box_f32(v));
+            cpu.pc = next_pc;
+        }
+        Instr::FsubS { rd, rs1, rs2, rm } => {
+            let rm = rounding_mode(cpu, pc, rm)?;
+            let (v, flags) = fpu::sub_f32(cpu.f32_reg(rs1), cpu.f32_reg(rs2), rm);
+            cpu.csr.set_fflags(flags);
+            cpu.set_f(rd, fpu::box_f32(v));
+            cpu.pc = next_pc;
+        }
+        Instr::FmulS { rd, rs1, rs2, rm } => {
+            let rm = rounding_mode(cpu, pc, rm)?;
+            let (v, flags) = fpu::mul_f32(cpu.f32_reg(rs1), cpu.f32_reg(rs2), rm);
+            cpu.csr.set_fflags(flags);
+            cpu.set_f(rd, fpu::box_f32(v));
+            cpu.pc = next_pc;
+        }
+        Instr::FdivS { rd, rs1, rs2, rm } => {
+            let rm = rounding_mode(cpu, pc, rm)?;
+            let (v, flags) = fpu::div_f32(cpu.f32_reg(rs1), cpu.f32_reg(rs2), rm);
+            cpu.csr.set_fflags(flags);
+            cpu.set_f(rd, fpu::box_f32(v));
+            cpu.pc = next_pc;
+        }
+        Instr::FsqrtS { rd, rs1, rm } => {
+            let rm = rounding_mode(cpu, pc, rm)?;
+            let (v, flags) = fpu::sqrt_f32(cpu.f32_reg(rs1), rm);
+            cpu.csr.set_fflags(flags);
+            cpu.set_f(rd, fpu::box_f32(v));
+            cpu.pc = next_pc;
+        }
+        Instr::FcvtWS { rd, rs1, rm } => {
+            let rm = rounding_mode(cpu, pc, rm)?;
+            let (v, flags) = fpu::f32_to_int(cpu.f32_reg(rs1), rm, true, 32);
+            cpu.csr.set_fflags(flags);
+            cpu.set_reg(rd, v);
+            cpu.pc = next_pc;
+        }
+        Instr::FcvtWuS { rd, rs1, rm } => {
+            let rm = rounding_mode(cpu, pc, rm)?;
+            let (v, flags) = fpu::f32_to_int(cpu.f32_reg(rs1), rm, false, 32);
+            cpu.csr.set_fflags(flags);
+            cpu.set_reg(rd, v);
+            cpu.pc = next_pc;
+        }
+        Instr::FcvtLS { rd, rs1, rm } => {
+            let rm = rounding_mode(cpu, pc, rm)?;
+            let (v, flags) = fpu::f32_to_int(cpu.f32_reg(rs1), rm, true, 64);
+            cpu.csr.set_fflags(flags);
+            cpu.set_reg(rd, v);
+            cpu.pc = next_pc;
+        }
+        Instr::FcvtLuS { rd, rs1, rm } => {
+            let rm = rounding_mode(cpu, pc, rm)?;
+            let (v, flags) = fpu::f32_to_int(cpu.f32_reg(rs1), rm, false, 64);
+            cpu.csr.set_fflags(flags);
+            cpu.set_reg(rd, v);
+            cpu.pc = next_pc;
+        }
+        Instr::FcvtSW { rd, rs1, rm } => {
+            let rm = rounding_mode(cpu, pc, rm)?;
+            let (v, flags) = fpu::int_to_f32(cpu.reg(rs1) as i32 as i128, rm);
+            cpu.csr.set_fflags(flags);
+            cpu.set_f(rd, fpu::box_f32(v));
+            cpu.pc = next_pc;
+        }
+        Instr::FcvtSWu { rd, rs1, rm } => {
+            let rm = rounding_mode(cpu, pc, rm)?;
+            let (v, flags) = fpu::int_to_f32(cpu.reg(rs1) as u32 as i128, rm);
+            cpu.csr.set_fflags(flags);
+            cpu.set_f(rd, fpu::box_f32(v));
+            cpu.pc = next_pc;
+        }
+        Instr::FcvtSL { rd, rs1, rm } => {
+            let rm = rounding_mode(cpu, pc, rm)?;
+            let (v, flags) = fpu::int_to_f32(cpu.reg(rs1) as i64 as i128, rm);
+            cpu.csr.set_fflags(flags);
+            cpu.set_f(rd, fpu::box_f32(v));
+            cpu.pc = next_pc;
+        }
+        Instr::FcvtSLu { rd, rs1, rm } => {
+            let rm = rounding_mode(cpu, pc, rm)?;
+            let (v, flags) = fpu::int_to_f32(cpu.reg(rs1) as i128, rm);
+            cpu.csr.set_fflags(flags);
+            cpu.set_f(rd, fpu::box_f32(v));
+            cpu.pc = next_pc;
+        }
+        Instr::Fld { rd, rs1, off } => {
+            check_fpu(cpu, pc)?;
+            let addr = cpu.reg(rs1).wrapping_add(off as u64);
+            let dword = mem
+                .read_u64(addr, satp, priv_mode, mstatus, mmu)
+                .with_pc(pc)
+                .into_cpu_result()?;
+            cpu.set_f(rd, dword);
+            cpu.pc = next_pc;
+        }
+        Instr::Fsd { rs1, rs2, off } => {
+            check_fpu(cpu, pc)?;
+            let addr = cpu.reg(rs1).wrapping_add(off as u64);
+            mem.write_u64(addr, cpu.f[rs2 as usize], satp, priv_mode, mstatus, mmu)
+                .with_pc(pc)
+                .into_cpu_result()?;
+            invalidate_reservation(cpu, addr, 8);
+            cpu.pc = next_pc;
+        }
+        Instr::FaddD { rd, rs1, rs2, rm } => {
+            let rm = rounding_mode(cpu, pc, rm)?;
+            let (v, flags) = fpu::add_f64(cpu.f64_reg(rs1), cpu.f64_reg(rs2), rm);
+            cpu.csr.set_fflags(flags);
+            cpu.set_f(rd, v.to_bits());
+            cpu.pc = next_pc;
+        }
+        Instr::FsubD { rd, rs1, rs2, rm } => {
+            let rm = rounding_mode(cpu, pc, rm)?;
+            let (v, flags) = fpu::sub_f64(cpu.f64_reg(rs1), cpu.f64_reg(rs2), rm);
+            cpu.csr.set_fflags(flags);
+            cpu.set_f(rd, v.to_bits());
+            cpu.pc = next_pc;
+        }
+        Instr::FmulD { rd, rs1, rs2, rm } => {
+            let rm = rounding_mode(cpu, pc, rm)?;
+            let (v, flags) = fpu::mul_f64(cpu.f64_reg(rs1), cpu.f64_reg(rs2), rm);
+            cpu.csr.set_fflags(flags);
+            cpu.set_f(rd, v.to_bits());
+            cpu.pc = next_pc;
+        }
+        Instr::FdivD { rd, rs1, rs2, rm } => {
+            let rm = rounding_mode(cpu, pc, rm)?;
+            let (v, flags) = fpu::div_f64(cpu.f64_reg(rs1), cpu.f64_reg(rs2), rm);
+            cpu.csr.set_fflags(flags);
+            cpu.set_f(rd, v.to_bits());
+            cpu.pc = next_pc;
+        }
+        Instr::FsqrtD { rd, rs1, rm } => {
+            let rm = rounding_mode(cpu, pc, rm)?;
+            let (v, flags) = fpu::sqrt_f64(cpu.f64_reg(rs1), rm);
+            cpu.csr.set_fflags(flags);
+            cpu.set_f(rd, v.to_bits());
+            cpu.pc = next_pc;
+        }
+        Instr::FcvtSD { rd, rs1, rm } => {
+            let rm = rounding_mode(cpu, pc, rm)?;
+            let (v, flags) = fpu::f64_to_f32(cpu.f64_reg(rs1), rm);
+            cpu.csr.set_fflags(flags);
+            cpu.set_f(rd, fpu::box_f32(v));
+            cpu.pc = next_pc;
+        }
+        Instr::FcvtDS { rd, rs1, rm } => {
+            // Widening is exact, but a reserved rm is still illegal
+            rounding_mode(cpu, pc, rm)?;
+            let (v, flags) = fpu::f32_to_f64(cpu.f32_reg(rs1));
+            cpu.csr.set_fflags(flags);
+            cpu.set_f(rd, v.to_bits());
+            cpu.pc = next_pc;
+        }
+        Instr::FcvtWD { rd, rs1, rm } => {
+            let rm = rounding_mode(cpu, pc, rm)?;
+            let (v, flags) = fpu::f64_to_int(cpu.f64_reg(rs1), rm, true, 32);
+            cpu.csr.set_fflags(flags);
+            cpu.set_reg(rd, v);
+            cpu.pc = next_pc;
+        }
+        Instr::FcvtWuD { rd, rs1, rm } => {
+            let rm = rounding_mode(cpu, pc, rm)?;
+            let (v, flags) = fpu::f64_to_int(cpu.f64_reg(rs1), rm, false, 32);
+            cpu.csr.set_fflags(flags);
+            cpu.set_reg(rd, v);
+            cpu.pc = next_pc;
+        }
+        Instr::FcvtLD { rd, rs1, rm } => {
+            let rm = rounding_mode(cpu, pc, rm)?;
+            let (v, flags) = fpu::f64_to_int(cpu.f64_reg(rs1), rm, true, 64);
+            cpu.csr.set_fflags(flags);
+            cpu.set_reg(rd, v);
+            cpu.pc = next_pc;
+        }
+        Instr::FcvtLuD { rd, rs1, rm } => {
+            let rm = rounding_mode(cpu, pc, rm)?;
+            let (v, flags) = fpu::f64_to_int(cpu.f64_reg(rs1), rm, false, 64);
+            cpu.csr.set_fflags(flags);
+            cpu.set_reg(rd, v);
+            cpu.pc = next_pc;
+        }
+        Instr::FcvtDW { rd, rs1, rm } => {
+            let rm = rounding_mode(cpu, pc, rm)?;
+            let (v, flags) = fpu::int_to_f64(cpu.reg(rs1) as i32 as i128, rm);
+            cpu.csr.set_fflags(flags);
+            cpu.set_f(rd, v.to_bits());
+            cpu.pc = next_pc;
+        }
+        Instr::FcvtDWu { rd, rs1, rm } => {
+            let rm = rounding_mode(cpu, pc, rm)?;
+            let (v, flags) = fpu::int_to_f64(cpu.reg(rs1) as u32 as i128, rm);
+            cpu.csr.set_fflags(flags);
+            cpu.set_f(rd, v.to_bits());
+            cpu.pc = next_pc;
+        }
+        Instr::FcvtDL { rd, rs1, rm } => {
+            let rm = rounding_mode(cpu, pc, rm)?;
+            let (v, flags) = fpu::int_to_f64(cpu.reg(rs1) as i64 as i128, rm);
+            cpu.csr.set_fflags(flags);
+            cpu.set_f(rd, v.to_bits());
+            cpu.pc = next_pc;
+        }
+        Instr::FcvtDLu { rd, rs1, rm } => {
+            let rm = rounding_mode(cpu, pc, rm)?;
+            let (v, flags) = fpu::int_to_f64(cpu.reg(rs1) as i128, rm);
+            cpu.csr.set_fflags(flags);
+            cpu.set_f(rd, v.to_bits());
+            cpu.pc = next_pc;
+        }
+        Instr::FmvXD { rd, rs1 } => {
+            check_fpu(cpu, pc)?;
+            cpu.set_reg(rd, cpu.f[rs1 as usize]);
+            cpu.pc = next_pc;
+        }
+        Instr::FmvDX { rd, rs1 } => {
+            check_fpu(cpu, pc)?;
+            cpu.set_f(rd, cpu.reg(rs1));
+            cpu.pc = next_pc;
+        }
+        Instr::FeqS { rd, rs1, rs2 } => {
+            check_fpu(cpu, pc)?;
+            let (v, flags) = fpu::feq_f32(cpu.f32_reg(rs1), cpu.f32_reg(rs2));
+            cpu.csr.set_fflags(flags);
+            cpu.set_reg(rd, v as u64);
+            cpu.pc = next_pc;
+        }
+        Instr::FltS { rd, rs1, rs2 } => {
+            check_fpu(cpu, pc)?;
+            let (v, flags) = fpu::flt_f32(cpu.f32_reg(rs1), cpu.f32_reg(rs2));
+            cpu.csr.set_fflags(flags);
+            cpu.set_reg(rd, v as u64);
+            cpu.pc = next_pc;
+        }
+        Instr::FleS { rd, rs1, rs2 } => {
+            check_fpu(cpu, pc)?;
+            let (v, flags) = fpu::fle_f32(cpu.f32_reg(rs1), cpu.f32_reg(rs2));
+            cpu.csr.set_fflags(flags);
+            cpu.set_reg(rd, v as u64);
+            cpu.pc = next_pc;
+        }
+        Instr::FeqD { rd, rs1, rs2 } => {
+            check_fpu(cpu, pc)?;
+            let (v, flags) = fpu::feq_f64(cpu.f64_reg(rs1), cpu.f64_reg(rs2));
+            cpu.csr.set_fflags(flags);
+            cpu.set_reg(rd, v as u64);
+            cpu.pc = next_pc;
+        }
+        Instr::FltD { rd, rs1, rs2 } => {
+            check_fpu(cpu, pc)?;
+            let (v, flags) = fpu::flt_f64(cpu.f64_reg(rs1), cpu.f64_reg(rs2));
+            cpu.csr.set_fflags(flags);
+            cpu.set_reg(rd, v as u64);
+            cpu.pc = next_pc;
+        }
+        Instr::FleD { rd, rs1, rs2 } => {
+            check_fpu(cpu, pc)?;
+            let (v, flags) = fpu::fle_f64(cpu.f64_reg(rs1), cpu.f64_reg(rs2));
+            cpu.csr.set_fflags(flags);
+            cpu.set_reg(rd, v as u64);
+            cpu.pc = next_pc;
+        }
+        Instr::FclassS { rd, rs1 } => {
+            check_fpu(cpu, pc)?;
+            cpu.set_reg(rd, fpu::fclass_f32(cpu.f32_reg(rs1)));
+            cpu.pc = next_pc;
+        }
+        Instr::FclassD { rd, rs1 } => {
+            check_fpu(cpu, pc)?;
+            cpu.set_reg(rd, fpu::fclass_f64(cpu.f64_reg(rs1)));
+            cpu.pc = next_pc;
+        }
+        // Sign injection copies bits, so NaN payloads pass through untouched
+        Instr::FsgnjS { rd, rs1, rs2 } => {
+            check_fpu(cpu, pc)?;
+            cpu.set_f(
+                rd,
+                fpu::box_f32(cpu.f32_reg(rs1).copysign(cpu.f32_reg(rs2))),
+            );
+            cpu.pc = next_pc;
+        }
+        Instr::FsgnjnS { rd, rs1, rs2 } => {
+            check_fpu(cpu, pc)?;
+            cpu.set_f(
+                rd,
+                fpu::box_f32(cpu.f32_reg(rs1).copysign(-cpu.f32_reg(rs2))),
+            );
+            cpu.pc = next_pc;
+        }
+        Instr::FsgnjxS { rd, rs1, rs2 } => {
+            check_fpu(cpu, pc)?;
+            let sign = cpu.f32_reg(rs2).to_bits() & (1 << 31);
+            cpu.set_f(
+                rd,
+                fpu::box_f32(f32::from_bits(cpu.f32_reg(rs1).to_bits() ^ sign)),
+            );
+            cpu.pc = next_pc;
+        }
+        Instr::FsgnjD { rd, rs1, rs2 } => {
+            check_fpu(cpu, pc)?;
+            cpu.set_f(rd, cpu.f64_reg(rs1).copysign(cpu.f64_reg(rs2)).to_bits());
+            cpu.pc = next_pc;
+        }
+        Instr::FsgnjnD { rd, rs1, rs2 } => {
+            check_fpu(cpu, pc)?;
+            cpu.set_f(rd, cpu.f64_reg(rs1).copysign(-cpu.f64_reg(rs2)).to_bits());
+            cpu.pc = next_pc;
+        }
+        Instr::FsgnjxD { rd, rs1, rs2 } => {
+            check_fpu(cpu, pc)?;
+            let sign = cpu.f[rs2 as usize] & (1 << 63);
+            cpu.set_f(rd, cpu.f[rs1 as usize] ^ sign);
+            cpu.pc = next_pc;
+        }
+        Instr::FminS { rd, rs1, rs2 } => {
+            check_fpu(cpu, pc)?;
+            let (v, flags) = fpu::fmin_f32(cpu.f32_reg(rs1), cpu.f32_reg(rs2));
+            cpu.csr.set_fflags(flags);
+            cpu.set_f(rd, fpu::box_f32(v));
+            cpu.pc = next_pc;
+        }
+        Instr::FmaxS { rd, rs1, rs2 } => {
+            check_fpu(cpu, pc)?;
+            let (v, flags) = fpu::fmax_f32(cpu.f32_reg(rs1), cpu.f32_reg(rs2));
+            cpu.csr.set_fflags(flags);
+            cpu.set_f(rd, fpu::box_f32(v));
+            cpu.pc = next_pc;
+        }
+        Instr::FminD { rd, rs1, rs2 } => {
+            check_fpu(cpu, pc)?;
+            let (v, flags) = fpu::fmin_f64(cpu.f64_reg(rs1), cpu.f64_reg(rs2));
+            cpu.csr.set_fflags(flags);
+            cpu.set_f(rd, v.to_bits());
+            cpu.pc = next_pc;
+        }
+        Instr::FmaxD { rd, rs1, rs2 } => {
+            check_fpu(cpu, pc)?;
+            let (v, flags) = fpu::fmax_f64(cpu.f64_reg(rs1), cpu.f64_reg(rs2));
+            cpu.csr.set_fflags(flags);
+            cpu.set_f(rd, v.to_bits());
+            cpu.pc = next_pc;
+        }
+        // FMSUB negates the addend; FNMSUB/FNMADD negate the product
+        Instr::FmaddS {
+            rd,
+            rs1,
+            rs2,
+            rs3,
+            rm,
+        } => {
+            let rm = rounding_mode(cpu, pc, rm)?;
+            let (v, flags) = fpu::fma_f32(cpu.f32_reg(rs1), cpu.f32_reg(rs2), cpu.f32_reg(rs3), rm);
+            cpu.csr.set_fflags(flags);
+            cpu.set_f(rd, fpu::box_f32(v));
+            cpu.pc = next_pc;
+        }
+        Instr::FmsubS {
+            rd,
+            rs1,
+            rs2,
+            rs3,
+            rm,
+        } => {
+            let rm = rounding_mode(cpu, pc, rm)?;
+            let (v, flags) =
+                fpu::fma_f32(cpu.f32_reg(rs1), cpu.f32_reg(rs2), -cpu.f32_reg(rs3), rm);
+            cpu.csr.set_fflags(flags);
+            cpu.set_f(rd, fpu::box_f32(v));
+            cpu.pc = next_pc;
+        }
+        Instr::FnmsubS {
+            rd,
+            rs1,
+            rs2,
+            rs3,
+            rm,
+        } => {
+            let rm = rounding_mode(cpu, pc, rm)?;
+            let (v, flags) =
+                fpu::fma_f32(-cpu.f32_reg(rs1), cpu.f32_reg(rs2), cpu.f32_reg(rs3), rm);
+            cpu.csr.set_fflags(flags);
+            cpu.set_f(rd, fpu::box_f32(v));
+            cpu.pc = next_pc;
+        }
+        Instr::FnmaddS {
+            rd,
+            rs1,
+            rs2,
+            rs3,
+            rm,
+        } => {
+            let rm = rounding_mode(cpu, pc, rm)?;
+            let (v, flags) =
+                fpu::fma_f32(-cpu.f32_reg(rs1), cpu.f32_reg(rs2), -cpu.f32_reg(rs3), rm);
+            cpu.csr.set_fflags(flags);
+            cpu.set_f(rd, fpu::box_f32(v));
+            cpu.pc = next_pc;
+        }
+        Instr::FmaddD {
+            rd,
+            rs1,
+            rs2,
+            rs3,
+            rm,
+        } => {
+            let rm = rounding_mode(cpu, pc, rm)?;
+            let (v, flags) = fpu::fma_f64(cpu.f64_reg(rs1), cpu.f64_reg(rs2), cpu.f64_reg(rs3), rm);
+            cpu.csr.set_fflags(flags);
+            cpu.set_f(rd, v.to_bits());
+            cpu.pc = next_pc;
+        }
+        Instr::FmsubD {
+            rd,
+            rs1,
+            rs2,
+            rs3,
+            rm,
+        } => {
+            let rm = rounding_mode(cpu, pc, rm)?;
+            let (v, flags) =
+                fpu::fma_f64(cpu.f64_reg(rs1), cpu.f64_reg(rs2), -cpu.f64_reg(rs3), rm);
+            cpu.csr.set_fflags(flags);
+            cpu.set_f(rd, v.to_bits());
+            cpu.pc = next_pc;
+        }
+        Instr::FnmsubD {
+            rd,
+            rs1,
+            rs2,
+            rs3,
+            rm,
+        } => {
+            let rm = rounding_mode(cpu, pc, rm)?;
+            let (v, flags) =
+                fpu::fma_f64(-cpu.f64_reg(rs1), cpu.f64_reg(rs2), cpu.f64_reg(rs3), rm);
+            cpu.csr.set_fflags(flags);
+            cpu.set_f(rd, v.to_bits());
+            cpu.pc = next_pc;
+        }
+        Instr::FnmaddD {
+            rd,
+            rs1,
+            rs2,
+            rs3,
+            rm,
+        } => {
+            let rm = rounding_mode(cpu, pc, rm)?;
+            let (v, flags) =
+                fpu::fma_f64(-cpu.f64_reg(rs1), cpu.f64_reg(rs2), -cpu.f64_reg(rs3), rm);
+            cpu.csr.set_fflags(flags);
+            cpu.set_f(rd, v.to_bits());
+            cpu.pc = next_pc;
+        }
+        _ => unreachable!("not a float instruction: {:?}", instr),
     }
 
-    // Keep x0 pinned (extra safety)
-    cpu.regs[0] = 0;
     Ok(())
 }
 
+/// Control transfers need 4-byte aligned targets, or 2-byte with the C extension.
+/// The trap is raised by the jump itself, before rd is written.
+fn jump_target(cpu: &Cpu, pc: u64, target: u64) -> Result<u64, CpuStepResult> {
+    let align = if cpu.csr.ext_enabled(b'C') { 2 } else { 4 };
+    if target.is_multiple_of(align) {
+        Ok(target)
+    } else {
+        Err(CpuStepResult::Trapped(Trap::InstructionMisaligned {
+            pc,
+            addr: target,
+        }))
+    }
+}
+
+/// Float instructions are illegal while mstatus.FS is Off.
+fn check_fpu(cpu: &Cpu, pc: u64) -> Result<(), CpuStepResult> {
+    if cpu.csr.fpu_enabled() {
+        Ok(())
+    } else {
+        Err(CpuStepResult::Trapped(Trap::IllegalInstruction {
+            pc,
+            inst: 0,
+        }))
+    }
+}
+
+/// Resolve an instruction's rm field, where 0b111 defers to fcsr.frm. Reserved
+/// encodings, in either place, are illegal.
+fn rounding_mode(cpu: &Cpu, pc: u64, rm: u8) -> Result<RoundingMode, CpuStepResult> {
+    check_fpu(cpu, pc)?;
+    let rm = if rm == 0b111 { cpu.csr.frm() } else { rm };
+    RoundingMode::from_bits(rm).ok_or(CpuStepResult::Trapped(Trap::IllegalInstruction {
+        pc,
+        inst: 0,
+    }))
+}
+
+/// Atomics must be naturally aligned even when misaligned loads/stores are emulated.
+fn check_atomic_alignment(
+    pc: u64,
+    addr: u64,
+    size: u64,
+    is_store: bool,
+) -> Result<(), CpuStepResult> {
+    if addr.is_multiple_of(size) {
+        return Ok(());
+    }
+    let trap = if is_store {
+        Trap::StoreMisaligned { pc, addr }
+    } else {
+        Trap::LoadMisaligned { pc, addr }
+    };
+    Err(CpuStepResult::Trapped(trap))
+}
+
+/// AMOs read and write, so PMP must allow both; either failing is a
+/// store/AMO access fault.
+fn check_amo_pmp(
+    mem: &Memory,
+    paddr: u64,
+    size: u64,
+    priv_mode: crate::csr::PrivMode,
+    addr: u64,
+) -> Result<(), crate::mem::MemError> {
+    if mem.pmp.check(paddr, size, false, false, priv_mode) {
+        mem.check_pmp(paddr, size, false, true, priv_mode, addr)
+    } else {
+        Err(crate::mem::MemError::StoreAccessFault(addr))
+    }
+}
+
+/// Byte-swap a value read from or written to memory directly when data
+/// accesses at `priv_mode` are big-endian.
+fn data_order<T: SwapBytes>(priv_mode: crate::csr::PrivMode, mstatus: u64, v: T) -> T {
+    if crate::mem::big_endian(priv_mode, mstatus) {
+        v.swap_bytes()
+    } else {
+        v
+    }
+}
+
+trait SwapBytes {
+    fn swap_bytes(self) -> Self;
+}
+
+impl SwapBytes for u32 {
+    fn swap_bytes(self) -> Self {
+        u32::swap_bytes(self)
+    }
+}
+
+impl SwapBytes for u64 {
+    fn swap_bytes(self) -> Self {
+        u64::swap_bytes(self)
+    }
+}
+
+/// Value an AMO stores back, computed at `bits` width (32 for .W, 64 for .D).
+/// Only the low `bits` of the result are meaningful.
+fn amo_result(op: AmoOp, mem_val: u64, src: u64, bits: u32) -> u64 {
+    let shift = 64 - bits;
+    // Signed compares use sign-extended operands, unsigned ones zero-extended
+    let (sa, sb) = (
+        ((mem_val << shift) as i64) >> shift,
+        ((src << shift) as i64) >> shift,
+    );
+    let (ua, ub) = ((mem_val << shift) >> shift, (src << shift) >> shift);
+    match op {
+        AmoOp::Swap => src,
+        AmoOp::Add => mem_val.wrapping_add(src),
+        AmoOp::Xor => mem_val ^ src,
+        AmoOp::And => mem_val & src,
+        AmoOp::Or => mem_val | src,
+        AmoOp::Min => sa.min(sb) as u64,
+        AmoOp::Max => sa.max(sb) as u64,
+        AmoOp::Minu => ua.min(ub),
+        AmoOp::Maxu => ua.max(ub),
+    }
+}
+
+/// A store that touches the reserved doubleword breaks the LR/SC reservation.
+fn invalidate_reservation(cpu: &mut Cpu, addr: u64, size: u64) {
+    if let Some(reserved) = cpu.reservation {
+        let granule = reserved & !7;
+        let last = addr.wrapping_add(size - 1);
+        if addr & !7 == granule || last & !7 == granule {
+            cpu.reservation = None;
+        }
+    }
+}
+
+/// HTIF tohost protocol as used by riscv-tests and the proxy kernel.
+/// The packet is `dev[63:56] | cmd[55:48] | payload[47:0]`:
+///   dev 0, cmd 0, odd payload: exit; 1 is a pass, `(code << 1) | 1` fails with `code`
+///   dev 1, cmd 1: console putchar of the payload's low byte
+/// Anything else is dropped. tohost is cleared afterwards so guests polling it
+/// can make progress.
+fn htif_tohost(
+    mem: &mut Memory,
+    pc: u64,
+    paddr: u64,
+    value: u64,
+    gp: u64,
+) -> Result<(), CpuStepResult> {
+    let device = (value >> 56) & 0xff;
+    let cmd = (value >> 48) & 0xff;
+    let payload = value & 0xffff_ffff_ffff;
+
+    match (device, cmd) {
+        (0, 0) if payload & 1 != 0 => {
+            return Err(CpuStepResult::Halt(super::HaltReason::HostExit {
+                code: payload >> 1,
+                gp,
+            }));
+        }
+        (1, 1) => {
+            let mut out = std::io::stdout();
+            // Console output is best-effort; a closed stdout shouldn't stop the guest
+            let _ = out.write_all(&[payload as u8]);
+            let _ = out.flush();
+        }
+        _ => {}
+    }
+
+    mem.write_u64_phys(paddr, 0).with_pc(pc).into_cpu_result()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::cpu::{Cpu, HaltReason};
     use crate::cpu::decode::Instr;
-    use crate::csr::PrivMode;
+    use crate::cpu::trap::causes;
+    use crate::csr::{CsrFile, PrivMode};
 
     #[test]
     fn test_csrrw_rd_eq_rs1_uses_original_rs1_value() {
@@ -830,10 +1800,1558 @@ mod tests {
 
         match result {
             Err(CpuStepResult::Halt(HaltReason::HostExit { code, gp })) => {
-                assert_eq!(code, 1);
+                assert_eq!(code, 0, "payload 1 is a pass");
                 assert_eq!(gp, 0);
             }
             other => panic!("expected host exit halt, got: {:?}", other),
         }
     }
+
+    #[test]
+    fn test_tohost_failure_code_and_non_exit_packets() {
+        let mut cpu = Cpu::default();
+        let mut mem = Memory::new(0x10000);
+        let mut mmu = Mmu::new();
+        let tohost = 0x8000_1000;
+
+        cpu.regs[1] = tohost;
+        let (rs1, rs2, off) = (1, 2, 0);
+
+        // Failing test number 3 is reported as (3 << 1) | 1
+        cpu.regs[2] = (3 << 1) | 1;
+        cpu.regs[3] = 3;
+        match execute(
+            &mut cpu,
+            &mut mem,
+            &mut mmu,
+            Instr::SD { rs1, rs2, off },
+            Some(tohost),
+        ) {
+            Err(CpuStepResult::Halt(HaltReason::HostExit { code, gp })) => {
+                assert_eq!(code, 3);
+                assert_eq!(gp, 3);
+            }
+            other => panic!("expected host exit halt, got: {:?}", other),
+        }
+
+        // Even payloads with dev 0 are not exits; tohost is consumed and execution continues
+        cpu.pc = 0x8000_0000;
+        cpu.regs[2] = 0x100;
+        execute(
+            &mut cpu,
+            &mut mem,
+            &mut mmu,
+            Instr::SD { rs1, rs2, off },
+            Some(tohost),
+        )
+        .expect("non-exit packet should not halt");
+        assert_eq!(mem.read_u64_phys(tohost).unwrap(), 0);
+        assert_eq!(cpu.pc, 0x8000_0004);
+    }
+
+    #[test]
+    fn test_signed_and_unsigned_branches_disagree_on_negative_operand() {
+        let mut cpu = Cpu::default();
+        let mut mem = Memory::new(0x10000);
+        let mut mmu = Mmu::new();
+
+        cpu.regs[5] = (-1i64) as u64; // t0 = -1 (u64::MAX when unsigned)
+        cpu.regs[6] = 1; // t1 = 1
+
+        let (rs1, rs2, off) = (5, 6, -8);
+        let cases = [
+            (Instr::Blt { rs1, rs2, off }, true),
+            (Instr::Bge { rs1, rs2, off }, false),
+            (Instr::Bltu { rs1, rs2, off }, false),
+            (Instr::Bgeu { rs1, rs2, off }, true),
+        ];
+
+        for (instr, taken) in cases {
+            cpu.pc = 0x8000_0100;
+            execute(&mut cpu, &mut mem, &mut mmu, instr, None).expect("branch should execute");
+            let expected = if taken { 0x8000_00f8 } else { 0x8000_0104 };
+            assert_eq!(cpu.pc, expected, "{:?} taken={}", instr, taken);
+        }
+    }
+
+    #[test]
+    fn test_set_less_than_signedness() {
+        let mut cpu = Cpu::default();
+        let mut mem = Memory::new(0x10000);
+        let mut mmu = Mmu::new();
+
+        cpu.pc = 0x8000_0000;
+        let (rd, rs1, rs2) = (7, 5, 6);
+        cpu.regs[rs2 as usize] = 1;
+
+        let minus_one = (-1i64) as u64;
+        let cases = [
+            (minus_one, Instr::Slt { rd, rs1, rs2 }, 1),
+            (minus_one, Instr::Sltu { rd, rs1, rs2 }, 0),
+            (minus_one, Instr::Slti { rd, rs1, imm: 0 }, 1),
+            (minus_one, Instr::Sltiu { rd, rs1, imm: 0 }, 0),
+            // SLTIU sign-extends the immediate before the unsigned compare,
+            // so imm=-1 is u64::MAX and every value except u64::MAX is below it.
+            (1, Instr::Sltiu { rd, rs1, imm: -1 }, 1),
+            (minus_one, Instr::Sltiu { rd, rs1, imm: -1 }, 0),
+        ];
+
+        for (lhs, instr, expected) in cases {
+            cpu.regs[rs1 as usize] = lhs;
+            execute(&mut cpu, &mut mem, &mut mmu, instr, None).expect("slt should execute");
+            assert_eq!(cpu.regs[rd as usize], expected, "{:?}", instr);
+        }
+    }
+
+    #[test]
+    fn test_immediate_logic_and_full_width_shifts() {
+        let mut cpu = Cpu::default();
+        let mut mem = Memory::new(0x10000);
+        let mut mmu = Mmu::new();
+
+        cpu.pc = 0x8000_0000;
+        let (rd, rs1) = (7, 5);
+        let negative = 0x8000_0000_0000_00f0u64;
+        cpu.regs[rs1 as usize] = negative;
+
+        let cases = [
+            // Immediates are sign-extended, so -1 touches all 64 bits
+            (Instr::Andi { rd, rs1, imm: -1 }, negative),
+            (Instr::Ori { rd, rs1, imm: -16 }, 0xffff_ffff_ffff_fff0),
+            (Instr::Xori { rd, rs1, imm: -1 }, !negative),
+            (Instr::Slli { rd, rs1, shamt: 63 }, 0),
+            (Instr::Srli { rd, rs1, shamt: 63 }, 1),
+            (Instr::Srai { rd, rs1, shamt: 63 }, u64::MAX),
+            (Instr::Srai { rd, rs1, shamt: 4 }, 0xf800_0000_0000_000f),
+        ];
+
+        for (instr, expected) in cases {
+            execute(&mut cpu, &mut mem, &mut mmu, instr, None).expect("op-imm should execute");
+            assert_eq!(cpu.regs[rd as usize], expected, "{:?}", instr);
+        }
+    }
+
+    #[test]
+    fn test_register_shifts_mask_amount_to_six_bits() {
+        let mut cpu = Cpu::default();
+        let mut mem = Memory::new(0x10000);
+        let mut mmu = Mmu::new();
+
+        cpu.pc = 0x8000_0000;
+        let (rd, rs1, rs2) = (7, 5, 6);
+        cpu.regs[rs1 as usize] = 0x8000_0000_0000_0001;
+        // 0x41 & 0x3f == 1: only the low 6 bits of rs2 select the shift
+        cpu.regs[rs2 as usize] = 0x41;
+
+        let cases = [
+            (Instr::Sll { rd, rs1, rs2 }, 0x0000_0000_0000_0002),
+            (Instr::Srl { rd, rs1, rs2 }, 0x4000_0000_0000_0000),
+            (Instr::Sra { rd, rs1, rs2 }, 0xc000_0000_0000_0000),
+            (Instr::And { rd, rs1, rs2 }, 0x1),
+            (Instr::Or { rd, rs1, rs2 }, 0x8000_0000_0000_0041),
+            (Instr::Xor { rd, rs1, rs2 }, 0x8000_0000_0000_0040),
+        ];
+
+        for (instr, expected) in cases {
+            execute(&mut cpu, &mut mem, &mut mmu, instr, None).expect("op should execute");
+            assert_eq!(cpu.regs[rd as usize], expected, "{:?}", instr);
+        }
+    }
+
+    #[test]
+    fn test_word_ops_sign_extend_32_bit_result() {
+        let mut cpu = Cpu::default();
+        let mut mem = Memory::new(0x10000);
+        let mut mmu = Mmu::new();
+
+        cpu.pc = 0x8000_0000;
+        let (rd, rs1, rs2) = (7, 5, 6);
+        // Upper 32 bits of the sources must be ignored
+        cpu.regs[rs1 as usize] = 0xdead_beef_7fff_ffff;
+        cpu.regs[rs2 as usize] = 0x1234_5678_0000_0021; // shift amount 0x21 & 0x1f == 1
+
+        let cases = [
+            (Instr::Addw { rd, rs1, rs2 }, 0xffff_ffff_8000_0020),
+            (Instr::Subw { rd, rs1, rs2 }, 0x0000_0000_7fff_ffde),
+            (Instr::Sllw { rd, rs1, rs2 }, 0xffff_ffff_ffff_fffe),
+            (Instr::Srlw { rd, rs1, rs2 }, 0x0000_0000_3fff_ffff),
+            (Instr::Sraw { rd, rs1, rs2 }, 0x0000_0000_3fff_ffff),
+        ];
+
+        for (instr, expected) in cases {
+            execute(&mut cpu, &mut mem, &mut mmu, instr, None).expect("op-32 should execute");
+            assert_eq!(cpu.regs[rd as usize], expected, "{:?}", instr);
+        }
+
+        // SRAW on a negative word shifts in copies of bit 31, not bit 63
+        cpu.regs[rs1 as usize] = 0x0000_0000_8000_0000;
+        let sraw = Instr::Sraw { rd, rs1, rs2 };
+        execute(&mut cpu, &mut mem, &mut mmu, sraw, None).unwrap();
+        assert_eq!(cpu.regs[rd as usize], 0xffff_ffff_c000_0000);
+    }
+
+    #[test]
+    fn test_multiply_high_signedness() {
+        let mut cpu = Cpu::default();
+        let mut mem = Memory::new(0x10000);
+        let mut mmu = Mmu::new();
+
+        let (rd, rs1, rs2) = (7, 5, 6);
+        let ops = [
+            Instr::Mul { rd, rs1, rs2 },
+            Instr::Mulh { rd, rs1, rs2 },
+            Instr::Mulhsu { rd, rs1, rs2 },
+            Instr::Mulhu { rd, rs1, rs2 },
+        ];
+        // (rs1, rs2, [mul, mulh, mulhsu, mulhu])
+        let cases: [(u64, u64, [u64; 4]); 4] = [
+            // -1 * -1: MULHSU sees -1 * (2^64 - 1), a negative product
+            (u64::MAX, u64::MAX, [1, 0, u64::MAX, u64::MAX - 1]),
+            // i64::MIN * 2 = -2^64 signed, 2^64 unsigned
+            (1 << 63, 2, [0, u64::MAX, u64::MAX, 1]),
+            // 2 * rs2=all-ones: only MULH treats rs2 as -1
+            (2, u64::MAX, [u64::MAX - 1, u64::MAX, 1, 1]),
+            (
+                0x1234_5678_9abc_def0,
+                0xfedc_ba98_7654_3210,
+                [
+                    0x236d_88fe_5618_cf00,
+                    0xffeb_4992_3cc0_9532,
+                    0x121f_a00a_d77d_7422,
+                    0x121f_a00a_d77d_7422,
+                ],
+            ),
+        ];
+
+        for (a, b, expected) in cases {
+            cpu.regs[rs1 as usize] = a;
+            cpu.regs[rs2 as usize] = b;
+            for (instr, want) in ops.iter().zip(expected) {
+                execute(&mut cpu, &mut mem, &mut mmu, *instr, None).unwrap();
+                assert_eq!(cpu.regs[rd as usize], want, "{:?} {:#x} {:#x}", instr, a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn test_division_edge_cases() {
+        let mut cpu = Cpu::default();
+        let mut mem = Memory::new(0x10000);
+        let mut mmu = Mmu::new();
+
+        let (rd, rs1, rs2) = (7, 5, 6);
+        let ops = [
+            Instr::Div { rd, rs1, rs2 },
+            Instr::Divu { rd, rs1, rs2 },
+            Instr::Rem { rd, rs1, rs2 },
+            Instr::Remu { rd, rs1, rs2 },
+        ];
+        let neg = |v: i64| v as u64;
+        // (rs1, rs2, [div, divu, rem, remu])
+        let cases = [
+            // Signed division truncates toward zero; remainder takes the dividend's sign
+            (neg(-20), 3, [neg(-6), 0x5555_5555_5555_554e, neg(-2), 2]),
+            // Divide by zero: quotient is all ones, remainder is the dividend
+            (neg(-7), 0, [u64::MAX, u64::MAX, neg(-7), neg(-7)]),
+            // Signed overflow: i64::MIN / -1 = i64::MIN remainder 0
+            (1 << 63, u64::MAX, [1 << 63, 0, 0, 1 << 63]),
+        ];
+
+        for (a, b, expected) in cases {
+            cpu.regs[rs1 as usize] = a;
+            cpu.regs[rs2 as usize] = b;
+            for (instr, want) in ops.iter().zip(expected) {
+                execute(&mut cpu, &mut mem, &mut mmu, *instr, None).unwrap();
+                assert_eq!(cpu.regs[rd as usize], want, "{:?} {:#x} {:#x}", instr, a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn test_word_multiply_divide_use_low_32_bits() {
+        let mut cpu = Cpu::default();
+        let mut mem = Memory::new(0x10000);
+        let mut mmu = Mmu::new();
+
+        let (rd, rs1, rs2) = (7, 5, 6);
+        let ops = [
+            Instr::Mulw { rd, rs1, rs2 },
+            Instr::Divw { rd, rs1, rs2 },
+            Instr::Divuw { rd, rs1, rs2 },
+            Instr::Remw { rd, rs1, rs2 },
+            Instr::Remuw { rd, rs1, rs2 },
+        ];
+        let neg = |v: i64| v as u64;
+        let min = neg(i32::MIN as i64);
+        // (rs1, rs2, [mulw, divw, divuw, remw, remuw]); upper source bits are junk
+        let cases = [
+            (
+                0xdead_beef_ffff_ffec, // -20
+                0x1234_5678_0000_0003,
+                [neg(-60), neg(-6), 0x5555_554e, neg(-2), 2],
+            ),
+            // Divide by zero at 32-bit width
+            (
+                0xdead_beef_0000_0007,
+                0xffff_ffff_0000_0000,
+                [0, u64::MAX, u64::MAX, 7, 7],
+            ),
+            // i32::MIN / -1 overflows to i32::MIN with remainder 0
+            (
+                0xdead_beef_8000_0000,
+                0x1234_5678_ffff_ffff,
+                [min, min, 0, 0, min],
+            ),
+        ];
+
+        for (a, b, expected) in cases {
+            cpu.regs[rs1 as usize] = a;
+            cpu.regs[rs2 as usize] = b;
+            for (instr, want) in ops.iter().zip(expected) {
+                execute(&mut cpu, &mut mem, &mut mmu, *instr, None).unwrap();
+                assert_eq!(cpu.regs[rd as usize], want, "{:?} {:#x} {:#x}", instr, a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn test_word_immediate_ops_sign_extend_bit_31() {
+        let mut cpu = Cpu::default();
+        let mut mem = Memory::new(0x10000);
+        let mut mmu = Mmu::new();
+
+        cpu.pc = 0x8000_0000;
+        let (rd, rs1) = (7, 5);
+        cpu.regs[rs1 as usize] = 0x0000_0001_7fff_ffff;
+
+        let cases = [
+            (Instr::Addiw { rd, rs1, imm: 1 }, 0xffff_ffff_8000_0000),
+            (Instr::Slliw { rd, rs1, shamt: 1 }, 0xffff_ffff_ffff_fffe),
+            (Instr::Srliw { rd, rs1, shamt: 0 }, 0x0000_0000_7fff_ffff),
+            (Instr::Sraiw { rd, rs1, shamt: 31 }, 0),
+        ];
+
+        for (instr, expected) in cases {
+            execute(&mut cpu, &mut mem, &mut mmu, instr, None).expect("op-imm-32 should execute");
+            assert_eq!(cpu.regs[rd as usize], expected, "{:?}", instr);
+        }
+
+        cpu.regs[rs1 as usize] = 0x0000_0000_8000_0000;
+        let sraiw = Instr::Sraiw { rd, rs1, shamt: 4 };
+        execute(&mut cpu, &mut mem, &mut mmu, sraiw, None).unwrap();
+        assert_eq!(cpu.regs[rd as usize], 0xffff_ffff_f800_0000);
+        let srliw = Instr::Srliw { rd, rs1, shamt: 31 };
+        execute(&mut cpu, &mut mem, &mut mmu, srliw, None).unwrap();
+        assert_eq!(cpu.regs[rd as usize], 1);
+    }
+
+    #[test]
+    fn test_lw_sign_extends_and_lwu_zero_extends() {
+        let mut cpu = Cpu::default();
+        let mut mem = Memory::new(0x10000);
+        let mut mmu = Mmu::new();
+
+        mem.write_u32_phys(0x8000_0100, 0xffff_ffff).unwrap();
+        cpu.pc = 0x8000_0000;
+        let (rd, rs1, off) = (7, 5, 0x100);
+        cpu.regs[rs1 as usize] = 0x8000_0000;
+
+        let lw = Instr::LW { rd, rs1, off };
+        execute(&mut cpu, &mut mem, &mut mmu, lw, None).unwrap();
+        assert_eq!(cpu.regs[rd as usize], 0xffff_ffff_ffff_ffff);
+
+        let lwu = Instr::LWU { rd, rs1, off };
+        execute(&mut cpu, &mut mem, &mut mmu, lwu, None).unwrap();
+        assert_eq!(cpu.regs[rd as usize], 0x0000_0000_ffff_ffff);
+    }
+
+    #[test]
+    fn test_store_load_round_trip_each_width() {
+        let mut cpu = Cpu::default();
+        let mut mem = Memory::new(0x10000);
+        let mut mmu = Mmu::new();
+
+        cpu.pc = 0x8000_0000;
+        let (rd, rs1, rs2) = (7, 5, 6);
+        cpu.regs[rs1 as usize] = 0x8000_1000;
+        cpu.regs[rs2 as usize] = 0x1122_3344_5566_7788;
+
+        // Each store only writes the low bytes of rs2
+        let cases = [
+            (
+                Instr::SB { rs1, rs2, off: 0 },
+                Instr::LBU { rd, rs1, off: 0 },
+                0x88,
+            ),
+            (
+                Instr::SH { rs1, rs2, off: 8 },
+                Instr::LHU { rd, rs1, off: 8 },
+                0x7788,
+            ),
+            (
+                Instr::SW { rs1, rs2, off: 16 },
+                Instr::LWU { rd, rs1, off: 16 },
+                0x5566_7788,
+            ),
+            (
+                Instr::SD { rs1, rs2, off: 24 },
+                Instr::LD { rd, rs1, off: 24 },
+                0x1122_3344_5566_7788,
+            ),
+        ];
+
+        for (store, load, expected) in cases {
+            execute(&mut cpu, &mut mem, &mut mmu, store, None).expect("store should execute");
+            execute(&mut cpu, &mut mem, &mut mmu, load, None).expect("load should execute");
+            assert_eq!(cpu.regs[rd as usize], expected, "{:?}", store);
+        }
+
+        // Neighbouring bytes are untouched by narrower stores
+        assert_eq!(mem.read_u64_phys(0x8000_1000).unwrap(), 0x88);
+        assert_eq!(mem.read_u64_phys(0x8000_1008).unwrap(), 0x7788);
+    }
+
+    #[test]
+    fn test_csr_read_only_write_traps_but_x0_set_does_not() {
+        let mut cpu = Cpu::default();
+        let mut mem = Memory::new(0x10000);
+        let mut mmu = Mmu::new();
+
+        cpu.pc = 0x8000_0000;
+        cpu.regs[5] = 0x1;
+        cpu.regs[7] = 0x5555;
+        let csr = 0xF14; // mhartid (read-only)
+
+        // csrr t2, mhartid (csrrs with rs1=x0) must not attempt a write
+        let csrr = Instr::Csrrs { rd: 7, csr, rs1: 0 };
+        let r = execute(&mut cpu, &mut mem, &mut mmu, csrr, None);
+        assert!(r.is_ok(), "csrrs with rs1=x0 should not write");
+        assert_eq!(cpu.regs[7], 0);
+
+        // A real write to a read-only CSR is an illegal instruction, and rd is left untouched
+        cpu.pc = 0x8000_0000;
+        cpu.regs[7] = 0x5555;
+        let csrs = Instr::Csrrs { rd: 7, csr, rs1: 5 };
+        match execute(&mut cpu, &mut mem, &mut mmu, csrs, None) {
+            Err(CpuStepResult::Trapped(Trap::IllegalInstruction { pc, .. })) => {
+                assert_eq!(pc, 0x8000_0000);
+            }
+            other => panic!("expected illegal instruction, got {:?}", other),
+        }
+        assert_eq!(cpu.regs[7], 0x5555, "trapping csr op must not write rd");
+        assert_eq!(cpu.pc, 0x8000_0000);
+    }
+
+    #[test]
+    fn test_ebreak_raises_breakpoint_with_pc_as_tval() {
+        let mut cpu = Cpu::default();
+        let mut mem = Memory::new(0x10000);
+        let mut mmu = Mmu::new();
+
+        cpu.pc = 0x8000_0040;
+        match execute(&mut cpu, &mut mem, &mut mmu, Instr::Ebreak, None) {
+            Err(CpuStepResult::Trapped(trap @ Trap::Breakpoint { .. })) => {
+                assert_eq!(trap.cause(), 3);
+                assert_eq!(trap.tval(), 0x8000_0040);
+                assert_eq!(trap.pc(), 0x8000_0040);
+            }
+            other => panic!("expected breakpoint trap, got {:?}", other),
+        }
+        assert_eq!(cpu.pc, 0x8000_0040, "ebreak must not advance pc");
+    }
+
+    #[test]
+    fn test_mret_restores_privilege_stack_and_jumps_to_mepc() {
+        let mut cpu = Cpu::default();
+        let mut mem = Memory::new(0x10000);
+        let mut mmu = Mmu::new();
+
+        cpu.pc = 0x8000_0100;
+        cpu.csr.mepc = 0x8000_2000;
+        cpu.csr.set_mpp(PrivMode::Supervisor);
+        cpu.csr.mstatus |= (1 << 7) | (1 << 17); // MPIE, MPRV
+
+        execute(&mut cpu, &mut mem, &mut mmu, Instr::Mret, None).expect("mret in M-mode");
+
+        assert_eq!(cpu.pc, 0x8000_2000);
+        assert_eq!(cpu.csr.priv_mode, PrivMode::Supervisor);
+        assert_eq!(cpu.csr.mpp(), PrivMode::User, "MPP resets to U");
+        assert_ne!(cpu.csr.mstatus & (1 << 3), 0, "MIE restored from MPIE");
+        assert_ne!(cpu.csr.mstatus & (1 << 7), 0, "MPIE set to 1");
+        assert_eq!(cpu.csr.mstatus & (1 << 17), 0, "MPRV cleared");
+
+        // Now in S-mode, where MRET is illegal
+        let r = execute(&mut cpu, &mut mem, &mut mmu, Instr::Mret, None);
+        assert!(matches!(
+            r,
+            Err(CpuStepResult::Trapped(Trap::IllegalInstruction { .. }))
+        ));
+    }
+
+    #[test]
+    fn test_xret_without_c_returns_to_a_word_boundary() {
+        let mut cpu = Cpu::default();
+        let mut mem = Memory::new(0x10000);
+        let mut mmu = Mmu::new();
+        cpu.csr.misa &= !(1 << (b'C' - b'A'));
+        cpu.csr.set_mpp(PrivMode::Machine);
+        cpu.csr.write(0x341, 0x8000_2006).unwrap();
+        cpu.csr.write(0x141, 0x8000_3002).unwrap();
+
+        execute(&mut cpu, &mut mem, &mut mmu, Instr::Mret, None).unwrap();
+        assert_eq!(cpu.pc, 0x8000_2004);
+        execute(&mut cpu, &mut mem, &mut mmu, Instr::Sret, None).unwrap();
+        assert_eq!(cpu.pc, 0x8000_3000);
+    }
+
+    #[test]
+    fn test_sret_restores_privilege_stack_and_jumps_to_sepc() {
+        let mut cpu = Cpu::default();
+        let mut mem = Memory::new(0x10000);
+        let mut mmu = Mmu::new();
+
+        cpu.pc = 0x8000_0100;
+        cpu.csr.priv_mode = PrivMode::Supervisor;
+        cpu.csr.sepc = 0x8000_3000;
+        cpu.csr.set_spp(PrivMode::User);
+        cpu.csr.mstatus |= 1 << 5; // SPIE
+
+        execute(&mut cpu, &mut mem, &mut mmu, Instr::Sret, None).expect("sret in S-mode");
+
+        assert_eq!(cpu.pc, 0x8000_3000);
+        assert_eq!(cpu.csr.priv_mode, PrivMode::User);
+        assert_ne!(cpu.csr.mstatus & (1 << 1), 0, "SIE restored from SPIE");
+        assert_ne!(cpu.csr.mstatus & (1 << 5), 0, "SPIE set to 1");
+
+        let r = execute(&mut cpu, &mut mem, &mut mmu, Instr::Sret, None);
+        assert!(matches!(
+            r,
+            Err(CpuStepResult::Trapped(Trap::IllegalInstruction { .. }))
+        ));
+    }
+
+    #[test]
+    fn test_sfence_vma_makes_remapping_visible() {
+        let mut cpu = Cpu::default();
+        let mut mem = Memory::new(0x10000);
+        let mut mmu = Mmu::new();
+
+        // VA 0x4000_0000 -> PA 0x8000_8000 through root/L1/L0 tables
+        let (root, l1, l0) = (0x8000_2000u64, 0x8000_3000u64, 0x8000_4000u64);
+        let pte = |pa: u64, flags: u64| ((pa >> 12) << 10) | flags;
+        mem.write_u64_phys(root + 8, pte(l1, 0x1)).unwrap();
+        mem.write_u64_phys(l1, pte(l0, 0x1)).unwrap();
+        mem.write_u64_phys(l0, pte(0x8000_8000, 0xc7)).unwrap(); // V|R|W|A|D
+        mem.write_u64_phys(0x8000_8000, 0x11).unwrap();
+        mem.write_u64_phys(0x8000_9000, 0x22).unwrap();
+
+        cpu.csr.priv_mode = PrivMode::Supervisor;
+        cpu.csr.satp = (8u64 << 60) | (root >> 12);
+        cpu.regs[1] = 0x4000_0000;
+        let load = Instr::LD {
+            rd: 2,
+            rs1: 1,
+            off: 0,
+        };
+
+        execute(&mut cpu, &mut mem, &mut mmu, load, None).unwrap();
+        assert_eq!(cpu.regs[2], 0x11);
+
+        // Remap the page; the TLB still holds the old translation
+        mem.write_u64_phys(l0, pte(0x8000_9000, 0xc7)).unwrap();
+        execute(&mut cpu, &mut mem, &mut mmu, load, None).unwrap();
+        assert_eq!(cpu.regs[2], 0x11, "stale until sfence.vma");
+
+        let sfence = Instr::SfenceVma { rs1: 1, rs2: 0 };
+        execute(&mut cpu, &mut mem, &mut mmu, sfence, None).unwrap();
+        execute(&mut cpu, &mut mem, &mut mmu, load, None).unwrap();
+        assert_eq!(cpu.regs[2], 0x22);
+    }
+
+    #[test]
+    fn test_sfence_vma_illegal_in_user_mode_and_under_tvm() {
+        let mut cpu = Cpu::default();
+        let mut mem = Memory::new(0x10000);
+        let mut mmu = Mmu::new();
+        let sfence = Instr::SfenceVma { rs1: 0, rs2: 0 };
+
+        cpu.csr.priv_mode = PrivMode::User;
+        let r = execute(&mut cpu, &mut mem, &mut mmu, sfence, None);
+        assert!(matches!(
+            r,
+            Err(CpuStepResult::Trapped(Trap::IllegalInstruction { .. }))
+        ));
+
+        cpu.csr.priv_mode = PrivMode::Supervisor;
+        cpu.csr.mstatus |= CsrFile::MSTATUS_TVM;
+        let r = execute(&mut cpu, &mut mem, &mut mmu, sfence, None);
+        assert!(matches!(
+            r,
+            Err(CpuStepResult::Trapped(Trap::IllegalInstruction { .. }))
+        ));
+
+        // M-mode ignores TVM
+        cpu.csr.priv_mode = PrivMode::Machine;
+        assert!(execute(&mut cpu, &mut mem, &mut mmu, sfence, None).is_ok());
+    }
+
+    #[test]
+    fn test_tvm_traps_supervisor_satp_access() {
+        let mut cpu = Cpu::default();
+        let mut mem = Memory::new(0x10000);
+        let mut mmu = Mmu::new();
+        let read = Instr::Csrrs {
+            rd: 5,
+            csr: 0x180,
+            rs1: 0,
+        };
+        let write = Instr::Csrrw {
+            rd: 0,
+            csr: 0x180,
+            rs1: 0,
+        };
+
+        cpu.csr.priv_mode = PrivMode::Supervisor;
+        assert!(execute(&mut cpu, &mut mem, &mut mmu, read, None).is_ok());
+        cpu.csr.priv_mode = PrivMode::Machine;
+        cpu.csr.write(0x300, CsrFile::MSTATUS_TVM).unwrap();
+        cpu.csr.priv_mode = PrivMode::Supervisor;
+        for instr in [read, write] {
+            let r = execute(&mut cpu, &mut mem, &mut mmu, instr, None);
+            assert!(matches!(
+                r,
+                Err(CpuStepResult::Trapped(Trap::IllegalInstruction { .. }))
+            ));
+        }
+
+        cpu.csr.priv_mode = PrivMode::Machine;
+        assert!(execute(&mut cpu, &mut mem, &mut mmu, write, None).is_ok());
+    }
+
+    #[test]
+    fn test_tsr_traps_sret_in_supervisor_mode() {
+        let mut cpu = Cpu::default();
+        let mut mem = Memory::new(0x10000);
+        let mut mmu = Mmu::new();
+        cpu.csr.sepc = 0x8000_0100;
+
+        cpu.csr.write(0x300, CsrFile::MSTATUS_TSR).unwrap();
+        assert_ne!(cpu.csr.mstatus & CsrFile::MSTATUS_TSR, 0, "TSR is writable");
+        cpu.csr.priv_mode = PrivMode::Supervisor;
+        let r = execute(&mut cpu, &mut mem, &mut mmu, Instr::Sret, None);
+        assert!(matches!(
+            r,
+            Err(CpuStepResult::Trapped(Trap::IllegalInstruction { .. }))
+        ));
+
+        // M-mode may still use SRET
+        cpu.csr.priv_mode = PrivMode::Machine;
+        execute(&mut cpu, &mut mem, &mut mmu, Instr::Sret, None).unwrap();
+        assert_eq!(cpu.pc, 0x8000_0100);
+        assert_eq!(cpu.csr.priv_mode, PrivMode::User);
+    }
+
+    #[test]
+    fn test_wfi_privilege_and_stall() {
+        let mut cpu = Cpu::default();
+        let mut mem = Memory::new(0x10000);
+        let mut mmu = Mmu::new();
+        cpu.pc = 0x8000_0000;
+
+        cpu.csr.priv_mode = PrivMode::User;
+        let r = execute(&mut cpu, &mut mem, &mut mmu, Instr::Wfi, None);
+        assert!(matches!(
+            r,
+            Err(CpuStepResult::Trapped(Trap::IllegalInstruction { .. }))
+        ));
+        cpu.csr.priv_mode = PrivMode::Supervisor;
+        cpu.csr.mstatus |= CsrFile::MSTATUS_TW;
+        let r = execute(&mut cpu, &mut mem, &mut mmu, Instr::Wfi, None);
+        assert!(matches!(
+            r,
+            Err(CpuStepResult::Trapped(Trap::IllegalInstruction { .. }))
+        ));
+        assert!(!cpu.wfi);
+
+        // M-mode ignores TW; with nothing pending the hart stalls
+        cpu.csr.priv_mode = PrivMode::Machine;
+        execute(&mut cpu, &mut mem, &mut mmu, Instr::Wfi, None).unwrap();
+        assert!(cpu.wfi);
+        assert_eq!(cpu.pc, 0x8000_0004);
+
+        // An enabled pending interrupt makes it a nop
+        cpu.wfi = false;
+        cpu.csr.mie |= 1 << 3;
+        cpu.csr.mip |= 1 << 3;
+        execute(&mut cpu, &mut mem, &mut mmu, Instr::Wfi, None).unwrap();
+        assert!(!cpu.wfi);
+        assert_eq!(cpu.pc, 0x8000_0008);
+    }
+
+    #[test]
+    fn test_misaligned_access_traps_unless_allowed() {
+        let mut cpu = Cpu::default();
+        let mut mem = Memory::new(0x10000);
+        let mut mmu = Mmu::new();
+
+        let (rd, rs1, rs2) = (7, 5, 6);
+        cpu.regs[rs1 as usize] = 0x8000_1001;
+        cpu.regs[rs2 as usize] = 0x1122_3344_5566_7788;
+
+        let off = 0;
+        let load = causes::LOAD_ADDRESS_MISALIGNED;
+        let store = causes::STORE_ADDRESS_MISALIGNED;
+        let cases = [
+            (Instr::LH { rd, rs1, off }, load),
+            (Instr::LW { rd, rs1, off }, load),
+            (Instr::LD { rd, rs1, off }, load),
+            (Instr::SH { rs1, rs2, off }, store),
+            (Instr::SW { rs1, rs2, off }, store),
+            (Instr::SD { rs1, rs2, off }, store),
+        ];
+
+        for (instr, cause) in cases {
+            match execute(&mut cpu, &mut mem, &mut mmu, instr, None) {
+                Err(CpuStepResult::Trapped(trap)) => {
+                    assert_eq!(trap.cause(), cause, "{:?}", instr);
+                    assert_eq!(trap.tval(), 0x8000_1001, "{:?}", instr);
+                }
+                other => panic!("{:?}: expected misaligned trap, got {:?}", instr, other),
+            }
+        }
+
+        // With emulation enabled the same accesses succeed
+        mem.allow_misaligned = true;
+        let sd = Instr::SD { rs1, rs2, off };
+        execute(&mut cpu, &mut mem, &mut mmu, sd, None).unwrap();
+        let ld = Instr::LD { rd, rs1, off };
+        execute(&mut cpu, &mut mem, &mut mmu, ld, None).unwrap();
+        assert_eq!(cpu.regs[rd as usize], 0x1122_3344_5566_7788);
+    }
+
+    #[test]
+    fn test_unbacked_physical_access_raises_access_fault() {
+        let mut cpu = Cpu::default();
+        let mut mem = Memory::new(0x10000);
+        let mut mmu = Mmu::new();
+
+        let (rd, rs1, rs2, off) = (7, 5, 6, 0);
+        cpu.regs[rs1 as usize] = 0x4000_0000; // below RAM, no device
+        let cases = [
+            (Instr::LD { rd, rs1, off }, causes::LOAD_ACCESS_FAULT),
+            (Instr::SW { rs1, rs2, off }, causes::STORE_ACCESS_FAULT),
+            (Instr::SB { rs1, rs2, off }, causes::STORE_ACCESS_FAULT),
+        ];
+
+        for (instr, cause) in cases {
+            match execute(&mut cpu, &mut mem, &mut mmu, instr, None) {
+                Err(CpuStepResult::Trapped(trap)) => {
+                    assert_eq!(trap.cause(), cause, "{:?}", instr);
+                    assert_eq!(trap.tval(), 0x4000_0000, "{:?}", instr);
+                }
+                other => panic!("{:?}: expected access fault, got {:?}", instr, other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_mprv_translates_machine_loads_at_mpp() {
+        let mut cpu = Cpu::default();
+        let mut mem = Memory::new(0x10000);
+        let mut mmu = Mmu::new();
+
+        // VA 0x4000_0000 -> PA 0x8000_8000, user-accessible (V|R|W|U|A|D)
+        let (root, l1, l0) = (0x8000_2000u64, 0x8000_3000u64, 0x8000_4000u64);
+        let pte = |pa: u64, flags: u64| ((pa >> 12) << 10) | flags;
+        mem.write_u64_phys(root + 8, pte(l1, 0x1)).unwrap();
+        mem.write_u64_phys(l1, pte(l0, 0x1)).unwrap();
+        mem.write_u64_phys(l0, pte(0x8000_8000, 0xd7)).unwrap();
+        mem.write_u64_phys(0x8000_8000, 0x5a).unwrap();
+
+        cpu.csr.priv_mode = PrivMode::Machine;
+        cpu.csr.satp = (8u64 << 60) | (root >> 12);
+        cpu.csr.set_mpp(PrivMode::User);
+        cpu.regs[1] = 0x4000_0000;
+        let load = Instr::LD {
+            rd: 2,
+            rs1: 1,
+            off: 0,
+        };
+
+        // Without MPRV, M-mode is untranslated and 0x4000_0000 isn't RAM
+        let r = execute(&mut cpu, &mut mem, &mut mmu, load, None);
+        assert!(matches!(
+            r,
+            Err(CpuStepResult::Trapped(Trap::LoadAccessFault { .. }))
+        ));
+
+        cpu.csr.mstatus |= 1 << 17; // MPRV
+        execute(&mut cpu, &mut mem, &mut mmu, load, None).unwrap();
+        assert_eq!(cpu.regs[2], 0x5a);
+        assert_eq!(cpu.csr.priv_mode, PrivMode::Machine);
+
+        // MPP=S can't touch a U page (SUM clear)
+        cpu.csr.set_mpp(PrivMode::Supervisor);
+        let r = execute(&mut cpu, &mut mem, &mut mmu, load, None);
+        assert!(matches!(
+            r,
+            Err(CpuStepResult::Trapped(Trap::LoadPageFault { .. }))
+        ));
+    }
+
+    #[test]
+    fn test_lr_sc_reservation() {
+        let mut cpu = Cpu::default();
+        let mut mem = Memory::new(0x10000);
+        let mut mmu = Mmu::new();
+
+        let (rd, rs1, rs2) = (7, 5, 6);
+        cpu.regs[rs1 as usize] = 0x8000_1000;
+        cpu.regs[rs2 as usize] = 0x1122_3344_5566_7788;
+        mem.write_u64_phys(0x8000_1000, 0xffff_ffff_8000_0000)
+            .unwrap();
+        let lr_w = Instr::LrW { rd, rs1 };
+        let sc_w = Instr::ScW { rd, rs1, rs2 };
+        let sc_d = Instr::ScD { rd, rs1, rs2 };
+
+        // SC without a reservation fails and does not store
+        execute(&mut cpu, &mut mem, &mut mmu, sc_d, None).unwrap();
+        assert_eq!(cpu.regs[rd as usize], 1);
+        assert_eq!(
+            mem.read_u64_phys(0x8000_1000).unwrap(),
+            0xffff_ffff_8000_0000
+        );
+
+        // LR.W sign-extends; the paired SC.W succeeds once
+        execute(&mut cpu, &mut mem, &mut mmu, lr_w, None).unwrap();
+        assert_eq!(cpu.regs[rd as usize], 0xffff_ffff_8000_0000);
+        execute(&mut cpu, &mut mem, &mut mmu, sc_w, None).unwrap();
+        assert_eq!(cpu.regs[rd as usize], 0);
+        assert_eq!(mem.read_u32_phys(0x8000_1000).unwrap(), 0x5566_7788);
+        execute(&mut cpu, &mut mem, &mut mmu, sc_w, None).unwrap();
+        assert_eq!(cpu.regs[rd as usize], 1, "reservation is consumed by SC");
+
+        // An intervening store to the reserved doubleword breaks the reservation
+        execute(&mut cpu, &mut mem, &mut mmu, Instr::LrD { rd, rs1 }, None).unwrap();
+        let sb = Instr::SB { rs1, rs2, off: 7 };
+        execute(&mut cpu, &mut mem, &mut mmu, sb, None).unwrap();
+        execute(&mut cpu, &mut mem, &mut mmu, sc_d, None).unwrap();
+        assert_eq!(cpu.regs[rd as usize], 1);
+
+        // ...but a store elsewhere does not
+        execute(&mut cpu, &mut mem, &mut mmu, Instr::LrD { rd, rs1 }, None).unwrap();
+        let sd = Instr::SD { rs1, rs2, off: 8 };
+        execute(&mut cpu, &mut mem, &mut mmu, sd, None).unwrap();
+        execute(&mut cpu, &mut mem, &mut mmu, sc_d, None).unwrap();
+        assert_eq!(cpu.regs[rd as usize], 0);
+
+        // A trap in between also breaks it
+        execute(&mut cpu, &mut mem, &mut mmu, lr_w, None).unwrap();
+        cpu.take_trap(causes::ECALL_M, 0, false);
+        execute(&mut cpu, &mut mem, &mut mmu, sc_w, None).unwrap();
+        assert_eq!(cpu.regs[rd as usize], 1);
+    }
+
+    #[test]
+    fn test_lr_sc_require_alignment_even_when_emulated() {
+        let mut cpu = Cpu::default();
+        let mut mem = Memory::new(0x10000);
+        let mut mmu = Mmu::new();
+        mem.allow_misaligned = true;
+
+        let (rd, rs1, rs2) = (7, 5, 6);
+        cpu.regs[rs1 as usize] = 0x8000_1004;
+        let cases = [
+            (Instr::LrD { rd, rs1 }, causes::LOAD_ADDRESS_MISALIGNED),
+            (
+                Instr::ScD { rd, rs1, rs2 },
+                causes::STORE_ADDRESS_MISALIGNED,
+            ),
+        ];
+        for (instr, cause) in cases {
+            match execute(&mut cpu, &mut mem, &mut mmu, instr, None) {
+                Err(CpuStepResult::Trapped(trap)) => assert_eq!(trap.cause(), cause),
+                other => panic!("{:?}: expected misaligned trap, got {:?}", instr, other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_amo_ops_word_and_double() {
+        let mut cpu = Cpu::default();
+        let mut mem = Memory::new(0x10000);
+        let mut mmu = Mmu::new();
+
+        let (rd, rs1, rs2) = (7, 5, 6);
+        let addr = 0x8000_1000;
+        cpu.regs[rs1 as usize] = addr;
+
+        // (op, memory before, rs2, memory after); rd always gets the old value
+        let w_cases: [(AmoOp, u32, u64, u32); 9] = [
+            (AmoOp::Swap, 0x8000_0000, 0x1234_5678, 0x1234_5678),
+            (AmoOp::Add, 0xffff_ffff, 0x2, 0x1),
+            (AmoOp::Xor, 0xff00_ff00, 0x0ff0_0ff0, 0xf0f0_f0f0),
+            (AmoOp::And, 0xff00_ff00, 0x0ff0_0ff0, 0x0f00_0f00),
+            (AmoOp::Or, 0xff00_ff00, 0x0ff0_0ff0, 0xfff0_fff0),
+            // -1 vs 1 at 32 bits, with junk in the upper half of rs2 ignored
+            (AmoOp::Min, 0xffff_ffff, 0xdead_0000_0000_0001, 0xffff_ffff),
+            (AmoOp::Max, 0xffff_ffff, 0xdead_0000_0000_0001, 0x1),
+            (AmoOp::Minu, 0xffff_ffff, 0xdead_0000_0000_0001, 0x1),
+            (AmoOp::Maxu, 0xffff_ffff, 0xdead_0000_0000_0001, 0xffff_ffff),
+        ];
+        for (op, before, src, after) in w_cases {
+            mem.write_u32_phys(addr, before).unwrap();
+            cpu.regs[rs2 as usize] = src;
+            execute(
+                &mut cpu,
+                &mut mem,
+                &mut mmu,
+                Instr::AmoW { op, rd, rs1, rs2 },
+                None,
+            )
+            .unwrap();
+            assert_eq!(mem.read_u32_phys(addr).unwrap(), after, "{op:?}.W");
+            assert_eq!(
+                cpu.regs[rd as usize], before as i32 as i64 as u64,
+                "{op:?}.W rd"
+            );
+        }
+
+        let d_cases: [(AmoOp, u64, u64, u64); 5] = [
+            (AmoOp::Add, u64::MAX, 2, 1),
+            (AmoOp::Min, u64::MAX, 1, u64::MAX),
+            (AmoOp::Max, u64::MAX, 1, 1),
+            (AmoOp::Minu, u64::MAX, 1, 1),
+            (AmoOp::Maxu, 0x8000_0000_0000_0000, 1, 0x8000_0000_0000_0000),
+        ];
+        for (op, before, src, after) in d_cases {
+            mem.write_u64_phys(addr, before).unwrap();
+            cpu.regs[rs2 as usize] = src;
+            execute(
+                &mut cpu,
+                &mut mem,
+                &mut mmu,
+                Instr::AmoD { op, rd, rs1, rs2 },
+                None,
+            )
+            .unwrap();
+            assert_eq!(mem.read_u64_phys(addr).unwrap(), after, "{op:?}.D");
+            assert_eq!(cpu.regs[rd as usize], before, "{op:?}.D rd");
+        }
+
+        // rd == rs2 must still use the pre-instruction rs2 value
+        mem.write_u64_phys(addr, 10).unwrap();
+        cpu.regs[rs2 as usize] = 5;
+        let op = AmoOp::Add;
+        execute(
+            &mut cpu,
+            &mut mem,
+            &mut mmu,
+            Instr::AmoD {
+                op,
+                rd: rs2,
+                rs1,
+                rs2,
+            },
+            None,
+        )
+        .unwrap();
+        assert_eq!(mem.read_u64_phys(addr).unwrap(), 15);
+        assert_eq!(cpu.regs[rs2 as usize], 10);
+    }
+
+    #[test]
+    fn test_mbe_swaps_data_accesses() {
+        let mut cpu = Cpu::default();
+        let mut mem = Memory::new(0x10000);
+        let mut mmu = Mmu::new();
+        cpu.csr.mstatus |= CsrFile::MSTATUS_MBE;
+
+        let (rd, rs1, rs2) = (7, 5, 6);
+        let addr = 0x8000_1000;
+        cpu.regs[rs1 as usize] = addr;
+        cpu.regs[rs2 as usize] = 0x1122_3344;
+        execute(
+            &mut cpu,
+            &mut mem,
+            &mut mmu,
+            Instr::SW { rs1, rs2, off: 0 },
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            mem.read_bytes_phys(addr, 4).unwrap(),
+            [0x11, 0x22, 0x33, 0x44]
+        );
+
+        // The AMO sees the big-endian value and writes its result back the same way
+        cpu.regs[rs2 as usize] = 1;
+        let amoadd = Instr::AmoW {
+            op: AmoOp::Add,
+            rd,
+            rs1,
+            rs2,
+        };
+        execute(&mut cpu, &mut mem, &mut mmu, amoadd, None).unwrap();
+        assert_eq!(cpu.regs[rd as usize], 0x1122_3344);
+        assert_eq!(
+            mem.read_bytes_phys(addr, 4).unwrap(),
+            [0x11, 0x22, 0x33, 0x45]
+        );
+
+        execute(
+            &mut cpu,
+            &mut mem,
+            &mut mmu,
+            Instr::LD { rd, rs1, off: 0 },
+            None,
+        )
+        .unwrap();
+        assert_eq!(cpu.regs[rd as usize], 0x1122_3345_0000_0000);
+
+        // UBE doesn't apply to M-mode accesses
+        cpu.csr.mstatus ^= CsrFile::MSTATUS_MBE | CsrFile::MSTATUS_UBE;
+        execute(
+            &mut cpu,
+            &mut mem,
+            &mut mmu,
+            Instr::LD { rd, rs1, off: 0 },
+            None,
+        )
+        .unwrap();
+        assert_eq!(cpu.regs[rd as usize], 0x4533_2211);
+    }
+
+    #[test]
+    fn test_amo_misaligned_and_breaks_reservation() {
+        let mut cpu = Cpu::default();
+        let mut mem = Memory::new(0x10000);
+        let mut mmu = Mmu::new();
+        mem.allow_misaligned = true;
+
+        let (rd, rs1, rs2) = (7, 5, 6);
+        let op = AmoOp::Swap;
+        cpu.regs[rs1 as usize] = 0x8000_1004;
+        let r = execute(
+            &mut cpu,
+            &mut mem,
+            &mut mmu,
+            Instr::AmoD { op, rd, rs1, rs2 },
+            None,
+        );
+        assert!(matches!(
+            r,
+            Err(CpuStepResult::Trapped(Trap::StoreMisaligned {
+                addr: 0x8000_1004,
+                ..
+            }))
+        ));
+
+        execute(&mut cpu, &mut mem, &mut mmu, Instr::LrW { rd, rs1 }, None).unwrap();
+        execute(
+            &mut cpu,
+            &mut mem,
+            &mut mmu,
+            Instr::AmoW { op, rd, rs1, rs2 },
+            None,
+        )
+        .unwrap();
+        execute(
+            &mut cpu,
+            &mut mem,
+            &mut mmu,
+            Instr::ScW { rd, rs1, rs2 },
+            None,
+        )
+        .unwrap();
+        assert_eq!(cpu.regs[rd as usize], 1);
+    }
+
+    #[test]
+    fn test_misaligned_jump_target_traps_without_c() {
+        let mut cpu = Cpu::default();
+        let mut mem = Memory::new(0x10000);
+        let mut mmu = Mmu::new();
+        cpu.regs[5] = 0x8000_0202;
+
+        let jal = Instr::Jal { rd: 1, off: 0x102 };
+        let jalr = Instr::Jalr {
+            rd: 1,
+            rs1: 5,
+            off: 1,
+        }; // bit 0 is dropped
+        let beq = Instr::Beq {
+            rs1: 0,
+            rs2: 0,
+            off: -6,
+        };
+
+        // With C every even target is fine
+        for instr in [jal, jalr, beq] {
+            cpu.pc = 0x8000_0100;
+            execute(&mut cpu, &mut mem, &mut mmu, instr, None).unwrap();
+            assert_eq!(cpu.pc & 1, 0, "{instr:?}");
+        }
+
+        cpu.csr.misa &= !(1 << 2);
+        for (instr, target) in [(jal, 0x8000_0202), (jalr, 0x8000_0202), (beq, 0x8000_00fa)] {
+            cpu.pc = 0x8000_0100;
+            cpu.regs[1] = 0;
+            let r = execute(&mut cpu, &mut mem, &mut mmu, instr, None);
+            match r {
+                Err(CpuStepResult::Trapped(trap)) => {
+                    assert_eq!(trap.cause(), causes::INSTRUCTION_ADDRESS_MISALIGNED);
+                    assert_eq!(trap.tval(), target, "{instr:?}");
+                    assert_eq!(trap.pc(), 0x8000_0100);
+                }
+                other => panic!("{instr:?}: {other:?}"),
+            }
+            assert_eq!(cpu.regs[1], 0, "rd is not written by a trapping jump");
+        }
+
+        // A branch that isn't taken never checks its target
+        let bne = Instr::Bne {
+            rs1: 0,
+            rs2: 0,
+            off: -6,
+        };
+        execute(&mut cpu, &mut mem, &mut mmu, bne, None).unwrap();
+        assert_eq!(cpu.pc, 0x8000_0104);
+    }
+
+    #[test]
+    fn test_f_load_arith_store() {
+        let mut cpu = Cpu::default();
+        let mut mem = Memory::new(0x10000);
+        let mut mmu = Mmu::new();
+        cpu.csr.mstatus |= 1 << 13; // FS = Initial
+        cpu.regs[5] = 0x8000_1000;
+        mem.write_u32_phys(0x8000_1000, 1.5f32.to_bits()).unwrap();
+        mem.write_u32_phys(0x8000_1004, 3.0f32.to_bits()).unwrap();
+
+        let steps = [
+            Instr::Flw {
+                rd: 1,
+                rs1: 5,
+                off: 0,
+            },
+            Instr::Flw {
+                rd: 2,
+                rs1: 5,
+                off: 4,
+            },
+            Instr::FmulS {
+                rd: 3,
+                rs1: 1,
+                rs2: 2,
+                rm: 0b111,
+            },
+            Instr::FdivS {
+                rd: 4,
+                rs1: 3,
+                rs2: 2,
+                rm: 0,
+            },
+            Instr::FsqrtS {
+                rd: 5,
+                rs1: 4,
+                rm: 0,
+            },
+            Instr::Fsw {
+                rs1: 5,
+                rs2: 3,
+                off: 8,
+            },
+        ];
+        for instr in steps {
+            execute(&mut cpu, &mut mem, &mut mmu, instr, None).unwrap();
+        }
+        assert_eq!(cpu.f[1], 0xffff_ffff_3fc0_0000, "loads are NaN-boxed");
+        assert_eq!(mem.read_u32_phys(0x8000_1008).unwrap(), 4.5f32.to_bits());
+        assert_eq!(fpu::unbox_f32(cpu.f[4]), 1.5);
+        // sqrt(1.5) is inexact
+        assert_eq!(cpu.csr.fcsr, fpu::flags::NX as u64);
+    }
+
+    #[test]
+    fn test_f_rounding_mode_and_fs_gating() {
+        let mut cpu = Cpu::default();
+        let mut mem = Memory::new(0x10000);
+        let mut mmu = Mmu::new();
+        cpu.f[1] = fpu::box_f32(1.0);
+        cpu.f[2] = fpu::box_f32(3.0);
+        let div = |rm| Instr::FdivS {
+            rd: 3,
+            rs1: 1,
+            rs2: 2,
+            rm,
+        };
+
+        // FS = Off: the FPU is disabled
+        let r = execute(&mut cpu, &mut mem, &mut mmu, div(0), None);
+        assert!(matches!(
+            r,
+            Err(CpuStepResult::Trapped(Trap::IllegalInstruction { .. }))
+        ));
+        cpu.csr.mstatus |= 1 << 13;
+
+        // Dynamic rounding follows frm: RUP then RDN straddle 1/3
+        cpu.csr.fcsr = 0b011 << 5;
+        execute(&mut cpu, &mut mem, &mut mmu, div(0b111), None).unwrap();
+        let up = cpu.f[3] as u32;
+        cpu.csr.fcsr = 0b010 << 5;
+        execute(&mut cpu, &mut mem, &mut mmu, div(0b111), None).unwrap();
+        assert_eq!(up, cpu.f[3] as u32 + 1);
+        assert_eq!(cpu.csr.fcsr & 0x1f, fpu::flags::NX as u64);
+
+        // Reserved rm encodings trap, directly or via frm
+        for (rm, frm) in [(0b101, 0), (0b111, 0b110)] {
+            cpu.csr.fcsr = frm << 5;
+            let r = execute(&mut cpu, &mut mem, &mut mmu, div(rm), None);
+            assert!(matches!(
+                r,
+                Err(CpuStepResult::Trapped(Trap::IllegalInstruction { .. }))
+            ));
+        }
+
+        // An improperly boxed operand reads as the canonical NaN
+        cpu.f[1] = 1.0f32.to_bits() as u64;
+        execute(&mut cpu, &mut mem, &mut mmu, div(0), None).unwrap();
+        assert_eq!(
+            cpu.f[3],
+            fpu::box_f32(f32::from_bits(fpu::CANONICAL_NAN_F32))
+        );
+    }
+
+    #[test]
+    fn test_fp_state_changes_mark_fs_dirty() {
+        let mut cpu = Cpu::default();
+        let mut mem = Memory::new(0x10000);
+        let mut mmu = Mmu::new();
+        cpu.csr.mstatus |= 0b10 << 13; // FS = Clean
+        cpu.f[1] = fpu::box_f32(1.0);
+        cpu.set_reg(6, 0x8000_1000);
+        let fs = |cpu: &Cpu| (cpu.csr.mstatus & CsrFile::MSTATUS_FS) >> 13;
+
+        // Reading the FP state leaves it Clean
+        let reads = [
+            Instr::Fsw {
+                rs1: 6,
+                rs2: 1,
+                off: 0,
+            },
+            Instr::FeqS {
+                rd: 5,
+                rs1: 1,
+                rs2: 1,
+            },
+        ];
+        for instr in reads {
+            execute(&mut cpu, &mut mem, &mut mmu, instr, None).unwrap();
+        }
+        assert_eq!(fs(&cpu), 0b10);
+        assert_eq!(cpu.csr.read(0x300).unwrap() >> 63, 0, "SD is clear");
+
+        let add = Instr::FaddS {
+            rd: 2,
+            rs1: 1,
+            rs2: 1,
+            rm: 0,
+        };
+        execute(&mut cpu, &mut mem, &mut mmu, add, None).unwrap();
+        assert_eq!(fs(&cpu), 0b11);
+        assert_eq!(cpu.csr.read(0x300).unwrap() >> 63, 1);
+        assert_eq!(cpu.csr.read(0x100).unwrap() >> 63, 1, "sstatus.SD");
+
+        // Software cleans it again, e.g. after saving the registers
+        cpu.csr
+            .write(0x300, cpu.csr.mstatus & !(0b01 << 13))
+            .unwrap();
+        assert_eq!(fs(&cpu), 0b10);
+        assert_eq!(cpu.csr.read(0x100).unwrap() >> 63, 0);
+    }
+
+    #[test]
+    fn test_d_arith_and_conversions() {
+        let mut cpu = Cpu::default();
+        let mut mem = Memory::new(0x10000);
+        let mut mmu = Mmu::new();
+        cpu.csr.mstatus |= 1 << 13;
+        cpu.regs[5] = -7i64 as u64;
+        cpu.regs[6] = 0x8000_1000;
+
+        let steps = [
+            Instr::FcvtDL {
+                rd: 1,
+                rs1: 5,
+                rm: 0,
+            },
+            Instr::FmvDX { rd: 2, rs1: 0 },
+            Instr::FsubD {
+                rd: 3,
+                rs1: 2,
+                rs2: 1,
+                rm: 0,
+            }, // 0 - (-7)
+            Instr::FsqrtD {
+                rd: 4,
+                rs1: 3,
+                rm: 0b001,
+            },
+            Instr::FcvtWD {
+                rd: 7,
+                rs1: 4,
+                rm: 0b011,
+            }, // ceil(sqrt(7))
+            Instr::FcvtSD {
+                rd: 8,
+                rs1: 3,
+                rm: 0,
+            },
+            Instr::Fsd {
+                rs1: 6,
+                rs2: 3,
+                off: 0,
+            },
+            Instr::Fld {
+                rd: 9,
+                rs1: 6,
+                off: 0,
+            },
+            Instr::FmvXD { rd: 10, rs1: 9 },
+        ];
+        for instr in steps {
+            execute(&mut cpu, &mut mem, &mut mmu, instr, None).unwrap();
+        }
+        assert_eq!(f64::from_bits(cpu.f[1]), -7.0);
+        assert_eq!(cpu.regs[7], 3);
+        assert_eq!(
+            cpu.f[8],
+            fpu::box_f32(7.0),
+            "singles written by fcvt.s.d are boxed"
+        );
+        assert_eq!(cpu.regs[10], 7.0f64.to_bits());
+        assert_eq!(cpu.csr.fcsr & 0x1f, fpu::flags::NX as u64);
+
+        // fcvt.wu.d of a negative saturates to 0 and flags NV
+        cpu.csr.fcsr = 0;
+        execute(
+            &mut cpu,
+            &mut mem,
+            &mut mmu,
+            Instr::FcvtWuD {
+                rd: 7,
+                rs1: 1,
+                rm: 0,
+            },
+            None,
+        )
+        .unwrap();
+        assert_eq!(cpu.regs[7], 0);
+        assert_eq!(cpu.csr.fcsr, fpu::flags::NV as u64);
+    }
+
+    #[test]
+    fn test_fp_compare_writes_integer_rd_and_flags() {
+        let mut cpu = Cpu::default();
+        let mut mem = Memory::new(0x10000);
+        let mut mmu = Mmu::new();
+        cpu.csr.mstatus |= 1 << 13;
+        cpu.f[1] = fpu::box_f32(1.0);
+        cpu.f[2] = fpu::box_f32(f32::from_bits(0x7fc0_0000)); // quiet NaN
+
+        execute(
+            &mut cpu,
+            &mut mem,
+            &mut mmu,
+            Instr::FeqS {
+                rd: 5,
+                rs1: 1,
+                rs2: 2,
+            },
+            None,
+        )
+        .unwrap();
+        assert_eq!((cpu.regs[5], cpu.csr.fcsr), (0, 0), "feq is quiet");
+        execute(
+            &mut cpu,
+            &mut mem,
+            &mut mmu,
+            Instr::FltS {
+                rd: 5,
+                rs1: 1,
+                rs2: 2,
+            },
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            cpu.csr.fcsr,
+            fpu::flags::NV as u64,
+            "flt signals on a quiet NaN"
+        );
+        execute(
+            &mut cpu,
+            &mut mem,
+            &mut mmu,
+            Instr::FleS {
+                rd: 5,
+                rs1: 1,
+                rs2: 1,
+            },
+            None,
+        )
+        .unwrap();
+        assert_eq!(cpu.regs[5], 1);
+
+        // An unboxed single classifies as the canonical quiet NaN
+        cpu.f[3] = 1.0f64.to_bits();
+        execute(
+            &mut cpu,
+            &mut mem,
+            &mut mmu,
+            Instr::FclassS { rd: 6, rs1: 3 },
+            None,
+        )
+        .unwrap();
+        assert_eq!(cpu.regs[6], 1 << 9);
+        execute(
+            &mut cpu,
+            &mut mem,
+            &mut mmu,
+            Instr::FclassD { rd: 6, rs1: 3 },
+            None,
+        )
+        .unwrap();
+        assert_eq!(cpu.regs[6], 1 << 6);
+    }
+
+    #[test]
+    fn test_sign_injection_keeps_nan_payloads() {
+        let mut cpu = Cpu::default();
+        let mut mem = Memory::new(0x10000);
+        let mut mmu = Mmu::new();
+        cpu.csr.mstatus |= 1 << 13;
+        cpu.f[1] = fpu::box_f32(f32::from_bits(0x7f80_0001)); // signaling NaN
+        cpu.f[2] = fpu::box_f32(-0.0);
+        cpu.f[3] = (-2.5f64).to_bits();
+
+        let mut run = |cpu: &mut Cpu, instr| {
+            execute(cpu, &mut mem, &mut mmu, instr, None).unwrap();
+            cpu.f[4]
+        };
+        let fsgnj = Instr::FsgnjS {
+            rd: 4,
+            rs1: 1,
+            rs2: 2,
+        };
+        assert_eq!(
+            run(&mut cpu, fsgnj),
+            fpu::box_f32(f32::from_bits(0xff80_0001))
+        );
+        let fsgnjn = Instr::FsgnjnS {
+            rd: 4,
+            rs1: 2,
+            rs2: 2,
+        };
+        assert_eq!(run(&mut cpu, fsgnjn), fpu::box_f32(0.0));
+        let fsgnjx = Instr::FsgnjxD {
+            rd: 4,
+            rs1: 3,
+            rs2: 3,
+        };
+        assert_eq!(run(&mut cpu, fsgnjx), 2.5f64.to_bits());
+        assert_eq!(cpu.csr.fcsr, 0, "sign injection never raises flags");
+
+        let fmin = Instr::FminS {
+            rd: 4,
+            rs1: 1,
+            rs2: 2,
+        };
+        assert_eq!(run(&mut cpu, fmin), fpu::box_f32(-0.0));
+        assert_eq!(cpu.csr.fcsr, fpu::flags::NV as u64);
+    }
+
+    #[test]
+    fn test_fused_multiply_add_signs_and_flags() {
+        let mut cpu = Cpu::default();
+        let mut mem = Memory::new(0x10000);
+        let mut mmu = Mmu::new();
+        cpu.csr.mstatus |= 1 << 13;
+        cpu.f[1] = 2.0f64.to_bits();
+        cpu.f[2] = 3.0f64.to_bits();
+        cpu.f[3] = 1.0f64.to_bits();
+
+        let cases = [
+            (
+                Instr::FmaddD {
+                    rd: 4,
+                    rs1: 1,
+                    rs2: 2,
+                    rs3: 3,
+                    rm: 0,
+                },
+                7.0,
+            ),
+            (
+                Instr::FmsubD {
+                    rd: 4,
+                    rs1: 1,
+                    rs2: 2,
+                    rs3: 3,
+                    rm: 0,
+                },
+                5.0,
+            ),
+            (
+                Instr::FnmsubD {
+                    rd: 4,
+                    rs1: 1,
+                    rs2: 2,
+                    rs3: 3,
+                    rm: 0,
+                },
+                -5.0,
+            ),
+            (
+                Instr::FnmaddD {
+                    rd: 4,
+                    rs1: 1,
+                    rs2: 2,
+                    rs3: 3,
+                    rm: 0,
+                },
+                -7.0,
+            ),
+        ];
+        for (instr, want) in cases {
+            execute(&mut cpu, &mut mem, &mut mmu, instr, None).unwrap();
+            assert_eq!(f64::from_bits(cpu.f[4]), want, "{instr:?}");
+        }
+        assert_eq!(cpu.csr.fcsr, 0);
+
+        // Single precision: 0 * inf raises NV and writes a boxed canonical NaN
+        cpu.f[1] = fpu::box_f32(0.0);
+        cpu.f[2] = fpu::box_f32(f32::INFINITY);
+        cpu.f[3] = fpu::box_f32(1.0);
+        execute(
+            &mut cpu,
+            &mut mem,
+            &mut mmu,
+            Instr::FnmaddS {
+                rd: 4,
+                rs1: 1,
+                rs2: 2,
+                rs3: 3,
+                rm: 0b111,
+            },
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            cpu.f[4],
+            fpu::box_f32(f32::from_bits(fpu::CANONICAL_NAN_F32))
+        );
+        assert_eq!(cpu.csr.fcsr, fpu::flags::NV as u64);
+    }
 }
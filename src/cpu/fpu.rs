@@ -0,0 +1,1337 @@
+//! Floating-point arithmetic for the F and D extensions: IEEE 754 rounding under the
+//! five RISC-V rounding modes, accrued exception flags, and NaN boxing.
+//!
+//! Rust only computes in round-to-nearest-even, so each operation produces the
+//! nearest f64 plus the sign of its residual (exact - approx). That pair pins
+//! down the exact result's position between the two neighboring f32 values,
+//! which is all any rounding mode needs. Doubles have no wider type to fall
+//! back on, so their residual comes from TwoSum or an FMA instead.
+
+use std::cmp::Ordering;
+use std::num::FpCategory;
+
+/// fflags bits (fcsr[4:0])
+pub mod flags {
+    pub const NV: u8 = 1 << 4; // invalid operation
+    pub const DZ: u8 = 1 << 3; // divide by zero
+    pub const OF: u8 = 1 << 2; // overflow
+    pub const UF: u8 = 1 << 1; // underflow
+    pub const NX: u8 = 1 << 0; // inexact
+}
+
+pub const CANONICAL_NAN_F32: u32 = 0x7fc0_0000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RoundingMode {
+    /// Round to nearest, ties to even
+    Rne,
+    /// Round towards zero
+    Rtz,
+    /// Round down (towards -inf)
+    Rdn,
+    /// Round up (towards +inf)
+    Rup,
+    /// Round to nearest, ties to max magnitude
+    Rmm,
+}
+
+impl RoundingMode {
+    /// Decode a 3-bit rm/frm value. 0b111 (dynamic) is resolved by the caller.
+    pub fn from_bits(bits: u8) -> Option<Self> {
+        match bits {
+            0b000 => Some(RoundingMode::Rne),
+            0b001 => Some(RoundingMode::Rtz),
+            0b010 => Some(RoundingMode::Rdn),
+            0b011 => Some(RoundingMode::Rup),
+            0b100 => Some(RoundingMode::Rmm),
+            _ => None,
+        }
+    }
+}
+
+/// A single held in a 64-bit f register has its upper 32 bits all set.
+pub fn box_f32(v: f32) -> u64 {
+    0xffff_ffff_0000_0000 | v.to_bits() as u64
+}
+
+/// Read a single from an f register; an improperly boxed value reads as the canonical NaN.
+pub fn unbox_f32(reg: u64) -> f32 {
+    if reg >> 32 == 0xffff_ffff {
+        f32::from_bits(reg as u32)
+    } else {
+        f32::from_bits(CANONICAL_NAN_F32)
+    }
+}
+
+fn is_snan_f32(v: f32) -> bool {
+    v.is_nan() && v.to_bits() & (1 << 22) == 0
+}
+
+/// NaN inputs give the canonical NaN, raising NV only for a signaling NaN.
+fn propagate_nan_f32(inputs: &[f32]) -> Option<(f32, u8)> {
+    if !inputs.iter().any(|v| v.is_nan()) {
+        return None;
+    }
+    let flags = if inputs.iter().any(|&v| is_snan_f32(v)) {
+        flags::NV
+    } else {
+        0
+    };
+    Some((f32::from_bits(CANONICAL_NAN_F32), flags))
+}
+
+fn invalid_f32() -> (f32, u8) {
+    (f32::from_bits(CANONICAL_NAN_F32), flags::NV)
+}
+
+/// Which way to round a magnitude that falls between two representable values.
+#[derive(Clone, Copy)]
+enum Direction {
+    Nearest { ties_away: bool },
+    Down,
+    Up,
+}
+
+/// Round a non-negative `m` (with `err` the sign of exact - m) to f32 precision.
+/// Returns the magnitude and whether it differs from the exact value.
+fn round_magnitude_f32(m: f64, err: Ordering, dir: Direction) -> (f32, bool) {
+    let nearest = m as f32;
+    // The f32 neighbors bracketing the exact value, and where it sits relative
+    // to their midpoint
+    let (lo, hi, vs_mid) = if nearest as f64 == m {
+        match err {
+            Ordering::Equal => return (nearest, false),
+            Ordering::Greater => (nearest, nearest.next_up(), Ordering::Less),
+            Ordering::Less => (nearest.next_down(), nearest, Ordering::Greater),
+        }
+    } else {
+        let (lo, hi) = if (nearest as f64) < m {
+            (nearest, nearest.next_up())
+        } else {
+            (nearest.next_down(), nearest)
+        };
+        // Past f32::MAX the upper neighbor is infinity; use 2^128 for the midpoint
+        let hi_f64 = if hi.is_infinite() {
+            2f64.powi(128)
+        } else {
+            hi as f64
+        };
+        let mid = (lo as f64 + hi_f64) / 2.0;
+        (lo, hi, m.partial_cmp(&mid).unwrap().then(err))
+    };
+
+    let pick_hi = match dir {
+        Direction::Down => false,
+        Direction::Up => true,
+        Direction::Nearest { ties_away } => match vs_mid {
+            Ordering::Less => false,
+            Ordering::Greater => true,
+            Ordering::Equal => ties_away || hi.to_bits() & 1 == 0,
+        },
+    };
+    (if pick_hi { hi } else { lo }, true)
+}
+
+/// Round an exact result, given as `v` plus the sign of exact - v, to f32 under `rm`.
+fn round_f32(v: f64, err: Ordering, rm: RoundingMode) -> (f32, u8) {
+    let neg = v.is_sign_negative();
+    let (m, err) = if neg { (-v, err.reverse()) } else { (v, err) };
+    let dir = match (rm, neg) {
+        (RoundingMode::Rne, _) => Direction::Nearest { ties_away: false },
+        (RoundingMode::Rmm, _) => Direction::Nearest { ties_away: true },
+        (RoundingMode::Rtz, _) => Direction::Down,
+        (RoundingMode::Rdn, false) | (RoundingMode::Rup, true) => Direction::Down,
+        (RoundingMode::Rdn, true) | (RoundingMode::Rup, false) => Direction::Up,
+    };
+
+    let (mag, inexact) = round_magnitude_f32(m, err, dir);
+    let mut fflags = 0;
+    if inexact {
+        fflags |= flags::NX;
+        // Overflow: the result rounded with an unbounded exponent exceeds f32::MAX
+        if mag.is_infinite() || m >= 2f64.powi(128) {
+            fflags |= flags::OF;
+        }
+        // Underflow: tiny after rounding (again with an unbounded exponent),
+        // found by redoing the rounding scaled up into the normal range
+        let min = f32::MIN_POSITIVE;
+        if mag < min || (mag == min && m < min as f64) {
+            let scale = 2f64.powi(64);
+            let (scaled, _) = round_magnitude_f32(m * scale, err, dir);
+            if (scaled as f64) < min as f64 * scale {
+                fflags |= flags::UF;
+            }
+        }
+    }
+    (if neg { -mag } else { mag }, fflags)
+}
+
+pub fn add_f32(a: f32, b: f32, rm: RoundingMode) -> (f32, u8) {
+    if let Some(nan) = propagate_nan_f32(&[a, b]) {
+        return nan;
+    }
+    if a.is_infinite() && b.is_infinite() && a.is_sign_negative() != b.is_sign_negative() {
+        return invalid_f32();
+    }
+    // TwoSum: the exact rounding error of the f64 addition
+    let (sum, residual) = two_sum(a as f64, b as f64);
+    if sum == 0.0 && residual == 0.0 {
+        return (zero_sum(a as f64, b as f64, rm) as f32, 0);
+    }
+    round_f32(sum, sign_of(residual), rm)
+}
+
+pub fn sub_f32(a: f32, b: f32, rm: RoundingMode) -> (f32, u8) {
+    add_f32(a, -b, rm)
+}
+
+pub fn mul_f32(a: f32, b: f32, rm: RoundingMode) -> (f32, u8) {
+    if let Some(nan) = propagate_nan_f32(&[a, b]) {
+        return nan;
+    }
+    if (a == 0.0 && b.is_infinite()) || (a.is_infinite() && b == 0.0) {
+        return invalid_f32();
+    }
+    // A product of two 24-bit significands fits exactly in an f64
+    round_f32(a as f64 * b as f64, Ordering::Equal, rm)
+}
+
+pub fn div_f32(a: f32, b: f32, rm: RoundingMode) -> (f32, u8) {
+    if let Some(nan) = propagate_nan_f32(&[a, b]) {
+        return nan;
+    }
+    if (a == 0.0 && b == 0.0) || (a.is_infinite() && b.is_infinite()) {
+        return invalid_f32();
+    }
+    if b == 0.0 {
+        let sign_neg = a.is_sign_negative() != b.is_sign_negative();
+        let inf = if sign_neg {
+            f32::NEG_INFINITY
+        } else {
+            f32::INFINITY
+        };
+        // inf / 0 is an exact infinity, not a division by zero
+        return (inf, if a.is_infinite() { 0 } else { flags::DZ });
+    }
+    if a.is_infinite() || b.is_infinite() || a == 0.0 {
+        return ((a as f64 / b as f64) as f32, 0);
+    }
+    let (a64, b64) = (a as f64, b as f64);
+    let q = a64 / b64;
+    // a - q*b is exact under FMA; its sign relative to b's gives exact - q
+    let rem = (-q).mul_add(b64, a64);
+    let err = rem.partial_cmp(&0.0).unwrap();
+    let err = if b64 < 0.0 { err.reverse() } else { err };
+    round_f32(q, err, rm)
+}
+
+pub fn sqrt_f32(a: f32, rm: RoundingMode) -> (f32, u8) {
+    if let Some(nan) = propagate_nan_f32(&[a]) {
+        return nan;
+    }
+    if a == 0.0 || a == f32::INFINITY {
+        return (a, 0); // sqrt(-0) is -0
+    }
+    if a < 0.0 {
+        return invalid_f32();
+    }
+    let a64 = a as f64;
+    let s = a64.sqrt();
+    let rem = (-s).mul_add(s, a64);
+    round_f32(s, rem.partial_cmp(&0.0).unwrap(), rm)
+}
+
+/// Fused a*b + c with a single rounding. The FMSUB/FNMSUB/FNMADD forms are
+/// this with negated operands.
+pub fn fma_f32(a: f32, b: f32, c: f32, rm: RoundingMode) -> (f32, u8) {
+    // 0 * inf is invalid even when the addend is a quiet NaN
+    if (a == 0.0 && b.is_infinite()) || (a.is_infinite() && b == 0.0) {
+        return invalid_f32();
+    }
+    if let Some(nan) = propagate_nan_f32(&[a, b, c]) {
+        return nan;
+    }
+    if a.is_infinite() || b.is_infinite() {
+        let p = a * b;
+        if c.is_infinite() && c.is_sign_negative() != p.is_sign_negative() {
+            return invalid_f32();
+        }
+        return (p, 0);
+    }
+    if c.is_infinite() {
+        return (c, 0);
+    }
+    // The product is exact in f64, and TwoSum gives the exact error of adding c
+    let p = a as f64 * b as f64;
+    let (sum, residual) = two_sum(p, c as f64);
+    if sum == 0.0 && residual == 0.0 {
+        return (zero_sum(p, c as f64, rm) as f32, 0);
+    }
+    round_f32(sum, sign_of(residual), rm)
+}
+
+pub const CANONICAL_NAN_F64: u64 = 0x7ff8_0000_0000_0000;
+
+fn is_snan_f64(v: f64) -> bool {
+    v.is_nan() && v.to_bits() & (1 << 51) == 0
+}
+
+fn propagate_nan_f64(inputs: &[f64]) -> Option<(f64, u8)> {
+    if !inputs.iter().any(|v| v.is_nan()) {
+        return None;
+    }
+    let flags = if inputs.iter().any(|&v| is_snan_f64(v)) {
+        flags::NV
+    } else {
+        0
+    };
+    Some((f64::from_bits(CANONICAL_NAN_F64), flags))
+}
+
+fn invalid_f64() -> (f64, u8) {
+    (f64::from_bits(CANONICAL_NAN_F64), flags::NV)
+}
+
+fn sign_of(v: f64) -> Ordering {
+    v.partial_cmp(&0.0).unwrap_or(Ordering::Equal)
+}
+
+/// Whether an exact value `residual` away from `v` sits halfway to v's neighbor.
+fn is_tie(v: f64, residual: f64) -> bool {
+    let neighbor = if residual > 0.0 {
+        v.next_up()
+    } else {
+        v.next_down()
+    };
+    residual != 0.0 && (neighbor - v).abs() == 2.0 * residual.abs()
+}
+
+/// Round an exact result to f64 under `rm`, given the round-to-nearest `v`, the
+/// sign of exact - v, and whether the exact value was a tie. An infinite `v`
+/// from finite operands is an overflow.
+fn round_f64(v: f64, err: Ordering, tie: bool, rm: RoundingMode) -> (f64, u8) {
+    if v.is_infinite() {
+        let neg = v < 0.0;
+        let to_max = match rm {
+            RoundingMode::Rtz => true,
+            RoundingMode::Rdn => !neg,
+            RoundingMode::Rup => neg,
+            RoundingMode::Rne | RoundingMode::Rmm => false,
+        };
+        let result = if to_max { f64::MAX.copysign(v) } else { v };
+        return (result, flags::OF | flags::NX);
+    }
+    if err == Ordering::Equal {
+        return (v, 0);
+    }
+
+    let toward_exact = if err == Ordering::Greater {
+        v.next_up()
+    } else {
+        v.next_down()
+    };
+    // The exact value is further from zero than v
+    let away = (err == Ordering::Greater) == v.is_sign_positive();
+    let result = match rm {
+        RoundingMode::Rne => v,
+        RoundingMode::Rmm if tie && away => toward_exact,
+        RoundingMode::Rmm => v,
+        RoundingMode::Rtz if away => v,
+        RoundingMode::Rtz => toward_exact,
+        RoundingMode::Rdn if err == Ordering::Less => toward_exact,
+        RoundingMode::Rup if err == Ordering::Greater => toward_exact,
+        RoundingMode::Rdn | RoundingMode::Rup => v,
+    };
+
+    let mut fflags = flags::NX;
+    if result.is_infinite() {
+        fflags |= flags::OF;
+    }
+    if result.abs() < f64::MIN_POSITIVE || v.abs() < f64::MIN_POSITIVE {
+        fflags |= flags::UF;
+    }
+    (result, fflags)
+}
+
+pub fn add_f64(a: f64, b: f64, rm: RoundingMode) -> (f64, u8) {
+    if let Some(nan) = propagate_nan_f64(&[a, b]) {
+        return nan;
+    }
+    if a.is_infinite() || b.is_infinite() {
+        if a.is_infinite() && b.is_infinite() && a.is_sign_negative() != b.is_sign_negative() {
+            return invalid_f64();
+        }
+        return (a + b, 0);
+    }
+    let (sum, residual) = two_sum(a, b);
+    if sum.is_infinite() {
+        return round_f64(sum, Ordering::Equal, false, rm);
+    }
+    if sum == 0.0 && residual == 0.0 {
+        return (zero_sum(a, b, rm), 0);
+    }
+    round_f64(sum, sign_of(residual), is_tie(sum, residual), rm)
+}
+
+pub fn sub_f64(a: f64, b: f64, rm: RoundingMode) -> (f64, u8) {
+    add_f64(a, -b, rm)
+}
+
+pub fn mul_f64(a: f64, b: f64, rm: RoundingMode) -> (f64, u8) {
+    if let Some(nan) = propagate_nan_f64(&[a, b]) {
+        return nan;
+    }
+    if (a == 0.0 && b.is_infinite()) || (a.is_infinite() && b == 0.0) {
+        return invalid_f64();
+    }
+    let p = a * b;
+    if a.is_infinite() || b.is_infinite() || a == 0.0 || b == 0.0 {
+        return (p, 0);
+    }
+    if p == 0.0 {
+        // Underflowed all the way to a signed zero; the exact value is beyond it
+        let err = if p.is_sign_negative() {
+            Ordering::Less
+        } else {
+            Ordering::Greater
+        };
+        return round_f64(p, err, false, rm);
+    }
+    // FMA recovers the exact rounding error of the product
+    let residual = a.mul_add(b, -p);
+    round_f64(p, sign_of(residual), is_tie(p, residual), rm)
+}
+
+pub fn div_f64(a: f64, b: f64, rm: RoundingMode) -> (f64, u8) {
+    if let Some(nan) = propagate_nan_f64(&[a, b]) {
+        return nan;
+    }
+    if (a == 0.0 && b == 0.0) || (a.is_infinite() && b.is_infinite()) {
+        return invalid_f64();
+    }
+    if b == 0.0 {
+        return (a / b, if a.is_infinite() { 0 } else { flags::DZ });
+    }
+    let q = a / b;
+    if a.is_infinite() || b.is_infinite() || a == 0.0 {
+        return (q, 0);
+    }
+    if q == 0.0 {
+        let err = if q.is_sign_negative() {
+            Ordering::Less
+        } else {
+            Ordering::Greater
+        };
+        return round_f64(q, err, false, rm);
+    }
+    // A quotient can't land exactly halfway between doubles, so only the sign matters
+    let rem = (-q).mul_add(b, a);
+    let err = if b < 0.0 {
+        sign_of(rem).reverse()
+    } else {
+        sign_of(rem)
+    };
+    round_f64(q, err, false, rm)
+}
+
+pub fn sqrt_f64(a: f64, rm: RoundingMode) -> (f64, u8) {
+    if let Some(nan) = propagate_nan_f64(&[a]) {
+        return nan;
+    }
+    if a == 0.0 || a == f64::INFINITY {
+        return (a, 0);
+    }
+    if a < 0.0 {
+        return invalid_f64();
+    }
+    let s = a.sqrt();
+    let rem = (-s).mul_add(s, a);
+    round_f64(s, sign_of(rem), false, rm)
+}
+
+/// TwoSum: `a + b == sum + err` exactly, barring overflow.
+fn two_sum(a: f64, b: f64) -> (f64, f64) {
+    let sum = a + b;
+    let bv = sum - a;
+    (sum, (a - (sum - bv)) + (b - bv))
+}
+
+/// Sign of an exact zero sum x + y: zeros of the same sign keep it, otherwise
+/// it's +0, or -0 when rounding down.
+fn zero_sum(x: f64, y: f64, rm: RoundingMode) -> f64 {
+    if x == 0.0 && y == 0.0 && x.is_sign_negative() == y.is_sign_negative() {
+        x
+    } else if rm == RoundingMode::Rdn {
+        -0.0
+    } else {
+        0.0
+    }
+}
+
+/// Sign of the exact sum of `terms`. They're accumulated into a nonoverlapping
+/// expansion (Shewchuk's grow-expansion), whose largest component has the sign.
+fn exact_sum_sign(terms: &[f64]) -> Ordering {
+    let mut expansion: Vec<f64> = Vec::with_capacity(terms.len());
+    for &term in terms {
+        let mut q = term;
+        let mut grown = Vec::with_capacity(expansion.len() + 1);
+        for &e in &expansion {
+            let (sum, err) = two_sum(q, e);
+            if err != 0.0 {
+                grown.push(err);
+            }
+            q = sum;
+        }
+        if q != 0.0 {
+            grown.push(q);
+        }
+        expansion = grown;
+    }
+    expansion.last().map_or(Ordering::Equal, |&v| sign_of(v))
+}
+
+/// Fused a*b + c with a single rounding. The FMSUB/FNMSUB/FNMADD forms are
+/// this with negated operands.
+pub fn fma_f64(a: f64, b: f64, c: f64, rm: RoundingMode) -> (f64, u8) {
+    // 0 * inf is invalid even when the addend is a quiet NaN
+    if (a == 0.0 && b.is_infinite()) || (a.is_infinite() && b == 0.0) {
+        return invalid_f64();
+    }
+    if let Some(nan) = propagate_nan_f64(&[a, b, c]) {
+        return nan;
+    }
+    if a.is_infinite() || b.is_infinite() {
+        let p = a * b;
+        if c.is_infinite() && c.is_sign_negative() != p.is_sign_negative() {
+            return invalid_f64();
+        }
+        return (p, 0);
+    }
+    if c.is_infinite() {
+        return (c, 0);
+    }
+    if a == 0.0 || b == 0.0 {
+        let zero = if c == 0.0 { zero_sum(a * b, c, rm) } else { c };
+        return (zero, 0);
+    }
+    if c == 0.0 {
+        // Adding zero to a nonzero product can't change it, not even its sign
+        return mul_f64(a, b, rm);
+    }
+
+    let v = a.mul_add(b, c);
+    if v.is_infinite() {
+        return round_f64(v, Ordering::Equal, false, rm);
+    }
+    let p = a * b;
+    if p.is_infinite() {
+        // c pulls an overflowing product back into range. Halving a and c is
+        // exact here and doesn't move the rounding point.
+        let (half, fflags) = fma_f64(a * 0.5, b, c * 0.5, rm);
+        let result = half * 2.0;
+        let fflags = if result.is_infinite() {
+            fflags | flags::OF
+        } else {
+            fflags
+        };
+        return (result, fflags);
+    }
+
+    // Below this the product's FMA residual can be inexact
+    let tiny = 2f64.powi(-969);
+    if p.abs() < tiny {
+        let product_sign = if a.is_sign_negative() != b.is_sign_negative() {
+            Ordering::Less
+        } else {
+            Ordering::Greater
+        };
+        // Next to a c this large the product is under half an ulp, so v == c
+        if c.abs() >= 2f64.powi(-900) {
+            return round_f64(v, product_sign, false, rm);
+        }
+        // Scale the product (via its smaller factor) and c up into range
+        let scale = 2f64.powi(1000);
+        let (a, b) = if a.abs() < b.abs() {
+            (a * scale, b)
+        } else {
+            (a, b * scale)
+        };
+        if (a * b).abs() < tiny {
+            return round_f64(v, product_sign, false, rm);
+        }
+        return round_fma_f64(a, b, c * scale, v, scale, rm);
+    }
+    round_fma_f64(a, b, c, v, 1.0, rm)
+}
+
+/// Round a*b + c under `rm` given its round-to-nearest result `v`, where a*b
+/// and c (but not v) have been multiplied by `scale`. The exact error is
+/// expanded into error-free terms: a*b = p + pe and p + c = s + se.
+fn round_fma_f64(a: f64, b: f64, c: f64, v: f64, scale: f64, rm: RoundingMode) -> (f64, u8) {
+    let p = a * b;
+    let pe = a.mul_add(b, -p);
+    let (s, se) = two_sum(p, c);
+    let vs = v * scale;
+    let err = exact_sum_sign(&[pe, se, s, -vs]);
+    if err == Ordering::Equal {
+        // p and c are both nonzero, so an exact zero takes its sign from rm
+        let result = if v == 0.0 { zero_sum(p, c, rm) } else { v };
+        return (result, 0);
+    }
+
+    let neighbor = if err == Ordering::Greater {
+        v.next_up()
+    } else {
+        v.next_down()
+    };
+    let gap = (neighbor - v) * scale;
+    // A tie is 2 * (exact - v) == gap. Doubling keeps a subnormal gap exact;
+    // halving it keeps large terms from overflowing.
+    let tie = gap.is_finite()
+        && if vs.abs() < 1.0 {
+            exact_sum_sign(&[2.0 * pe, 2.0 * se, 2.0 * s, -2.0 * vs, -gap]) == Ordering::Equal
+        } else {
+            exact_sum_sign(&[pe, se, s, -vs, -0.5 * gap]) == Ordering::Equal
+        };
+    round_f64(v, err, tie, rm)
+}
+
+/// FCVT.D.S: exact, apart from quieting NaNs
+pub fn f32_to_f64(a: f32) -> (f64, u8) {
+    if let Some((_, fflags)) = propagate_nan_f32(&[a]) {
+        return (f64::from_bits(CANONICAL_NAN_F64), fflags);
+    }
+    (a as f64, 0)
+}
+
+/// FCVT.S.D
+pub fn f64_to_f32(a: f64, rm: RoundingMode) -> (f32, u8) {
+    if let Some((_, fflags)) = propagate_nan_f64(&[a]) {
+        return (f32::from_bits(CANONICAL_NAN_F32), fflags);
+    }
+    if a.is_infinite() || a == 0.0 {
+        return (a as f32, 0);
+    }
+    round_f32(a, Ordering::Equal, rm)
+}
+
+/// Round to an integral value, still as an f64.
+fn round_integral(v: f64, rm: RoundingMode) -> f64 {
+    match rm {
+        RoundingMode::Rne => v.round_ties_even(),
+        RoundingMode::Rtz => v.trunc(),
+        RoundingMode::Rdn => v.floor(),
+        RoundingMode::Rup => v.ceil(),
+        RoundingMode::Rmm => v.round(),
+    }
+}
+
+/// Float to a `width`-bit integer (FCVT.{W,WU,L,LU}). Out-of-range inputs and
+/// NaN saturate and raise NV; NaN and +inf go to the maximum. A 32-bit result
+/// comes back sign-extended, as the spec writes it into rd.
+pub fn f64_to_int(v: f64, rm: RoundingMode, signed: bool, width: u32) -> (u64, u8) {
+    let (min, max): (i128, i128) = if signed {
+        (-(1 << (width - 1)), (1 << (width - 1)) - 1)
+    } else {
+        (0, (1 << width) - 1)
+    };
+    let r = round_integral(v, rm);
+    let (value, fflags) = if v.is_nan() || r >= (max + 1) as f64 {
+        (max, flags::NV)
+    } else if r < min as f64 {
+        (min, flags::NV)
+    } else {
+        (r as i128, if r != v { flags::NX } else { 0 })
+    };
+    let value = if width == 32 {
+        value as i32 as i64 as u64
+    } else {
+        value as u64
+    };
+    (value, fflags)
+}
+
+/// f32 to a `width`-bit integer (FCVT.{W,WU,L,LU}.S), saturating like
+/// `f64_to_int`; widening to f64 first is exact, NaNs included.
+pub fn f32_to_int(v: f32, rm: RoundingMode, signed: bool, width: u32) -> (u64, u8) {
+    f64_to_int(v as f64, rm, signed, width)
+}
+
+/// Integer to f32 (FCVT.S.{W,WU,L,LU}); anything past 24 bits can be inexact.
+pub fn int_to_f32(v: i128, rm: RoundingMode) -> (f32, u8) {
+    // The nearest f64 and the sign of what it lost are enough to round once
+    let approx = v as f64;
+    round_f32(approx, (v - approx as i128).cmp(&0), rm)
+}
+
+/// Integer to f64 (FCVT.D.{W,WU,L,LU}); 64-bit sources can be inexact.
+pub fn int_to_f64(v: i128, rm: RoundingMode) -> (f64, u8) {
+    let approx = v as f64;
+    let diff = v - approx as i128;
+    let err = diff.cmp(&0);
+    let tie = diff != 0 && {
+        let neighbor = if diff > 0 {
+            approx.next_up()
+        } else {
+            approx.next_down()
+        };
+        (neighbor as i128 - approx as i128).abs() == 2 * diff.abs()
+    };
+    round_f64(approx, err, tie, rm)
+}
+
+/// FEQ is a quiet comparison and only flags signaling NaNs; FLT/FLE signal on any NaN.
+fn compare_flags(any_nan: bool, any_snan: bool, signaling: bool) -> u8 {
+    if any_snan || (signaling && any_nan) {
+        flags::NV
+    } else {
+        0
+    }
+}
+
+pub fn feq_f32(a: f32, b: f32) -> (bool, u8) {
+    let fflags = compare_flags(false, is_snan_f32(a) || is_snan_f32(b), false);
+    (a == b, fflags)
+}
+
+pub fn flt_f32(a: f32, b: f32) -> (bool, u8) {
+    (a < b, compare_flags(a.is_nan() || b.is_nan(), false, true))
+}
+
+pub fn fle_f32(a: f32, b: f32) -> (bool, u8) {
+    (a <= b, compare_flags(a.is_nan() || b.is_nan(), false, true))
+}
+
+pub fn feq_f64(a: f64, b: f64) -> (bool, u8) {
+    let fflags = compare_flags(false, is_snan_f64(a) || is_snan_f64(b), false);
+    (a == b, fflags)
+}
+
+pub fn flt_f64(a: f64, b: f64) -> (bool, u8) {
+    (a < b, compare_flags(a.is_nan() || b.is_nan(), false, true))
+}
+
+pub fn fle_f64(a: f64, b: f64) -> (bool, u8) {
+    (a <= b, compare_flags(a.is_nan() || b.is_nan(), false, true))
+}
+
+/// FMIN/FMAX return the other operand when one input is a NaN and the
+/// canonical NaN (None here) when both are; -0.0 orders below +0.0. f32
+/// operands widen to f64 exactly, so this serves both widths.
+fn min_max(a: f64, b: f64, max: bool) -> Option<f64> {
+    match (a.is_nan(), b.is_nan()) {
+        (true, true) => None,
+        (true, false) => Some(b),
+        (false, true) => Some(a),
+        // Equal operands differ at most in the sign of zero
+        _ if a == b && a.is_sign_negative() != max => Some(a),
+        _ if a == b => Some(b),
+        _ if max => Some(a.max(b)),
+        _ => Some(a.min(b)),
+    }
+}
+
+/// FMIN.S/FMAX.S; of NaN inputs, only signaling ones raise NV.
+fn min_max_f32(a: f32, b: f32, max: bool) -> (f32, u8) {
+    let fflags = compare_flags(false, is_snan_f32(a) || is_snan_f32(b), false);
+    let v =
+        min_max(a as f64, b as f64, max).map_or(f32::from_bits(CANONICAL_NAN_F32), |v| v as f32);
+    (v, fflags)
+}
+
+/// FMIN.D/FMAX.D, flagged like `min_max_f32`.
+fn min_max_f64(a: f64, b: f64, max: bool) -> (f64, u8) {
+    let fflags = compare_flags(false, is_snan_f64(a) || is_snan_f64(b), false);
+    let v = min_max(a, b, max).unwrap_or(f64::from_bits(CANONICAL_NAN_F64));
+    (v, fflags)
+}
+
+pub fn fmin_f32(a: f32, b: f32) -> (f32, u8) {
+    min_max_f32(a, b, false)
+}
+
+pub fn fmax_f32(a: f32, b: f32) -> (f32, u8) {
+    min_max_f32(a, b, true)
+}
+
+pub fn fmin_f64(a: f64, b: f64) -> (f64, u8) {
+    min_max_f64(a, b, false)
+}
+
+pub fn fmax_f64(a: f64, b: f64) -> (f64, u8) {
+    min_max_f64(a, b, true)
+}
+
+/// FCLASS result: exactly one of ten bits, from bit 0 (-inf) through
+/// normal/subnormal/zero of each sign to bit 7 (+inf), then 8 (sNaN) and 9 (qNaN).
+fn class_mask(category: FpCategory, negative: bool, signaling: bool) -> u64 {
+    let bit = match (category, negative) {
+        (FpCategory::Nan, _) => {
+            if signaling {
+                8
+            } else {
+                9
+            }
+        }
+        (FpCategory::Infinite, true) => 0,
+        (FpCategory::Normal, true) => 1,
+        (FpCategory::Subnormal, true) => 2,
+        (FpCategory::Zero, true) => 3,
+        (FpCategory::Zero, false) => 4,
+        (FpCategory::Subnormal, false) => 5,
+        (FpCategory::Normal, false) => 6,
+        (FpCategory::Infinite, false) => 7,
+    };
+    1 << bit
+}
+
+pub fn fclass_f32(v: f32) -> u64 {
+    class_mask(v.classify(), v.is_sign_negative(), is_snan_f32(v))
+}
+
+pub fn fclass_f64(v: f64) -> u64 {
+    class_mask(v.classify(), v.is_sign_negative(), is_snan_f64(v))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL_MODES: [RoundingMode; 5] = [
+        RoundingMode::Rne,
+        RoundingMode::Rtz,
+        RoundingMode::Rdn,
+        RoundingMode::Rup,
+        RoundingMode::Rmm,
+    ];
+
+    #[test]
+    fn test_nan_boxing() {
+        assert_eq!(box_f32(1.5), 0xffff_ffff_3fc0_0000);
+        assert_eq!(unbox_f32(0xffff_ffff_3fc0_0000), 1.5);
+        // Upper bits not all ones: the value reads as the canonical NaN
+        assert_eq!(
+            unbox_f32(0x0000_0000_3fc0_0000).to_bits(),
+            CANONICAL_NAN_F32
+        );
+    }
+
+    #[test]
+    fn test_rounding_modes_on_inexact_sum() {
+        // 1 + 2^-24 is exactly halfway between 1 and the next single up
+        let half_ulp = f32::from_bits(0x3380_0000);
+        let up = f32::from_bits(0x3f80_0001);
+        let expected = [
+            (RoundingMode::Rne, 1.0),
+            (RoundingMode::Rtz, 1.0),
+            (RoundingMode::Rdn, 1.0),
+            (RoundingMode::Rup, up),
+            (RoundingMode::Rmm, up),
+        ];
+        for (rm, want) in expected {
+            let (got, fflags) = add_f32(1.0, half_ulp, rm);
+            assert_eq!(got, want, "{rm:?}");
+            assert_eq!(fflags, flags::NX, "{rm:?}");
+        }
+
+        // -(1 + 2^-24): directed modes mirror on the negative side
+        let (got, _) = add_f32(-1.0, -half_ulp, RoundingMode::Rdn);
+        assert_eq!(got, -up);
+        let (got, _) = add_f32(-1.0, -half_ulp, RoundingMode::Rtz);
+        assert_eq!(got, -1.0);
+    }
+
+    #[test]
+    fn test_tiny_addend_beyond_f64_precision_still_rounds_up() {
+        // 1 + 2^-100 is exact in neither f32 nor f64; only the residual shows it
+        let tiny = f32::from_bits(0x0d80_0000);
+        let (got, fflags) = add_f32(1.0, tiny, RoundingMode::Rup);
+        assert_eq!(got.to_bits(), 0x3f80_0001);
+        assert_eq!(fflags, flags::NX);
+        assert_eq!(add_f32(1.0, tiny, RoundingMode::Rne).0, 1.0);
+    }
+
+    #[test]
+    fn test_exact_zero_sum_sign() {
+        for rm in ALL_MODES {
+            let (z, fflags) = sub_f32(1.0, 1.0, rm);
+            assert_eq!(fflags, 0);
+            assert_eq!(z.is_sign_negative(), rm == RoundingMode::Rdn, "{rm:?}");
+        }
+        assert!(add_f32(-0.0, -0.0, RoundingMode::Rne).0.is_sign_negative());
+        assert!(add_f32(0.0, 0.0, RoundingMode::Rdn).0.is_sign_positive());
+    }
+
+    #[test]
+    fn test_overflow_saturates_by_mode() {
+        for (rm, want) in [
+            (RoundingMode::Rne, f32::INFINITY),
+            (RoundingMode::Rtz, f32::MAX),
+            (RoundingMode::Rdn, f32::MAX),
+            (RoundingMode::Rup, f32::INFINITY),
+        ] {
+            let (got, fflags) = mul_f32(f32::MAX, 2.0, rm);
+            assert_eq!(got, want, "{rm:?}");
+            assert_eq!(fflags, flags::OF | flags::NX, "{rm:?}");
+        }
+    }
+
+    #[test]
+    fn test_underflow_and_exceptions() {
+        // MIN_POSITIVE * 0.75 lands on a subnormal grid point: tiny but exact
+        let (got, fflags) = mul_f32(f32::MIN_POSITIVE, 0.75, RoundingMode::Rne);
+        assert_eq!(got, f32::MIN_POSITIVE * 0.75);
+        assert_eq!(fflags, 0);
+        // Smallest subnormal halved rounds to zero: tiny and inexact
+        let (got, fflags) = mul_f32(f32::from_bits(1), 0.5, RoundingMode::Rne);
+        assert_eq!(got, 0.0);
+        assert_eq!(fflags, flags::UF | flags::NX);
+
+        assert_eq!(
+            div_f32(1.0, 0.0, RoundingMode::Rne),
+            (f32::INFINITY, flags::DZ)
+        );
+        assert_eq!(div_f32(0.0, 0.0, RoundingMode::Rne).1, flags::NV);
+        assert_eq!(mul_f32(0.0, f32::INFINITY, RoundingMode::Rne).1, flags::NV);
+        assert_eq!(sqrt_f32(-1.0, RoundingMode::Rne).1, flags::NV);
+        assert!(sqrt_f32(-0.0, RoundingMode::Rne).0.is_sign_negative());
+
+        // Quiet NaN propagates silently, signaling NaN raises NV
+        let qnan = f32::from_bits(0x7fc0_0001);
+        let snan = f32::from_bits(0x7f80_0001);
+        let (got, fflags) = add_f32(qnan, 1.0, RoundingMode::Rne);
+        assert_eq!((got.to_bits(), fflags), (CANONICAL_NAN_F32, 0));
+        assert_eq!(add_f32(snan, 1.0, RoundingMode::Rne).1, flags::NV);
+    }
+
+    #[test]
+    fn test_div_and_sqrt_directed_rounding() {
+        // 1/3 and sqrt(2) are inexact; RUP and RDN must straddle the true value
+        let (dn, _) = div_f32(1.0, 3.0, RoundingMode::Rdn);
+        let (up, fflags) = div_f32(1.0, 3.0, RoundingMode::Rup);
+        assert_eq!(up.to_bits(), dn.to_bits() + 1);
+        assert_eq!(fflags, flags::NX);
+        assert!((dn as f64) < 1.0 / 3.0 && (up as f64) > 1.0 / 3.0);
+
+        let (dn, _) = sqrt_f32(2.0, RoundingMode::Rtz);
+        let (up, _) = sqrt_f32(2.0, RoundingMode::Rup);
+        assert_eq!(up.to_bits(), dn.to_bits() + 1);
+        assert_eq!(sqrt_f32(4.0, RoundingMode::Rup), (2.0, 0));
+    }
+
+    #[test]
+    fn test_f64_directed_rounding() {
+        // 1 + 2^-53 is a tie between 1 and 1 + 2^-52
+        let half_ulp = f64::EPSILON / 2.0;
+        let up = 1.0f64.next_up();
+        for (rm, want) in [
+            (RoundingMode::Rne, 1.0),
+            (RoundingMode::Rtz, 1.0),
+            (RoundingMode::Rdn, 1.0),
+            (RoundingMode::Rup, up),
+            (RoundingMode::Rmm, up),
+        ] {
+            assert_eq!(add_f64(1.0, half_ulp, rm), (want, flags::NX), "{rm:?}");
+        }
+        // Just over half an ulp is not a tie
+        let (got, _) = add_f64(-1.0, -(half_ulp + half_ulp / 4.0), RoundingMode::Rne);
+        assert_eq!(got, -up);
+        let (got, _) = add_f64(-1.0, -(half_ulp + half_ulp / 4.0), RoundingMode::Rtz);
+        assert_eq!(got, -1.0);
+
+        let (dn, _) = div_f64(1.0, 3.0, RoundingMode::Rdn);
+        let (up, fflags) = div_f64(1.0, 3.0, RoundingMode::Rup);
+        assert_eq!(up, dn.next_up());
+        assert_eq!(fflags, flags::NX);
+        assert_eq!(
+            sqrt_f64(2.0, RoundingMode::Rup).0,
+            sqrt_f64(2.0, RoundingMode::Rtz).0.next_up()
+        );
+
+        // (1 + 2^-52)^2 = 1 + 2^-51 + 2^-104 needs the FMA residual to round up
+        let x = 1.0f64.next_up();
+        assert_eq!(mul_f64(x, x, RoundingMode::Rne).0, 1.0 + 2.0 * f64::EPSILON);
+        assert_eq!(
+            mul_f64(x, x, RoundingMode::Rup).0,
+            (1.0 + 2.0 * f64::EPSILON).next_up()
+        );
+
+        for (rm, want) in [
+            (RoundingMode::Rne, f64::INFINITY),
+            (RoundingMode::Rtz, f64::MAX),
+            (RoundingMode::Rup, f64::INFINITY),
+        ] {
+            assert_eq!(
+                mul_f64(f64::MAX, 2.0, rm),
+                (want, flags::OF | flags::NX),
+                "{rm:?}"
+            );
+        }
+        assert_eq!(mul_f64(-f64::MAX, 2.0, RoundingMode::Rup).0, -f64::MAX);
+    }
+
+    #[test]
+    fn test_fma_rounds_once() {
+        // (1 + 2^-12)^2 - 1: rounding the product first would lose the 2^-24 term
+        let x = 1.0 + 2f32.powi(-12);
+        let (got, fflags) = fma_f32(x, x, -1.0, RoundingMode::Rne);
+        assert_eq!((got, fflags), (2f32.powi(-11) + 2f32.powi(-24), 0));
+        let (got, fflags) = fma_f32(x, x, 1.0, RoundingMode::Rup);
+        assert_eq!(got, (2.0 + 2f32.powi(-11)).next_up());
+        assert_eq!(fflags, flags::NX);
+
+        // (1 + 2^-27)^2 - 1 = 2^-26 + 2^-54, exact only when fused
+        let x = 1.0 + 2f64.powi(-27);
+        assert_eq!(
+            fma_f64(x, x, -1.0, RoundingMode::Rne),
+            (2f64.powi(-26) + 2f64.powi(-54), 0)
+        );
+        // 1*1 + 2^-53 is a tie, decided by the rounding mode
+        let half_ulp = f64::EPSILON / 2.0;
+        for (rm, want) in [
+            (RoundingMode::Rne, 1.0),
+            (RoundingMode::Rtz, 1.0),
+            (RoundingMode::Rup, 1.0f64.next_up()),
+            (RoundingMode::Rmm, 1.0f64.next_up()),
+        ] {
+            assert_eq!(fma_f64(1.0, 1.0, half_ulp, rm), (want, flags::NX), "{rm:?}");
+        }
+        // A product far below the smallest subnormal still nudges directed modes
+        let tiny = f64::from_bits(1);
+        let (got, fflags) = fma_f64(2f64.powi(-600), 2f64.powi(-500), tiny, RoundingMode::Rup);
+        assert_eq!((got, fflags), (tiny * 2.0, flags::UF | flags::NX));
+        assert_eq!(
+            fma_f64(2f64.powi(-600), -2f64.powi(-500), tiny, RoundingMode::Rne).0,
+            tiny
+        );
+        // An overflowing product brought back into range by c
+        let (got, fflags) = fma_f64(f64::MAX, 2.0, -f64::MAX, RoundingMode::Rne);
+        assert_eq!((got, fflags), (f64::MAX, 0));
+    }
+
+    #[test]
+    fn test_fma_invalid_and_zero_signs() {
+        let qnan = f32::from_bits(0x7fc0_0001);
+        // 0 * inf is invalid even with a quiet NaN addend
+        let (got, fflags) = fma_f32(0.0, f32::INFINITY, qnan, RoundingMode::Rne);
+        assert_eq!((got.to_bits(), fflags), (CANONICAL_NAN_F32, flags::NV));
+        assert_eq!(fma_f32(1.0, 2.0, qnan, RoundingMode::Rne).1, 0);
+        assert_eq!(
+            fma_f64(f64::INFINITY, 1.0, f64::NEG_INFINITY, RoundingMode::Rne).1,
+            flags::NV
+        );
+        assert_eq!(
+            fma_f64(f64::INFINITY, -1.0, f64::NEG_INFINITY, RoundingMode::Rne),
+            (f64::NEG_INFINITY, 0)
+        );
+
+        // -0 * 1 + -0 keeps the sign; an exact cancellation follows the mode
+        assert!(
+            fma_f64(-0.0, 1.0, -0.0, RoundingMode::Rne)
+                .0
+                .is_sign_negative()
+        );
+        assert!(
+            fma_f64(2.0, 3.0, -6.0, RoundingMode::Rne)
+                .0
+                .is_sign_positive()
+        );
+        assert!(
+            fma_f64(2.0, 3.0, -6.0, RoundingMode::Rdn)
+                .0
+                .is_sign_negative()
+        );
+        assert!(
+            fma_f32(2.0, 3.0, -6.0, RoundingMode::Rdn)
+                .0
+                .is_sign_negative()
+        );
+    }
+
+    #[test]
+    fn test_precision_conversions() {
+        // Mirrors rv64ud fcvt: widening is exact, narrowing rounds
+        assert_eq!(f32_to_f64(-1.5), (-1.5, 0));
+        let snan = f32::from_bits(0x7f80_0001);
+        assert_eq!(f32_to_f64(snan).0.to_bits(), CANONICAL_NAN_F64);
+        assert_eq!(f32_to_f64(snan).1, flags::NV);
+
+        let third = 1.0f64 / 3.0;
+        let (s, fflags) = f64_to_f32(third, RoundingMode::Rne);
+        assert_eq!((s, fflags), (1.0f32 / 3.0, flags::NX));
+        // The nearest single to 1/3 is above it
+        assert_eq!(f64_to_f32(third, RoundingMode::Rup).0, s);
+        assert_eq!(f64_to_f32(third, RoundingMode::Rdn).0, s.next_down());
+        assert_eq!(
+            f64_to_f32(1e300, RoundingMode::Rne),
+            (f32::INFINITY, flags::OF | flags::NX)
+        );
+        assert_eq!(f64_to_f32(1e300, RoundingMode::Rtz).0, f32::MAX);
+    }
+
+    #[test]
+    fn test_float_to_int_saturates() {
+        let rne = RoundingMode::Rne;
+        // (input, W, L), results as written to rd
+        type Converted = (u64, u8);
+        let cases: [(f64, Converted, Converted); 7] = [
+            (-1.1, (-1i64 as u64, flags::NX), (-1i64 as u64, flags::NX)),
+            (2.5, (2, flags::NX), (2, flags::NX)),
+            (1e10, (0x7fff_ffff, flags::NV), (10_000_000_000, 0)),
+            (
+                -3e9,
+                (-(1i64 << 31) as u64, flags::NV),
+                (-3_000_000_000i64 as u64, 0),
+            ),
+            (
+                f64::INFINITY,
+                (0x7fff_ffff, flags::NV),
+                (i64::MAX as u64, flags::NV),
+            ),
+            (
+                f64::NEG_INFINITY,
+                ((i32::MIN as i64) as u64, flags::NV),
+                (i64::MIN as u64, flags::NV),
+            ),
+            (
+                f64::NAN,
+                (0x7fff_ffff, flags::NV),
+                (i64::MAX as u64, flags::NV),
+            ),
+        ];
+        for (v, w, l) in cases {
+            assert_eq!(f64_to_int(v, rne, true, 32), w, "fcvt.w.d {v}");
+            assert_eq!(f64_to_int(v, rne, true, 64), l, "fcvt.l.d {v}");
+        }
+        // Unsigned: negatives clamp to 0, and WU results are still sign-extended
+        assert_eq!(f64_to_int(-1.0, rne, false, 64), (0, flags::NV));
+        assert_eq!(f64_to_int(-0.4, rne, false, 32), (0, flags::NX));
+        assert_eq!(f64_to_int(4294967295.0, rne, false, 32), (u64::MAX, 0));
+        assert_eq!(f64_to_int(f64::NAN, rne, false, 32), (u64::MAX, flags::NV));
+        // 2^63 is one past i64::MAX
+        assert_eq!(f64_to_int(2f64.powi(63), rne, true, 64).1, flags::NV);
+        assert_eq!(f64_to_int(2.5, RoundingMode::Rmm, true, 32).0, 3);
+        assert_eq!(
+            f64_to_int(-2.5, RoundingMode::Rdn, true, 32).0,
+            -3i64 as u64
+        );
+    }
+
+    #[test]
+    fn test_f32_to_int_saturates() {
+        let rtz = RoundingMode::Rtz;
+        let nv = flags::NV;
+        // Results as written to rd, for W, WU, L and LU
+        type Converted = (u64, u8);
+        let cases: [(f32, [Converted; 4]); 6] = [
+            (1.5, [(1, flags::NX); 4]),
+            (
+                f32::INFINITY,
+                [
+                    (0x7fff_ffff, nv),
+                    (u64::MAX, nv),
+                    (i64::MAX as u64, nv),
+                    (u64::MAX, nv),
+                ],
+            ),
+            (
+                f32::NEG_INFINITY,
+                [
+                    (i32::MIN as i64 as u64, nv),
+                    (0, nv),
+                    (i64::MIN as u64, nv),
+                    (0, nv),
+                ],
+            ),
+            (
+                f32::NAN,
+                [
+                    (0x7fff_ffff, nv),
+                    (u64::MAX, nv),
+                    (i64::MAX as u64, nv),
+                    (u64::MAX, nv),
+                ],
+            ),
+            // 2^31 and 2^63 are one past the signed limits
+            (
+                2f32.powi(31),
+                [
+                    (0x7fff_ffff, nv),
+                    (0xffff_ffff_8000_0000, 0),
+                    (1 << 31, 0),
+                    (1 << 31, 0),
+                ],
+            ),
+            (
+                2f32.powi(63),
+                [
+                    (0x7fff_ffff, nv),
+                    (u64::MAX, nv),
+                    (i64::MAX as u64, nv),
+                    (1 << 63, 0),
+                ],
+            ),
+        ];
+        for (v, [w, wu, l, lu]) in cases {
+            assert_eq!(f32_to_int(v, rtz, true, 32), w, "fcvt.w.s {v}");
+            assert_eq!(f32_to_int(v, rtz, false, 32), wu, "fcvt.wu.s {v}");
+            assert_eq!(f32_to_int(v, rtz, true, 64), l, "fcvt.l.s {v}");
+            assert_eq!(f32_to_int(v, rtz, false, 64), lu, "fcvt.lu.s {v}");
+        }
+        // The limits themselves are in range
+        let below = 2f32.powi(31).next_down();
+        assert_eq!(f32_to_int(below, rtz, true, 32), (below as u64, 0));
+        assert_eq!(
+            f32_to_int(-2f32.powi(31), rtz, true, 32),
+            (i32::MIN as i64 as u64, 0)
+        );
+        assert_eq!(f32_to_int(-0.5, RoundingMode::Rdn, false, 32), (0, nv));
+    }
+
+    #[test]
+    fn test_int_to_f32() {
+        assert_eq!(int_to_f32(-7, RoundingMode::Rne), (-7.0, 0));
+        // 2^24 + 1 is a tie between 2^24 and 2^24 + 2
+        let v = (1i128 << 24) + 1;
+        assert_eq!(int_to_f32(v, RoundingMode::Rne), (16777216.0, flags::NX));
+        assert_eq!(int_to_f32(v, RoundingMode::Rmm).0, 16777218.0);
+        assert_eq!(int_to_f32(-v, RoundingMode::Rdn).0, -16777218.0);
+        // Past f64 precision the lost low bit still breaks the tie
+        let v = (1i128 << 60) + (1 << 36) + 1;
+        assert_eq!(
+            int_to_f32(v, RoundingMode::Rne).0,
+            2f32.powi(60) + 2f32.powi(37)
+        );
+        assert_eq!(
+            int_to_f32(u64::MAX as i128, RoundingMode::Rtz),
+            (2f32.powi(64).next_down(), flags::NX)
+        );
+        assert_eq!(
+            int_to_f32(u64::MAX as i128, RoundingMode::Rne).0,
+            2f32.powi(64)
+        );
+    }
+
+    #[test]
+    fn test_int_to_f64() {
+        assert_eq!(int_to_f64(-7, RoundingMode::Rne), (-7.0, 0));
+        // 2^53 + 1 is a tie between 2^53 and 2^53 + 2
+        let v = (1i128 << 53) + 1;
+        assert_eq!(
+            int_to_f64(v, RoundingMode::Rne),
+            (9007199254740992.0, flags::NX)
+        );
+        assert_eq!(int_to_f64(v, RoundingMode::Rmm).0, 9007199254740994.0);
+        assert_eq!(int_to_f64(v, RoundingMode::Rup).0, 9007199254740994.0);
+        assert_eq!(
+            int_to_f64(u64::MAX as i128, RoundingMode::Rtz).0,
+            18446744073709549568.0
+        );
+    }
+
+    #[test]
+    fn test_compare_nan_flags() {
+        let qnan = f32::from_bits(0x7fc0_0000);
+        let snan = f32::from_bits(0x7f80_0001);
+        // (a, b, feq, flt, fle) as (result, fflags)
+        let cases = [
+            (1.0, 2.0, (false, 0), (true, 0), (true, 0)),
+            (-0.0, 0.0, (true, 0), (false, 0), (true, 0)),
+            (
+                qnan,
+                1.0,
+                (false, 0),
+                (false, flags::NV),
+                (false, flags::NV),
+            ),
+            (
+                1.0,
+                qnan,
+                (false, 0),
+                (false, flags::NV),
+                (false, flags::NV),
+            ),
+            (
+                snan,
+                1.0,
+                (false, flags::NV),
+                (false, flags::NV),
+                (false, flags::NV),
+            ),
+            (
+                qnan,
+                qnan,
+                (false, 0),
+                (false, flags::NV),
+                (false, flags::NV),
+            ),
+        ];
+        for (a, b, eq, lt, le) in cases {
+            assert_eq!(feq_f32(a, b), eq, "feq.s {a} {b}");
+            assert_eq!(flt_f32(a, b), lt, "flt.s {a} {b}");
+            assert_eq!(fle_f32(a, b), le, "fle.s {a} {b}");
+            assert_eq!(feq_f64(f32_to_f64(a).0, f32_to_f64(b).0).0, eq.0);
+        }
+
+        let snan64 = f64::from_bits(0x7ff0_0000_0000_0001);
+        let qnan64 = f64::from_bits(CANONICAL_NAN_F64);
+        assert_eq!(feq_f64(snan64, 0.0), (false, flags::NV));
+        assert_eq!(feq_f64(qnan64, 0.0), (false, 0));
+        assert_eq!(fle_f64(qnan64, 0.0), (false, flags::NV));
+        assert_eq!(flt_f64(-1.0, 0.0), (true, 0));
+    }
+
+    #[test]
+    fn test_fclass() {
+        let cases: [(f32, u64); 10] = [
+            (f32::NEG_INFINITY, 1 << 0),
+            (-1.0, 1 << 1),
+            (-f32::from_bits(1), 1 << 2),
+            (-0.0, 1 << 3),
+            (0.0, 1 << 4),
+            (f32::from_bits(1), 1 << 5),
+            (1.0, 1 << 6),
+            (f32::INFINITY, 1 << 7),
+            (f32::from_bits(0x7f80_0001), 1 << 8),
+            (f32::from_bits(0x7fc0_0000), 1 << 9),
+        ];
+        for (v, mask) in cases {
+            assert_eq!(fclass_f32(v), mask, "fclass.s {:#x}", v.to_bits());
+        }
+        assert_eq!(fclass_f64(-f64::from_bits(1)), 1 << 2);
+        assert_eq!(fclass_f64(f64::from_bits(0x7ff0_0000_0000_0001)), 1 << 8);
+        assert_eq!(fclass_f64(f64::from_bits(CANONICAL_NAN_F64)), 1 << 9);
+    }
+
+    #[test]
+    fn test_min_max_signed_zeros() {
+        assert_eq!(fmin_f32(0.0, -0.0).0.to_bits(), (-0.0f32).to_bits());
+        assert_eq!(fmin_f32(-0.0, 0.0).0.to_bits(), (-0.0f32).to_bits());
+        assert_eq!(fmax_f32(-0.0, 0.0).0.to_bits(), 0);
+        assert_eq!(fmax_f32(0.0, -0.0).0.to_bits(), 0);
+        assert_eq!(fmin_f64(0.0, -0.0).0.to_bits(), (-0.0f64).to_bits());
+        assert_eq!(fmax_f64(-0.0, 0.0).0.to_bits(), 0);
+        assert_eq!(fmin_f32(-1.5, 2.0), (-1.5, 0));
+        assert_eq!(fmax_f64(-1.5, 2.0), (2.0, 0));
+    }
+
+    #[test]
+    fn test_min_max_nan_propagation() {
+        let qnan = f32::from_bits(0x7fc0_0001);
+        let snan = f32::from_bits(0x7f80_0001);
+        // One NaN yields the other operand; only a signaling NaN sets NV
+        assert_eq!(fmin_f32(qnan, 3.0), (3.0, 0));
+        assert_eq!(fmax_f32(1.0, snan), (1.0, flags::NV));
+        // Both NaN yields the canonical NaN, not either payload
+        let (v, fflags) = fmax_f32(qnan, snan);
+        assert_eq!((v.to_bits(), fflags), (CANONICAL_NAN_F32, flags::NV));
+        assert_eq!(fmin_f32(qnan, qnan).0.to_bits(), CANONICAL_NAN_F32);
+
+        let qnan64 = f64::from_bits(0x7ff8_0000_0000_0001);
+        let snan64 = f64::from_bits(0x7ff0_0000_0000_0001);
+        assert_eq!(fmin_f64(snan64, -2.0), (-2.0, flags::NV));
+        assert_eq!(fmax_f64(qnan64, 4.0), (4.0, 0));
+        let (v, fflags) = fmin_f64(snan64, qnan64);
+        assert_eq!((v.to_bits(), fflags), (CANONICAL_NAN_F64, flags::NV));
+    }
+}
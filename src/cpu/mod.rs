@@ -1,46 +1,190 @@
+pub mod block_cache;
+pub mod builder;
 pub mod decode;
+pub mod decode_cache;
 pub mod exec;
+pub mod fpu;
 pub mod trap;
 
-use crate::cpu::trap::WithPc;
-use crate::csr::CsrFile;
-use crate::mem::Memory;
-use crate::mmu::Mmu;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
 
-#[derive(Default)]
+use crate::clint::{Clint, TIMEBASE_HZ};
+use crate::cpu::block_cache::{Block, BlockCache};
+use crate::cpu::builder::MachineBuilder;
+use crate::cpu::decode_cache::DecodeCache;
+use crate::cpu::trap::{InterruptLine, WithPc};
+use crate::csr::{CsrFile, PrivMode};
+use crate::debug::profile::Profile;
+use crate::debug::trap_stats::TrapStats;
+use crate::mem::{Memory, Watchpoint};
+use crate::mmu::{Mmu, PteAdMode};
+use crate::plic::Plic;
+use crate::proxy::Proxy;
+use crate::snapshot::{Reader, SnapshotError, Writer};
+use crate::syscon::SysconRequest;
+
+#[derive(Clone, Default)]
 pub struct Cpu {
     pub regs: [u64; 32],
+    /// Float registers; singles are NaN-boxed into the low 32 bits
+    pub f: [u64; 32],
     pub pc: u64,
     pub csr: CsrFile,
+    /// Address reserved by the last LR; SC only succeeds while it is still held
+    pub reservation: Option<u64>,
+    /// Stalled in WFI; no instructions are fetched until an interrupt is pending
+    pub wfi: bool,
+}
+
+/// What the hart implements, as opposed to the state it's in. `reset` and
+/// `restore` leave it alone and hand it back to the fresh CSR file and MMU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MachineConfig {
+    /// Extensions the hart implements, laid out like misa (see
+    /// `csr::parse_isa`). Writes to misa can clear these but never add one.
+    pub isa: u64,
+    /// Whether satp accepts Sv48 (mode 9) as well as Sv39
+    pub sv48: bool,
+    /// How page walks handle clear A/D bits
+    pub ad_mode: PteAdMode,
+}
+
+impl Default for MachineConfig {
+    fn default() -> Self {
+        Self {
+            isa: CsrFile::MISA,
+            sv48: false,
+            ad_mode: PteAdMode::Update,
+        }
+    }
 }
 
 pub struct Machine {
     pub cpu: Cpu,
     pub mem: Memory,
     pub mmu: Mmu,
+    /// Fixed for the machine's lifetime; set it through `MachineBuilder`, or
+    /// call `reset` after changing it
+    pub config: MachineConfig,
     pub host_exit_addr: Option<u64>,
     pub max_insns: u64,
     pub executed: u64,
+    /// Where pc starts after `reset()`; defaults to the base of RAM
+    pub reset_vector: u64,
+    /// Privilege mode after `reset()`; defaults to M-mode
+    pub reset_priv: PrivMode,
+    /// sp after `reset()`, such as a loader's user stack; defaults to 0 like
+    /// the other registers
+    pub reset_sp: Option<u64>,
+    /// When set, counts each instruction handed to exec by kind
+    pub profile: Option<Profile>,
+    /// When set, counts every trap taken by cause
+    pub trap_stats: Option<TrapStats>,
+    /// Let `run` sleep the host thread while the hart idles in WFI, instead of
+    /// spinning a step per mtime tick. mtime then follows the host clock while
+    /// idle, so runs are no longer deterministic.
+    pub sleep_on_wfi: bool,
+    /// Halt with `HaltReason::Deadlock` once an instruction has jumped to
+    /// itself this many times in a row (0 = never). Opt-in, since spin-waits
+    /// on an interrupt look the same.
+    pub deadlock_after: u64,
+    /// Consecutive jumps to self so far, for `deadlock_after`
+    self_jumps: u64,
+    /// When set, decoded instructions are cached by pc and reused instead of
+    /// fetching and decoding again. Off by default.
+    pub decode_cache: Option<DecodeCache>,
+    /// When set, `run` executes cached straight-line blocks of decoded
+    /// instructions (see `BlockCache`). Off by default.
+    pub block_cache: Option<BlockCache>,
+    /// When set, ECALLs from U-mode are served by this proxy kernel instead
+    /// of trapping
+    pub proxy: Option<Proxy>,
+    /// mip bits held high by `raise_interrupt`
+    injected_irqs: u64,
+    /// Exception causes (bit n for cause n) that halt with
+    /// `HaltReason::TrapHalt` instead of entering the guest's handler
+    pub halt_on_trap: u64,
+    /// The trap a `TrapHalt` stopped at, taken by the next step
+    pub(crate) held_trap: Option<trap::Trap>,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum HaltReason {
-    HostExit { code: u64, gp: u64 },
+    /// HTIF exit request; `code` is 0 on pass, otherwise the failing test number
+    HostExit {
+        code: u64,
+        gp: u64,
+    },
     MaxInsns,
+    /// Syscon poweroff; `code` is 0 on pass
+    Poweroff {
+        code: u64,
+    },
+    /// Syscon reset request. `Machine::reset` restarts the hart, keeping RAM.
+    Reset,
+    /// The program called exit through the proxy kernel; `code` is its status
+    Exit {
+        code: u64,
+    },
+    /// Stalled in WFI with every interrupt disabled in mie, so nothing can wake it
+    WfiDeadlock,
+    /// Looping on a jump to itself at `pc` (see `Machine::deadlock_after`)
+    Deadlock {
+        pc: u64,
+    },
+    /// An instruction accessed RAM under a watchpoint; `old` and `new` are the
+    /// bytes at physical `addr` before and after (equal for a read)
+    Watchpoint {
+        addr: u64,
+        old: u64,
+        new: u64,
+    },
+    /// An exception selected by `Machine::halt_on_trap`, stopped at before
+    /// the handler runs. Stepping again delivers it to the guest.
+    TrapHalt {
+        cause: u64,
+        pc: u64,
+    },
+    /// A trap with no handler to take it
+    Trap(trap::Trap),
 }
 
 impl std::fmt::Display for HaltReason {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
+            HaltReason::HostExit { code: 0, gp } => write!(f, "host exit [PASS] (gp={})", gp),
             HaltReason::HostExit { code, gp } => {
-                let status = if *code == 1 { "PASS" } else { "FAIL" };
-                write!(f, "host exit [{}] (code={}, gp={})", status, code, gp)
+                write!(f, "host exit [FAIL] (code={}, gp={})", code, gp)
             }
             HaltReason::MaxInsns => write!(f, "maximum instructions executed"),
+            HaltReason::Poweroff { code: 0 } => write!(f, "poweroff [PASS]"),
+            HaltReason::Poweroff { code } => write!(f, "poweroff [FAIL] (code={})", code),
+            HaltReason::Reset => write!(f, "reset requested"),
+            HaltReason::Exit { code } => write!(f, "exit({})", code),
+            HaltReason::WfiDeadlock => write!(f, "WFI with all interrupts disabled"),
+            HaltReason::Deadlock { pc } => write!(f, "jump-to-self loop at pc=0x{:016x}", pc),
+            HaltReason::Watchpoint { addr, old, new } => write!(
+                f,
+                "watchpoint at 0x{:016x}: 0x{:x} -> 0x{:x}",
+                addr, old, new
+            ),
+            HaltReason::TrapHalt { cause, pc } => {
+                write!(f, "{} at pc=0x{:016x}", trap::cause_name(*cause, false), pc)
+            }
+            HaltReason::Trap(trap) => write!(f, "unhandled trap: {}", trap),
         }
     }
 }
 
+/// Result of `Machine::run`: either the step budget ran out with the machine
+/// still running, or it stopped for good.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepOutcome {
+    Continued,
+    Halted(HaltReason),
+}
+
 pub enum CpuStepResult {
     Continue,
     Trapped(trap::Trap),
@@ -69,6 +213,204 @@ impl std::fmt::Debug for CpuStepResult {
 
 impl std::error::Error for CpuStepResult {}
 
+impl Cpu {
+    /// Read integer register `idx`; x0 always reads as zero.
+    pub fn reg(&self, idx: u8) -> u64 {
+        self.regs[idx as usize]
+    }
+
+    /// Write integer register `idx`. Writes to x0 are discarded.
+    pub fn set_reg(&mut self, idx: u8, val: u64) {
+        if idx != 0 {
+            self.regs[idx as usize] = val;
+        }
+    }
+
+    /// Write f register `idx`, marking the FP state Dirty in mstatus.FS.
+    pub fn set_f(&mut self, idx: u8, val: u64) {
+        self.f[idx as usize] = val;
+        self.csr.mark_fs_dirty();
+    }
+
+    /// Single-precision view of f register `idx`; an improperly NaN-boxed
+    /// value reads as the canonical NaN.
+    pub fn f32_reg(&self, idx: u8) -> f32 {
+        fpu::unbox_f32(self.f[idx as usize])
+    }
+
+    pub fn f64_reg(&self, idx: u8) -> f64 {
+        f64::from_bits(self.f[idx as usize])
+    }
+
+    // Registers by ABI name
+    pub fn ra(&self) -> u64 {
+        self.reg(1)
+    }
+
+    pub fn sp(&self) -> u64 {
+        self.reg(2)
+    }
+
+    pub fn gp(&self) -> u64 {
+        self.reg(3)
+    }
+
+    pub fn tp(&self) -> u64 {
+        self.reg(4)
+    }
+
+    pub fn a0(&self) -> u64 {
+        self.reg(10)
+    }
+
+    pub fn a1(&self) -> u64 {
+        self.reg(11)
+    }
+
+    pub fn a2(&self) -> u64 {
+        self.reg(12)
+    }
+
+    pub fn a3(&self) -> u64 {
+        self.reg(13)
+    }
+
+    pub fn a4(&self) -> u64 {
+        self.reg(14)
+    }
+
+    pub fn a5(&self) -> u64 {
+        self.reg(15)
+    }
+
+    pub fn a6(&self) -> u64 {
+        self.reg(16)
+    }
+
+    pub fn a7(&self) -> u64 {
+        self.reg(17)
+    }
+
+    pub(crate) fn save(&self, w: &mut Writer) {
+        for reg in self.regs.iter().chain(self.f.iter()) {
+            w.u64(*reg);
+        }
+        w.u64(self.pc);
+        w.bool(self.reservation.is_some());
+        w.u64(self.reservation.unwrap_or(0));
+        w.bool(self.wfi);
+        self.csr.save(w);
+    }
+
+    pub(crate) fn load(r: &mut Reader) -> Result<Self, SnapshotError> {
+        let mut cpu = Cpu::default();
+        for reg in cpu.regs.iter_mut().chain(cpu.f.iter_mut()) {
+            *reg = r.u64()?;
+        }
+        cpu.pc = r.u64()?;
+        let reserved = r.bool()?;
+        let addr = r.u64()?;
+        cpu.reservation = reserved.then_some(addr);
+        cpu.wfi = r.bool()?;
+        cpu.csr = CsrFile::load(r)?;
+        Ok(cpu)
+    }
+
+    /// Enter a trap taken at the current pc.
+    ///
+    /// Picks M- or S-mode via medeleg/mideleg, saves epc/cause/tval, pushes the
+    /// interrupt-enable and privilege stack in mstatus, and jumps to the trap vector.
+    /// Returns false when the selected tvec is unconfigured (base 0); the CSR
+    /// updates still happen but pc is left at the faulting instruction.
+    pub fn take_trap(&mut self, cause: u64, tval: u64, is_interrupt: bool) -> bool {
+        use crate::csr::PrivMode;
+
+        let fault_pc = self.pc;
+
+        // A privilege change breaks any LR/SC sequence in flight
+        self.reservation = None;
+
+        // Determine if this trap should be delegated to S-mode
+        let delegate_to_s = if is_interrupt {
+            self.csr.should_delegate_interrupt(cause)
+        } else {
+            self.csr.should_delegate_exception(cause)
+        };
+
+        let target_mode = if delegate_to_s {
+            PrivMode::Supervisor
+        } else {
+            PrivMode::Machine
+        };
+
+        // Set cause (with interrupt bit if applicable)
+        let cause_val = if is_interrupt {
+            cause | (1 << 63)
+        } else {
+            cause
+        };
+
+        // Trap entry writes are architectural side-effects and must bypass CSR
+        // privilege checks based on the pre-trap mode.
+        let tvec = if target_mode == PrivMode::Supervisor {
+            self.csr.sepc = fault_pc & !0b1;
+            self.csr.scause = cause_val;
+            self.csr.stval = tval;
+            self.csr.stvec
+        } else {
+            self.csr.mepc = fault_pc & !0b1;
+            self.csr.mcause = cause_val;
+            self.csr.mtval = tval;
+            self.csr.mtvec
+        };
+
+        // Update mstatus/sstatus privilege stack
+        let current_mode = self.csr.priv_mode;
+        if target_mode == PrivMode::Machine {
+            // Save current MIE to MPIE
+            let mie = (self.csr.mstatus >> 3) & 1;
+            self.csr.mstatus = (self.csr.mstatus & !(1 << 7)) | (mie << 7);
+            // Clear MIE
+            self.csr.mstatus &= !(1 << 3);
+            // Save previous privilege mode to MPP
+            self.csr.set_mpp(current_mode);
+        } else {
+            // Supervisor mode trap
+            // Save current SIE to SPIE
+            let sie = (self.csr.mstatus >> 1) & 1;
+            self.csr.mstatus = (self.csr.mstatus & !(1 << 5)) | (sie << 5);
+            // Clear SIE
+            self.csr.mstatus &= !(1 << 1);
+            // Save previous privilege mode to SPP
+            self.csr.set_spp(current_mode);
+        }
+
+        // Update privilege mode
+        self.csr.priv_mode = target_mode;
+
+        // Jump to trap vector. In vectored mode interrupts go to base + 4 * cause;
+        // exceptions, and every trap in direct mode, go to base.
+        let base = tvec & !CsrFile::TVEC_MODE;
+
+        if base == 0 {
+            return false;
+        }
+
+        self.pc = if tvec & CsrFile::TVEC_MODE == CsrFile::TVEC_VECTORED && is_interrupt {
+            base.wrapping_add(4 * cause)
+        } else {
+            base
+        };
+
+        true
+    }
+
+    /// `take_trap` for `trap`, using its spec-defined cause and trap value.
+    pub fn enter_trap(&mut self, trap: &trap::Trap) -> bool {
+        self.take_trap(trap.cause(), trap.tval(), trap.is_interrupt())
+    }
+}
+
 /// Helper trait to convert Result<T, Trap> into Result<T, CpuStepResult>
 pub(crate) trait IntoCpuResult<T> {
     fn into_cpu_result(self) -> Result<T, CpuStepResult>;
@@ -81,20 +423,122 @@ impl<T> IntoCpuResult<T> for Result<T, trap::Trap> {
 }
 
 impl Machine {
+    /// A machine with `ram_bytes` of RAM and the default memory map; see
+    /// `MachineBuilder` for other layouts.
     pub fn new(ram_bytes: usize) -> Self {
-        Self {
-            cpu: Cpu::default(),
-            mem: Memory::new(ram_bytes),
-            mmu: Mmu::new(),
-            host_exit_addr: None,
-            max_insns: 0,
-            executed: 0,
+        Self::builder()
+            .ram_size(ram_bytes)
+            .build()
+            .expect("the default memory map has no overlaps")
+    }
+
+    pub fn builder() -> MachineBuilder {
+        MachineBuilder::new()
+    }
+
+    /// Assert an interrupt line on behalf of a host-side device. The line
+    /// stays pending, whatever the CLINT, PLIC or guest writes to mip say,
+    /// until `clear_interrupt`; it survives `reset` like any external device.
+    pub fn raise_interrupt(&mut self, line: InterruptLine) {
+        self.injected_irqs |= line.mip_bit();
+        self.cpu.csr.mip |= line.mip_bit();
+    }
+
+    /// Release a line raised with `raise_interrupt`. mip drops with it; a bit
+    /// a built-in device also drives comes back on the next step.
+    pub fn clear_interrupt(&mut self, line: InterruptLine) {
+        self.injected_irqs &= !line.mip_bit();
+        self.cpu.csr.mip &= !line.mip_bit();
+    }
+
+    /// Return to the power-on state: integer and float registers cleared, CSRs
+    /// at their defaults (mstatus=0, every implemented extension enabled in
+    /// misa) in `reset_priv` mode, TLBs flushed, CLINT
+    /// and PLIC reset, pc at `reset_vector` and sp at `reset_sp`. RAM is left
+    /// intact so a loaded program can be run again, and `executed` keeps
+    /// counting so `max_insns` bounds the whole run, resets included.
+    pub fn reset(&mut self) {
+        self.cpu = Cpu::default();
+        self.mmu = Mmu::new();
+        self.apply_config();
+        self.cpu.csr.misa = self.config.isa;
+        self.cpu.pc = self.reset_vector;
+        self.cpu.csr.priv_mode = self.reset_priv;
+        if let Some(sp) = self.reset_sp {
+            self.cpu.set_reg(2, sp);
         }
+        self.mem.clint = Clint::new();
+        self.mem.plic = Plic::new();
+        self.self_jumps = 0;
+        self.held_trap = None;
+        self.flush_decode_cache();
+    }
+
+    /// Give the CSR file and MMU the parts of `config` they enforce.
+    pub(crate) fn apply_config(&mut self) {
+        self.cpu.csr.isa = self.config.isa;
+        self.cpu.csr.sv48 = self.config.sv48;
+        self.mmu.ad_mode = self.config.ad_mode;
     }
 
     pub fn step(&mut self) -> Result<(), CpuStepResult> {
+        self.step_decoded().map(|_| ())
+    }
+
+    /// Like `step`, also returning the pc and decoded form of the instruction
+    /// the step executed, for tools that trace or analyze execution. None when
+    /// no instruction ran: the hart idled in WFI, or an interrupt or a fetch
+    /// or decode fault was taken instead. An instruction that traps still
+    /// counts as executed, as with the profiler.
+    pub fn step_decoded(&mut self) -> Result<Option<(u64, decode::Instr)>, CpuStepResult> {
+        if !self.begin_step()? {
+            return Ok(None);
+        }
+
+        // Fetch and decode
+        let (decoded, len) = match self.fetch_decoded() {
+            Ok(d) => d,
+            Err(CpuStepResult::Trapped(trap)) => {
+                self.handle_trap(trap)?;
+                return self.finish_step().map(|()| None);
+            }
+            Err(e) => return Err(e),
+        };
+
+        let pc = self.cpu.pc;
+        self.execute_decoded(decoded, len)?;
+        Ok(Some((pc, decoded)))
+    }
+
+    /// Per-step work ahead of the fetch: advance time, service devices, sync
+    /// PMP, then wake from WFI or take a pending interrupt. Returns false if
+    /// that used up the step (already finished), so nothing should be fetched.
+    fn begin_step(&mut self) -> Result<bool, CpuStepResult> {
         use crate::cpu::trap::Trap;
 
+        // Finish the step a TrapHalt interrupted; time already moved for it
+        if let Some(trap) = self.held_trap.take() {
+            self.deliver_trap(trap)?;
+            return self.finish_step().map(|()| false);
+        }
+
+        self.tick_clint();
+        self.mem.service_devices();
+        self.update_plic();
+        // Memory enforces PMP, so it needs the current configuration
+        self.mem.pmp.clone_from(&self.cpu.csr.pmp);
+        // Only this step's instruction can hit a watchpoint
+        self.mem.take_watch_hit();
+
+        // A hart stalled in WFI resumes once an interrupt is pending and enabled
+        // locally, even if it's masked globally. Until then, steps just let time pass.
+        if self.cpu.wfi {
+            if !self.cpu.csr.interrupt_pending_locally() {
+                return self.finish_step().map(|()| false);
+            }
+            self.cpu.wfi = false;
+        }
+
         // Check for pending interrupts before fetching
         if let Some(cause) = self.cpu.csr.check_pending_interrupt() {
             let pc = self.cpu.pc;
@@ -105,62 +549,75 @@ impl Machine {
                 7 => Trap::MachineTimerInterrupt { pc },
                 9 => Trap::SupervisorExternalInterrupt { pc },
                 11 => Trap::MachineExternalInterrupt { pc },
-                _ => return self.finish_step(), // Unknown interrupt, ignore
+                _ => return self.finish_step().map(|()| false), // Unknown interrupt, ignore
             };
             self.handle_trap(trap)?;
-            return self.finish_step();
+            return self.finish_step().map(|()| false);
         }
+        Ok(true)
+    }
 
-        // Fetch
-        let inst = match self
-            .mem
-            .read_u32_exec(
-                self.cpu.pc,
-                self.cpu.csr.satp,
-                self.cpu.csr.priv_mode,
-                &mut self.mmu,
-            )
-            .with_pc(self.cpu.pc)
-            .into_cpu_result()
-        {
-            Ok(i) => i,
-            Err(CpuStepResult::Trapped(trap)) => {
-                self.handle_trap(trap)?;
-                return self.finish_step();
-            }
-            Err(e) => return Err(e),
-        };
-
-        // Decode
-        let decoded = match decode::decode(self.cpu.pc, inst)
-            .with_pc(self.cpu.pc)
-            .into_cpu_result()
-        {
-            Ok(d) => d,
-            Err(CpuStepResult::Trapped(trap)) => {
-                self.handle_trap(trap)?;
-                return self.finish_step();
-            }
-            Err(e) => return Err(e),
-        };
+    /// Execute an instruction decoded from pc and finish the step: retire it
+    /// or take the trap it raised, then check for halts.
+    fn execute_decoded(&mut self, decoded: decode::Instr, len: u64) -> Result<(), CpuStepResult> {
+        if let Some(profile) = &mut self.profile {
+            profile.record(&decoded);
+        }
 
         // Execute
+        let pc = self.cpu.pc;
         // TODO: temp for riscv-tests
-        match exec::execute(
+        match exec::execute_with_len(
             &mut self.cpu,
             &mut self.mem,
             &mut self.mmu,
             decoded,
+            len,
             self.host_exit_addr,
         ) {
-            Ok(()) => {}
+            Ok(()) => {
+                self.cpu.csr.instret = self.cpu.csr.instret.wrapping_add(1);
+                if matches!(decoded, decode::Instr::FenceI) {
+                    self.on_fence_i();
+                }
+                if (self.decode_cache.is_some() || self.block_cache.is_some())
+                    && invalidates_decode_cache(&decoded)
+                {
+                    self.flush_decode_cache();
+                }
+                if let Some(request) = self.mem.syscon.take_request() {
+                    self.executed += 1;
+                    return Err(CpuStepResult::Halt(match request {
+                        SysconRequest::Poweroff { code } => HaltReason::Poweroff { code },
+                        SysconRequest::Reset => HaltReason::Reset,
+                    }));
+                }
+                if let Some(hit) = self.mem.take_watch_hit() {
+                    self.executed += 1;
+                    return Err(CpuStepResult::Halt(HaltReason::Watchpoint {
+                        addr: hit.addr,
+                        old: hit.old,
+                        new: hit.new,
+                    }));
+                }
+                if self.deadlock_after != 0 {
+                    self.self_jumps = if self.cpu.pc == pc {
+                        self.self_jumps + 1
+                    } else {
+                        0
+                    };
+                    if self.self_jumps >= self.deadlock_after {
+                        self.executed += 1;
+                        return Err(CpuStepResult::Halt(HaltReason::Deadlock { pc }));
+                    }
+                }
+            }
             Err(CpuStepResult::Halt(reason)) => {
                 self.executed += 1;
                 return Err(CpuStepResult::Halt(reason));
             }
             Err(CpuStepResult::Trapped(trap)) => {
                 self.handle_trap(trap)?;
-                return self.finish_step();
             }
             Err(e) => return Err(e),
         }
@@ -168,117 +625,474 @@ impl Machine {
         self.finish_step()
     }
 
-    fn finish_step(&mut self) -> Result<(), CpuStepResult> {
-        // Increment instruction counter and check max_insns
-        self.executed += 1;
-        if self.max_insns != 0 && self.executed >= self.max_insns {
-            return Err(CpuStepResult::Halt(HaltReason::MaxInsns));
+    /// Run up to `limit` steps from the cached block at pc, one dispatch per
+    /// instruction and no fetch or decode. Only the first step services
+    /// devices and checks for interrupts, so they are taken at block
+    /// boundaries; mtime still advances every step. The block is cut short if
+    /// an instruction traps or a store lands on a page holding cached code.
+    /// Returns the number of steps run.
+    fn step_block(&mut self, limit: u64) -> Result<u64, CpuStepResult> {
+        if !self.begin_step()? {
+            return Ok(1);
         }
+        let Some(block) = self.block_at_pc() else {
+            // Fetch and decode faults are raised by the ordinary path
+            let (decoded, len) = match self.fetch_decoded() {
+                Ok(d) => d,
+                Err(CpuStepResult::Trapped(trap)) => {
+                    self.handle_trap(trap)?;
+                    return self.finish_step().map(|()| 1);
+                }
+                Err(e) => return Err(e),
+            };
+            return self.execute_decoded(decoded, len).map(|()| 1);
+        };
 
-        // Increment cycle counter
-        self.cpu.csr.cycle = self.cpu.csr.cycle.wrapping_add(1);
-
-        Ok(())
+        let mut steps = 0;
+        for &(decoded, len) in block.instrs.iter().take(limit as usize) {
+            if steps > 0 {
+                self.tick_clint();
+            }
+            let pc = self.cpu.pc;
+            self.execute_decoded(decoded, len)?;
+            steps += 1;
+            if self.mem.take_code_written() {
+                self.flush_decode_cache();
+                break;
+            }
+            if self.cpu.pc != pc.wrapping_add(len) {
+                break;
+            }
+        }
+        Ok(steps)
     }
 
-    fn handle_trap(&mut self, trap: trap::Trap) -> Result<(), CpuStepResult> {
-        use crate::csr::PrivMode;
+    /// The block starting at pc, decoding and caching it on a miss. None if
+    /// the first instruction can't be fetched or decoded.
+    fn block_at_pc(&mut self) -> Option<Rc<Block>> {
+        if self.mem.take_code_written() {
+            self.flush_decode_cache();
+        }
+        let (pc, satp, priv_mode) = (self.cpu.pc, self.cpu.csr.satp, self.cpu.csr.priv_mode);
+        if let Some(block) = self.block_cache.as_mut()?.get(pc, satp, priv_mode) {
+            return Some(block);
+        }
 
-        let fault_pc = trap.pc();
-        let cause = trap.cause();
-        let tval = trap.tval();
-        let is_interrupt = trap.is_interrupt();
+        // Decode up to the first instruction that ends a block, staying on
+        // pc's page so the whole block shares one translation
+        let mut instrs = Vec::new();
+        let mut addr = pc;
+        while instrs.len() < BlockCache::MAX_LEN {
+            let Ok((inst, len)) = self.fetch_at(addr) else {
+                break;
+            };
+            if crate::mem::crosses_page(pc, addr + len - pc) {
+                break;
+            }
+            let Ok(decoded) = decode_fetched(&self.cpu, addr, inst, len) else {
+                break;
+            };
+            instrs.push((decoded, len));
+            addr += len;
+            if ends_block(&decoded) {
+                break;
+            }
+        }
+        if instrs.is_empty() {
+            return None;
+        }
 
-        // Determine if this trap should be delegated to S-mode
-        let delegate_to_s = if is_interrupt {
-            self.cpu.csr.should_delegate_interrupt(cause)
-        } else {
-            self.cpu.csr.should_delegate_exception(cause)
-        };
+        let mstatus = self.cpu.csr.mstatus;
+        if let Ok(paddr) =
+            self.mem
+                .translate_addr(pc, satp, true, false, priv_mode, mstatus, &mut self.mmu)
+        {
+            self.mem.track_code_page(paddr);
+        }
+        let block = Rc::new(Block { instrs });
+        self.block_cache
+            .as_mut()?
+            .insert(pc, satp, priv_mode, Rc::clone(&block));
+        Some(block)
+    }
 
-        let target_mode = if delegate_to_s {
-            PrivMode::Supervisor
-        } else {
-            PrivMode::Machine
-        };
+    /// Step until the machine halts or `max` steps have run (0 = no limit).
+    /// Unlike `step`, an unhandled trap and a WFI nothing can wake up from
+    /// also end the run, as halts.
+    pub fn run(&mut self, max: u64) -> StepOutcome {
+        let mut steps = 0u64;
+        while max == 0 || steps < max {
+            if self.cpu.wfi && self.cpu.csr.mie == 0 {
+                return StepOutcome::Halted(HaltReason::WfiDeadlock);
+            }
+            if self.sleep_on_wfi && self.cpu.wfi && !self.cpu.csr.interrupt_pending_locally() {
+                self.sleep_until_wakeup();
+            }
+            let result = if self.block_cache.is_some() {
+                let limit = if max == 0 { u64::MAX } else { max - steps };
+                self.step_block(limit)
+            } else {
+                self.step().map(|()| 1)
+            };
+            match result {
+                Ok(n) => steps += n,
+                Err(CpuStepResult::Continue) => steps += 1,
+                Err(CpuStepResult::Halt(reason)) => return StepOutcome::Halted(reason),
+                Err(CpuStepResult::Trapped(trap)) => {
+                    return StepOutcome::Halted(HaltReason::Trap(trap));
+                }
+            }
+        }
+        StepOutcome::Continued
+    }
 
-        // Set cause (with interrupt bit if applicable)
-        let cause_val = if is_interrupt {
-            cause | (1 << 63)
-        } else {
-            cause
-        };
+    /// Sleep until the CLINT timer deadline or until UART input arrives, then
+    /// advance mtime by the time slept (at `TIMEBASE_HZ`), stopping one tick
+    /// short of the deadline so the next step raises the interrupt itself.
+    /// Sleeps are capped, so a deadline that never comes still gets rechecked.
+    fn sleep_until_wakeup(&mut self) {
+        const MAX_SLEEP: Duration = Duration::from_millis(100);
+        const NANOS_PER_TICK: u64 = 1_000_000_000 / TIMEBASE_HZ;
 
-        // Trap entry writes are architectural side-effects and must bypass CSR
-        // privilege checks based on the pre-trap mode.
-        let tvec = if target_mode == PrivMode::Supervisor {
-            self.cpu.csr.sepc = fault_pc & !0b1;
-            self.cpu.csr.scause = cause_val;
-            self.cpu.csr.stval = tval;
-            self.cpu.csr.stvec
+        let clint = &self.mem.clint;
+        let ticks = clint.mtimecmp.saturating_sub(clint.mtime);
+        let timeout = if ticks == 0 {
+            MAX_SLEEP
         } else {
-            self.cpu.csr.mepc = fault_pc & !0b1;
-            self.cpu.csr.mcause = cause_val;
-            self.cpu.csr.mtval = tval;
-            self.cpu.csr.mtvec
+            Duration::from_nanos(ticks.saturating_mul(NANOS_PER_TICK)).min(MAX_SLEEP)
         };
 
-        // Update mstatus/sstatus privilege stack
-        let current_mode = self.cpu.csr.priv_mode;
-        if target_mode == PrivMode::Machine {
-            // Save current MIE to MPIE
-            let mie = (self.cpu.csr.mstatus >> 3) & 1;
-            self.cpu.csr.mstatus = (self.cpu.csr.mstatus & !(1 << 7)) | (mie << 7);
-            // Clear MIE
-            self.cpu.csr.mstatus &= !(1 << 3);
-            // Save previous privilege mode to MPP
-            self.cpu.csr.set_mpp(current_mode);
-        } else {
-            // Supervisor mode trap
-            // Save current SIE to SPIE
-            let sie = (self.cpu.csr.mstatus >> 1) & 1;
-            self.cpu.csr.mstatus = (self.cpu.csr.mstatus & !(1 << 5)) | (sie << 5);
-            // Clear SIE
-            self.cpu.csr.mstatus &= !(1 << 1);
-            // Save previous privilege mode to SPP
-            self.cpu.csr.set_spp(current_mode);
+        let start = Instant::now();
+        self.mem.uart.wait_rx(timeout);
+        let mut slept = (start.elapsed().as_nanos() / NANOS_PER_TICK as u128) as u64;
+        if ticks != 0 {
+            slept = slept.min(ticks - 1);
         }
+        self.mem.clint.mtime = self.mem.clint.mtime.wrapping_add(slept);
+    }
 
-        // Update privilege mode
-        self.cpu.csr.priv_mode = target_mode;
+    /// Halt `step` with `HaltReason::Watchpoint` after an instruction reads
+    /// (`on_read`) or writes (`on_write`) RAM in the physical range
+    /// `[addr, addr + len)`. The instruction completes first.
+    pub fn add_watchpoint(&mut self, addr: u64, len: u64, on_write: bool, on_read: bool) {
+        self.mem.watchpoints.push(Watchpoint {
+            addr,
+            len,
+            on_write,
+            on_read,
+        });
+    }
 
-        // Jump to trap vector
-        let mode = tvec & 0b11;
-        let base = tvec & !0b11;
+    /// Called after each FENCE.I, after which fetches must see every earlier
+    /// store. Flushes the decode and block caches, so self-modifying code (JITs,
+    /// dynamic loaders) doesn't run stale instructions.
+    pub fn on_fence_i(&mut self) {
+        self.flush_decode_cache();
+    }
 
-        if base == 0 {
-            // No trap handler configured
-            return Err(CpuStepResult::Trapped(trap));
+    /// Drop every cached decode, single or in blocks, and stop watching their
+    /// pages for stores.
+    pub fn flush_decode_cache(&mut self) {
+        if let Some(cache) = &mut self.decode_cache {
+            cache.flush();
+        }
+        if let Some(cache) = &mut self.block_cache {
+            cache.flush();
         }
+        self.mem.clear_code_pages();
+    }
 
-        self.cpu.pc = match mode {
-            0 => base, // Direct mode
-            1 => {
-                // Vectored mode: base + 4 * cause for interrupts
-                if is_interrupt {
-                    base.wrapping_add(4 * cause)
-                } else {
-                    base
-                }
+    /// Fetch and decode the instruction at pc, through the decode cache when
+    /// it's enabled.
+    fn fetch_decoded(&mut self) -> Result<(decode::Instr, u64), CpuStepResult> {
+        if self.decode_cache.is_none() {
+            let (inst, len) = self.fetch()?;
+            let decoded = decode_fetched(&self.cpu, self.cpu.pc, inst, len)
+                .with_pc(self.cpu.pc)
+                .into_cpu_result()?;
+            return Ok((decoded, len));
+        }
+
+        // A store since the last step may have rewritten cached code
+        if self.mem.take_code_written() {
+            self.flush_decode_cache();
+        }
+        let (pc, satp, priv_mode) = (self.cpu.pc, self.cpu.csr.satp, self.cpu.csr.priv_mode);
+        if let Some(hit) = self
+            .decode_cache
+            .as_mut()
+            .and_then(|cache| cache.get(pc, satp, priv_mode))
+        {
+            return Ok(hit);
+        }
+
+        let (inst, len) = self.fetch()?;
+        let decoded = decode_fetched(&self.cpu, self.cpu.pc, inst, len)
+            .with_pc(pc)
+            .into_cpu_result()?;
+        // The fetch just translated these, so this can't fault
+        let mstatus = self.cpu.csr.mstatus;
+        for addr in [pc, pc.wrapping_add(len - 1)] {
+            if let Ok(paddr) =
+                self.mem
+                    .translate_addr(addr, satp, true, false, priv_mode, mstatus, &mut self.mmu)
+            {
+                self.mem.track_code_page(paddr);
             }
-            _ => base, // Reserved modes default to direct
-        };
+        }
+        if let Some(cache) = &mut self.decode_cache {
+            cache.insert(pc, satp, priv_mode, decoded, len);
+        }
+        Ok((decoded, len))
+    }
 
-        Ok(())
+    /// Fetch the instruction at pc, returning it with its length in bytes.
+    /// The low parcel decides the length; a 4-byte instruction may straddle a
+    /// page, so its upper parcel is fetched (and can fault) separately.
+    fn fetch(&mut self) -> Result<(u32, u64), CpuStepResult> {
+        self.fetch_at(self.cpu.pc)
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::{CpuStepResult, HaltReason, Machine};
+    fn fetch_at(&mut self, pc: u64) -> Result<(u32, u64), CpuStepResult> {
+        let (satp, priv_mode, mstatus) = (
+            self.cpu.csr.satp,
+            self.cpu.csr.priv_mode,
+            self.cpu.csr.mstatus,
+        );
+        let lo = self
+            .mem
+            .fetch_u16(pc, satp, priv_mode, mstatus, &mut self.mmu)
+            .with_pc(pc)
+            .into_cpu_result()?;
+        if lo & 0b11 != 0b11 {
+            return Ok((lo as u32, 2));
+        }
+        let hi = self
+            .mem
+            .fetch_u16(pc.wrapping_add(2), satp, priv_mode, mstatus, &mut self.mmu)
+            .with_pc(pc)
+            .into_cpu_result()?;
+        Ok(((hi as u32) << 16 | lo as u32, 4))
+    }
 
-    #[test]
-    fn test_step_trap_path_counts_toward_max_insns() {
+    /// Decode the instruction at pc without executing it, or None if fetching
+    /// or decoding it would trap. Like `peek_raw`, this changes nothing.
+    pub fn peek_instr(&self) -> Option<decode::Instr> {
+        let (inst, len) = self.peek_raw()?;
+        decode_fetched(&self.cpu, self.cpu.pc, inst, len).ok()
+    }
+
+    /// Read the raw encoding at pc and its length in bytes without executing
+    /// it, or None if the fetch would fault or pc isn't in RAM. Nothing the
+    /// guest can observe changes: no TLB fill, no A/D update, no device read.
+    pub fn peek_raw(&self) -> Option<(u32, u64)> {
+        let (pc, satp, priv_mode, mstatus) = (
+            self.cpu.pc,
+            self.cpu.csr.satp,
+            self.cpu.csr.priv_mode,
+            self.cpu.csr.mstatus,
+        );
+        let parcel = |addr| {
+            self.mem
+                .peek_fetch_u16(addr, satp, priv_mode, mstatus, &self.mmu)
+                .ok()
+        };
+        let lo = parcel(pc)?;
+        if lo & 0b11 != 0b11 {
+            return Some((lo as u32, 2));
+        }
+        let hi = parcel(pc.wrapping_add(2))?;
+        Some(((hi as u32) << 16 | lo as u32, 4))
+    }
+
+    /// Advance CLINT mtime, shadow it into the time CSR and mirror the CLINT's
+    /// interrupt lines into mip.
+    fn tick_clint(&mut self) {
+        self.mem.clint.tick();
+        self.cpu.csr.time = self.mem.clint.mtime;
+
+        if self.mem.clint.timer_pending() {
+            self.cpu.csr.set_timer_interrupt(true);
+        } else {
+            self.cpu.csr.clear_timer_interrupt(true);
+        }
+
+        if self.mem.clint.software_pending() {
+            self.cpu.csr.set_software_interrupt(true);
+        } else {
+            self.cpu.csr.clear_software_interrupt(true);
+        }
+        self.cpu.csr.mip |= self.injected_irqs;
+    }
+
+    /// Feed device interrupt lines into the PLIC and mirror its context
+    /// outputs into mip.MEIP/SEIP.
+    fn update_plic(&mut self) {
+        use crate::plic::{CONTEXT_M, CONTEXT_S};
+        use crate::uart::UART_IRQ;
+
+        self.mem.plic.set_irq(UART_IRQ, self.mem.uart.irq_pending());
+        for (source, level) in self.mem.bus.irqs() {
+            self.mem.plic.set_irq(source, level);
+        }
+
+        for (context, is_machine) in [(CONTEXT_M, true), (CONTEXT_S, false)] {
+            if self.mem.plic.irq_pending(context) {
+                self.cpu.csr.set_external_interrupt(is_machine);
+            } else {
+                self.cpu.csr.clear_external_interrupt(is_machine);
+            }
+        }
+        self.cpu.csr.mip |= self.injected_irqs;
+    }
+
+    fn finish_step(&mut self) -> Result<(), CpuStepResult> {
+        // Every step takes a cycle, whether or not an instruction retired
+        self.cpu.csr.cycle = self.cpu.csr.cycle.wrapping_add(1);
+
+        // Increment instruction counter and check max_insns
+        self.executed += 1;
+        if self.max_insns != 0 && self.executed >= self.max_insns {
+            return Err(CpuStepResult::Halt(HaltReason::MaxInsns));
+        }
+
+        Ok(())
+    }
+
+    fn handle_trap(&mut self, trap: trap::Trap) -> Result<(), CpuStepResult> {
+        let cause = trap.cause();
+        if !trap.is_interrupt() && self.halt_on_trap & (1 << cause) != 0 {
+            self.held_trap = Some(trap);
+            return Err(CpuStepResult::Halt(HaltReason::TrapHalt {
+                cause,
+                pc: trap.pc(),
+            }));
+        }
+        self.deliver_trap(trap)
+    }
+
+    /// Take `trap` as the hart would, or serve it on the host if it's an
+    /// ECALL for the proxy kernel.
+    fn deliver_trap(&mut self, trap: trap::Trap) -> Result<(), CpuStepResult> {
+        if let (trap::Trap::Ecall { pc }, Some(proxy)) = (&trap, &mut self.proxy) {
+            // Served on the host, so the ECALL retires like any instruction
+            self.cpu.pc = pc.wrapping_add(4);
+            self.cpu.csr.instret = self.cpu.csr.instret.wrapping_add(1);
+            if let Some(code) = proxy.syscall(&mut self.cpu, &mut self.mem, &mut self.mmu) {
+                return Err(CpuStepResult::Halt(HaltReason::Exit { code }));
+            }
+            return Ok(());
+        }
+        if let Some(stats) = &mut self.trap_stats {
+            stats.record(trap.cause(), trap.is_interrupt());
+        }
+        if !self.cpu.enter_trap(&trap) {
+            // No trap handler configured
+            return Err(CpuStepResult::Trapped(trap));
+        }
+        Ok(())
+    }
+}
+
+/// Instructions besides FENCE.I (see `Machine::on_fence_i`) after which
+/// cached decodes may be stale: SFENCE.VMA, and CSR accesses to misa (C
+/// decides whether 2-byte encodings are legal) or the PMP, which can revoke
+/// execute permission.
+fn invalidates_decode_cache(instr: &decode::Instr) -> bool {
+    use decode::Instr;
+    match *instr {
+        Instr::SfenceVma { .. } => true,
+        Instr::Csrrw { csr, .. }
+        | Instr::Csrrs { csr, .. }
+        | Instr::Csrrc { csr, .. }
+        | Instr::Csrrwi { csr, .. }
+        | Instr::Csrrsi { csr, .. }
+        | Instr::Csrrci { csr, .. } => csr == 0x301 || (0x3a0..=0x3ef).contains(&csr),
+        _ => false,
+    }
+}
+
+/// Instructions a block ends with: control transfers, which leave the straight
+/// line, and system instructions, which can change privilege, translation or
+/// what is legal to decode.
+fn ends_block(instr: &decode::Instr) -> bool {
+    use decode::Instr;
+    matches!(
+        instr,
+        Instr::Jal { .. }
+            | Instr::Jalr { .. }
+            | Instr::Beq { .. }
+            | Instr::Bne { .. }
+            | Instr::Blt { .. }
+            | Instr::Bge { .. }
+            | Instr::Bltu { .. }
+            | Instr::Bgeu { .. }
+            | Instr::Ecall
+            | Instr::Ebreak
+            | Instr::Mret
+            | Instr::Sret
+            | Instr::Wfi
+            | Instr::SfenceVma { .. }
+            | Instr::FenceI
+            | Instr::Csrrw { .. }
+            | Instr::Csrrs { .. }
+            | Instr::Csrrc { .. }
+            | Instr::Csrrwi { .. }
+            | Instr::Csrrsi { .. }
+            | Instr::Csrrci { .. }
+    )
+}
+
+/// Decode an instruction fetched from pc. Instructions of extensions disabled
+/// in misa are illegal, as are 2-byte encodings with C cleared.
+fn decode_fetched(
+    cpu: &Cpu,
+    pc: u64,
+    inst: u32,
+    len: u64,
+) -> Result<decode::Instr, decode::DecodeError> {
+    let instr = if len == 2 {
+        if !cpu.csr.ext_enabled(b'C') {
+            return Err(decode::DecodeError::InvalidOpcode { inst });
+        }
+        decode::decode_compressed(pc, inst as u16)
+    } else {
+        decode::decode(pc, inst)
+    }?;
+    if !cpu.csr.ext_enabled(instr.extension()) {
+        return Err(decode::DecodeError::InvalidOpcode { inst });
+    }
+    Ok(instr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        BlockCache, Cpu, CpuStepResult, HaltReason, InterruptLine, Machine, StepOutcome, decode,
+        fpu,
+    };
+    use crate::cpu::trap::Trap;
+    use crate::csr::{CsrFile, PrivMode};
+    use crate::mem::{Perms, Region};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_register_accessors_pin_x0() {
+        let mut cpu = Cpu::default();
+        cpu.set_reg(0, 0xdead);
+        assert_eq!(cpu.reg(0), 0);
+
+        cpu.set_reg(1, 0x8000_0010);
+        cpu.set_reg(2, 0x8000_f000);
+        cpu.set_reg(10, 7);
+        cpu.set_reg(17, 93);
+        assert_eq!((cpu.ra(), cpu.sp()), (0x8000_0010, 0x8000_f000));
+        assert_eq!((cpu.a0(), cpu.a7()), (7, 93));
+        assert_eq!(cpu.regs[10], 7, "accessors alias the register file");
+    }
+
+    #[test]
+    fn test_step_trap_path_counts_toward_max_insns() {
         let mut m = Machine::new(0x10000);
         // Force an immediate fetch trap: PC is outside mapped RAM base (0x8000_0000+).
         m.cpu.pc = 0x0;
@@ -294,4 +1108,901 @@ mod tests {
         assert_eq!(m.executed, 1, "trap-handled step should increment executed");
         assert_eq!(m.cpu.pc, 0x8000_0100, "trap should vector to mtvec base");
     }
+
+    #[test]
+    fn test_instret_counts_retired_instructions_and_cycle_every_step() {
+        let mut m = Machine::new(0x1000);
+        m.cpu.csr.mtvec = 0x8000_0100;
+        let program = [
+            0x0000_0013, // nop
+            0x0000_0013, // nop
+            0xc020_2573, // csrr a0, instret
+            0xc000_25f3, // csrr a1, cycle
+            0x0000_0073, // ecall: traps, so doesn't retire
+        ];
+        for (i, inst) in program.iter().enumerate() {
+            m.mem
+                .write_u32_phys(0x8000_0000 + 4 * i as u64, *inst)
+                .unwrap();
+        }
+        m.mem.write_u32_phys(0x8000_0100, 0xb020_2673).unwrap(); // csrr a2, minstret
+        m.mem.write_u32_phys(0x8000_0104, 0xb000_26f3).unwrap(); // csrr a3, mcycle
+
+        for _ in 0..7 {
+            m.step().unwrap();
+        }
+        assert_eq!(m.cpu.a0(), 2);
+        assert_eq!(m.cpu.a1(), 3);
+        assert_eq!(m.cpu.a2(), 4, "the ecall didn't retire");
+        assert_eq!(m.cpu.a3(), 6, "but it took a cycle");
+        assert_eq!(m.cpu.csr.instret, 6);
+        assert_eq!(m.cpu.csr.cycle, 7);
+    }
+
+    #[test]
+    fn test_user_mode_counter_reads_measure_a_loop() {
+        let program = [
+            0xc020_2473, // csrr s0, instret
+            0x00a0_0293, // li t0, 10
+            0xfff2_8293, // 1: addi t0, t0, -1
+            0xfe02_9ee3, // bnez t0, 1b
+            0xc020_24f3, // csrr s1, instret
+            0xc000_2973, // csrr s2, cycle
+            0xc010_29f3, // csrr s3, time
+            0x0000_0073, // ecall
+        ];
+        // Both run loops must count alike
+        for blocks in [false, true] {
+            let mut m = Machine::new(0x10000);
+            for (i, inst) in program.iter().enumerate() {
+                m.mem
+                    .write_u32_phys(0x8000_0000 + 4 * i as u64, *inst)
+                    .unwrap();
+            }
+            if blocks {
+                m.block_cache = Some(BlockCache::new());
+            }
+            m.cpu.csr.priv_mode = PrivMode::User;
+            m.cpu.csr.mcounteren = 0b111;
+            m.cpu.csr.scounteren = 0b111;
+
+            let halted = m.run(100);
+            assert_eq!(
+                halted,
+                StepOutcome::Halted(HaltReason::Trap(Trap::Ecall { pc: 0x8000_001c }))
+            );
+            // The first read, li, then ten addi/bnez pairs retired in between
+            let (s0, s1, s2, s3) = (m.cpu.reg(8), m.cpu.reg(9), m.cpu.reg(18), m.cpu.reg(19));
+            assert_eq!(s1 - s0, 22, "blocks: {blocks}");
+            // Nothing trapped, so every earlier step retired, and mtime ticks per step
+            assert_eq!((s0, s2, s3), (0, 23, 25), "blocks: {blocks}");
+        }
+
+        // With scounteren.IR clear, U-mode can't read instret
+        let mut m = Machine::new(0x10000);
+        m.mem.write_u32_phys(0x8000_0000, program[0]).unwrap();
+        m.cpu.csr.mtvec = 0x8000_0100;
+        m.cpu.csr.priv_mode = PrivMode::User;
+        m.cpu.csr.mcounteren = 0b111;
+        m.cpu.csr.scounteren = 0b011;
+        m.step().unwrap();
+        assert_eq!(m.cpu.csr.mcause, 2);
+        assert_eq!(m.cpu.pc, 0x8000_0100);
+    }
+
+    #[test]
+    fn test_ecall_cause_depends_on_privilege() {
+        for (mode, cause) in [
+            (PrivMode::User, 8),
+            (PrivMode::Supervisor, 9),
+            (PrivMode::Machine, 11),
+        ] {
+            let mut m = Machine::new(0x10000);
+            m.mem.write_u32_phys(0x8000_0000, 0x0000_0073).unwrap(); // ecall
+            m.cpu.pc = 0x8000_0000;
+            m.cpu.csr.mtvec = 0x8000_0100;
+            m.cpu.csr.priv_mode = mode;
+
+            m.step().expect("ecall should vector to mtvec");
+
+            assert_eq!(m.cpu.csr.mcause, cause, "ecall from {:?}", mode);
+            assert_eq!(m.cpu.csr.mepc, 0x8000_0000);
+            assert_eq!(m.cpu.csr.mpp(), mode);
+            assert_eq!(m.cpu.pc, 0x8000_0100);
+        }
+    }
+
+    #[test]
+    fn test_fetch_from_unbacked_address_is_instruction_access_fault() {
+        let mut m = Machine::new(0x10000);
+        m.cpu.pc = 0x4000_0000;
+        m.cpu.csr.mtvec = 0x8000_0100;
+
+        m.step().expect("fetch fault should vector to mtvec");
+
+        assert_eq!(m.cpu.csr.mcause, 1);
+        assert_eq!(m.cpu.csr.mtval, 0x4000_0000);
+        assert_eq!(m.cpu.csr.mepc, 0x4000_0000);
+        assert_eq!(m.cpu.pc, 0x8000_0100);
+    }
+
+    #[test]
+    fn test_fetch_checks_execute_permission() {
+        // VA 0x4000_0000 -> PA 0x8000_0000 via a 1 GiB gigapage in the root table
+        let root = 0x8000_2000u64;
+        for (flags, cause) in [(0xc3, Some(12)), (0xcb, None)] {
+            let mut m = Machine::new(0x10000);
+            let leaf = (0x80000 << 10) | flags;
+            m.mem.write_u64_phys(root + 8, leaf).unwrap();
+            m.mem.write_u32_phys(0x8000_0000, 0x0000_0013).unwrap(); // nop
+            m.cpu.csr.priv_mode = PrivMode::Supervisor;
+            m.cpu.csr.satp = (8u64 << 60) | (root >> 12);
+            m.cpu.csr.mtvec = 0x8000_0100;
+            m.cpu.pc = 0x4000_0000;
+
+            m.step().unwrap();
+
+            match cause {
+                // V|R|A|D: readable but not executable
+                Some(cause) => {
+                    assert_eq!(m.cpu.csr.mcause, cause);
+                    assert_eq!(m.cpu.csr.mtval, 0x4000_0000);
+                    assert_eq!(m.cpu.pc, 0x8000_0100);
+                }
+                // V|R|X|A|D: the nop executes
+                None => assert_eq!(m.cpu.pc, 0x4000_0004),
+            }
+        }
+    }
+
+    #[test]
+    fn test_peek_leaves_page_tables_and_tlb_alone() {
+        // VA 0x4000_0000 -> PA 0x8000_0000 via a gigapage with A still clear
+        let root = 0x8000_2000u64;
+        let leaf = (0x80000 << 10) | 0x0b; // V|R|X
+        let mut m = Machine::new(0x10000);
+        m.mem.write_u64_phys(root + 8, leaf).unwrap();
+        m.mem.write_u32_phys(0x8000_0000, 0x0000_0013).unwrap(); // nop
+        m.cpu.csr.priv_mode = PrivMode::Supervisor;
+        m.cpu.csr.satp = (8u64 << 60) | (root >> 12);
+        m.cpu.pc = 0x4000_0000;
+
+        assert_eq!(m.peek_raw(), Some((0x0000_0013, 4)));
+        assert!(m.peek_instr().is_some());
+        assert_eq!(m.mem.read_u64_phys(root + 8).unwrap(), leaf);
+        let (itlb, _) = m.mmu.tlb_stats();
+        assert_eq!((itlb.hits, itlb.misses), (0, 0));
+
+        // Device windows aren't read
+        m.cpu.csr.priv_mode = PrivMode::Machine;
+        m.cpu.pc = crate::uart::UART_BASE;
+        assert_eq!(m.peek_raw(), None);
+    }
+
+    #[test]
+    fn test_run_reports_why_the_machine_stopped() {
+        let mut m = Machine::new(0x1000);
+        m.mem.write_u32_phys(0x8000_0000, 0x0000_006f).unwrap(); // j .
+        assert_eq!(m.run(10), StepOutcome::Continued);
+        assert_eq!(m.executed, 10);
+
+        m.max_insns = 15;
+        assert_eq!(m.run(0), StepOutcome::Halted(HaltReason::MaxInsns));
+        assert_eq!(m.executed, 15);
+
+        // ecall with no trap handler
+        let mut m = Machine::new(0x1000);
+        m.mem.write_u32_phys(0x8000_0000, 0x0000_0073).unwrap();
+        assert_eq!(
+            m.run(0),
+            StepOutcome::Halted(HaltReason::Trap(Trap::EcallFromM { pc: 0x8000_0000 }))
+        );
+
+        // wfi with mie clear can never wake up
+        let mut m = Machine::new(0x1000);
+        m.mem.write_u32_phys(0x8000_0000, 0x1050_0073).unwrap();
+        assert_eq!(m.run(0), StepOutcome::Halted(HaltReason::WfiDeadlock));
+        m.cpu.csr.mie = 1 << 7; // MTIE
+        assert_eq!(m.run(5), StepOutcome::Continued);
+    }
+
+    #[test]
+    fn test_jump_to_self_halts_when_guarded() {
+        let mut m = Machine::new(0x10000);
+        m.mem.write_u32_phys(0x8000_0000, 0x0000_0013).unwrap(); // nop
+        m.mem.write_u32_phys(0x8000_0004, 0x0000_006f).unwrap(); // j .
+        m.cpu.pc = 0x8000_0000;
+        assert_eq!(m.run(100), StepOutcome::Continued, "off by default");
+
+        m.cpu.pc = 0x8000_0000;
+        m.deadlock_after = 10;
+        assert_eq!(
+            m.run(0),
+            StepOutcome::Halted(HaltReason::Deadlock { pc: 0x8000_0004 })
+        );
+        assert_eq!(m.executed, 100 + 11);
+    }
+
+    #[test]
+    fn test_watchpoint_reports_the_clobbering_store() {
+        let machine = || {
+            let mut m = Machine::new(0x10000);
+            m.mem.write_u32_phys(0x8000_0000, 0x0002_a303).unwrap(); // lw t1, 0(t0)
+            m.mem.write_u32_phys(0x8000_0004, 0x0062_9223).unwrap(); // sh t1, 4(t0)
+            m.mem.write_u32_phys(0x8000_0008, 0x0000_006f).unwrap(); // j .
+            m.mem
+                .write_u64_phys(0x8000_1000, 0x1111_2222_3333_4444)
+                .unwrap();
+            m.cpu.regs[5] = 0x8000_1000;
+            m.cpu.pc = 0x8000_0000;
+            m
+        };
+
+        // The load doesn't trip a write watchpoint; the store halts after it
+        // completes
+        let mut m = machine();
+        m.add_watchpoint(0x8000_1005, 1, true, false);
+        let hit = HaltReason::Watchpoint {
+            addr: 0x8000_1004,
+            old: 0x2222,
+            new: 0x4444,
+        };
+        assert_eq!(m.run(100), StepOutcome::Halted(hit));
+        assert_eq!(m.cpu.pc, 0x8000_0008);
+        assert_eq!(m.executed, 2);
+        assert_eq!(
+            m.mem.read_u64_phys(0x8000_1000).unwrap(),
+            0x1111_4444_3333_4444
+        );
+
+        // Fetches aren't reads
+        let mut m = machine();
+        m.add_watchpoint(0x8000_0000, 0x2000, false, true);
+        let hit = HaltReason::Watchpoint {
+            addr: 0x8000_1000,
+            old: 0x3333_4444,
+            new: 0x3333_4444,
+        };
+        assert_eq!(m.run(100), StepOutcome::Halted(hit));
+        assert_eq!(m.executed, 1);
+    }
+
+    #[test]
+    fn test_step_decoded_returns_the_executed_instruction() {
+        let mut m = Machine::new(0x10000);
+        m.mem.write_u32_phys(0x8000_0000, 0x0015_0513).unwrap(); // addi a0, a0, 1
+        m.mem.write_u32_phys(0x8000_0004, 0x0000_0073).unwrap(); // ecall
+        m.cpu.pc = 0x8000_0000;
+        m.cpu.csr.mtvec = 0x8000_0100;
+
+        let (pc, instr) = m.step_decoded().unwrap().unwrap();
+        assert_eq!(pc, 0x8000_0000);
+        assert!(matches!(
+            instr,
+            decode::Instr::Addi {
+                rd: 10,
+                rs1: 10,
+                imm: 1
+            }
+        ));
+        // Trapping instructions still ran
+        let (pc, instr) = m.step_decoded().unwrap().unwrap();
+        assert_eq!(pc, 0x8000_0004);
+        assert!(matches!(instr, decode::Instr::Ecall));
+
+        // A fetch fault at the handler runs nothing
+        m.cpu.pc = 0x1000;
+        assert!(m.step_decoded().unwrap().is_none());
+        assert_eq!(m.cpu.pc, 0x8000_0100);
+    }
+
+    #[test]
+    fn test_fence_i_makes_stored_instructions_visible() {
+        let program = [
+            0x0000_0297, // auipc t0, 0
+            0x02a0_0337, // lui t1, 0x2a00
+            0x5133_0313, // addi t1, t1, 0x513 (t1 = addi a0, zero, 42)
+            0x0062_aa23, // sw t1, 20(t0)
+            0x0000_100f, // fence.i
+            0x0010_0513, // addi a0, zero, 1 (overwritten)
+        ];
+        let mut m = Machine::new(0x10000);
+        for (i, inst) in program.iter().enumerate() {
+            m.mem
+                .write_u32_phys(0x8000_0000 + 4 * i as u64, *inst)
+                .unwrap();
+        }
+        m.cpu.pc = 0x8000_0000;
+
+        for _ in 0..program.len() {
+            m.step().unwrap();
+        }
+        assert_eq!(m.cpu.a0(), 42);
+    }
+
+    #[test]
+    fn test_clearing_misa_c_makes_compressed_instructions_illegal() {
+        const CSRRC_MISA_T0: u32 = 0x3012_b073; // csrrc zero, misa, t0
+        let mut m = Machine::new(0x1000);
+        m.cpu.set_reg(5, 1 << (b'C' - b'A'));
+        m.mem.write_u32_phys(0x8000_0000, CSRRC_MISA_T0).unwrap();
+        m.mem.write_u32_phys(0x8000_0004, 0x0001).unwrap(); // c.nop
+        m.cpu.pc = 0x8000_0000;
+
+        m.step().unwrap();
+        assert!(!m.cpu.csr.ext_enabled(b'C'));
+        assert!(matches!(
+            m.step(),
+            Err(CpuStepResult::Trapped(Trap::IllegalInstruction {
+                pc: 0x8000_0004,
+                inst: 0x0001
+            }))
+        ));
+
+        // The whole write is dropped when the next instruction is not 4-byte
+        // aligned, F included
+        let mut m = Machine::new(0x1000);
+        m.cpu
+            .set_reg(5, (1 << (b'C' - b'A')) | (1 << (b'F' - b'A')));
+        m.mem
+            .write_u32_phys(0x8000_0000, (CSRRC_MISA_T0 << 16) | 0x0001)
+            .unwrap(); // c.nop; csrrc (low half)
+        m.mem
+            .write_u32_phys(0x8000_0004, CSRRC_MISA_T0 >> 16)
+            .unwrap();
+        m.cpu.pc = 0x8000_0000;
+        m.step().unwrap();
+        m.step().unwrap();
+        assert_eq!(m.cpu.pc, 0x8000_0006);
+        assert!(m.cpu.csr.ext_enabled(b'C'));
+        assert!(m.cpu.csr.ext_enabled(b'F'));
+    }
+
+    #[test]
+    fn test_disabled_extensions_are_illegal() {
+        let mut m = Machine::new(0x1000);
+        m.config.isa = crate::csr::parse_isa("rv64ic").unwrap();
+        m.reset();
+        m.cpu.csr.mstatus |= crate::csr::CsrFile::MSTATUS_FS;
+        for inst in [
+            0x02b5_0533, // mul a0, a0, a1
+            0x00b5_302f, // amoadd.d zero, a1, (a0)
+            0x0000_0053, // fadd.s ft0, ft0, ft0, rne
+            0x2000_3007, // fld ft0, 512(zero)
+        ] {
+            m.mem.write_u32_phys(0x8000_0000, inst).unwrap();
+            m.cpu.pc = 0x8000_0000;
+            assert!(
+                matches!(
+                    m.step(),
+                    Err(CpuStepResult::Trapped(Trap::IllegalInstruction { .. }))
+                ),
+                "0x{:08x} should be illegal",
+                inst
+            );
+        }
+
+        // Only the hart's own extensions survive a reset
+        m.reset();
+        assert_eq!(m.cpu.csr.isa_string(), "rv64ic_zicsr_zifencei");
+    }
+
+    #[test]
+    fn test_fp_is_illegal_until_the_kernel_enables_fs() {
+        const FADD_S: u32 = 0x0020_f1d3; // fadd.s ft3, ft1, ft2
+        const FRCSR: u32 = 0x0030_2573; // csrrs a0, fcsr, zero
+        let mut m = Machine::new(0x1000);
+        m.cpu.f[1] = fpu::box_f32(1.5);
+        m.cpu.f[2] = fpu::box_f32(2.0);
+        m.mem.write_u32_phys(0x8000_0000, FADD_S).unwrap();
+        m.mem.write_u32_phys(0x8000_0004, FRCSR).unwrap();
+
+        // FS = Off after reset: both the instruction and fcsr trap
+        assert_eq!(m.cpu.csr.mstatus & CsrFile::MSTATUS_FS, 0);
+        for pc in [0x8000_0000, 0x8000_0004] {
+            m.cpu.pc = pc;
+            assert!(matches!(
+                m.step(),
+                Err(CpuStepResult::Trapped(Trap::IllegalInstruction { .. }))
+            ));
+            assert_eq!(m.cpu.csr.mcause, 2);
+            assert_eq!(m.cpu.csr.mepc, pc);
+        }
+        assert_eq!(m.cpu.f[3], 0, "nothing was written");
+
+        m.cpu.csr.mstatus |= 1 << 13; // FS = Initial
+        m.cpu.pc = 0x8000_0000;
+        m.step().unwrap();
+        m.step().unwrap();
+        assert_eq!(m.cpu.f32_reg(3), 3.5);
+        assert_eq!(m.cpu.a0(), 0);
+        assert_eq!(m.cpu.pc, 0x8000_0008);
+    }
+
+    #[test]
+    fn test_syscon_write_halts_the_machine() {
+        for (value, reason) in [
+            (0x5555, HaltReason::Poweroff { code: 0 }),
+            ((7 << 16) | 0x3333, HaltReason::Poweroff { code: 7 }),
+            (0x7777, HaltReason::Reset),
+        ] {
+            let mut m = Machine::new(0x1000);
+            m.cpu.set_reg(5, 0x10_0000);
+            m.cpu.set_reg(6, value);
+            m.mem.write_u32_phys(0x8000_0000, 0x0062_a023).unwrap(); // sw t1, 0(t0)
+            assert_eq!(m.run(0), StepOutcome::Halted(reason));
+            assert_eq!(m.cpu.pc, 0x8000_0004);
+            assert_eq!(m.executed, 1);
+        }
+    }
+
+    #[test]
+    fn test_store_straddling_into_unmapped_page_faults() {
+        // Sv39 4 KiB pages: VA 0x4000_0000 -> PA 0x8000_0000 (code) and
+        // VA 0x4000_1000 -> PA 0x8000_1000; VA 0x4000_2000 is unmapped
+        let (root, l1, l0) = (0x8000_2000u64, 0x8000_3000u64, 0x8000_4000u64);
+        let pte = |paddr: u64, flags: u64| ((paddr >> 12) << 10) | flags;
+        let mut m = Machine::new(0x10000);
+        m.mem.allow_misaligned = true;
+        m.mem.write_u64_phys(root + 8, pte(l1, 0x01)).unwrap();
+        m.mem.write_u64_phys(l1, pte(l0, 0x01)).unwrap();
+        m.mem.write_u64_phys(l0, pte(0x8000_0000, 0xcb)).unwrap(); // V|R|X|A|D
+        m.mem
+            .write_u64_phys(l0 + 8, pte(0x8000_1000, 0xc7))
+            .unwrap(); // V|R|W|A|D
+        m.mem.write_u32_phys(0x8000_0000, 0x0062_a023).unwrap(); // sw t1, 0(t0)
+        m.cpu.csr.priv_mode = PrivMode::Supervisor;
+        m.cpu.csr.satp = (8u64 << 60) | (root >> 12);
+        m.cpu.csr.mtvec = 0x8000_0100;
+        m.cpu.pc = 0x4000_0000;
+        m.cpu.set_reg(5, 0x4000_1ffe);
+        m.cpu.set_reg(6, 0xdead_beef);
+
+        m.step().unwrap();
+
+        assert_eq!(m.cpu.csr.mcause, 15, "store page fault");
+        assert_eq!(m.cpu.csr.mtval, 0x4000_2000);
+        assert_eq!(m.cpu.pc, 0x8000_0100);
+        assert_eq!(m.mem.read_u16_phys(0x8000_1ffe).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_fetch_denied_from_non_executable_region() {
+        let data = Perms {
+            read: true,
+            write: true,
+            execute: false,
+        };
+        let mut m = Machine::new(0x10000);
+        m.mem.regions.push(Region {
+            start: 0x8000_1000,
+            end: 0x8000_2000,
+            perms: data,
+        });
+        m.mem.write_u32_phys(0x8000_0000, 0x0000_0013).unwrap(); // nop
+        m.mem.write_u32_phys(0x8000_1000, 0x0000_0013).unwrap(); // nop
+        m.cpu.csr.mtvec = 0x8000_0100;
+
+        // Outside the region: unrestricted
+        m.cpu.pc = 0x8000_0000;
+        m.step().unwrap();
+        assert_eq!(m.cpu.pc, 0x8000_0004);
+
+        // Inside it: access fault, even in bare mode
+        m.cpu.pc = 0x8000_1000;
+        m.step().unwrap();
+        assert_eq!(m.cpu.csr.mcause, 1);
+        assert_eq!(m.cpu.csr.mtval, 0x8000_1000);
+        assert_eq!(m.cpu.pc, 0x8000_0100);
+    }
+
+    #[test]
+    fn test_pmp_denies_supervisor_load_but_not_machine() {
+        let mut m = Machine::new(0x10000);
+        m.mem.write_u32_phys(0x8000_0000, 0x0002_b283).unwrap(); // ld t0, 0(t0)
+        m.cpu.csr.mtvec = 0x8000_0100;
+        // Entry 0: TOR over [0, 0x8000_8000) with X only; entry 1: NAPOT over
+        // everything with RWX, so only the low part of RAM refuses loads
+        m.cpu.csr.write(0x3B0, 0x8000_8000 >> 2).unwrap();
+        m.cpu.csr.write(0x3B1, u64::MAX >> 10).unwrap();
+        m.cpu.csr.write(0x3A0, 0x1f0c).unwrap();
+
+        m.cpu.regs[5] = 0x8000_1000;
+        m.cpu.pc = 0x8000_0000;
+        m.step().unwrap();
+        assert_eq!(m.cpu.pc, 0x8000_0004);
+
+        m.cpu.csr.priv_mode = PrivMode::Supervisor;
+        m.cpu.regs[5] = 0x8000_1000;
+        m.cpu.pc = 0x8000_0000;
+        m.step().unwrap();
+        assert_eq!(m.cpu.csr.mcause, 5);
+        assert_eq!(m.cpu.csr.mtval, 0x8000_1000);
+    }
+
+    #[test]
+    fn test_take_trap_delegates_exception_to_supervisor() {
+        let mut m = Machine::new(0x10000);
+        m.cpu.pc = 0x8000_0040;
+        m.cpu.csr.priv_mode = PrivMode::User;
+        m.cpu.csr.medeleg = 1 << 13; // load page fault
+        m.cpu.csr.stvec = 0x8000_0200;
+        m.cpu.csr.mtvec = 0x8000_0100;
+        m.cpu.csr.mstatus |= 1 << 1; // SIE
+
+        assert!(m.cpu.take_trap(13, 0xdead_b000, false));
+
+        assert_eq!(m.cpu.pc, 0x8000_0200);
+        assert_eq!(m.cpu.csr.priv_mode, PrivMode::Supervisor);
+        assert_eq!(m.cpu.csr.sepc, 0x8000_0040);
+        assert_eq!(m.cpu.csr.scause, 13);
+        assert_eq!(m.cpu.csr.stval, 0xdead_b000);
+        assert_eq!(m.cpu.csr.spp(), PrivMode::User);
+        assert_eq!(m.cpu.csr.mstatus & (1 << 1), 0, "SIE cleared");
+        assert_ne!(m.cpu.csr.mstatus & (1 << 5), 0, "SPIE holds old SIE");
+        assert_eq!(m.cpu.csr.mcause, 0, "M-mode CSRs untouched");
+    }
+
+    #[test]
+    fn test_pending_timer_interrupt_vectors_to_mtvec() {
+        let mut m = Machine::new(0x10000);
+        m.cpu.pc = 0x8000_0000;
+        m.cpu.csr.mtvec = 0x8000_0100;
+        m.cpu.csr.mstatus |= 1 << 3; // MIE
+        m.cpu.csr.mie |= 1 << 7; // MTIE
+        m.mem.clint.mtimecmp = 0;
+
+        m.step().expect("interrupt should vector to mtvec");
+
+        assert_eq!(m.cpu.pc, 0x8000_0100);
+        assert_eq!(m.cpu.csr.mcause, 0x8000_0000_0000_0007);
+        assert_eq!(m.cpu.csr.mepc, 0x8000_0000, "mepc is the interrupted pc");
+        assert_eq!(m.cpu.csr.mstatus & (1 << 3), 0, "MIE cleared on entry");
+    }
+
+    #[test]
+    fn test_injected_external_interrupt_is_taken_until_cleared() {
+        let mut m = Machine::new(0x10000);
+        m.cpu.csr.mtvec = 0x8000_0100;
+        m.cpu.csr.mie |= 1 << 11; // MEIE
+        for addr in [0x8000_0000, 0x8000_0100] {
+            m.mem.write_u32_phys(addr, 0x0000_0013).unwrap(); // nop
+        }
+        m.raise_interrupt(InterruptLine::Meip);
+
+        // Held through the PLIC update, though no source is pending there
+        m.step().unwrap();
+        assert_eq!(m.cpu.pc, 0x8000_0004, "masked while mstatus.MIE is clear");
+        assert_ne!(m.cpu.csr.mip & (1 << 11), 0);
+
+        m.cpu.csr.mstatus |= 1 << 3; // MIE
+        m.step().expect("interrupt should vector to mtvec");
+        assert_eq!(m.cpu.pc, 0x8000_0100);
+        assert_eq!(m.cpu.csr.mcause, 0x8000_0000_0000_000b);
+        assert_eq!(m.cpu.csr.mepc, 0x8000_0004);
+
+        m.clear_interrupt(InterruptLine::Meip);
+        m.cpu.csr.mstatus |= 1 << 3;
+        m.step().unwrap();
+        assert_eq!(m.cpu.csr.mip & (1 << 11), 0);
+        assert_eq!(m.cpu.pc, 0x8000_0104);
+    }
+
+    #[test]
+    fn test_trap_stats_count_taken_traps_by_cause() {
+        use crate::cpu::trap::causes;
+        use crate::debug::trap_stats::TrapStats;
+
+        let mut m = Machine::new(0x10000);
+        m.trap_stats = Some(TrapStats::new());
+        m.cpu.csr.mtvec = 0x8000_0100;
+        m.mem.write_u32_phys(0x8000_0000, 0xc000_1073).unwrap(); // unimp
+        m.mem.write_u32_phys(0x8000_0100, 0x0000_0013).unwrap(); // nop
+        m.step().unwrap();
+        m.cpu.csr.mstatus |= 1 << 3; // MIE
+        m.cpu.csr.mie |= 1 << 7; // MTIE
+        m.mem.clint.mtimecmp = 0;
+        m.step().unwrap();
+
+        let stats = m.trap_stats.as_ref().unwrap();
+        assert_eq!(stats.count(causes::ILLEGAL_INSTRUCTION, false), 1);
+        assert_eq!(stats.count(causes::MTI, true), 1);
+
+        // A run's totals survive the guest resetting the hart
+        m.reset();
+        assert_eq!(m.trap_stats.as_ref().unwrap().total(), 2);
+    }
+
+    #[test]
+    fn test_halt_on_trap_stops_before_the_handler_runs() {
+        let mut m = Machine::new(0x10000);
+        m.halt_on_trap = 1 << 2; // illegal instruction
+        m.cpu.csr.mtvec = 0x8000_0100;
+        m.mem.write_u32_phys(0x8000_0000, 0x0000_0073).unwrap(); // ecall
+        m.mem.write_u32_phys(0x8000_0100, 0x3410_2373).unwrap(); // csrr t1, mepc
+        m.mem.write_u32_phys(0x8000_0104, 0x0043_0313).unwrap(); // addi t1, t1, 4
+        m.mem.write_u32_phys(0x8000_0108, 0x3413_1073).unwrap(); // csrw mepc, t1
+        m.mem.write_u32_phys(0x8000_010c, 0x3020_0073).unwrap(); // mret
+        m.mem.write_u32_phys(0x8000_0004, 0xc000_1073).unwrap(); // unimp
+
+        // Other causes go to the guest as usual
+        assert_eq!(
+            m.run(100),
+            StepOutcome::Halted(HaltReason::TrapHalt {
+                cause: 2,
+                pc: 0x8000_0004
+            })
+        );
+        assert_eq!(m.cpu.csr.mcause, 11, "only the ECALL was taken");
+        assert_eq!(m.cpu.pc, 0x8000_0004);
+        assert_eq!(m.executed, 5);
+
+        // Stepping on delivers the held trap, once
+        m.step().unwrap();
+        assert_eq!(m.cpu.pc, 0x8000_0100);
+        assert_eq!(m.cpu.csr.mcause, 2);
+        assert_eq!(m.cpu.csr.mepc, 0x8000_0004);
+        assert_eq!(m.executed, 6);
+        m.step().unwrap();
+        assert_eq!(m.cpu.reg(6), 0x8000_0004);
+    }
+
+    #[test]
+    fn test_vectored_tvec_offsets_interrupts_only() {
+        let mut m = Machine::new(0x10000);
+        m.cpu.pc = 0x8000_0000;
+        m.cpu.csr.write(0x305, 0x8000_0100 | 1).unwrap(); // vectored
+        m.cpu.csr.mstatus |= 1 << 3; // MIE
+        m.cpu.csr.mie |= 1 << 7; // MTIE
+        m.mem.clint.mtimecmp = 0;
+
+        m.step().unwrap();
+        assert_eq!(m.cpu.pc, 0x8000_0100 + 4 * 7);
+        assert_eq!(m.cpu.csr.mcause, 0x8000_0000_0000_0007);
+
+        // Exceptions still go to BASE
+        m.cpu.pc = 0x8000_0000;
+        assert!(m.cpu.take_trap(2, 0, false));
+        assert_eq!(m.cpu.pc, 0x8000_0100);
+
+        // Same for stvec on a delegated interrupt
+        m.cpu.csr.priv_mode = PrivMode::Supervisor;
+        m.cpu.csr.mideleg = 1 << 5; // STI
+        m.cpu.csr.write(0x105, 0x8000_0200 | 1).unwrap();
+        assert!(m.cpu.take_trap(5, 0, true));
+        assert_eq!(m.cpu.pc, 0x8000_0200 + 4 * 5);
+        assert_eq!(m.cpu.csr.scause, 0x8000_0000_0000_0005);
+    }
+
+    #[test]
+    fn test_clint_mtimecmp_store_raises_timer_interrupt() {
+        let mut m = Machine::new(0x10000);
+        // li t0, 0x02004000 ; sd x0, 0(t0)  -- mtimecmp = 0
+        m.mem.write_u32_phys(0x8000_0000, 0x0200_42b7).unwrap(); // lui t0, 0x2004
+        m.mem.write_u32_phys(0x8000_0004, 0x0002_b023).unwrap(); // sd x0, 0(t0)
+        m.mem.write_u32_phys(0x8000_0008, 0x0000_0013).unwrap(); // nop
+        m.cpu.pc = 0x8000_0000;
+        m.cpu.csr.mtvec = 0x8000_0100;
+        m.cpu.csr.mie |= 1 << 7; // MTIE
+        m.cpu.csr.mstatus |= 1 << 3; // MIE
+
+        m.step().unwrap();
+        m.step().unwrap();
+        assert_eq!(m.mem.clint.mtimecmp, 0);
+        assert_eq!(m.cpu.pc, 0x8000_0008, "store itself must not trap");
+
+        m.step().unwrap();
+        assert_eq!(m.cpu.pc, 0x8000_0100, "MTIP taken on the next step");
+        assert_eq!(m.cpu.csr.mcause, 0x8000_0000_0000_0007);
+    }
+
+    #[test]
+    fn test_time_csr_reads_clint_mtime() {
+        let mut m = Machine::new(0x1000);
+        let program = [
+            0xc010_2573, // csrr a0, time
+            0x0200_c2b7, // lui t0, 0x200c
+            0xff82_8293, // addi t0, t0, -8  (mtime)
+            0x3e80_0313, // li t1, 1000
+            0x0062_b023, // sd t1, 0(t0)
+            0xc010_25f3, // csrr a1, time
+            0xc015_1073, // csrw time, a0
+        ];
+        for (i, inst) in program.iter().enumerate() {
+            m.mem
+                .write_u32_phys(0x8000_0000 + 4 * i as u64, *inst)
+                .unwrap();
+        }
+        m.cpu.csr.mtvec = 0x8000_0100;
+
+        for _ in 0..6 {
+            m.step().unwrap();
+        }
+        // mtime ticks at the start of each step
+        assert_eq!(m.cpu.a0(), 1);
+        assert_eq!(m.cpu.a1(), 1001, "a store to mtime shows up in time");
+
+        m.step().unwrap();
+        assert_eq!(m.cpu.csr.mcause, 2, "time is read-only");
+        assert_eq!(m.mem.clint.mtime, 1002);
+    }
+
+    #[test]
+    fn test_clint_msip_raises_software_interrupt() {
+        let mut m = Machine::new(0x10000);
+        m.cpu.pc = 0x8000_0000;
+        m.cpu.csr.mtvec = 0x8000_0100;
+        m.cpu.csr.mie |= 1 << 3; // MSIE
+        m.cpu.csr.mstatus |= 1 << 3; // MIE
+        m.mem.write_u32_phys(0x0200_0000, 1).unwrap();
+
+        m.step().unwrap();
+        assert_eq!(m.cpu.pc, 0x8000_0100);
+        assert_eq!(m.cpu.csr.mcause, 0x8000_0000_0000_0003);
+    }
+
+    #[test]
+    fn test_reset_restores_power_on_state() {
+        let mut m = Machine::new(0x10000);
+        assert_eq!(m.cpu.pc, 0x8000_0000, "pc starts at the reset vector");
+
+        let program = [
+            0x02a0_0293u32, // addi t0, x0, 42
+            0x3402_9073,    // csrw mscratch, t0
+            0x0200_4337,    // lui t1, 0x2004
+            0x0003_3023,    // sd x0, 0(t1)   -- mtimecmp = 0
+        ];
+        for (i, inst) in program.iter().enumerate() {
+            m.mem
+                .write_u32_phys(0x8000_0000 + 4 * i as u64, *inst)
+                .unwrap();
+        }
+        for _ in 0..4 {
+            m.step().unwrap();
+        }
+        m.cpu.f[3] = 1.5f64.to_bits();
+        m.cpu.csr.priv_mode = PrivMode::Supervisor;
+        m.cpu.csr.mstatus |= 1 << 1;
+        assert_eq!(m.cpu.regs[5], 42);
+        assert_eq!(m.cpu.csr.mscratch, 42);
+        assert_eq!(m.mem.clint.mtimecmp, 0);
+
+        m.reset_vector = 0x8000_0004;
+        m.reset();
+        assert_eq!(m.cpu.pc, 0x8000_0004);
+        assert_eq!(m.cpu.regs, [0; 32]);
+        assert_eq!(m.cpu.f, [0; 32]);
+        assert_eq!(m.cpu.csr.priv_mode, PrivMode::Machine);
+        assert_eq!((m.cpu.csr.mstatus, m.cpu.csr.mscratch), (0, 0));
+        assert_eq!(m.cpu.csr.misa, crate::csr::CsrFile::MISA);
+        assert_eq!(m.mem.clint.mtimecmp, u64::MAX);
+        assert_eq!(m.executed, 4, "the count runs on across resets");
+        // RAM survives, so the program reruns from the new vector
+        m.step().unwrap();
+        assert_eq!(m.cpu.csr.mscratch, 0, "csrw of the cleared t0");
+        assert_eq!(m.cpu.pc, 0x8000_0008);
+
+        // A loader's stack pointer is set up again
+        m.reset_sp = Some(0x8000_8000);
+        m.reset();
+        assert_eq!(m.cpu.reg(2), 0x8000_8000);
+    }
+
+    #[test]
+    fn test_uart_interrupt_routes_through_plic() {
+        let mut m = Machine::new(0x10000);
+        let (tx, rx) = std::sync::mpsc::channel();
+        m.mem.uart.attach_input(rx);
+        m.mem.write_u32_phys(0x8000_0000, 0x0000_0013).unwrap(); // nop
+        m.cpu.pc = 0x8000_0000;
+        m.cpu.csr.mtvec = 0x8000_0100;
+        m.cpu.csr.mie |= 1 << 11; // MEIE
+        m.cpu.csr.mstatus |= 1 << 3; // MIE
+
+        let plic = crate::plic::PLIC_BASE;
+        m.mem.write_u32_phys(plic + 4 * 10, 1).unwrap(); // UART priority
+        m.mem.write_u32_phys(plic + 0x2000, 1 << 10).unwrap(); // enable for context 0
+        m.mem.write_u32_phys(0x1000_0001, 1).unwrap(); // UART IER: rx available
+
+        m.step().unwrap();
+        assert_eq!(m.cpu.pc, 0x8000_0004, "no input yet");
+
+        tx.send(b'k').unwrap();
+        m.step().unwrap();
+        assert_eq!(m.cpu.pc, 0x8000_0100);
+        assert_eq!(m.cpu.csr.mcause, 0x8000_0000_0000_000b);
+
+        // The handler claims the source, drains the UART and completes
+        let claim = plic + 0x20_0004;
+        assert_eq!(m.mem.read_u32_phys(claim).unwrap(), 10);
+        assert_eq!(m.mem.read_u32_phys(0x1000_0000).unwrap() as u8, b'k');
+        m.mem.write_u32_phys(claim, 10).unwrap();
+        m.cpu.csr.mstatus |= 1 << 3;
+        m.mem.write_u32_phys(0x8000_0100, 0x0000_0013).unwrap(); // nop
+        m.step().unwrap();
+        assert_eq!(m.cpu.pc, 0x8000_0104, "line dropped, no new interrupt");
+        assert_eq!(m.cpu.csr.mip & (1 << 11), 0);
+    }
+
+    #[test]
+    fn test_wfi_stalls_until_timer_fires() {
+        for global_mie in [true, false] {
+            let mut m = Machine::new(0x10000);
+            m.mem.write_u32_phys(0x8000_0000, 0x1050_0073).unwrap(); // wfi
+            m.mem.write_u32_phys(0x8000_0004, 0x0000_0013).unwrap(); // nop
+            m.cpu.pc = 0x8000_0000;
+            m.cpu.csr.mtvec = 0x8000_0100;
+            m.cpu.csr.mie |= 1 << 7; // MTIE
+            if global_mie {
+                m.cpu.csr.mstatus |= 1 << 3;
+            }
+            m.mem.clint.mtimecmp = 5;
+
+            m.step().unwrap();
+            assert!(m.cpu.wfi);
+            for _ in 0..3 {
+                m.step().unwrap();
+                assert_eq!(m.cpu.pc, 0x8000_0004, "stalled steps don't fetch");
+            }
+            m.step().unwrap();
+            assert!(!m.cpu.wfi);
+            if global_mie {
+                assert_eq!(m.cpu.pc, 0x8000_0100);
+                assert_eq!(m.cpu.csr.mepc, 0x8000_0004, "epc is past the wfi");
+            } else {
+                // Masked globally: the hart wakes and carries on with the nop
+                assert_eq!(m.cpu.pc, 0x8000_0008);
+            }
+        }
+    }
+
+    #[test]
+    fn test_sleep_on_wfi_skips_idle_steps() {
+        let mut m = Machine::new(0x10000);
+        m.mem.write_u32_phys(0x8000_0000, 0x1050_0073).unwrap(); // wfi
+        m.cpu.pc = 0x8000_0000;
+        m.cpu.csr.mtvec = 0x8000_0100;
+        m.cpu.csr.mie |= 1 << 7; // MTIE
+        m.cpu.csr.mstatus |= 1 << 3;
+        m.mem.clint.mtimecmp = 20_000; // 2 ms
+        m.sleep_on_wfi = true;
+
+        let start = Instant::now();
+        let mut steps = 0;
+        while m.cpu.pc != 0x8000_0100 {
+            assert_eq!(m.run(1), StepOutcome::Continued);
+            steps += 1;
+            assert!(steps < 1000, "idle steps should be skipped by sleeping");
+        }
+        assert!(start.elapsed() >= Duration::from_millis(1));
+        assert_eq!(m.mem.clint.mtime, 20_000, "the interrupt is taken on time");
+    }
+
+    #[test]
+    fn test_mixed_compressed_and_full_width_fetch() {
+        let mut m = Machine::new(0x10000);
+        let base = 0x8000_0000u64;
+        m.mem.write_u16_phys(base, 0x4515).unwrap(); // c.li a0, 5
+        m.mem.write_u32_phys(base + 2, 0x0015_0513).unwrap(); // addi a0, a0, 1
+        m.mem.write_u16_phys(base + 6, 0x9582).unwrap(); // c.jalr a1
+        m.cpu.pc = base;
+        m.cpu.regs[11] = base + 0x100;
+
+        m.step().unwrap();
+        assert_eq!(m.cpu.pc, base + 2);
+        m.step().unwrap();
+        assert_eq!(
+            m.cpu.pc,
+            base + 6,
+            "a 4-byte instruction may sit at pc % 4 == 2"
+        );
+        assert_eq!(m.cpu.regs[10], 6);
+        m.step().unwrap();
+        assert_eq!(m.cpu.pc, base + 0x100);
+        assert_eq!(
+            m.cpu.regs[1],
+            base + 8,
+            "link is pc + 2 for a compressed jump"
+        );
+    }
 }
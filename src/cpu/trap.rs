@@ -27,7 +27,67 @@ pub mod causes {
     pub const MEI: u64 = 11;
 }
 
-#[derive(Error, Debug)]
+/// The privileged spec's name for a trap cause.
+pub fn cause_name(cause: u64, is_interrupt: bool) -> String {
+    let name = if is_interrupt {
+        match cause {
+            causes::SSI => "supervisor software interrupt",
+            causes::MSI => "machine software interrupt",
+            causes::STI => "supervisor timer interrupt",
+            causes::MTI => "machine timer interrupt",
+            causes::SEI => "supervisor external interrupt",
+            causes::MEI => "machine external interrupt",
+            _ => return format!("interrupt {}", cause),
+        }
+    } else {
+        match cause {
+            causes::INSTRUCTION_ADDRESS_MISALIGNED => "instruction address misaligned",
+            causes::INSTRUCTION_ACCESS_FAULT => "instruction access fault",
+            causes::ILLEGAL_INSTRUCTION => "illegal instruction",
+            causes::BREAKPOINT => "breakpoint",
+            causes::LOAD_ADDRESS_MISALIGNED => "load address misaligned",
+            causes::LOAD_ACCESS_FAULT => "load access fault",
+            causes::STORE_ADDRESS_MISALIGNED => "store/AMO address misaligned",
+            causes::STORE_ACCESS_FAULT => "store/AMO access fault",
+            causes::ECALL_U => "environment call from U-mode",
+            causes::ECALL_S => "environment call from S-mode",
+            causes::ECALL_M => "environment call from M-mode",
+            causes::INSTRUCTION_PAGE_FAULT => "instruction page fault",
+            causes::LOAD_PAGE_FAULT => "load page fault",
+            causes::STORE_PAGE_FAULT => "store/AMO page fault",
+            _ => return format!("exception {}", cause),
+        }
+    };
+    name.to_string()
+}
+
+/// An interrupt line into the hart, by its bit in mip
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InterruptLine {
+    Ssip,
+    Msip,
+    Stip,
+    Mtip,
+    Seip,
+    Meip,
+}
+
+impl InterruptLine {
+    /// The line's mip bit, which is also its interrupt cause code
+    pub fn mip_bit(self) -> u64 {
+        let cause = match self {
+            InterruptLine::Ssip => causes::SSI,
+            InterruptLine::Msip => causes::MSI,
+            InterruptLine::Stip => causes::STI,
+            InterruptLine::Mtip => causes::MTI,
+            InterruptLine::Seip => causes::SEI,
+            InterruptLine::Meip => causes::MEI,
+        };
+        1 << cause
+    }
+}
+
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Trap {
     #[error("illegal instruction at pc=0x{pc:x} inst=0x{inst:08x}")]
     IllegalInstruction { pc: u64, inst: u32 },
@@ -38,6 +98,18 @@ pub enum Trap {
     #[error("breakpoint at pc=0x{pc:x}")]
     Breakpoint { pc: u64 },
 
+    #[error("instruction address misaligned at pc=0x{pc:x}, target=0x{addr:x}")]
+    InstructionMisaligned { pc: u64, addr: u64 },
+
+    #[error("instruction access fault at pc=0x{pc:x}, addr=0x{addr:x}")]
+    InstructionAccessFault { pc: u64, addr: u64 },
+
+    #[error("load access fault at pc=0x{pc:x}, addr=0x{addr:x}")]
+    LoadAccessFault { pc: u64, addr: u64 },
+
+    #[error("store access fault at pc=0x{pc:x}, addr=0x{addr:x}")]
+    StoreAccessFault { pc: u64, addr: u64 },
+
     #[error("load address misaligned at pc=0x{pc:x}, addr=0x{addr:x}")]
     LoadMisaligned { pc: u64, addr: u64 },
 
@@ -89,6 +161,10 @@ impl Trap {
             // Exceptions (no interrupt bit)
             Trap::IllegalInstruction { .. } => causes::ILLEGAL_INSTRUCTION,
             Trap::Breakpoint { .. } => causes::BREAKPOINT,
+            Trap::InstructionMisaligned { .. } => causes::INSTRUCTION_ADDRESS_MISALIGNED,
+            Trap::InstructionAccessFault { .. } => causes::INSTRUCTION_ACCESS_FAULT,
+            Trap::LoadAccessFault { .. } => causes::LOAD_ACCESS_FAULT,
+            Trap::StoreAccessFault { .. } => causes::STORE_ACCESS_FAULT,
             Trap::LoadMisaligned { .. } => causes::LOAD_ADDRESS_MISALIGNED,
             Trap::StoreMisaligned { .. } => causes::STORE_ADDRESS_MISALIGNED,
             Trap::EcallFromS { .. } => causes::ECALL_S,
@@ -97,7 +173,17 @@ impl Trap {
             Trap::InstructionPageFault { .. } => causes::INSTRUCTION_PAGE_FAULT,
             Trap::LoadPageFault { .. } => causes::LOAD_PAGE_FAULT,
             Trap::StorePageFault { .. } => causes::STORE_PAGE_FAULT,
-            Trap::Mem { .. } => causes::LOAD_ACCESS_FAULT, // Load/store access fault
+            Trap::Mem { err, .. } => match err {
+                MemError::InstructionPageFault(_) => causes::INSTRUCTION_PAGE_FAULT,
+                MemError::LoadPageFault(_) => causes::LOAD_PAGE_FAULT,
+                MemError::StorePageFault(_) => causes::STORE_PAGE_FAULT,
+                MemError::InstructionAccessFault(_) => causes::INSTRUCTION_ACCESS_FAULT,
+                MemError::StoreAccessFault(_) => causes::STORE_ACCESS_FAULT,
+                MemError::LoadMisaligned(_) => causes::LOAD_ADDRESS_MISALIGNED,
+                MemError::StoreMisaligned(_) => causes::STORE_ADDRESS_MISALIGNED,
+                // An access nothing claims; the direction is lost by now
+                MemError::LoadAccessFault(_) | MemError::Oob(_) => causes::LOAD_ACCESS_FAULT,
+            },
 
             // Interrupts (cause without interrupt bit)
             Trap::SupervisorSoftwareInterrupt { .. } => causes::SSI,
@@ -126,12 +212,17 @@ impl Trap {
     pub fn tval(&self) -> u64 {
         match self {
             Trap::IllegalInstruction { inst, .. } => *inst as u64,
+            Trap::Breakpoint { pc } => *pc,
+            Trap::InstructionMisaligned { addr, .. } => *addr,
+            Trap::InstructionAccessFault { addr, .. } => *addr,
+            Trap::LoadAccessFault { addr, .. } => *addr,
+            Trap::StoreAccessFault { addr, .. } => *addr,
             Trap::LoadMisaligned { addr, .. } => *addr,
             Trap::StoreMisaligned { addr, .. } => *addr,
             Trap::InstructionPageFault { addr, .. } => *addr,
             Trap::LoadPageFault { addr, .. } => *addr,
             Trap::StorePageFault { addr, .. } => *addr,
-            Trap::Mem { .. } => 0, // Could extract fault address if needed
+            Trap::Mem { err, .. } => err.addr(),
             _ => 0,
         }
     }
@@ -142,6 +233,10 @@ impl Trap {
             Trap::IllegalInstruction { pc, .. } => *pc,
             Trap::Mem { pc, .. } => *pc,
             Trap::Breakpoint { pc } => *pc,
+            Trap::InstructionMisaligned { pc, .. } => *pc,
+            Trap::InstructionAccessFault { pc, .. } => *pc,
+            Trap::LoadAccessFault { pc, .. } => *pc,
+            Trap::StoreAccessFault { pc, .. } => *pc,
             Trap::LoadMisaligned { pc, .. } => *pc,
             Trap::StoreMisaligned { pc, .. } => *pc,
             Trap::Ecall { pc } => *pc,
@@ -182,6 +277,11 @@ impl<T> WithPc<T> for Result<T, MemError> {
             MemError::InstructionPageFault(addr) => Trap::InstructionPageFault { pc, addr },
             MemError::LoadPageFault(addr) => Trap::LoadPageFault { pc, addr },
             MemError::StorePageFault(addr) => Trap::StorePageFault { pc, addr },
+            MemError::InstructionAccessFault(addr) => Trap::InstructionAccessFault { pc, addr },
+            MemError::LoadAccessFault(addr) => Trap::LoadAccessFault { pc, addr },
+            MemError::StoreAccessFault(addr) => Trap::StoreAccessFault { pc, addr },
+            MemError::LoadMisaligned(addr) => Trap::LoadMisaligned { pc, addr },
+            MemError::StoreMisaligned(addr) => Trap::StoreMisaligned { pc, addr },
             _ => Trap::Mem { pc, err },
         })
     }
@@ -205,6 +305,59 @@ impl<T> WithPc<T> for Result<T, crate::cpu::decode::DecodeError> {
             crate::cpu::decode::DecodeError::InvalidFunct { inst } => {
                 Trap::IllegalInstruction { pc, inst }
             }
+            crate::cpu::decode::DecodeError::Reserved { inst } => {
+                Trap::IllegalInstruction { pc, inst }
+            }
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cause_and_tval_follow_the_spec() {
+        let cases = [
+            (
+                Trap::IllegalInstruction {
+                    pc: 4,
+                    inst: 0xdead,
+                },
+                2,
+                0xdead,
+            ),
+            (Trap::Breakpoint { pc: 8 }, 3, 8),
+            (Trap::EcallFromS { pc: 8 }, 9, 0),
+            (
+                Trap::StorePageFault {
+                    pc: 0,
+                    addr: 0x1234,
+                },
+                15,
+                0x1234,
+            ),
+            (Trap::MachineTimerInterrupt { pc: 0 }, 7, 0),
+            (
+                Trap::Mem {
+                    pc: 0,
+                    err: MemError::StoreAccessFault(0x40),
+                },
+                7,
+                0x40,
+            ),
+            (
+                Trap::Mem {
+                    pc: 0,
+                    err: MemError::Oob(0x50),
+                },
+                5,
+                0x50,
+            ),
+        ];
+        for (trap, cause, tval) in cases {
+            assert_eq!((trap.cause(), trap.tval()), (cause, tval), "{:?}", trap);
+        }
+        assert!(Trap::MachineTimerInterrupt { pc: 0 }.is_interrupt());
+    }
+}
@@ -1,10 +1,17 @@
 use std::fmt;
 
+use crate::pmp::Pmp;
+use crate::snapshot::{Reader, SnapshotError, Writer};
+
 #[derive(Debug, Clone)]
 pub enum CsrError {
     UnsupportedRead(u16),
     UnsupportedWrite(u16),
     PrivilegeViolation(u16),
+    ReadOnly(u16),
+    FpuDisabled(u16),
+    CounterDisabled(u16),
+    VmTrapped(u16),
 }
 
 impl fmt::Display for CsrError {
@@ -15,24 +22,101 @@ impl fmt::Display for CsrError {
             CsrError::PrivilegeViolation(csr) => {
                 write!(f, "privilege violation accessing CSR: 0x{:03x}", csr)
             }
+            CsrError::ReadOnly(csr) => write!(f, "write to read-only CSR: 0x{:03x}", csr),
+            CsrError::FpuDisabled(csr) => {
+                write!(f, "float CSR 0x{:03x} accessed with mstatus.FS off", csr)
+            }
+            CsrError::CounterDisabled(csr) => {
+                write!(
+                    f,
+                    "counter CSR 0x{:03x} disabled by mcounteren/scounteren",
+                    csr
+                )
+            }
+            CsrError::VmTrapped(csr) => {
+                write!(
+                    f,
+                    "CSR 0x{:03x} accessed from S-mode with mstatus.TVM set",
+                    csr
+                )
+            }
         }
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IsaError {
+    /// Not "rv64" followed by the base I (or G)
+    Base(String),
+    Unsupported(char),
+    UnsupportedMulti(String),
+    DWithoutF,
+}
+
+impl fmt::Display for IsaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IsaError::Base(isa) => {
+                write!(f, "ISA string '{}' must start with rv64i or rv64g", isa)
+            }
+            IsaError::Unsupported(ext) => write!(f, "extension '{}' is not supported", ext),
+            IsaError::UnsupportedMulti(ext) => {
+                write!(f, "extension '{}' is not supported", ext)
+            }
+            IsaError::DWithoutF => write!(f, "the D extension requires F"),
+        }
+    }
+}
+
+impl std::error::Error for IsaError {}
+
+/// Parse an ISA string such as "rv64gc" or "rv64imac_zicsr" into misa bits,
+/// with S- and U-mode present. G stands for IMAFD plus Zicsr and Zifencei,
+/// which are always implemented and may also be named after an underscore.
+/// Case is ignored.
+pub fn parse_isa(isa: &str) -> Result<u64, IsaError> {
+    let bit = |ext: u8| 1u64 << (ext - b'a');
+    let lower = isa.to_ascii_lowercase();
+    let rest = lower
+        .strip_prefix("rv64")
+        .ok_or_else(|| IsaError::Base(isa.to_string()))?;
+    let (letters, multi) = rest.split_once('_').unwrap_or((rest, ""));
+
+    let mut misa = (2 << 62) | bit(b's') | bit(b'u');
+    let mut letters = letters.bytes();
+    match letters.next() {
+        Some(b'i') => misa |= bit(b'i'),
+        Some(b'g') => misa |= bit(b'i') | bit(b'm') | bit(b'a') | bit(b'f') | bit(b'd'),
+        // RV64E has no misa encoding of its own here
+        Some(b'e') => return Err(IsaError::Unsupported('e')),
+        _ => return Err(IsaError::Base(isa.to_string())),
+    }
+    for ext in letters {
+        match ext {
+            b'm' | b'a' | b'f' | b'd' | b'c' => misa |= bit(ext),
+            _ => return Err(IsaError::Unsupported(ext as char)),
+        }
+    }
+    for ext in multi.split('_').filter(|ext| !ext.is_empty()) {
+        if ext != "zicsr" && ext != "zifencei" {
+            return Err(IsaError::UnsupportedMulti(ext.to_string()));
+        }
+    }
+    if misa & bit(b'd') != 0 && misa & bit(b'f') == 0 {
+        return Err(IsaError::DWithoutF);
+    }
+    Ok(misa)
+}
+
 /// Privilege modes
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
 pub enum PrivMode {
     User = 0,
     Supervisor = 1,
+    #[default]
     Machine = 3,
 }
 
-impl Default for PrivMode {
-    fn default() -> Self {
-        PrivMode::Machine
-    }
-}
-
 impl PrivMode {
     pub fn from_u64(val: u64) -> Option<Self> {
         match val {
@@ -44,12 +128,22 @@ impl PrivMode {
     }
 }
 
-#[derive(Default)]
+#[derive(Clone)]
 pub struct CsrFile {
     // Current privilege mode
     pub priv_mode: PrivMode,
 
+    /// Extensions the hart implements, laid out like misa. Writes to misa can
+    /// clear extensions but never set one outside this set. Copied from
+    /// `MachineConfig::isa`.
+    pub(crate) isa: u64,
+
+    /// Whether satp accepts Sv48 (mode 9) as well as Sv39. Copied from
+    /// `MachineConfig::sv48`.
+    pub(crate) sv48: bool,
+
     // Machine-mode CSRs
+    pub misa: u64,
     pub mstatus: u64,
     pub mtvec: u64,
     pub mepc: u64,
@@ -60,6 +154,7 @@ pub struct CsrFile {
     pub medeleg: u64,
     pub mideleg: u64,
     pub mscratch: u64,
+    pub mcounteren: u64,
 
     // Supervisor-mode CSRs
     pub stvec: u64,
@@ -68,19 +163,61 @@ pub struct CsrFile {
     pub stval: u64,
     pub sscratch: u64,
     pub satp: u64,
+    pub scounteren: u64,
+
+    // Floating-point control and status: frm in [7:5], fflags in [4:0]
+    pub fcsr: u64,
 
-    // Counters
+    // Counters: cycle counts steps (trapped ones included), instret retired
+    // instructions, and time is a read-only shadow of CLINT mtime that the
+    // machine refreshes every step
     pub cycle: u64,
+    pub instret: u64,
     pub time: u64,
 
-    // Physical Memory Protection (minimal support)
-    pmpaddr: [u64; 16],
-    pmpcfg: [u8; 16],
+    // Physical Memory Protection; the machine mirrors it into `Memory`, which
+    // enforces it
+    pub pmp: Pmp,
 
     // Hardware thread ID
     mhartid: u64,
 }
 
+impl Default for CsrFile {
+    fn default() -> Self {
+        Self {
+            priv_mode: PrivMode::default(),
+            isa: Self::MISA,
+            sv48: false,
+            misa: Self::MISA,
+            mstatus: 0,
+            mtvec: 0,
+            mepc: 0,
+            mcause: 0,
+            mtval: 0,
+            mie: 0,
+            mip: 0,
+            medeleg: 0,
+            mideleg: 0,
+            mscratch: 0,
+            mcounteren: 0,
+            stvec: 0,
+            sepc: 0,
+            scause: 0,
+            stval: 0,
+            sscratch: 0,
+            satp: 0,
+            scounteren: 0,
+            fcsr: 0,
+            cycle: 0,
+            instret: 0,
+            time: 0,
+            pmp: Pmp::default(),
+            mhartid: 0,
+        }
+    }
+}
+
 impl CsrFile {
     pub fn new() -> Self {
         let csr = Self::default();
@@ -98,12 +235,113 @@ impl CsrFile {
     const MSTATUS_SPIE: u64 = 1 << 5;
     const MSTATUS_MPP: u64 = 0b11 << 11;
     const MSTATUS_SPP: u64 = 1 << 8;
-    #[allow(dead_code)]
     const MSTATUS_MPRV: u64 = 1 << 17;
-    #[allow(dead_code)]
-    const MSTATUS_SUM: u64 = 1 << 18;
-    #[allow(dead_code)]
-    const MSTATUS_MXR: u64 = 1 << 19;
+    pub const MSTATUS_SUM: u64 = 1 << 18;
+    pub const MSTATUS_MXR: u64 = 1 << 19;
+    pub const MSTATUS_TVM: u64 = 1 << 20;
+    pub const MSTATUS_TW: u64 = 1 << 21;
+    pub const MSTATUS_TSR: u64 = 1 << 22;
+    pub const MSTATUS_FS: u64 = 0b11 << 13;
+    pub const MSTATUS_XS: u64 = 0b11 << 15;
+    pub const MSTATUS_SD: u64 = 1 << 63;
+    pub const MSTATUS_UBE: u64 = 1 << 6;
+    pub const MSTATUS_SBE: u64 = 1 << 36;
+    pub const MSTATUS_MBE: u64 = 1 << 37;
+
+    /// mtvec/stvec MODE field: 0 is direct, 1 vectored; 2 and 3 are reserved
+    pub const TVEC_MODE: u64 = 0b11;
+    pub const TVEC_VECTORED: u64 = 1;
+
+    /// mcounteren/scounteren bits for the counters that exist: CY, TM, IR
+    const COUNTEREN_WRITABLE: u64 = 0b111;
+
+    /// Reset value of misa: MXL=2 (RV64) with I, M, A, F, D, C plus S- and U-mode
+    /// (bit n is the extension letter 'A' + n)
+    pub const MISA: u64 = (2 << 62) | 0x0014_112d;
+
+    /// misa bits software may clear: M, A, F, D and C. MXL, I and the
+    /// privilege modes are fixed, and extensions outside `isa` stay absent.
+    const MISA_WRITABLE: u64 = 0x0000_102d;
+
+    /// Implement the extensions in `misa` (as returned by `parse_isa`), all
+    /// enabled.
+    pub fn set_isa(&mut self, misa: u64) {
+        self.isa = misa;
+        self.misa = misa;
+    }
+
+    /// Apply a write to misa. D depends on F, so clearing F clears D too.
+    fn legalize_misa(&self, value: u64) -> u64 {
+        let writable = Self::MISA_WRITABLE & self.isa;
+        let mut misa = (self.misa & !writable) | (value & writable);
+        if misa & (1 << (b'F' - b'A')) == 0 {
+            misa &= !(1 << (b'D' - b'A'));
+        }
+        misa
+    }
+
+    /// Whether the single-letter extension `ext` (e.g. `b'C'`) is present in misa.
+    pub fn ext_enabled(&self, ext: u8) -> bool {
+        self.misa & (1 << (ext - b'A')) != 0
+    }
+
+    /// ISA string for the extensions enabled in misa, in canonical order, as
+    /// a device tree's riscv,isa (e.g. "rv64imafdc_zicsr_zifencei").
+    pub fn isa_string(&self) -> String {
+        let letters: String = "IMAFDQC"
+            .bytes()
+            .filter(|&ext| self.ext_enabled(ext))
+            .map(|ext| ext.to_ascii_lowercase() as char)
+            .collect();
+        format!("rv64{}_zicsr_zifencei", letters)
+    }
+
+    /// The FPU is usable unless mstatus.FS is Off.
+    pub fn fpu_enabled(&self) -> bool {
+        self.mstatus & Self::MSTATUS_FS != 0
+    }
+
+    /// Record that the FP registers or fcsr changed: FS goes to Dirty, from
+    /// Initial or Clean. Only software moves it back.
+    pub fn mark_fs_dirty(&mut self) {
+        self.mstatus |= Self::MSTATUS_FS;
+    }
+
+    /// mepc/sepc as software (and xRET) reads them. Bit 0 is never stored;
+    /// bit 1 is, but reads as zero while C is disabled, so a trap return can't
+    /// land on a 2-byte boundary. Re-enabling C brings it back.
+    fn read_epc(&self, epc: u64) -> u64 {
+        if self.ext_enabled(b'C') {
+            epc
+        } else {
+            epc & !0b11
+        }
+    }
+
+    /// mstatus as software reads it. SD isn't stored; it summarises whether
+    /// FS or XS is Dirty.
+    fn read_mstatus(&self) -> u64 {
+        let dirty = self.mstatus & Self::MSTATUS_FS == Self::MSTATUS_FS
+            || self.mstatus & Self::MSTATUS_XS == Self::MSTATUS_XS;
+        if dirty {
+            self.mstatus | Self::MSTATUS_SD
+        } else {
+            self.mstatus
+        }
+    }
+
+    /// Dynamic rounding mode, fcsr.frm
+    pub fn frm(&self) -> u8 {
+        ((self.fcsr >> 5) & 0b111) as u8
+    }
+
+    /// Accrue exception flags into fcsr.fflags
+    pub fn set_fflags(&mut self, flags: u8) {
+        if flags & 0x1f != 0 {
+            self.fcsr |= (flags & 0x1f) as u64;
+            self.mark_fs_dirty();
+        }
+    }
 
     /// Extract MPP field from mstatus
     pub fn mpp(&self) -> PrivMode {
@@ -116,6 +354,16 @@ impl CsrFile {
         self.mstatus = (self.mstatus & !Self::MSTATUS_MPP) | ((mode as u64) << 11);
     }
 
+    /// Privilege that data loads and stores are translated at: MPP while
+    /// MPRV is set in M-mode, otherwise the current mode. Fetches ignore MPRV.
+    pub fn data_priv_mode(&self) -> PrivMode {
+        if self.priv_mode == PrivMode::Machine && self.mstatus & Self::MSTATUS_MPRV != 0 {
+            self.mpp()
+        } else {
+            self.priv_mode
+        }
+    }
+
     /// Extract SPP field from mstatus
     pub fn spp(&self) -> PrivMode {
         if (self.mstatus & Self::MSTATUS_SPP) != 0 {
@@ -155,6 +403,7 @@ impl CsrFile {
         // sstatus is a restricted view of mstatus
         const SSTATUS_MASK: u64 = (1 << 1) |  // SIE
             (1 << 5) |  // SPIE
+            (1 << 6) |  // UBE
             (1 << 8) |  // SPP
             (1 << 18) | // SUM
             (1 << 19) | // MXR
@@ -162,14 +411,16 @@ impl CsrFile {
             (0b11 << 15) | // XS
             (1 << 63) | // SD
             (0b1111 << 32); // UXL
-        self.mstatus & SSTATUS_MASK
+        self.read_mstatus() & SSTATUS_MASK
     }
 
     /// Write sstatus (update only writable bits of mstatus)
     fn write_sstatus(&mut self, value: u64) {
         const SSTATUS_WRITABLE: u64 = (1 << 1) |  // SIE
             (1 << 5) |  // SPIE
+            (1 << 6) |  // UBE
             (1 << 8) |  // SPP
+            (0b11 << 13) | // FS
             (1 << 18) | // SUM
             (1 << 19); // MXR
         self.mstatus = (self.mstatus & !SSTATUS_WRITABLE) | (value & SSTATUS_WRITABLE);
@@ -212,21 +463,62 @@ impl CsrFile {
         if (self.priv_mode as u64) < (required as u64) {
             return Err(CsrError::PrivilegeViolation(csr));
         }
+        // TVM lets M-mode intercept S-mode's address translation changes
+        if csr == 0x180
+            && self.priv_mode == PrivMode::Supervisor
+            && self.mstatus & Self::MSTATUS_TVM != 0
+        {
+            return Err(CsrError::VmTrapped(csr));
+        }
+        Ok(())
+    }
+
+    /// Reserved tvec modes aren't supported; they read back as direct.
+    fn legalize_tvec(value: u64) -> u64 {
+        if value & Self::TVEC_MODE > Self::TVEC_VECTORED {
+            value & !Self::TVEC_MODE
+        } else {
+            value
+        }
+    }
+
+    /// Below M-mode, the cycle/time/instret CSRs are readable only when the
+    /// matching mcounteren bit is set, and in U-mode also the scounteren bit.
+    fn check_counter_enabled(&self, csr: u16) -> Result<(), CsrError> {
+        let bit = 1 << (csr - 0xC00);
+        let enabled = match self.priv_mode {
+            PrivMode::Machine => true,
+            PrivMode::Supervisor => self.mcounteren & bit != 0,
+            PrivMode::User => self.mcounteren & self.scounteren & bit != 0,
+        };
+        if !enabled {
+            return Err(CsrError::CounterDisabled(csr));
+        }
         Ok(())
     }
 
     pub fn read(&self, csr: u16) -> Result<u64, CsrError> {
         self.check_csr_privilege(csr)?;
+        if (0xC00..=0xC02).contains(&csr) {
+            self.check_counter_enabled(csr)?;
+        }
 
         match csr {
+            // Floating-point control and status
+            0x001..=0x003 if !self.fpu_enabled() => Err(CsrError::FpuDisabled(csr)),
+            0x001 => Ok(self.fcsr & 0x1f),
+            0x002 => Ok(self.frm() as u64),
+            0x003 => Ok(self.fcsr),
+
             // Supervisor trap setup
             0x100 => Ok(self.sstatus()),
             0x104 => Ok(self.sie()),
             0x105 => Ok(self.stvec),
+            0x106 => Ok(self.scounteren),
 
             // Supervisor trap handling
             0x140 => Ok(self.sscratch),
-            0x141 => Ok(self.sepc),
+            0x141 => Ok(self.read_epc(self.sepc)),
             0x142 => Ok(self.scause),
             0x143 => Ok(self.stval),
             0x144 => Ok(self.sip()),
@@ -241,30 +533,32 @@ impl CsrFile {
             0xF14 => Ok(self.mhartid),
 
             // Machine trap setup
-            0x300 => Ok(self.mstatus),
-            0x301 => Ok(0x8000000000141101), // misa: RV64IMAC
+            0x300 => Ok(self.read_mstatus()),
+            0x301 => Ok(self.misa),
             0x302 => Ok(self.medeleg),
             0x303 => Ok(self.mideleg),
             0x304 => Ok(self.mie),
             0x305 => Ok(self.mtvec),
+            0x306 => Ok(self.mcounteren),
 
             // Machine trap handling
             0x340 => Ok(self.mscratch),
-            0x341 => Ok(self.mepc),
+            0x341 => Ok(self.read_epc(self.mepc)),
             0x342 => Ok(self.mcause),
             0x343 => Ok(self.mtval),
             0x344 => Ok(self.mip),
 
             // Machine counters/timers
-            0xB00 => Ok(self.cycle), // mcycle
-            0xB02 => Ok(self.time),  // minstret (use cycle for now)
-            0xC00 => Ok(self.cycle), // cycle
-            0xC01 => Ok(self.time),  // time
-            0xC02 => Ok(self.cycle), // instret
+            0xB00 => Ok(self.cycle),   // mcycle
+            0xB02 => Ok(self.instret), // minstret
+            0xC00 => Ok(self.cycle),   // cycle
+            0xC01 => Ok(self.time),    // time
+            0xC02 => Ok(self.instret), // instret
 
             // Physical memory protection
-            0x3A0 => Ok(self.pmpcfg[0] as u64),
-            0x3B0 => Ok(self.pmpaddr[0]),
+            // (RV64 has only the even pmpcfg CSRs, each packing 8 entries)
+            0x3A0 | 0x3A2 => Ok(self.pmp.cfg[(csr as usize - 0x3A0) / 2]),
+            0x3B0..=0x3BF => Ok(self.pmp.addr[csr as usize - 0x3B0]),
 
             _ => Err(CsrError::UnsupportedRead(csr)),
         }
@@ -275,10 +569,29 @@ impl CsrFile {
 
         // Check if CSR is read-only (top 2 bits == 0b11)
         if (csr >> 10) == 0b11 {
-            return Ok(()); // Silently ignore writes to read-only CSRs
+            return Err(CsrError::ReadOnly(csr));
         }
 
         match csr {
+            // Floating-point control and status
+            // fflags and frm are views of fcsr's low five bits and bits 7:5
+            0x001..=0x003 if !self.fpu_enabled() => Err(CsrError::FpuDisabled(csr)),
+            0x001 => {
+                self.fcsr = (self.fcsr & !0x1f) | (value & 0x1f);
+                self.mark_fs_dirty();
+                Ok(())
+            }
+            0x002 => {
+                self.fcsr = (self.fcsr & 0x1f) | ((value & 0b111) << 5);
+                self.mark_fs_dirty();
+                Ok(())
+            }
+            0x003 => {
+                self.fcsr = value & 0xff;
+                self.mark_fs_dirty();
+                Ok(())
+            }
+
             // Supervisor trap setup
             0x100 => {
                 self.write_sstatus(value);
@@ -289,7 +602,11 @@ impl CsrFile {
                 Ok(())
             }
             0x105 => {
-                self.stvec = value;
+                self.stvec = Self::legalize_tvec(value);
+                Ok(())
+            }
+            0x106 => {
+                self.scounteren = value & Self::COUNTEREN_WRITABLE;
                 Ok(())
             }
 
@@ -317,13 +634,17 @@ impl CsrFile {
 
             // Supervisor address translation
             0x180 => {
-                // Accept mode 0 (bare) or mode 8 (Sv39)
+                // Accept mode 0 (bare), mode 8 (Sv39) or, if enabled, mode 9 (Sv48)
                 let mode = value >> 60;
                 match mode {
                     0 | 8 => {
                         self.satp = value;
                         Ok(())
                     }
+                    9 if self.sv48 => {
+                        self.satp = value;
+                        Ok(())
+                    }
                     _ => Err(CsrError::UnsupportedWrite(csr)),
                 }
             }
@@ -334,15 +655,26 @@ impl CsrFile {
                 const MSTATUS_WRITABLE: u64 = (1 << 1) |  // SIE
                     (1 << 3) |  // MIE
                     (1 << 5) |  // SPIE
+                    (1 << 6) |  // UBE
                     (1 << 7) |  // MPIE
                     (1 << 8) |  // SPP
                     (0b11 << 11) | // MPP
+                    (0b11 << 13) | // FS
                     (1 << 17) | // MPRV
                     (1 << 18) | // SUM
-                    (1 << 19); // MXR
+                    (1 << 19) | // MXR
+                    (1 << 20) | // TVM
+                    (1 << 21) | // TW
+                    (1 << 22) | // TSR
+                    (1 << 36) | // SBE
+                    (1 << 37); // MBE
                 self.mstatus = (self.mstatus & !MSTATUS_WRITABLE) | (value & MSTATUS_WRITABLE);
                 Ok(())
             }
+            0x301 => {
+                self.misa = self.legalize_misa(value);
+                Ok(())
+            }
             0x302 => {
                 self.medeleg = value;
                 Ok(())
@@ -356,7 +688,11 @@ impl CsrFile {
                 Ok(())
             }
             0x305 => {
-                self.mtvec = value;
+                self.mtvec = Self::legalize_tvec(value);
+                Ok(())
+            }
+            0x306 => {
+                self.mcounteren = value & Self::COUNTEREN_WRITABLE;
                 Ok(())
             }
 
@@ -378,19 +714,19 @@ impl CsrFile {
                 Ok(())
             }
             0x344 => {
-                // mip - some bits writable by software
-                const MIP_WRITABLE: u64 = (1 << 1) | (1 << 3); // SSIP, MSIP
+                // mip - some bits writable by software; MSIP/MTIP mirror the CLINT
+                const MIP_WRITABLE: u64 = 1 << 1; // SSIP
                 self.mip = (self.mip & !MIP_WRITABLE) | (value & MIP_WRITABLE);
                 Ok(())
             }
 
             // Physical memory protection
-            0x3A0 => {
-                self.pmpcfg[0] = value as u8;
+            0x3A0 | 0x3A2 => {
+                self.pmp.write_cfg((csr as usize - 0x3A0) / 2, value);
                 Ok(())
             }
-            0x3B0 => {
-                self.pmpaddr[0] = value;
+            0x3B0..=0x3BF => {
+                self.pmp.write_addr(csr as usize - 0x3B0, value);
                 Ok(())
             }
 
@@ -408,6 +744,12 @@ impl CsrFile {
         self.write(csr, current & !mask)
     }
 
+    /// Whether any interrupt is both pending and enabled in mie, ignoring the
+    /// global enables. This is what wakes a hart stalled in WFI.
+    pub fn interrupt_pending_locally(&self) -> bool {
+        self.mip & self.mie != 0
+    }
+
     /// Check for pending and enabled interrupts, return highest priority interrupt cause if any
     pub fn check_pending_interrupt(&self) -> Option<u64> {
         // Calculate which interrupts are pending and enabled
@@ -417,37 +759,33 @@ impl CsrFile {
             return None;
         }
 
-        // Check if interrupts are globally enabled for current privilege level
-        let interrupts_enabled = match self.priv_mode {
+        // Interrupts not delegated by mideleg target M-mode: always taken below
+        // M-mode, and in M-mode only when MIE is set.
+        let m_enabled = match self.priv_mode {
             PrivMode::Machine => (self.mstatus & Self::MSTATUS_MIE) != 0,
-            PrivMode::Supervisor => {
-                // S-mode: enabled if SIE=1, or if in lower privilege
-                (self.mstatus & Self::MSTATUS_SIE) != 0
-            }
-            PrivMode::User => true, // Interrupts always enabled in U-mode
+            _ => true,
         };
 
-        if !interrupts_enabled {
-            return None;
+        // Delegated interrupts target S-mode: never taken in M-mode, taken in
+        // S-mode when SIE is set, and always taken in U-mode.
+        let s_enabled = match self.priv_mode {
+            PrivMode::Machine => false,
+            PrivMode::Supervisor => (self.mstatus & Self::MSTATUS_SIE) != 0,
+            PrivMode::User => true,
+        };
+
+        let mut takeable = 0;
+        if m_enabled {
+            takeable |= pending_enabled & !self.mideleg;
+        }
+        if s_enabled {
+            takeable |= pending_enabled & self.mideleg;
         }
 
         // Priority order: MEI, MSI, MTI, SEI, SSI, STI
-        // Check M-mode interrupts first
-        if (pending_enabled & (1 << 11)) != 0 {
-            Some(11) // MEI
-        } else if (pending_enabled & (1 << 3)) != 0 {
-            Some(3) // MSI
-        } else if (pending_enabled & (1 << 7)) != 0 {
-            Some(7) // MTI
-        } else if (pending_enabled & (1 << 9)) != 0 {
-            Some(9) // SEI
-        } else if (pending_enabled & (1 << 1)) != 0 {
-            Some(1) // SSI
-        } else if (pending_enabled & (1 << 5)) != 0 {
-            Some(5) // STI
-        } else {
-            None
-        }
+        [11, 3, 7, 9, 1, 5]
+            .into_iter()
+            .find(|&cause| (takeable & (1 << cause)) != 0)
     }
 
     /// Set a timer interrupt pending
@@ -467,6 +805,111 @@ impl CsrFile {
             self.mip &= !(1 << 5); // STIP
         }
     }
+
+    /// Set a software interrupt pending
+    pub fn set_software_interrupt(&mut self, is_machine: bool) {
+        if is_machine {
+            self.mip |= 1 << 3; // MSIP
+        } else {
+            self.mip |= 1 << 1; // SSIP
+        }
+    }
+
+    /// Clear a software interrupt
+    pub fn clear_software_interrupt(&mut self, is_machine: bool) {
+        if is_machine {
+            self.mip &= !(1 << 3); // MSIP
+        } else {
+            self.mip &= !(1 << 1); // SSIP
+        }
+    }
+
+    /// Set an external interrupt pending
+    pub fn set_external_interrupt(&mut self, is_machine: bool) {
+        if is_machine {
+            self.mip |= 1 << 11; // MEIP
+        } else {
+            self.mip |= 1 << 9; // SEIP
+        }
+    }
+
+    /// Clear an external interrupt
+    pub fn clear_external_interrupt(&mut self, is_machine: bool) {
+        if is_machine {
+            self.mip &= !(1 << 11); // MEIP
+        } else {
+            self.mip &= !(1 << 9); // SEIP
+        }
+    }
+
+    pub(crate) fn save(&self, w: &mut Writer) {
+        w.u8(self.priv_mode as u8);
+        for value in [
+            self.misa,
+            self.mstatus,
+            self.mtvec,
+            self.mepc,
+            self.mcause,
+            self.mtval,
+            self.mie,
+            self.mip,
+            self.medeleg,
+            self.mideleg,
+            self.mscratch,
+            self.mcounteren,
+            self.stvec,
+            self.sepc,
+            self.scause,
+            self.stval,
+            self.sscratch,
+            self.satp,
+            self.scounteren,
+            self.fcsr,
+            self.cycle,
+            self.instret,
+            self.time,
+        ] {
+            w.u64(value);
+        }
+        self.pmp.save(w);
+        w.u64(self.mhartid);
+    }
+
+    pub(crate) fn load(r: &mut Reader) -> Result<Self, SnapshotError> {
+        let mut csr = Self::new();
+        csr.priv_mode =
+            PrivMode::from_u64(r.u8()? as u64).ok_or(SnapshotError::Invalid("privilege mode"))?;
+        for field in [
+            &mut csr.misa,
+            &mut csr.mstatus,
+            &mut csr.mtvec,
+            &mut csr.mepc,
+            &mut csr.mcause,
+            &mut csr.mtval,
+            &mut csr.mie,
+            &mut csr.mip,
+            &mut csr.medeleg,
+            &mut csr.mideleg,
+            &mut csr.mscratch,
+            &mut csr.mcounteren,
+            &mut csr.stvec,
+            &mut csr.sepc,
+            &mut csr.scause,
+            &mut csr.stval,
+            &mut csr.sscratch,
+            &mut csr.satp,
+            &mut csr.scounteren,
+            &mut csr.fcsr,
+            &mut csr.cycle,
+            &mut csr.instret,
+            &mut csr.time,
+        ] {
+            *field = r.u64()?;
+        }
+        csr.pmp = Pmp::load(r)?;
+        csr.mhartid = r.u64()?;
+        Ok(csr)
+    }
 }
 
 #[cfg(test)]
@@ -490,12 +933,20 @@ mod tests {
         assert_eq!(csr.satp, satp_sv39, "satp should be updated with mode 8");
         println!("✓ satp mode 8 (Sv39) accepted");
 
-        // Test 3: Mode 9 (invalid) should be rejected
-        let satp_invalid = (9u64 << 60) | 0x5678;
-        let result = csr.write(0x180, satp_invalid);
-        assert!(result.is_err(), "Mode 9 should be invalid");
+        // Test 3: Mode 9 (Sv48) should be rejected on an Sv39-only hart
+        let satp_sv48 = (9u64 << 60) | 0x5678;
+        let result = csr.write(0x180, satp_sv48);
+        assert!(result.is_err(), "Mode 9 should be invalid without Sv48");
         assert_eq!(csr.satp, satp_sv39, "satp should NOT be updated");
-        println!("✓ satp mode 9 (invalid) rejected");
+        println!("✓ satp mode 9 (Sv48 disabled) rejected");
+
+        // ...and accepted once Sv48 is implemented
+        csr.sv48 = true;
+        let result = csr.write(0x180, satp_sv48);
+        assert!(result.is_ok(), "Mode 9 (Sv48) should be valid");
+        assert_eq!(csr.satp, satp_sv48, "satp should be updated with mode 9");
+        println!("✓ satp mode 9 (Sv48) accepted");
+        csr.write(0x180, satp_sv39).unwrap();
 
         // Test 4: Mode 1 (invalid) should be rejected
         let satp_invalid2 = (1u64 << 60) | 0xabcd;
@@ -506,4 +957,185 @@ mod tests {
 
         println!("✅ satp mode validation tests passed");
     }
+
+    #[test]
+    fn test_read_only_csr_write_is_rejected() {
+        let mut csr = CsrFile::new();
+
+        assert!(csr.read(0xF14).is_ok(), "mhartid should be readable");
+        assert!(
+            matches!(csr.write(0xF14, 1), Err(CsrError::ReadOnly(0xF14))),
+            "writing mhartid should be rejected"
+        );
+        assert!(
+            matches!(csr.write(0xC00, 1), Err(CsrError::ReadOnly(0xC00))),
+            "writing cycle should be rejected"
+        );
+    }
+
+    #[test]
+    fn test_pending_interrupt_respects_delegation() {
+        let mut csr = CsrFile::new();
+        const STIP: u64 = 1 << 5;
+        const MTIP: u64 = 1 << 7;
+
+        // Delegated STI is masked while in M-mode, even with MIE set
+        csr.mip = STIP;
+        csr.mie = STIP;
+        csr.mideleg = STIP;
+        csr.mstatus |= CsrFile::MSTATUS_MIE;
+        assert_eq!(csr.check_pending_interrupt(), None);
+
+        // In S-mode it is gated by SIE
+        csr.priv_mode = PrivMode::Supervisor;
+        assert_eq!(csr.check_pending_interrupt(), None);
+        csr.mstatus |= CsrFile::MSTATUS_SIE;
+        assert_eq!(csr.check_pending_interrupt(), Some(5));
+
+        // Non-delegated MTI is taken in S-mode regardless of MIE, and wins priority
+        csr.mstatus &= !CsrFile::MSTATUS_MIE;
+        csr.mip |= MTIP;
+        csr.mie |= MTIP;
+        assert_eq!(csr.check_pending_interrupt(), Some(7));
+    }
+
+    #[test]
+    fn test_counteren_gates_counter_reads() {
+        let mut csr = CsrFile::new();
+        csr.time = 42;
+        assert_eq!(csr.read(0xC01).unwrap(), 42);
+
+        csr.priv_mode = PrivMode::Supervisor;
+        assert!(matches!(
+            csr.read(0xC01),
+            Err(CsrError::CounterDisabled(0xC01))
+        ));
+        csr.mcounteren = 1 << 1; // TM
+        assert_eq!(csr.read(0xC01).unwrap(), 42);
+        assert!(csr.read(0xC00).is_err());
+        // Only CY, TM and IR exist
+        csr.write(0x106, u64::MAX).unwrap();
+        assert_eq!(csr.scounteren, 0b111);
+
+        csr.priv_mode = PrivMode::User;
+        assert_eq!(csr.read(0xC01).unwrap(), 42);
+        csr.scounteren = 0;
+        assert!(csr.read(0xC01).is_err());
+    }
+
+    #[test]
+    fn test_reserved_tvec_mode_reads_as_direct() {
+        let mut csr = CsrFile::new();
+        csr.write(0x305, 0x8000_0101).unwrap();
+        assert_eq!(csr.read(0x305).unwrap(), 0x8000_0101);
+        csr.write(0x305, 0x8000_0102).unwrap();
+        assert_eq!(csr.read(0x305).unwrap(), 0x8000_0100);
+        csr.write(0x105, 0x8000_0203).unwrap();
+        assert_eq!(csr.read(0x105).unwrap(), 0x8000_0200);
+    }
+
+    #[test]
+    fn test_misa_write_only_clears_supported_extensions() {
+        let mut csr = CsrFile::new();
+        assert_eq!(csr.isa_string(), "rv64imafdc_zicsr_zifencei");
+
+        // MXL, I, S and U are fixed; setting absent letters (here B, V) is ignored
+        csr.write(0x301, 0).unwrap();
+        assert_eq!(csr.misa, CsrFile::MISA & !CsrFile::MISA_WRITABLE);
+        csr.write(0x301, u64::MAX).unwrap();
+        assert_eq!(csr.misa, CsrFile::MISA);
+
+        csr.clear_bits(0x301, 1 << (b'C' - b'A')).unwrap();
+        assert!(!csr.ext_enabled(b'C'));
+        assert_eq!(csr.isa_string(), "rv64imafd_zicsr_zifencei");
+
+        // D cannot outlive F
+        csr.clear_bits(0x301, 1 << (b'F' - b'A')).unwrap();
+        assert!(!csr.ext_enabled(b'D'));
+    }
+
+    #[test]
+    fn test_fcsr_writes_dirty_fs_and_set_sd() {
+        let mut csr = CsrFile::new();
+        csr.mstatus |= 1 << 13; // FS = Initial
+
+        // Accruing no flags changes nothing
+        csr.set_fflags(0);
+        assert_eq!(csr.read(0x300).unwrap() & CsrFile::MSTATUS_SD, 0);
+
+        csr.write(0x003, 0).unwrap();
+        assert_eq!(csr.mstatus & CsrFile::MSTATUS_FS, CsrFile::MSTATUS_FS);
+        assert_ne!(csr.read(0x300).unwrap() & CsrFile::MSTATUS_SD, 0);
+        assert_eq!(csr.mstatus & CsrFile::MSTATUS_SD, 0, "SD is never stored");
+
+        csr.write(0x100, 0).unwrap(); // FS = Off
+        csr.mstatus |= 1 << 13;
+        csr.set_fflags(1); // NX
+        assert_ne!(csr.read(0x100).unwrap() & CsrFile::MSTATUS_SD, 0);
+    }
+
+    #[test]
+    fn test_fflags_and_frm_are_views_of_fcsr() {
+        let mut csr = CsrFile::new();
+        csr.priv_mode = PrivMode::User;
+        csr.mstatus |= 1 << 13; // FS = Initial
+
+        csr.write(0x002, 0b011).unwrap(); // frm = RUP
+        assert_eq!(csr.read(0x003).unwrap(), 0b011 << 5);
+        csr.write(0x001, 0xff).unwrap(); // only five flags exist
+        assert_eq!(csr.read(0x003).unwrap(), (0b011 << 5) | 0x1f);
+        assert_eq!(csr.read(0x001).unwrap(), 0x1f);
+
+        csr.write(0x003, (0b100 << 5) | 0b1).unwrap();
+        assert_eq!(csr.read(0x002).unwrap(), 0b100);
+        assert_eq!(csr.read(0x001).unwrap(), 0b1);
+        csr.write(0x002, 0xff).unwrap();
+        assert_eq!(csr.read(0x003).unwrap(), (0b111 << 5) | 0b1);
+    }
+
+    #[test]
+    fn test_epc_alignment_follows_misa_c() {
+        let mut csr = CsrFile::new();
+        for epc in [0x341, 0x141] {
+            csr.write(epc, 0x8000_0003).unwrap();
+            assert_eq!(csr.read(epc).unwrap(), 0x8000_0002, "bit 0 is never kept");
+        }
+
+        // RV64I: only 4-byte aligned returns
+        csr.clear_bits(0x301, 1 << (b'C' - b'A')).unwrap();
+        for epc in [0x341, 0x141] {
+            assert_eq!(csr.read(epc).unwrap(), 0x8000_0000);
+            csr.write(epc, 0x8000_0106).unwrap();
+            assert_eq!(csr.read(epc).unwrap(), 0x8000_0104);
+        }
+
+        csr.set_bits(0x301, 1 << (b'C' - b'A')).unwrap();
+        assert_eq!(csr.read(0x341).unwrap(), 0x8000_0106);
+    }
+
+    #[test]
+    fn test_parse_isa() {
+        assert_eq!(parse_isa("rv64gc").unwrap(), CsrFile::MISA);
+        assert_eq!(
+            parse_isa("RV64IMAFDC_Zicsr_Zifencei").unwrap(),
+            CsrFile::MISA
+        );
+
+        let mut csr = CsrFile::new();
+        csr.set_isa(parse_isa("rv64imac").unwrap());
+        assert_eq!(csr.isa_string(), "rv64imac_zicsr_zifencei");
+        // Extensions the hart lacks can't be turned on through misa
+        csr.write(0x301, u64::MAX).unwrap();
+        assert!(!csr.ext_enabled(b'F'));
+
+        assert_eq!(parse_isa("rv64imad"), Err(IsaError::DWithoutF));
+        assert_eq!(parse_isa("rv64imafdqc"), Err(IsaError::Unsupported('q')));
+        assert_eq!(parse_isa("rv64ec"), Err(IsaError::Unsupported('e')));
+        assert!(matches!(parse_isa("rv32imac"), Err(IsaError::Base(_))));
+        assert!(matches!(parse_isa("rv64mac"), Err(IsaError::Base(_))));
+        assert!(matches!(
+            parse_isa("rv64gc_zba"),
+            Err(IsaError::UnsupportedMulti(_))
+        ));
+    }
 }
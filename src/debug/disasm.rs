@@ -0,0 +1,623 @@
+use crate::cpu::decode::{AmoOp, Instr};
+use crate::debug::ABI_NAMES;
+
+/// ABI names of f0-f31
+const FP_ABI_NAMES: [&str; 32] = [
+    "ft0", "ft1", "ft2", "ft3", "ft4", "ft5", "ft6", "ft7", "fs0", "fs1", "fa0", "fa1", "fa2",
+    "fa3", "fa4", "fa5", "fa6", "fa7", "fs2", "fs3", "fs4", "fs5", "fs6", "fs7", "fs8", "fs9",
+    "fs10", "fs11", "ft8", "ft9", "ft10", "ft11",
+];
+
+/// Render `instr` in canonical (non-pseudo) RISC-V assembly with ABI register
+/// names, e.g. `addi a0, sp, 16`. Branch and jump targets are shown as
+/// pc-relative offsets; use `disasm_at` for absolute targets.
+pub fn disasm(instr: &Instr) -> String {
+    render(instr, |off| off.to_string())
+}
+
+/// Like `disasm`, for an instruction at `pc`: branch and jump targets are
+/// shown as absolute addresses, e.g. `beq t0, t1, 0x80000abc`.
+pub fn disasm_at(instr: &Instr, pc: u64) -> String {
+    render(instr, |off| format!("0x{:x}", pc.wrapping_add(off as u64)))
+}
+
+fn render(instr: &Instr, target: impl Fn(i64) -> String) -> String {
+    let x = |r: u8| ABI_NAMES[r as usize];
+    let f = |r: u8| FP_ABI_NAMES[r as usize];
+    // The rounding mode is only spelled out when it isn't dynamic
+    let rm = |rm: u8| match rm {
+        0b000 => ", rne".to_string(),
+        0b001 => ", rtz".to_string(),
+        0b010 => ", rdn".to_string(),
+        0b011 => ", rup".to_string(),
+        0b100 => ", rmm".to_string(),
+        0b111 => String::new(),
+        other => format!(", {}", other),
+    };
+    let csr = |csr: u16| match csr_name(csr) {
+        Some(name) => name.to_string(),
+        None => format!("0x{:x}", csr),
+    };
+
+    let (mnemonic, operands) = match *instr {
+        Instr::Add { rd, rs1, rs2 } => ("add", format!("{}, {}, {}", x(rd), x(rs1), x(rs2))),
+        Instr::Sub { rd, rs1, rs2 } => ("sub", format!("{}, {}, {}", x(rd), x(rs1), x(rs2))),
+        Instr::Xor { rd, rs1, rs2 } => ("xor", format!("{}, {}, {}", x(rd), x(rs1), x(rs2))),
+        Instr::Or { rd, rs1, rs2 } => ("or", format!("{}, {}, {}", x(rd), x(rs1), x(rs2))),
+        Instr::And { rd, rs1, rs2 } => ("and", format!("{}, {}, {}", x(rd), x(rs1), x(rs2))),
+        Instr::Sll { rd, rs1, rs2 } => ("sll", format!("{}, {}, {}", x(rd), x(rs1), x(rs2))),
+        Instr::Srl { rd, rs1, rs2 } => ("srl", format!("{}, {}, {}", x(rd), x(rs1), x(rs2))),
+        Instr::Sra { rd, rs1, rs2 } => ("sra", format!("{}, {}, {}", x(rd), x(rs1), x(rs2))),
+        Instr::Slt { rd, rs1, rs2 } => ("slt", format!("{}, {}, {}", x(rd), x(rs1), x(rs2))),
+        Instr::Sltu { rd, rs1, rs2 } => ("sltu", format!("{}, {}, {}", x(rd), x(rs1), x(rs2))),
+        Instr::Mul { rd, rs1, rs2 } => ("mul", format!("{}, {}, {}", x(rd), x(rs1), x(rs2))),
+        Instr::Mulh { rd, rs1, rs2 } => ("mulh", format!("{}, {}, {}", x(rd), x(rs1), x(rs2))),
+        Instr::Mulhsu { rd, rs1, rs2 } => ("mulhsu", format!("{}, {}, {}", x(rd), x(rs1), x(rs2))),
+        Instr::Mulhu { rd, rs1, rs2 } => ("mulhu", format!("{}, {}, {}", x(rd), x(rs1), x(rs2))),
+        Instr::Div { rd, rs1, rs2 } => ("div", format!("{}, {}, {}", x(rd), x(rs1), x(rs2))),
+        Instr::Divu { rd, rs1, rs2 } => ("divu", format!("{}, {}, {}", x(rd), x(rs1), x(rs2))),
+        Instr::Rem { rd, rs1, rs2 } => ("rem", format!("{}, {}, {}", x(rd), x(rs1), x(rs2))),
+        Instr::Remu { rd, rs1, rs2 } => ("remu", format!("{}, {}, {}", x(rd), x(rs1), x(rs2))),
+
+        Instr::Addi { rd, rs1, imm } => ("addi", format!("{}, {}, {}", x(rd), x(rs1), imm)),
+        Instr::Xori { rd, rs1, imm } => ("xori", format!("{}, {}, {}", x(rd), x(rs1), imm)),
+        Instr::Ori { rd, rs1, imm } => ("ori", format!("{}, {}, {}", x(rd), x(rs1), imm)),
+        Instr::Andi { rd, rs1, imm } => ("andi", format!("{}, {}, {}", x(rd), x(rs1), imm)),
+        Instr::Slti { rd, rs1, imm } => ("slti", format!("{}, {}, {}", x(rd), x(rs1), imm)),
+        Instr::Sltiu { rd, rs1, imm } => ("sltiu", format!("{}, {}, {}", x(rd), x(rs1), imm)),
+        Instr::Slli { rd, rs1, shamt } => ("slli", format!("{}, {}, {}", x(rd), x(rs1), shamt)),
+        Instr::Srli { rd, rs1, shamt } => ("srli", format!("{}, {}, {}", x(rd), x(rs1), shamt)),
+        Instr::Srai { rd, rs1, shamt } => ("srai", format!("{}, {}, {}", x(rd), x(rs1), shamt)),
+
+        Instr::LB { rd, rs1, off } => ("lb", format!("{}, {}({})", x(rd), off, x(rs1))),
+        Instr::LBU { rd, rs1, off } => ("lbu", format!("{}, {}({})", x(rd), off, x(rs1))),
+        Instr::LH { rd, rs1, off } => ("lh", format!("{}, {}({})", x(rd), off, x(rs1))),
+        Instr::LHU { rd, rs1, off } => ("lhu", format!("{}, {}({})", x(rd), off, x(rs1))),
+        Instr::LW { rd, rs1, off } => ("lw", format!("{}, {}({})", x(rd), off, x(rs1))),
+        Instr::LWU { rd, rs1, off } => ("lwu", format!("{}, {}({})", x(rd), off, x(rs1))),
+        Instr::LD { rd, rs1, off } => ("ld", format!("{}, {}({})", x(rd), off, x(rs1))),
+        Instr::SB { rs1, rs2, off } => ("sb", format!("{}, {}({})", x(rs2), off, x(rs1))),
+        Instr::SH { rs1, rs2, off } => ("sh", format!("{}, {}({})", x(rs2), off, x(rs1))),
+        Instr::SW { rs1, rs2, off } => ("sw", format!("{}, {}({})", x(rs2), off, x(rs1))),
+        Instr::SD { rs1, rs2, off } => ("sd", format!("{}, {}({})", x(rs2), off, x(rs1))),
+
+        Instr::Beq { rs1, rs2, off } => ("beq", format!("{}, {}, {}", x(rs1), x(rs2), target(off))),
+        Instr::Bne { rs1, rs2, off } => ("bne", format!("{}, {}, {}", x(rs1), x(rs2), target(off))),
+        Instr::Blt { rs1, rs2, off } => ("blt", format!("{}, {}, {}", x(rs1), x(rs2), target(off))),
+        Instr::Bge { rs1, rs2, off } => ("bge", format!("{}, {}, {}", x(rs1), x(rs2), target(off))),
+        Instr::Bltu { rs1, rs2, off } => {
+            ("bltu", format!("{}, {}, {}", x(rs1), x(rs2), target(off)))
+        }
+        Instr::Bgeu { rs1, rs2, off } => {
+            ("bgeu", format!("{}, {}, {}", x(rs1), x(rs2), target(off)))
+        }
+        Instr::Jal { rd, off } => ("jal", format!("{}, {}", x(rd), target(off))),
+        Instr::Jalr { rd, rs1, off } => ("jalr", format!("{}, {}({})", x(rd), off, x(rs1))),
+        Instr::Lui { rd, imm } => ("lui", format!("{}, 0x{:x}", x(rd), (imm >> 12) & 0xfffff)),
+        Instr::Auipc { rd, imm } => ("auipc", format!("{}, 0x{:x}", x(rd), (imm >> 12) & 0xfffff)),
+        Instr::Ecall => ("ecall", String::new()),
+        Instr::Ebreak => ("ebreak", String::new()),
+
+        Instr::Addiw { rd, rs1, imm } => ("addiw", format!("{}, {}, {}", x(rd), x(rs1), imm)),
+        Instr::Slliw { rd, rs1, shamt } => ("slliw", format!("{}, {}, {}", x(rd), x(rs1), shamt)),
+        Instr::Srliw { rd, rs1, shamt } => ("srliw", format!("{}, {}, {}", x(rd), x(rs1), shamt)),
+        Instr::Sraiw { rd, rs1, shamt } => ("sraiw", format!("{}, {}, {}", x(rd), x(rs1), shamt)),
+        Instr::Addw { rd, rs1, rs2 } => ("addw", format!("{}, {}, {}", x(rd), x(rs1), x(rs2))),
+        Instr::Subw { rd, rs1, rs2 } => ("subw", format!("{}, {}, {}", x(rd), x(rs1), x(rs2))),
+        Instr::Sllw { rd, rs1, rs2 } => ("sllw", format!("{}, {}, {}", x(rd), x(rs1), x(rs2))),
+        Instr::Srlw { rd, rs1, rs2 } => ("srlw", format!("{}, {}, {}", x(rd), x(rs1), x(rs2))),
+        Instr::Sraw { rd, rs1, rs2 } => ("sraw", format!("{}, {}, {}", x(rd), x(rs1), x(rs2))),
+        Instr::Mulw { rd, rs1, rs2 } => ("mulw", format!("{}, {}, {}", x(rd), x(rs1), x(rs2))),
+        Instr::Divw { rd, rs1, rs2 } => ("divw", format!("{}, {}, {}", x(rd), x(rs1), x(rs2))),
+        Instr::Divuw { rd, rs1, rs2 } => ("divuw", format!("{}, {}, {}", x(rd), x(rs1), x(rs2))),
+        Instr::Remw { rd, rs1, rs2 } => ("remw", format!("{}, {}, {}", x(rd), x(rs1), x(rs2))),
+        Instr::Remuw { rd, rs1, rs2 } => ("remuw", format!("{}, {}, {}", x(rd), x(rs1), x(rs2))),
+
+        Instr::Csrrw { rd, csr: c, rs1 } => ("csrrw", format!("{}, {}, {}", x(rd), csr(c), x(rs1))),
+        Instr::Csrrs { rd, csr: c, rs1 } => ("csrrs", format!("{}, {}, {}", x(rd), csr(c), x(rs1))),
+        Instr::Csrrc { rd, csr: c, rs1 } => ("csrrc", format!("{}, {}, {}", x(rd), csr(c), x(rs1))),
+        Instr::Csrrwi { rd, csr: c, uimm } => {
+            ("csrrwi", format!("{}, {}, {}", x(rd), csr(c), uimm))
+        }
+        Instr::Csrrsi { rd, csr: c, uimm } => {
+            ("csrrsi", format!("{}, {}, {}", x(rd), csr(c), uimm))
+        }
+        Instr::Csrrci { rd, csr: c, uimm } => {
+            ("csrrci", format!("{}, {}, {}", x(rd), csr(c), uimm))
+        }
+        Instr::Mret => ("mret", String::new()),
+        Instr::Sret => ("sret", String::new()),
+        Instr::SfenceVma { rs1, rs2 } => ("sfence.vma", format!("{}, {}", x(rs1), x(rs2))),
+        Instr::Wfi => ("wfi", String::new()),
+
+        Instr::LrW { rd, rs1 } => ("lr.w", format!("{}, ({})", x(rd), x(rs1))),
+        Instr::LrD { rd, rs1 } => ("lr.d", format!("{}, ({})", x(rd), x(rs1))),
+        Instr::ScW { rd, rs1, rs2 } => ("sc.w", format!("{}, {}, ({})", x(rd), x(rs2), x(rs1))),
+        Instr::ScD { rd, rs1, rs2 } => ("sc.d", format!("{}, {}, ({})", x(rd), x(rs2), x(rs1))),
+        Instr::AmoW { op, rd, rs1, rs2 } | Instr::AmoD { op, rd, rs1, rs2 } => {
+            let width = if matches!(instr, Instr::AmoW { .. }) {
+                "w"
+            } else {
+                "d"
+            };
+            return format!(
+                "amo{}.{} {}, {}, ({})",
+                amo_name(op),
+                width,
+                x(rd),
+                x(rs2),
+                x(rs1)
+            );
+        }
+
+        Instr::Flw { rd, rs1, off } => ("flw", format!("{}, {}({})", f(rd), off, x(rs1))),
+        Instr::Fld { rd, rs1, off } => ("fld", format!("{}, {}({})", f(rd), off, x(rs1))),
+        Instr::Fsw { rs1, rs2, off } => ("fsw", format!("{}, {}({})", f(rs2), off, x(rs1))),
+        Instr::Fsd { rs1, rs2, off } => ("fsd", format!("{}, {}({})", f(rs2), off, x(rs1))),
+        Instr::FaddS {
+            rd,
+            rs1,
+            rs2,
+            rm: m,
+        } => (
+            "fadd.s",
+            format!("{}, {}, {}{}", f(rd), f(rs1), f(rs2), rm(m)),
+        ),
+        Instr::FsubS {
+            rd,
+            rs1,
+            rs2,
+            rm: m,
+        } => (
+            "fsub.s",
+            format!("{}, {}, {}{}", f(rd), f(rs1), f(rs2), rm(m)),
+        ),
+        Instr::FmulS {
+            rd,
+            rs1,
+            rs2,
+            rm: m,
+        } => (
+            "fmul.s",
+            format!("{}, {}, {}{}", f(rd), f(rs1), f(rs2), rm(m)),
+        ),
+        Instr::FdivS {
+            rd,
+            rs1,
+            rs2,
+            rm: m,
+        } => (
+            "fdiv.s",
+            format!("{}, {}, {}{}", f(rd), f(rs1), f(rs2), rm(m)),
+        ),
+        Instr::FsqrtS { rd, rs1, rm: m } => ("fsqrt.s", format!("{}, {}{}", f(rd), f(rs1), rm(m))),
+        Instr::FcvtWS { rd, rs1, rm: m } => ("fcvt.w.s", format!("{}, {}{}", x(rd), f(rs1), rm(m))),
+        Instr::FcvtWuS { rd, rs1, rm: m } => {
+            ("fcvt.wu.s", format!("{}, {}{}", x(rd), f(rs1), rm(m)))
+        }
+        Instr::FcvtLS { rd, rs1, rm: m } => ("fcvt.l.s", format!("{}, {}{}", x(rd), f(rs1), rm(m))),
+        Instr::FcvtLuS { rd, rs1, rm: m } => {
+            ("fcvt.lu.s", format!("{}, {}{}", x(rd), f(rs1), rm(m)))
+        }
+        Instr::FcvtSW { rd, rs1, rm: m } => ("fcvt.s.w", format!("{}, {}{}", f(rd), x(rs1), rm(m))),
+        Instr::FcvtSWu { rd, rs1, rm: m } => {
+            ("fcvt.s.wu", format!("{}, {}{}", f(rd), x(rs1), rm(m)))
+        }
+        Instr::FcvtSL { rd, rs1, rm: m } => ("fcvt.s.l", format!("{}, {}{}", f(rd), x(rs1), rm(m))),
+        Instr::FcvtSLu { rd, rs1, rm: m } => {
+            ("fcvt.s.lu", format!("{}, {}{}", f(rd), x(rs1), rm(m)))
+        }
+        Instr::FaddD {
+            rd,
+            rs1,
+            rs2,
+            rm: m,
+        } => (
+            "fadd.d",
+            format!("{}, {}, {}{}", f(rd), f(rs1), f(rs2), rm(m)),
+        ),
+        Instr::FsubD {
+            rd,
+            rs1,
+            rs2,
+            rm: m,
+        } => (
+            "fsub.d",
+            format!("{}, {}, {}{}", f(rd), f(rs1), f(rs2), rm(m)),
+        ),
+        Instr::FmulD {
+            rd,
+            rs1,
+            rs2,
+            rm: m,
+        } => (
+            "fmul.d",
+            format!("{}, {}, {}{}", f(rd), f(rs1), f(rs2), rm(m)),
+        ),
+        Instr::FdivD {
+            rd,
+            rs1,
+            rs2,
+            rm: m,
+        } => (
+            "fdiv.d",
+            format!("{}, {}, {}{}", f(rd), f(rs1), f(rs2), rm(m)),
+        ),
+        Instr::FsqrtD { rd, rs1, rm: m } => ("fsqrt.d", format!("{}, {}{}", f(rd), f(rs1), rm(m))),
+        Instr::FcvtSD { rd, rs1, rm: m } => ("fcvt.s.d", format!("{}, {}{}", f(rd), f(rs1), rm(m))),
+        // Exact conversions have no rounding mode to show
+        Instr::FcvtDS { rd, rs1, .. } => ("fcvt.d.s", format!("{}, {}", f(rd), f(rs1))),
+        Instr::FcvtWD { rd, rs1, rm: m } => ("fcvt.w.d", format!("{}, {}{}", x(rd), f(rs1), rm(m))),
+        Instr::FcvtWuD { rd, rs1, rm: m } => {
+            ("fcvt.wu.d", format!("{}, {}{}", x(rd), f(rs1), rm(m)))
+        }
+        Instr::FcvtLD { rd, rs1, rm: m } => ("fcvt.l.d", format!("{}, {}{}", x(rd), f(rs1), rm(m))),
+        Instr::FcvtLuD { rd, rs1, rm: m } => {
+            ("fcvt.lu.d", format!("{}, {}{}", x(rd), f(rs1), rm(m)))
+        }
+        Instr::FcvtDW { rd, rs1, .. } => ("fcvt.d.w", format!("{}, {}", f(rd), x(rs1))),
+        Instr::FcvtDWu { rd, rs1, .. } => ("fcvt.d.wu", format!("{}, {}", f(rd), x(rs1))),
+        Instr::FcvtDL { rd, rs1, rm: m } => ("fcvt.d.l", format!("{}, {}{}", f(rd), x(rs1), rm(m))),
+        Instr::FcvtDLu { rd, rs1, rm: m } => {
+            ("fcvt.d.lu", format!("{}, {}{}", f(rd), x(rs1), rm(m)))
+        }
+        Instr::FmvXD { rd, rs1 } => ("fmv.x.d", format!("{}, {}", x(rd), f(rs1))),
+        Instr::FmvDX { rd, rs1 } => ("fmv.d.x", format!("{}, {}", f(rd), x(rs1))),
+        Instr::FeqS { rd, rs1, rs2 } => ("feq.s", format!("{}, {}, {}", x(rd), f(rs1), f(rs2))),
+        Instr::FltS { rd, rs1, rs2 } => ("flt.s", format!("{}, {}, {}", x(rd), f(rs1), f(rs2))),
+        Instr::FleS { rd, rs1, rs2 } => ("fle.s", format!("{}, {}, {}", x(rd), f(rs1), f(rs2))),
+        Instr::FeqD { rd, rs1, rs2 } => ("feq.d", format!("{}, {}, {}", x(rd), f(rs1), f(rs2))),
+        Instr::FltD { rd, rs1, rs2 } => ("flt.d", format!("{}, {}, {}", x(rd), f(rs1), f(rs2))),
+        Instr::FleD { rd, rs1, rs2 } => ("fle.d", format!("{}, {}, {}", x(rd), f(rs1), f(rs2))),
+        Instr::FclassS { rd, rs1 } => ("fclass.s", format!("{}, {}", x(rd), f(rs1))),
+        Instr::FclassD { rd, rs1 } => ("fclass.d", format!("{}, {}", x(rd), f(rs1))),
+        Instr::FsgnjS { rd, rs1, rs2 } => ("fsgnj.s", format!("{}, {}, {}", f(rd), f(rs1), f(rs2))),
+        Instr::FsgnjnS { rd, rs1, rs2 } => {
+            ("fsgnjn.s", format!("{}, {}, {}", f(rd), f(rs1), f(rs2)))
+        }
+        Instr::FsgnjxS { rd, rs1, rs2 } => {
+            ("fsgnjx.s", format!("{}, {}, {}", f(rd), f(rs1), f(rs2)))
+        }
+        Instr::FsgnjD { rd, rs1, rs2 } => ("fsgnj.d", format!("{}, {}, {}", f(rd), f(rs1), f(rs2))),
+        Instr::FsgnjnD { rd, rs1, rs2 } => {
+            ("fsgnjn.d", format!("{}, {}, {}", f(rd), f(rs1), f(rs2)))
+        }
+        Instr::FsgnjxD { rd, rs1, rs2 } => {
+            ("fsgnjx.d", format!("{}, {}, {}", f(rd), f(rs1), f(rs2)))
+        }
+        Instr::FminS { rd, rs1, rs2 } => ("fmin.s", format!("{}, {}, {}", f(rd), f(rs1), f(rs2))),
+        Instr::FmaxS { rd, rs1, rs2 } => ("fmax.s", format!("{}, {}, {}", f(rd), f(rs1), f(rs2))),
+        Instr::FminD { rd, rs1, rs2 } => ("fmin.d", format!("{}, {}, {}", f(rd), f(rs1), f(rs2))),
+        Instr::FmaxD { rd, rs1, rs2 } => ("fmax.d", format!("{}, {}, {}", f(rd), f(rs1), f(rs2))),
+        Instr::FmaddS {
+            rd,
+            rs1,
+            rs2,
+            rs3,
+            rm: m,
+        }
+        | Instr::FmsubS {
+            rd,
+            rs1,
+            rs2,
+            rs3,
+            rm: m,
+        }
+        | Instr::FnmsubS {
+            rd,
+            rs1,
+            rs2,
+            rs3,
+            rm: m,
+        }
+        | Instr::FnmaddS {
+            rd,
+            rs1,
+            rs2,
+            rs3,
+            rm: m,
+        }
+        | Instr::FmaddD {
+            rd,
+            rs1,
+            rs2,
+            rs3,
+            rm: m,
+        }
+        | Instr::FmsubD {
+            rd,
+            rs1,
+            rs2,
+            rs3,
+            rm: m,
+        }
+        | Instr::FnmsubD {
+            rd,
+            rs1,
+            rs2,
+            rs3,
+            rm: m,
+        }
+        | Instr::FnmaddD {
+            rd,
+            rs1,
+            rs2,
+            rs3,
+            rm: m,
+        } => {
+            let mnemonic = match instr {
+                Instr::FmaddS { .. } => "fmadd.s",
+                Instr::FmsubS { .. } => "fmsub.s",
+                Instr::FnmsubS { .. } => "fnmsub.s",
+                Instr::FnmaddS { .. } => "fnmadd.s",
+                Instr::FmaddD { .. } => "fmadd.d",
+                Instr::FmsubD { .. } => "fmsub.d",
+                Instr::FnmsubD { .. } => "fnmsub.d",
+                _ => "fnmadd.d",
+            };
+            let operands = format!("{}, {}, {}, {}{}", f(rd), f(rs1), f(rs2), f(rs3), rm(m));
+            (mnemonic, operands)
+        }
+
+        Instr::Fence => ("fence", String::new()),
+        Instr::FenceI => ("fence.i", String::new()),
+    };
+
+    if operands.is_empty() {
+        mnemonic.to_string()
+    } else {
+        format!("{} {}", mnemonic, operands)
+    }
+}
+
+fn amo_name(op: AmoOp) -> &'static str {
+    match op {
+        AmoOp::Swap => "swap",
+        AmoOp::Add => "add",
+        AmoOp::Xor => "xor",
+        AmoOp::And => "and",
+        AmoOp::Or => "or",
+        AmoOp::Min => "min",
+        AmoOp::Max => "max",
+        AmoOp::Minu => "minu",
+        AmoOp::Maxu => "maxu",
+    }
+}
+
+/// Names of the CSRs the emulator implements; others are shown by number.
+fn csr_name(csr: u16) -> Option<&'static str> {
+    Some(match csr {
+        0x001 => "fflags",
+        0x002 => "frm",
+        0x003 => "fcsr",
+        0x100 => "sstatus",
+        0x104 => "sie",
+        0x105 => "stvec",
+        0x140 => "sscratch",
+        0x141 => "sepc",
+        0x142 => "scause",
+        0x143 => "stval",
+        0x144 => "sip",
+        0x180 => "satp",
+        0x300 => "mstatus",
+        0x301 => "misa",
+        0x302 => "medeleg",
+        0x303 => "mideleg",
+        0x304 => "mie",
+        0x305 => "mtvec",
+        0x340 => "mscratch",
+        0x341 => "mepc",
+        0x342 => "mcause",
+        0x343 => "mtval",
+        0x344 => "mip",
+        0x3a0 => "pmpcfg0",
+        0x3b0 => "pmpaddr0",
+        0xb00 => "mcycle",
+        0xb02 => "minstret",
+        0xc00 => "cycle",
+        0xc01 => "time",
+        0xc02 => "instret",
+        0xf11 => "mvendorid",
+        0xf12 => "marchid",
+        0xf13 => "mimpid",
+        0xf14 => "mhartid",
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::decode::decode;
+
+    #[test]
+    fn test_every_decoded_variant_disassembles() {
+        // Expected text is llvm-mc's no-alias output, less the dynamic rounding mode
+        let cases = [
+            (0x00c5_8533, "add a0, a1, a2"),
+            (0x4073_02b3, "sub t0, t1, t2"),
+            (0x00d4_c433, "xor s0, s1, a3"),
+            (0x0107_e733, "or a4, a5, a6"),
+            (0x0139_78b3, "and a7, s2, s3"),
+            (0x016a_9a33, "sll s4, s5, s6"),
+            (0x019c_5bb3, "srl s7, s8, s9"),
+            (0x41cd_dd33, "sra s10, s11, t3"),
+            (0x01ff_2eb3, "slt t4, t5, t6"),
+            (0x0020_b033, "sltu zero, ra, sp"),
+            (0x02a2_01b3, "mul gp, tp, a0"),
+            (0x02c5_9533, "mulh a0, a1, a2"),
+            (0x02c5_a533, "mulhsu a0, a1, a2"),
+            (0x02c5_b533, "mulhu a0, a1, a2"),
+            (0x02c5_c533, "div a0, a1, a2"),
+            (0x02c5_d533, "divu a0, a1, a2"),
+            (0x02c5_e533, "rem a0, a1, a2"),
+            (0x02c5_f533, "remu a0, a1, a2"),
+            (0x0101_0513, "addi a0, sp, 16"),
+            (0xfff5_c513, "xori a0, a1, -1"),
+            (0x0ff5_e513, "ori a0, a1, 255"),
+            (0x8005_f513, "andi a0, a1, -2048"),
+            (0x03f5_9513, "slli a0, a1, 63"),
+            (0x0015_d513, "srli a0, a1, 1"),
+            (0x4205_d513, "srai a0, a1, 32"),
+            (0xffb5_a513, "slti a0, a1, -5"),
+            (0x7ff5_b513, "sltiu a0, a1, 2047"),
+            (0xfff5_8503, "lb a0, -1(a1)"),
+            (0x0005_c503, "lbu a0, 0(a1)"),
+            (0x0025_9503, "lh a0, 2(a1)"),
+            (0x0045_d503, "lhu a0, 4(a1)"),
+            (0x8001_2503, "lw a0, -2048(sp)"),
+            (0x00a5_80a3, "sb a0, 1(a1)"),
+            (0xfea5_9f23, "sh a0, -2(a1)"),
+            (0x7ea1_2fa3, "sw a0, 2047(sp)"),
+            (0xfe62_8ce3, "beq t0, t1, -8"),
+            (0x0062_9863, "bne t0, t1, 16"),
+            (0x7eb5_4fe3, "blt a0, a1, 4094"),
+            (0x80b5_5063, "bge a0, a1, -4096"),
+            (0x0005_6463, "bltu a0, zero, 8"),
+            (0x00a0_7663, "bgeu zero, a0, 12"),
+            (0x0100_00ef, "jal ra, 16"),
+            (0x8000_006f, "jal zero, -1048576"),
+            (0x0005_00e7, "jalr ra, 0(a0)"),
+            (0xffc2_8067, "jalr zero, -4(t0)"),
+            (0x1234_5537, "lui a0, 0x12345"),
+            (0xffff_f537, "lui a0, 0xfffff"),
+            (0x0000_1297, "auipc t0, 0x1"),
+            (0x0000_0073, "ecall"),
+            (0x0010_0073, "ebreak"),
+            (0xfff5_851b, "addiw a0, a1, -1"),
+            (0x01f5_951b, "slliw a0, a1, 31"),
+            (0x0055_d51b, "srliw a0, a1, 5"),
+            (0x4075_d51b, "sraiw a0, a1, 7"),
+            (0x00c5_853b, "addw a0, a1, a2"),
+            (0x40c5_853b, "subw a0, a1, a2"),
+            (0x00c5_953b, "sllw a0, a1, a2"),
+            (0x00c5_d53b, "srlw a0, a1, a2"),
+            (0x40c5_d53b, "sraw a0, a1, a2"),
+            (0x02c5_853b, "mulw a0, a1, a2"),
+            (0x02c5_c53b, "divw a0, a1, a2"),
+            (0x02c5_d53b, "divuw a0, a1, a2"),
+            (0x02c5_e53b, "remw a0, a1, a2"),
+            (0x02c5_f53b, "remuw a0, a1, a2"),
+            (0x0081_6503, "lwu a0, 8(sp)"),
+            (0xff01_3503, "ld a0, -16(sp)"),
+            (0x0011_3c23, "sd ra, 24(sp)"),
+            (0x3005_9573, "csrrw a0, mstatus, a1"),
+            (0x0010_2573, "csrrs a0, fflags, zero"),
+            (0x3042_b073, "csrrc zero, mie, t0"),
+            (0x1800_5573, "csrrwi a0, satp, 0"),
+            (0x7c01_e573, "csrrsi a0, 0x7c0, 3"),
+            (0x344f_f573, "csrrci a0, mip, 31"),
+            (0x3020_0073, "mret"),
+            (0x1020_0073, "sret"),
+            (0x12b5_0073, "sfence.vma a0, a1"),
+            (0x1200_0073, "sfence.vma zero, zero"),
+            (0x1050_0073, "wfi"),
+            (0x1005_a52f, "lr.w a0, (a1)"),
+            (0x18c5_a52f, "sc.w a0, a2, (a1)"),
+            (0x1001_32af, "lr.d t0, (sp)"),
+            (0x18c5_b52f, "sc.d a0, a2, (a1)"),
+            (0x08c5_a52f, "amoswap.w a0, a2, (a1)"),
+            (0x00c5_a52f, "amoadd.w a0, a2, (a1)"),
+            (0x20c5_a52f, "amoxor.w a0, a2, (a1)"),
+            (0x60c5_a52f, "amoand.w a0, a2, (a1)"),
+            (0x40c5_a52f, "amoor.w a0, a2, (a1)"),
+            (0x80c5_a52f, "amomin.w a0, a2, (a1)"),
+            (0xa0c5_a52f, "amomax.w a0, a2, (a1)"),
+            (0xc0c5_a52f, "amominu.w a0, a2, (a1)"),
+            (0xe0c5_a52f, "amomaxu.w a0, a2, (a1)"),
+            (0x08c5_b52f, "amoswap.d a0, a2, (a1)"),
+            (0x00c5_b52f, "amoadd.d a0, a2, (a1)"),
+            (0x20c5_b52f, "amoxor.d a0, a2, (a1)"),
+            (0x60c5_b52f, "amoand.d a0, a2, (a1)"),
+            (0x40c5_b52f, "amoor.d a0, a2, (a1)"),
+            (0x80c5_b52f, "amomin.d a0, a2, (a1)"),
+            (0xa0c5_b52f, "amomax.d a0, a2, (a1)"),
+            (0xc0c5_b52f, "amominu.d a0, a2, (a1)"),
+            (0xe0c5_b52f, "amomaxu.d a0, a2, (a1)"),
+            (0x0041_2507, "flw fa0, 4(sp)"),
+            (0xfe05_2e27, "fsw ft0, -4(a0)"),
+            (0x00c5_f553, "fadd.s fa0, fa1, fa2"),
+            (0x09ee_9e53, "fsub.s ft8, ft9, ft10, rtz"),
+            (0x1124_8453, "fmul.s fs0, fs1, fs2, rne"),
+            (0x19fd_ad53, "fdiv.s fs10, fs11, ft11, rdn"),
+            (0x5805_b553, "fsqrt.s fa0, fa1, rup"),
+            (0xc005_9553, "fcvt.w.s a0, fa1, rtz"),
+            (0xc015_f553, "fcvt.wu.s a0, fa1"),
+            (0xc025_b553, "fcvt.l.s a0, fa1, rup"),
+            (0xc035_f553, "fcvt.lu.s a0, fa1"),
+            (0xd005_f553, "fcvt.s.w fa0, a1"),
+            (0xd015_9553, "fcvt.s.wu fa0, a1, rtz"),
+            (0xd025_f553, "fcvt.s.l fa0, a1"),
+            (0xd035_8553, "fcvt.s.lu fa0, a1, rne"),
+            (0x20c5_8553, "fsgnj.s fa0, fa1, fa2"),
+            (0x20c5_9553, "fsgnjn.s fa0, fa1, fa2"),
+            (0x20c5_a553, "fsgnjx.s fa0, fa1, fa2"),
+            (0x28c5_8553, "fmin.s fa0, fa1, fa2"),
+            (0x28c5_9553, "fmax.s fa0, fa1, fa2"),
+            (0x0081_3507, "fld fa0, 8(sp)"),
+            (0xfe84_3c27, "fsd fs0, -8(s0)"),
+            (0x02c5_c553, "fadd.d fa0, fa1, fa2, rmm"),
+            (0x0ac5_f553, "fsub.d fa0, fa1, fa2"),
+            (0x12c5_f553, "fmul.d fa0, fa1, fa2"),
+            (0x1ac5_f553, "fdiv.d fa0, fa1, fa2"),
+            (0x5a05_f553, "fsqrt.d fa0, fa1"),
+            (0x4015_f553, "fcvt.s.d fa0, fa1"),
+            (0x4205_8553, "fcvt.d.s fa0, fa1"),
+            (0xc205_9553, "fcvt.w.d a0, fa1, rtz"),
+            (0xc215_f553, "fcvt.wu.d a0, fa1"),
+            (0xc225_9553, "fcvt.l.d a0, fa1, rtz"),
+            (0xc235_f553, "fcvt.lu.d a0, fa1"),
+            (0xd205_8553, "fcvt.d.w fa0, a1"),
+            (0xd215_8553, "fcvt.d.wu fa0, a1"),
+            (0xd225_f553, "fcvt.d.l fa0, a1"),
+            (0xd235_8553, "fcvt.d.lu fa0, a1, rne"),
+            (0x22c5_8553, "fsgnj.d fa0, fa1, fa2"),
+            (0x2324_9453, "fsgnjn.d fs0, fs1, fs2"),
+            (0x22c5_a553, "fsgnjx.d fa0, fa1, fa2"),
+            (0x2a20_8053, "fmin.d ft0, ft1, ft2"),
+            (0x2ac5_9553, "fmax.d fa0, fa1, fa2"),
+            (0xe205_0553, "fmv.x.d a0, fa0"),
+            (0xf205_0553, "fmv.d.x fa0, a0"),
+            (0xa0b5_2553, "feq.s a0, fa0, fa1"),
+            (0xa0b5_1553, "flt.s a0, fa0, fa1"),
+            (0xa0b5_0553, "fle.s a0, fa0, fa1"),
+            (0xa2b5_2553, "feq.d a0, fa0, fa1"),
+            (0xa2b5_1553, "flt.d a0, fa0, fa1"),
+            (0xa2b5_0553, "fle.d a0, fa0, fa1"),
+            (0xe005_1553, "fclass.s a0, fa0"),
+            (0xe205_1553, "fclass.d a0, fa0"),
+            (0x68c5_f543, "fmadd.s fa0, fa1, fa2, fa3"),
+            (0x68c5_9547, "fmsub.s fa0, fa1, fa2, fa3, rtz"),
+            (0x68c5_f54b, "fnmsub.s fa0, fa1, fa2, fa3"),
+            (0x68c5_f54f, "fnmadd.s fa0, fa1, fa2, fa3"),
+            (0x6ac5_f543, "fmadd.d fa0, fa1, fa2, fa3"),
+            (0x6ac5_f547, "fmsub.d fa0, fa1, fa2, fa3"),
+            (0x6ac5_f54b, "fnmsub.d fa0, fa1, fa2, fa3"),
+            (0x1a20_a04f, "fnmadd.d ft0, ft1, ft2, ft3, rdn"),
+            (0x0ff0_000f, "fence"),
+            (0x0000_100f, "fence.i"),
+        ];
+        let mut kinds = std::collections::HashSet::new();
+        for (inst, expected) in cases {
+            let instr = decode(0, inst).unwrap();
+            assert_eq!(disasm(&instr), expected, "0x{:08x}", inst);
+
+            let mnemonic = expected.split(' ').next().unwrap();
+            if !mnemonic.starts_with("amo") {
+                assert_eq!(Instr::KIND_NAMES[instr.kind()], mnemonic);
+            }
+            kinds.insert(instr.kind());
+        }
+        assert_eq!(kinds.len(), Instr::KINDS, "every variant is covered");
+    }
+
+    #[test]
+    fn test_disasm_at_shows_absolute_targets() {
+        let beq = decode(0, 0xfe62_8ce3).unwrap(); // beq t0, t1, -8
+        assert_eq!(disasm_at(&beq, 0x8000_0ac4), "beq t0, t1, 0x80000abc");
+        let jal = decode(0, 0x0100_00ef).unwrap(); // jal ra, 16
+        assert_eq!(disasm_at(&jal, 0x8000_0000), "jal ra, 0x80000010");
+        let addi = decode(0, 0x0101_0513).unwrap();
+        assert_eq!(disasm_at(&addi, 0x8000_0000), "addi a0, sp, 16");
+    }
+}
@@ -0,0 +1,166 @@
+use std::fmt::Write as _;
+use std::io::{self, BufWriter, Write};
+
+use super::{ABI_NAMES, disasm};
+use crate::cpu::decode;
+use crate::cpu::{Cpu, Machine};
+use crate::csr::PrivMode;
+
+/// State captured before a step, compared against the state after it.
+struct Pending {
+    step: u64,
+    pc: u64,
+    inst: u32,
+    len: u64,
+    priv_mode: PrivMode,
+    instret: u64,
+    regs: [u64; 32],
+    f: [u64; 32],
+}
+
+/// Line-delimited JSON trace: one object per retired instruction with its pc,
+/// raw encoding, disassembly, privilege and the registers it changed, e.g.
+///
+/// `{"step":3,"pc":"0x80000008","inst":"0x00a00513","len":4,"priv":"M","disasm":"addi a0, zero, 10","regs":{"a0":"0xa"}}`
+///
+/// 64-bit values are hex strings, as JSON numbers lose precision above 2^53.
+/// Call `before` and `after` around each single step.
+pub struct JsonTrace<W: Write> {
+    out: BufWriter<W>,
+    pending: Option<Pending>,
+}
+
+impl<W: Write> JsonTrace<W> {
+    pub fn new(out: W) -> Self {
+        Self {
+            out: BufWriter::new(out),
+            pending: None,
+        }
+    }
+
+    /// Capture the instruction at pc and the registers before it runs. Nothing
+    /// is recorded for the step if the fetch would fault.
+    pub fn before(&mut self, machine: &mut Machine) {
+        self.pending = machine.peek_raw().map(|(inst, len)| Pending {
+            step: machine.executed,
+            pc: machine.cpu.pc,
+            inst,
+            len,
+            priv_mode: machine.cpu.csr.priv_mode,
+            instret: machine.cpu.csr.instret,
+            regs: machine.cpu.regs,
+            f: machine.cpu.f,
+        });
+    }
+
+    /// Write a line for the instruction captured by `before` if it retired;
+    /// steps that trapped, took an interrupt or idled in WFI are skipped.
+    pub fn after(&mut self, cpu: &Cpu) -> io::Result<()> {
+        let Some(p) = self.pending.take() else {
+            return Ok(());
+        };
+        if cpu.csr.instret == p.instret {
+            return Ok(());
+        }
+
+        let decoded = if p.len == 2 {
+            decode::decode_compressed(p.pc, p.inst as u16)
+        } else {
+            decode::decode(p.pc, p.inst)
+        };
+        let text = match decoded {
+            Ok(instr) => disasm::disasm_at(&instr, p.pc),
+            Err(_) => "<invalid>".to_string(),
+        };
+        let priv_mode = match p.priv_mode {
+            PrivMode::User => 'U',
+            PrivMode::Supervisor => 'S',
+            PrivMode::Machine => 'M',
+        };
+
+        let mut line = format!(
+            "{{\"step\":{},\"pc\":\"0x{:x}\",\"inst\":\"0x{:0width$x}\",\"len\":{},\"priv\":\"{}\",\"disasm\":\"{}\",\"regs\":{{",
+            p.step,
+            p.pc,
+            p.inst,
+            p.len,
+            priv_mode,
+            escape(&text),
+            width = 2 * p.len as usize
+        );
+        let mut first = true;
+        let x_changes = (0..32)
+            .filter(|&i| cpu.regs[i] != p.regs[i])
+            .map(|i| (ABI_NAMES[i].to_string(), cpu.regs[i]));
+        let f_changes = (0..32)
+            .filter(|&i| cpu.f[i] != p.f[i])
+            .map(|i| (format!("f{}", i), cpu.f[i]));
+        for (name, value) in x_changes.chain(f_changes) {
+            if !first {
+                line.push(',');
+            }
+            first = false;
+            let _ = write!(line, "\"{}\":\"0x{:x}\"", name, value);
+        }
+        line.push_str("}}\n");
+        self.out.write_all(line.as_bytes())
+    }
+
+    /// Flush buffered lines and return the underlying writer.
+    pub fn finish(self) -> io::Result<W> {
+        self.out.into_inner().map_err(|e| e.into_error())
+    }
+}
+
+/// Escape `s` for use inside a JSON string.
+fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_one_line_per_retired_instruction() {
+        let mut m = Machine::new(0x1000);
+        m.mem.write_u32_phys(0x8000_0000, 0x00a0_0513).unwrap(); // addi a0, zero, 10
+        m.mem.write_u32_phys(0x8000_0004, 0x0073_0001).unwrap(); // c.nop; ecall (traps)
+        m.mem.write_u32_phys(0x8000_0008, 0x0000_0000).unwrap();
+        m.cpu.pc = 0x8000_0000;
+
+        let mut trace = JsonTrace::new(Vec::new());
+        for _ in 0..3 {
+            trace.before(&mut m);
+            let _ = m.step();
+            trace.after(&m.cpu).unwrap();
+        }
+        let out = String::from_utf8(trace.finish().unwrap()).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "{\"step\":0,\"pc\":\"0x80000000\",\"inst\":\"0x00a00513\",\"len\":4,\"priv\":\"M\",\
+                 \"disasm\":\"addi a0, zero, 10\",\"regs\":{\"a0\":\"0xa\"}}",
+                "{\"step\":1,\"pc\":\"0x80000004\",\"inst\":\"0x0001\",\"len\":2,\"priv\":\"M\",\
+                 \"disasm\":\"addi zero, zero, 0\",\"regs\":{}}",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape("a\"b\\c\n"), "a\\\"b\\\\c\\u000a");
+    }
+}
@@ -1,13 +1,99 @@
+pub mod disasm;
+pub mod json_trace;
+pub mod profile;
+pub mod ref_trace;
+pub mod trap_stats;
+
+use std::fmt::Write;
+
 use crate::cpu::Cpu;
+use crate::cpu::decode::Instr;
+use crate::csr::PrivMode;
+
+/// ABI names of x0-x31
+pub const ABI_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
 
-pub fn trace(cpu: &Cpu, step: u64) {
+/// One line per step: pc, the instruction about to run (`None` if it can't be
+/// fetched or decoded) and a few key registers.
+pub fn trace(cpu: &Cpu, step: u64, instr: Option<&Instr>) {
+    let text = match instr {
+        Some(instr) => disasm::disasm_at(instr, cpu.pc),
+        None => "<invalid>".to_string(),
+    };
     eprintln!(
-        "[{:08}] pc=0x{:016x} x1=0x{:016x} x2=0x{:016x} x3(gp)=0x{:016x} x5=0x{:016x}",
+        "[{:08}] pc=0x{:016x} {:<32} x1=0x{:016x} x2=0x{:016x} x3(gp)=0x{:016x} x5=0x{:016x}",
         step,
         cpu.pc,
-        cpu.regs[1],
-        cpu.regs[2],
-        cpu.regs[3],
-        cpu.regs[5]
+        text,
+        cpu.ra(),
+        cpu.sp(),
+        cpu.gp(),
+        cpu.reg(5)
+    );
+}
+
+/// Full register dump for a step: pc, privilege mode and the trap CSRs, then
+/// all 32 integer registers by ABI name, four to a row.
+pub fn trace_full(cpu: &Cpu, step: u64) {
+    eprint!("{}", full_state(cpu, step));
+}
+
+fn full_state(cpu: &Cpu, step: u64) -> String {
+    let priv_mode = match cpu.csr.priv_mode {
+        PrivMode::User => 'U',
+        PrivMode::Supervisor => 'S',
+        PrivMode::Machine => 'M',
+    };
+    let mut out = format!(
+        "[{:08}] pc=0x{:016x} priv={} mstatus=0x{:016x} mcause=0x{:016x} mepc=0x{:016x}\n",
+        step, cpu.pc, priv_mode, cpu.csr.mstatus, cpu.csr.mcause, cpu.csr.mepc
     );
+    for row in 0..8u8 {
+        for idx in row * 4..row * 4 + 4 {
+            let _ = write!(
+                out,
+                " {:>4}=0x{:016x}",
+                ABI_NAMES[idx as usize],
+                cpu.reg(idx)
+            );
+        }
+        out.push('\n');
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_full_state_lists_every_register() {
+        let mut cpu = Cpu {
+            pc: 0x8000_0010,
+            ..Default::default()
+        };
+        cpu.set_reg(2, 0x8000_8000);
+        cpu.set_reg(31, 0xdead_beef);
+        cpu.csr.priv_mode = PrivMode::Supervisor;
+        cpu.csr.mcause = 2;
+
+        let dump = full_state(&cpu, 7);
+        let lines: Vec<&str> = dump.lines().collect();
+        assert_eq!(lines.len(), 9);
+        assert_eq!(
+            lines[0],
+            "[00000007] pc=0x0000000080000010 priv=S mstatus=0x0000000000000000 \
+             mcause=0x0000000000000002 mepc=0x0000000000000000"
+        );
+        assert_eq!(
+            lines[1],
+            " zero=0x0000000000000000   ra=0x0000000000000000   sp=0x0000000080008000   \
+             gp=0x0000000000000000"
+        );
+        assert!(lines[8].ends_with("  t6=0x00000000deadbeef"));
+    }
 }
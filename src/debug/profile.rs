@@ -0,0 +1,88 @@
+use std::fmt::Write;
+
+use crate::cpu::decode::Instr;
+
+/// Execution counts per instruction kind, indexed by `Instr::kind()`.
+pub struct Profile {
+    counts: [u64; Instr::KINDS],
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Profile {
+    pub fn new() -> Self {
+        Self {
+            counts: [0; Instr::KINDS],
+        }
+    }
+
+    pub fn record(&mut self, instr: &Instr) {
+        self.counts[instr.kind()] += 1;
+    }
+
+    pub fn count(&self, kind: usize) -> u64 {
+        self.counts[kind]
+    }
+
+    pub fn total(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Histogram of the instructions that ran, most frequent first, with
+    /// counts and their share of the total.
+    pub fn report(&self) -> String {
+        let total = self.total();
+        let mut kinds: Vec<usize> = (0..Instr::KINDS).filter(|&k| self.counts[k] != 0).collect();
+        kinds.sort_by_key(|&k| (std::cmp::Reverse(self.counts[k]), k));
+
+        let mut out = format!("Instruction profile ({} executed):\n", total);
+        for k in kinds {
+            let count = self.counts[k];
+            let _ = writeln!(
+                out,
+                "  {:<12} {:>12} {:>7.2}%",
+                Instr::KIND_NAMES[k],
+                count,
+                100.0 * count as f64 / total as f64
+            );
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report_sorts_by_count() {
+        let mut profile = Profile::new();
+        let addi = Instr::Addi {
+            rd: 10,
+            rs1: 10,
+            imm: 1,
+        };
+        let bne = Instr::Bne {
+            rs1: 10,
+            rs2: 0,
+            off: -4,
+        };
+        for _ in 0..3 {
+            profile.record(&addi);
+        }
+        profile.record(&bne);
+
+        assert_eq!(profile.total(), 4);
+        assert_eq!(profile.count(addi.kind()), 3);
+        assert_eq!(
+            profile.report(),
+            "Instruction profile (4 executed):\n  \
+             addi                    3   75.00%\n  \
+             bne                     1   25.00%\n"
+        );
+    }
+}
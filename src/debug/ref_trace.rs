@@ -0,0 +1,372 @@
+use std::fmt::Write as _;
+use std::io::{self, BufRead};
+
+use thiserror::Error;
+
+use super::ABI_NAMES;
+use crate::cpu::Cpu;
+
+/// A register an entry of the reference trace gives a value for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reg {
+    X(u8),
+    F(u8),
+}
+
+impl Reg {
+    /// `xN`, `fN` or an integer ABI name such as `a0`
+    fn parse(name: &str) -> Option<Self> {
+        let index = |digits: &str| digits.parse::<u8>().ok().filter(|&n| n < 32);
+        if let Some(n) = name.strip_prefix('x').and_then(index) {
+            return Some(Reg::X(n));
+        }
+        if let Some(n) = name.strip_prefix('f').and_then(index) {
+            return Some(Reg::F(n));
+        }
+        let n = ABI_NAMES.iter().position(|&abi| abi == name)?;
+        Some(Reg::X(n as u8))
+    }
+
+    fn value(self, cpu: &Cpu) -> u64 {
+        match self {
+            Reg::X(n) => cpu.regs[n as usize],
+            Reg::F(n) => cpu.f[n as usize],
+        }
+    }
+
+    fn name(self) -> String {
+        match self {
+            Reg::X(n) => format!("x{} ({})", n, ABI_NAMES[n as usize]),
+            Reg::F(n) => format!("f{}", n),
+        }
+    }
+}
+
+/// One retired instruction of the reference run: its pc and the values of the
+/// registers it lists after the instruction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RefEntry {
+    /// 1-based line of the trace the entry came from
+    pub line: usize,
+    pub pc: u64,
+    pub regs: Vec<(Reg, u64)>,
+}
+
+#[derive(Error, Debug)]
+pub enum RefTraceError {
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    #[error("reference trace line {line}: {msg}")]
+    Parse { line: usize, msg: String },
+    #[error("diverged from reference trace line {line} after {retired} instructions:\n{diff}")]
+    Divergence {
+        line: usize,
+        retired: u64,
+        diff: String,
+    },
+}
+
+fn parse_hex(s: &str) -> Option<u64> {
+    u64::from_str_radix(s.strip_prefix("0x")?, 16).ok()
+}
+
+/// Parse one line of a reference trace, or None for lines that describe no
+/// retired instruction. Two formats are accepted:
+///
+/// - Spike's `--log-commits` output, e.g.
+///   `core   0: 3 0x0000000080000000 (0x00000297) x5  0x0000000080000000`.
+///   Memory accesses and CSR writes are ignored, as are Spike's disassembly,
+///   exception and label lines.
+/// - A pc followed by `reg=value` pairs, e.g. `0x80000000 x5=0x80000000 a0=0x1`.
+///   Blank lines and `#` comments are skipped.
+pub fn parse_line(line: usize, text: &str) -> Result<Option<RefEntry>, RefTraceError> {
+    let err = |msg: String| RefTraceError::Parse { line, msg };
+    let text = text.trim();
+    if text.is_empty() || text.starts_with('#') {
+        return Ok(None);
+    }
+
+    if let Some(rest) = text.strip_prefix("core") {
+        let Some((_, rest)) = rest.split_once(':') else {
+            return Ok(None);
+        };
+        let tokens: Vec<&str> = rest.split_whitespace().collect();
+        // Commit lines are `priv pc (inst) [name value]...`
+        let is_commit = tokens.len() >= 3
+            && tokens[0].len() == 1
+            && tokens[0].as_bytes()[0].is_ascii_digit()
+            && tokens[2].starts_with('(');
+        if !is_commit {
+            return Ok(None);
+        }
+        let pc = parse_hex(tokens[1]).ok_or_else(|| err(format!("bad pc '{}'", tokens[1])))?;
+        let mut regs = Vec::new();
+        let mut i = 3;
+        while i < tokens.len() {
+            let name = tokens[i];
+            if name == "mem" {
+                // `mem addr` for a load, `mem addr value` for a store
+                i += 2;
+                if tokens.get(i).is_some_and(|t| t.starts_with("0x")) {
+                    i += 1;
+                }
+                continue;
+            }
+            let value = tokens
+                .get(i + 1)
+                .and_then(|t| parse_hex(t))
+                .ok_or_else(|| err(format!("missing value for '{}'", name)))?;
+            // CSR writes such as `c768_mstatus` aren't compared
+            if let Some(reg) = Reg::parse(name) {
+                regs.push((reg, value));
+            }
+            i += 2;
+        }
+        return Ok(Some(RefEntry { line, pc, regs }));
+    }
+
+    let mut tokens = text.split_whitespace();
+    let pc_text = tokens.next().unwrap_or_default();
+    let pc = parse_hex(pc_text).ok_or_else(|| err(format!("bad pc '{}'", pc_text)))?;
+    let regs = tokens
+        .map(|pair| {
+            let (name, value) = pair
+                .split_once('=')
+                .ok_or_else(|| err(format!("expected reg=value, got '{}'", pair)))?;
+            let reg =
+                Reg::parse(name).ok_or_else(|| err(format!("unknown register '{}'", name)))?;
+            let value = parse_hex(value).ok_or_else(|| err(format!("bad value '{}'", value)))?;
+            Ok((reg, value))
+        })
+        .collect::<Result<_, RefTraceError>>()?;
+    Ok(Some(RefEntry { line, pc, regs }))
+}
+
+/// State captured before a step
+struct Pending {
+    pc: u64,
+    instret: u64,
+    regs: [u64; 32],
+    f: [u64; 32],
+}
+
+/// Lockstep comparison against a reference trace. Call `before` and `after`
+/// around each single step; `after` checks every retired instruction against
+/// the next entry of the trace. Leading entries before the first pc the
+/// emulator runs, such as Spike's boot ROM, are skipped.
+pub struct RefChecker<R: BufRead> {
+    reader: R,
+    line: usize,
+    synced: bool,
+    pending: Option<Pending>,
+    retired: u64,
+    finished: bool,
+}
+
+impl<R: BufRead> RefChecker<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            line: 0,
+            synced: false,
+            pending: None,
+            retired: 0,
+            finished: false,
+        }
+    }
+
+    /// Whether the reference trace has run out; nothing more is compared.
+    pub fn finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Instructions checked against the reference so far
+    pub fn retired(&self) -> u64 {
+        self.retired
+    }
+
+    fn next_entry(&mut self) -> Result<Option<RefEntry>, RefTraceError> {
+        let mut text = String::new();
+        loop {
+            text.clear();
+            if self.reader.read_line(&mut text)? == 0 {
+                return Ok(None);
+            }
+            self.line += 1;
+            if let Some(entry) = parse_line(self.line, &text)? {
+                return Ok(Some(entry));
+            }
+        }
+    }
+
+    pub fn before(&mut self, cpu: &Cpu) {
+        self.pending = Some(Pending {
+            pc: cpu.pc,
+            instret: cpu.csr.instret,
+            regs: cpu.regs,
+            f: cpu.f,
+        });
+    }
+
+    /// Compare the step since `before` with the next reference entry if an
+    /// instruction retired. Besides the listed values, a register the
+    /// emulator changed that the entry doesn't list is a divergence.
+    pub fn after(&mut self, cpu: &Cpu) -> Result<(), RefTraceError> {
+        let Some(p) = self.pending.take() else {
+            return Ok(());
+        };
+        if self.finished || cpu.csr.instret == p.instret {
+            return Ok(());
+        }
+
+        let entry = loop {
+            match self.next_entry()? {
+                None => {
+                    self.finished = true;
+                    return Ok(());
+                }
+                Some(entry) if !self.synced && entry.pc != p.pc => continue,
+                Some(entry) => break entry,
+            }
+        };
+        self.synced = true;
+
+        let mut diff = String::new();
+        if entry.pc != p.pc {
+            let _ = writeln!(diff, "  pc: 0x{:016x}, expected 0x{:016x}", p.pc, entry.pc);
+        }
+        for &(reg, expected) in &entry.regs {
+            let actual = reg.value(cpu);
+            if actual != expected {
+                let _ = writeln!(
+                    diff,
+                    "  {}: 0x{:016x}, expected 0x{:016x}",
+                    reg.name(),
+                    actual,
+                    expected
+                );
+            }
+        }
+        let changed = (1..32u8)
+            .filter(|&n| cpu.regs[n as usize] != p.regs[n as usize])
+            .map(Reg::X)
+            .chain(
+                (0..32u8)
+                    .filter(|&n| cpu.f[n as usize] != p.f[n as usize])
+                    .map(Reg::F),
+            );
+        for reg in changed {
+            if !entry.regs.iter().any(|&(r, _)| r == reg) {
+                let _ = writeln!(
+                    diff,
+                    "  {}: written with 0x{:016x}, expected unchanged",
+                    reg.name(),
+                    reg.value(cpu)
+                );
+            }
+        }
+
+        if !diff.is_empty() {
+            let _ = write!(diff, "  (instruction at pc 0x{:016x})", p.pc);
+            return Err(RefTraceError::Divergence {
+                line: entry.line,
+                retired: self.retired,
+                diff,
+            });
+        }
+        self.retired += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::Machine;
+
+    #[test]
+    fn test_parse_spike_commit_log() {
+        let spike = [
+            "core   0: 0x0000000080000000 (0x00000297) auipc   t0, 0x0",
+            "core   0: 3 0x0000000080000000 (0x00000297) x5  0x0000000080000000",
+            "core   0: 3 0x0000000080000004 (0x0082b023) mem 0x0000000080001000 0x0000000000000001",
+            "core   0: 3 0x0000000080000008 (0x0002b503) x10 0x0000000000000001 mem 0x0000000080001000",
+            "core   0: 3 0x000000008000000c (0x30529073) c773_mtvec 0x0000000080000010",
+            "core   0: exception trap_illegal_instruction, epc 0x0000000080000010",
+        ];
+        let entries: Vec<_> = spike
+            .iter()
+            .enumerate()
+            .filter_map(|(i, line)| parse_line(i + 1, line).unwrap())
+            .collect();
+        assert_eq!(
+            entries,
+            [
+                RefEntry {
+                    line: 2,
+                    pc: 0x8000_0000,
+                    regs: vec![(Reg::X(5), 0x8000_0000)]
+                },
+                RefEntry {
+                    line: 3,
+                    pc: 0x8000_0004,
+                    regs: vec![]
+                },
+                RefEntry {
+                    line: 4,
+                    pc: 0x8000_0008,
+                    regs: vec![(Reg::X(10), 1)]
+                },
+                RefEntry {
+                    line: 5,
+                    pc: 0x8000_000c,
+                    regs: vec![]
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_simple_format() {
+        assert_eq!(
+            parse_line(7, "0x80000000 t0=0x3 f1=0xffffffff3f800000").unwrap(),
+            Some(RefEntry {
+                line: 7,
+                pc: 0x8000_0000,
+                regs: vec![(Reg::X(5), 3), (Reg::F(1), 0xffff_ffff_3f80_0000)]
+            })
+        );
+        assert!(parse_line(1, "# comment").unwrap().is_none());
+        assert!(matches!(
+            parse_line(2, "0x80000000 x32=0x1"),
+            Err(RefTraceError::Parse { line: 2, .. })
+        ));
+    }
+
+    #[test]
+    fn test_reports_first_divergence() {
+        let mut m = Machine::new(0x1000);
+        m.mem.write_u32_phys(0x8000_0000, 0x0030_0293).unwrap(); // addi t0, zero, 3
+        m.mem.write_u32_phys(0x8000_0004, 0x0010_0313).unwrap(); // addi t1, zero, 1
+        m.cpu.pc = 0x8000_0000;
+
+        // A boot ROM entry to skip, a match, then a wrong value for t1
+        let reference = "0x1000\n0x80000000 t0=0x3\n0x80000004 t1=0x2\n";
+        let mut checker = RefChecker::new(reference.as_bytes());
+        for _ in 0..2 {
+            checker.before(&m.cpu);
+            m.step().unwrap();
+            if let Err(e) = checker.after(&m.cpu) {
+                assert_eq!(checker.retired(), 1);
+                assert_eq!(
+                    e.to_string(),
+                    "diverged from reference trace line 3 after 1 instructions:\n  \
+                     x6 (t1): 0x0000000000000001, expected 0x0000000000000002\n  \
+                     (instruction at pc 0x0000000080000004)"
+                );
+                return;
+            }
+        }
+        panic!("divergence not reported");
+    }
+}
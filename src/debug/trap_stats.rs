@@ -0,0 +1,99 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use crate::cpu::trap::cause_name;
+
+/// Traps taken per cause, with exceptions and interrupts kept apart.
+#[derive(Clone, Default)]
+pub struct TrapStats {
+    exceptions: BTreeMap<u64, u64>,
+    interrupts: BTreeMap<u64, u64>,
+}
+
+impl TrapStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&mut self, cause: u64, is_interrupt: bool) {
+        let counts = if is_interrupt {
+            &mut self.interrupts
+        } else {
+            &mut self.exceptions
+        };
+        *counts.entry(cause).or_default() += 1;
+    }
+
+    pub fn count(&self, cause: u64, is_interrupt: bool) -> u64 {
+        let counts = if is_interrupt {
+            &self.interrupts
+        } else {
+            &self.exceptions
+        };
+        counts.get(&cause).copied().unwrap_or(0)
+    }
+
+    pub fn total(&self) -> u64 {
+        self.exceptions
+            .values()
+            .chain(self.interrupts.values())
+            .sum()
+    }
+
+    /// Exceptions, then interrupts, each most frequent first.
+    pub fn report(&self) -> String {
+        let mut out = format!("Trap summary ({} taken):\n", self.total());
+        for (heading, counts, is_interrupt) in [
+            ("Exceptions", &self.exceptions, false),
+            ("Interrupts", &self.interrupts, true),
+        ] {
+            let total: u64 = counts.values().sum();
+            let _ = writeln!(out, "  {} ({}):", heading, total);
+            let mut causes: Vec<(&u64, &u64)> = counts.iter().collect();
+            causes.sort_by_key(|&(&cause, &count)| (std::cmp::Reverse(count), cause));
+            for (&cause, &count) in causes {
+                let _ = writeln!(
+                    out,
+                    "    {:<32} {:>12}",
+                    cause_name(cause, is_interrupt),
+                    count
+                );
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::trap::causes;
+
+    #[test]
+    fn test_report_separates_exceptions_from_interrupts() {
+        let mut stats = TrapStats::new();
+        for _ in 0..3 {
+            stats.record(causes::LOAD_PAGE_FAULT, false);
+        }
+        stats.record(causes::ILLEGAL_INSTRUCTION, false);
+        stats.record(causes::MTI, true);
+
+        assert_eq!(stats.total(), 5);
+        assert_eq!(stats.count(causes::LOAD_PAGE_FAULT, false), 3);
+        assert_eq!(
+            stats.count(causes::MTI, false),
+            0,
+            "cause 7 is a store fault"
+        );
+
+        let report = stats.report();
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines[0], "Trap summary (5 taken):");
+        assert_eq!(lines[1], "  Exceptions (4):");
+        assert!(lines[2].starts_with("    load page fault "));
+        assert!(lines[2].ends_with(" 3"));
+        assert!(lines[3].starts_with("    illegal instruction "));
+        assert_eq!(lines[4], "  Interrupts (1):");
+        assert!(lines[5].starts_with("    machine timer interrupt "));
+    }
+}
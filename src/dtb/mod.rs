@@ -0,0 +1,511 @@
+use thiserror::Error;
+
+use crate::clint::{CLINT_BASE, CLINT_SIZE, TIMEBASE_HZ};
+use crate::cpu::Machine;
+use crate::elf::USER_STACK_SIZE;
+use crate::plic::{NUM_SOURCES, PLIC_BASE, PLIC_SIZE};
+use crate::syscon::{SYSCON_BASE, SYSCON_SIZE};
+use crate::uart::{UART_BASE, UART_IRQ, UART_SIZE};
+use crate::virtio::{VIRTIO_BASE, VIRTIO_IRQ, VIRTIO_SIZE};
+
+/// First word of every flattened device tree, big-endian
+pub const FDT_MAGIC: u32 = 0xd00d_feed;
+/// Size of the FDT header, which holds the magic and the blob's total size
+const HEADER_SIZE: usize = 40;
+
+// Structure block tokens
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_END: u32 = 9;
+
+// Phandles of the interrupt controllers
+const PHANDLE_CPU_INTC: u32 = 1;
+const PHANDLE_PLIC: u32 = 2;
+
+// Local interrupt numbers, as in mip
+const IRQ_M_SOFT: u32 = 3;
+const IRQ_M_TIMER: u32 = 7;
+const IRQ_S_EXT: u32 = 9;
+const IRQ_M_EXT: u32 = 11;
+
+/// Builds the structure and strings blocks of a flattened device tree.
+#[derive(Default)]
+struct FdtWriter {
+    structure: Vec<u8>,
+    strings: Vec<u8>,
+}
+
+impl FdtWriter {
+    fn token(&mut self, token: u32) {
+        self.structure.extend_from_slice(&token.to_be_bytes());
+    }
+
+    /// Append `bytes`, zero-padded to a 4-byte boundary
+    fn padded(&mut self, bytes: &[u8]) {
+        self.structure.extend_from_slice(bytes);
+        let pad = self.structure.len().next_multiple_of(4) - self.structure.len();
+        self.structure.extend(std::iter::repeat_n(0, pad));
+    }
+
+    fn begin_node(&mut self, name: &str) {
+        self.token(FDT_BEGIN_NODE);
+        self.padded(format!("{}\0", name).as_bytes());
+    }
+
+    fn end_node(&mut self) {
+        self.token(FDT_END_NODE);
+    }
+
+    fn prop(&mut self, name: &str, value: &[u8]) {
+        let mut key = name.as_bytes().to_vec();
+        key.push(0);
+        let offset = match self.strings.windows(key.len()).position(|w| w == key) {
+            Some(offset) => offset,
+            None => {
+                self.strings.extend_from_slice(&key);
+                self.strings.len() - key.len()
+            }
+        };
+        self.token(FDT_PROP);
+        self.structure
+            .extend_from_slice(&(value.len() as u32).to_be_bytes());
+        self.structure
+            .extend_from_slice(&(offset as u32).to_be_bytes());
+        self.padded(value);
+    }
+
+    fn prop_empty(&mut self, name: &str) {
+        self.prop(name, &[]);
+    }
+
+    fn prop_u32(&mut self, name: &str, value: u32) {
+        self.prop_cells(name, &[value]);
+    }
+
+    fn prop_cells(&mut self, name: &str, cells: &[u32]) {
+        let bytes: Vec<u8> = cells.iter().flat_map(|c| c.to_be_bytes()).collect();
+        self.prop(name, &bytes);
+    }
+
+    /// `<base size>` with two cells each, matching #address-cells = #size-cells = 2
+    fn prop_reg(&mut self, base: u64, size: u64) {
+        let cells = [base >> 32, base, size >> 32, size].map(|c| c as u32);
+        self.prop_cells("reg", &cells);
+    }
+
+    /// One or more strings, each NUL-terminated
+    fn prop_strs(&mut self, name: &str, values: &[&str]) {
+        let bytes: Vec<u8> = values
+            .iter()
+            .flat_map(|v| v.bytes().chain(std::iter::once(0)))
+            .collect();
+        self.prop(name, &bytes);
+    }
+
+    /// The complete blob: header, an empty memory reservation map, then the
+    /// structure and strings blocks.
+    fn finish(mut self) -> Vec<u8> {
+        self.token(FDT_END);
+        let rsvmap = HEADER_SIZE as u32;
+        let structure = rsvmap + 16;
+        let strings = structure + self.structure.len() as u32;
+        let total = strings + self.strings.len() as u32;
+        let header = [
+            FDT_MAGIC,
+            total,
+            structure,
+            strings,
+            rsvmap,
+            17, // version
+            16, // last compatible version
+            0,  // boot cpu
+            self.strings.len() as u32,
+            self.structure.len() as u32,
+        ];
+        let mut blob: Vec<u8> = header.iter().flat_map(|w| w.to_be_bytes()).collect();
+        blob.extend_from_slice(&[0; 16]);
+        blob.extend_from_slice(&self.structure);
+        blob.extend_from_slice(&self.strings);
+        blob
+    }
+}
+
+/// A device tree describing `machine` as configured: its RAM, the hart with
+/// the ISA enabled in misa, and each attached device (CLINT, PLIC, UART,
+/// syscon, and a virtio-blk disk if one is on the bus). The UART is the
+/// console.
+pub fn generate_dtb(machine: &Machine) -> Vec<u8> {
+    let mem = &machine.mem;
+    let hartid = machine.cpu.csr.read(0xF14).unwrap_or(0) as u32;
+    let mut w = FdtWriter::default();
+
+    w.begin_node("");
+    w.prop_u32("#address-cells", 2);
+    w.prop_u32("#size-cells", 2);
+    w.prop_strs("compatible", &["riscv-emu"]);
+    w.prop_strs("model", &["riscv-emu"]);
+
+    w.begin_node("chosen");
+    if mem.devices.uart {
+        w.prop_strs("stdout-path", &[&format!("/soc/serial@{:x}", UART_BASE)]);
+    }
+    w.end_node();
+
+    w.begin_node("cpus");
+    w.prop_u32("#address-cells", 1);
+    w.prop_u32("#size-cells", 0);
+    w.prop_u32("timebase-frequency", TIMEBASE_HZ as u32);
+    w.begin_node(&format!("cpu@{:x}", hartid));
+    w.prop_strs("device_type", &["cpu"]);
+    w.prop_u32("reg", hartid);
+    w.prop_strs("status", &["okay"]);
+    w.prop_strs("compatible", &["riscv"]);
+    w.prop_strs("riscv,isa", &[&machine.cpu.csr.isa_string()]);
+    let mmu_type = if machine.config.sv48 {
+        "riscv,sv48"
+    } else {
+        "riscv,sv39"
+    };
+    w.prop_strs("mmu-type", &[mmu_type]);
+    w.begin_node("interrupt-controller");
+    w.prop_u32("#interrupt-cells", 1);
+    w.prop_empty("interrupt-controller");
+    w.prop_strs("compatible", &["riscv,cpu-intc"]);
+    w.prop_u32("phandle", PHANDLE_CPU_INTC);
+    w.end_node();
+    w.end_node();
+    w.end_node();
+
+    w.begin_node(&format!("memory@{:x}", mem.base));
+    w.prop_strs("device_type", &["memory"]);
+    w.prop_reg(mem.base, mem.end_addr() - mem.base);
+    w.end_node();
+
+    w.begin_node("soc");
+    w.prop_u32("#address-cells", 2);
+    w.prop_u32("#size-cells", 2);
+    w.prop_strs("compatible", &["simple-bus"]);
+    w.prop_empty("ranges");
+    if mem.devices.clint {
+        w.begin_node(&format!("clint@{:x}", CLINT_BASE));
+        w.prop_strs("compatible", &["riscv,clint0"]);
+        w.prop_reg(CLINT_BASE, CLINT_SIZE);
+        let intc = PHANDLE_CPU_INTC;
+        w.prop_cells(
+            "interrupts-extended",
+            &[intc, IRQ_M_SOFT, intc, IRQ_M_TIMER],
+        );
+        w.end_node();
+    }
+    if mem.devices.plic {
+        w.begin_node(&format!("plic@{:x}", PLIC_BASE));
+        w.prop_strs("compatible", &["riscv,plic0"]);
+        w.prop_reg(PLIC_BASE, PLIC_SIZE);
+        w.prop_u32("#interrupt-cells", 1);
+        w.prop_empty("interrupt-controller");
+        w.prop_u32("riscv,ndev", NUM_SOURCES as u32 - 1);
+        let intc = PHANDLE_CPU_INTC;
+        w.prop_cells("interrupts-extended", &[intc, IRQ_M_EXT, intc, IRQ_S_EXT]);
+        w.prop_u32("phandle", PHANDLE_PLIC);
+        w.end_node();
+    }
+    if mem.devices.uart {
+        w.begin_node(&format!("serial@{:x}", UART_BASE));
+        w.prop_strs("compatible", &["ns16550a"]);
+        w.prop_reg(UART_BASE, UART_SIZE);
+        w.prop_u32("clock-frequency", 3_686_400);
+        if mem.devices.plic {
+            w.prop_u32("interrupt-parent", PHANDLE_PLIC);
+            w.prop_u32("interrupts", UART_IRQ);
+        }
+        w.end_node();
+    }
+    if mem.devices.syscon {
+        w.begin_node(&format!("test@{:x}", SYSCON_BASE));
+        w.prop_strs("compatible", &["sifive,test1", "sifive,test0", "syscon"]);
+        w.prop_reg(SYSCON_BASE, SYSCON_SIZE);
+        w.end_node();
+    }
+    if mem
+        .bus
+        .ranges()
+        .any(|range| range == (VIRTIO_BASE, VIRTIO_SIZE))
+    {
+        w.begin_node(&format!("virtio_mmio@{:x}", VIRTIO_BASE));
+        w.prop_strs("compatible", &["virtio,mmio"]);
+        w.prop_reg(VIRTIO_BASE, VIRTIO_SIZE);
+        if mem.devices.plic {
+            w.prop_u32("interrupt-parent", PHANDLE_PLIC);
+            w.prop_u32("interrupts", VIRTIO_IRQ);
+        }
+        w.end_node();
+    }
+    w.end_node();
+
+    w.end_node();
+    w.finish()
+}
+
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DtbError {
+    #[error("not a flattened device tree")]
+    BadMagic,
+    #[error("device tree is truncated: header says {expected} bytes, got {actual}")]
+    Truncated { expected: u64, actual: u64 },
+    #[error("device tree address 0x{0:x} isn't 8-byte aligned")]
+    Misaligned(u64),
+    #[error("device tree of {size} bytes at 0x{addr:x} doesn't fit in RAM")]
+    OutOfRam { addr: u64, size: u64 },
+    #[error("device tree at 0x{addr:x} overlaps the loaded image at [0x{start:x}, 0x{end:x})")]
+    Overlap { addr: u64, start: u64, end: u64 },
+}
+
+/// Check `blob` starts with an FDT header and is as long as the header says.
+/// Returns the blob with any trailing bytes cut off.
+pub fn check_header(blob: &[u8]) -> Result<&[u8], DtbError> {
+    let word = |i: usize| u32::from_be_bytes(blob[i..i + 4].try_into().unwrap());
+    if blob.len() < HEADER_SIZE || word(0) != FDT_MAGIC {
+        return Err(DtbError::BadMagic);
+    }
+    let total = word(4) as usize;
+    if total < HEADER_SIZE || total > blob.len() {
+        return Err(DtbError::Truncated {
+            expected: total as u64,
+            actual: blob.len() as u64,
+        });
+    }
+    Ok(&blob[..total])
+}
+
+/// Where `main` puts a DTB of `size` bytes by default: just under where a user
+/// stack would go at the top of RAM, or at the very top if RAM is too small
+/// for both. 8-byte aligned.
+pub fn default_addr(machine: &Machine, size: u64) -> u64 {
+    let end = machine.mem.end_addr();
+    let under_stack = end.saturating_sub(USER_STACK_SIZE).saturating_sub(size) & !0x7;
+    if under_stack >= machine.mem.base {
+        under_stack
+    } else {
+        end.saturating_sub(size) & !0x7
+    }
+}
+
+/// Copy the device tree `blob` into RAM at `addr` and set up the registers
+/// firmware expects at entry: a0 = hartid, a1 = the DTB's address.
+///
+/// The DTB may not overlap anything already loaded (`mem.regions`). Call this
+/// after loading the image and before the first step; `Machine::reset` clears
+/// the registers again.
+pub fn load_dtb(machine: &mut Machine, blob: &[u8], addr: u64) -> Result<(), DtbError> {
+    let blob = check_header(blob)?;
+    let size = blob.len() as u64;
+    if !addr.is_multiple_of(8) {
+        return Err(DtbError::Misaligned(addr));
+    }
+    let end = addr.checked_add(size);
+    if addr < machine.mem.base || end.is_none_or(|end| end > machine.mem.end_addr()) {
+        return Err(DtbError::OutOfRam { addr, size });
+    }
+    if let Some(r) = machine
+        .mem
+        .regions
+        .iter()
+        .find(|r| r.start < addr + size && addr < r.end)
+    {
+        return Err(DtbError::Overlap {
+            addr,
+            start: r.start,
+            end: r.end,
+        });
+    }
+
+    machine
+        .mem
+        .write_bytes_phys(addr, blob)
+        .map_err(|_| DtbError::OutOfRam { addr, size })?;
+    let hartid = machine.cpu.csr.read(0xF14).unwrap_or(0);
+    machine.cpu.set_reg(10, hartid);
+    machine.cpu.set_reg(11, addr);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::{Perms, Region};
+
+    #[derive(Debug, PartialEq)]
+    struct Node {
+        name: String,
+        props: Vec<(String, Vec<u8>)>,
+        children: Vec<Node>,
+    }
+
+    impl Node {
+        fn prop(&self, name: &str) -> &[u8] {
+            let (_, v) = self.props.iter().find(|(n, _)| n == name).unwrap();
+            v
+        }
+
+        fn child(&self, name: &str) -> &Node {
+            self.children.iter().find(|c| c.name == name).unwrap()
+        }
+    }
+
+    /// Parse the structure block of a blob back into its node tree
+    fn parse(blob: &[u8]) -> Node {
+        let word = |i: usize| u32::from_be_bytes(blob[i..i + 4].try_into().unwrap()) as usize;
+        let (structure, strings) = (word(8), word(12));
+        let cstr = |at: usize| {
+            let len = blob[at..].iter().position(|&b| b == 0).unwrap();
+            String::from_utf8(blob[at..at + len].to_vec()).unwrap()
+        };
+
+        let mut stack: Vec<Node> = Vec::new();
+        let mut pos = structure;
+        loop {
+            let token = word(pos) as u32;
+            pos += 4;
+            match token {
+                FDT_BEGIN_NODE => {
+                    let name = cstr(pos);
+                    pos += (name.len() + 1).next_multiple_of(4);
+                    stack.push(Node {
+                        name,
+                        props: Vec::new(),
+                        children: Vec::new(),
+                    });
+                }
+                FDT_PROP => {
+                    let (len, nameoff) = (word(pos), word(pos + 4));
+                    let value = blob[pos + 8..pos + 8 + len].to_vec();
+                    pos += 8 + len.next_multiple_of(4);
+                    let node = stack.last_mut().unwrap();
+                    node.props.push((cstr(strings + nameoff), value));
+                }
+                FDT_END_NODE => {
+                    let node = stack.pop().unwrap();
+                    match stack.last_mut() {
+                        Some(parent) => parent.children.push(node),
+                        None => {
+                            assert_eq!(word(pos) as u32, FDT_END);
+                            return node;
+                        }
+                    }
+                }
+                _ => panic!("unexpected token {} at {}", token, pos - 4),
+            }
+        }
+    }
+
+    #[test]
+    fn test_generated_dtb_parses_back_into_the_machine_tree() {
+        let mut m = Machine::new(0x10_0000);
+        m.mem.devices.syscon = false;
+        let blob = generate_dtb(&m);
+        assert_eq!(check_header(&blob), Ok(blob.as_slice()));
+        let root = parse(&blob);
+
+        let names: Vec<&str> = root.children.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, ["chosen", "cpus", "memory@80000000", "soc"]);
+        let memory = root.child("memory@80000000");
+        assert_eq!(
+            memory.prop("reg"),
+            [0, 0, 0, 0, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0x10, 0, 0]
+        );
+
+        let cpu = root.child("cpus").child("cpu@0");
+        assert_eq!(
+            cpu.prop("riscv,isa"),
+            b"rv64imafdc_zicsr_zifencei\0".as_slice()
+        );
+        assert_eq!(
+            cpu.child("interrupt-controller").prop("phandle"),
+            PHANDLE_CPU_INTC.to_be_bytes()
+        );
+
+        // Only attached devices are described
+        let soc = root.child("soc");
+        let names: Vec<&str> = soc.children.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, ["clint@2000000", "plic@c000000", "serial@10000000"]);
+        assert_eq!(
+            soc.child("serial@10000000").prop("interrupts"),
+            UART_IRQ.to_be_bytes()
+        );
+        assert_eq!(
+            root.child("chosen").prop("stdout-path"),
+            b"/soc/serial@10000000\0".as_slice()
+        );
+    }
+
+    /// Header-only tree of `total` bytes, padded out to `len`
+    fn blob(total: u32, len: usize) -> Vec<u8> {
+        let mut b = vec![0; len];
+        b[0..4].copy_from_slice(&FDT_MAGIC.to_be_bytes());
+        b[4..8].copy_from_slice(&total.to_be_bytes());
+        b[8] = 0xaa;
+        b
+    }
+
+    #[test]
+    fn test_load_dtb_sets_boot_registers() {
+        let big = Machine::new(0x20_0000);
+        let addr = default_addr(&big, 64);
+        assert_eq!(addr, 0x8000_0000 + 0x20_0000 - USER_STACK_SIZE - 64);
+        let mut m = Machine::new(0x10_0000);
+        assert_eq!(default_addr(&m, 64), 0x8000_0000 + 0x10_0000 - 64);
+
+        load_dtb(&mut m, &blob(64, 72), 0x8000_1000).unwrap();
+        assert_eq!(m.cpu.a0(), 0, "hartid");
+        assert_eq!(m.cpu.a1(), 0x8000_1000);
+        assert_eq!(m.mem.read_bytes_phys(0x8000_1000, 9).unwrap()[8], 0xaa);
+        // Bytes past the header's total size aren't copied
+        m.mem.write_bytes_phys(0x8000_1040, &[0x77]).unwrap();
+        load_dtb(&mut m, &blob(64, 72), 0x8000_1000).unwrap();
+        assert_eq!(m.mem.read_u8_phys(0x8000_1040).unwrap(), 0x77);
+    }
+
+    #[test]
+    fn test_load_dtb_rejects_bad_blobs_and_placements() {
+        let mut m = Machine::new(0x10_0000);
+        m.mem.regions.push(Region {
+            start: 0x8000_0000,
+            end: 0x8000_1000,
+            perms: Perms {
+                read: true,
+                write: false,
+                execute: true,
+            },
+        });
+        let good = blob(64, 64);
+
+        let mut bad = good.clone();
+        bad[0] = 0;
+        assert_eq!(load_dtb(&mut m, &bad, 0x8000_2000), Err(DtbError::BadMagic));
+        assert_eq!(
+            load_dtb(&mut m, &blob(128, 64), 0x8000_2000),
+            Err(DtbError::Truncated {
+                expected: 128,
+                actual: 64
+            })
+        );
+        assert_eq!(
+            load_dtb(&mut m, &good, 0x8000_2004),
+            Err(DtbError::Misaligned(0x8000_2004))
+        );
+        assert!(matches!(
+            load_dtb(&mut m, &good, 0x800f_fff8),
+            Err(DtbError::OutOfRam { .. })
+        ));
+        assert_eq!(
+            load_dtb(&mut m, &good, 0x8000_0fc8),
+            Err(DtbError::Overlap {
+                addr: 0x8000_0fc8,
+                start: 0x8000_0000,
+                end: 0x8000_1000
+            })
+        );
+        assert_eq!(m.cpu.a1(), 0, "nothing was loaded");
+    }
+}
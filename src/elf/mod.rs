@@ -1,14 +1,63 @@
-use crate::mem::{MemError, Memory};
+use crate::mem::{MemError, Memory, Perms, Region};
 use goblin::elf::{
     Elf,
     header::{self, ELFCLASS64, ELFDATA2LSB, EM_RISCV, ET_DYN, ET_EXEC},
+    program_header::{PF_R, PF_W, PF_X},
+    section_header, sym,
 };
 use std::fs;
 
+/// What `load_elf_into_memory` put in memory: the entry point and each
+/// PT_LOAD segment's address range with its permissions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoadedImage {
+    pub entry: u64,
+    /// Offset added to every address in the file; nonzero only for ET_DYN
+    pub bias: u64,
+    pub segments: Vec<Region>,
+    /// Address of the program headers, if a segment maps them
+    pub phdr: Option<u64>,
+    pub phnum: u16,
+}
+
+/// Size of the stack `setup_user_stack` reserves at the top of RAM
+pub const USER_STACK_SIZE: u64 = 1 << 20;
+
+// Auxiliary vector keys, from the Linux ABI
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
+const AT_RANDOM: u64 = 25;
+
+// R_RISCV_* dynamic relocation types
+const R_RISCV_NONE: u32 = 0;
+const R_RISCV_RELATIVE: u32 = 3;
+
+/// Load an ELF's PT_LOAD segments into RAM. The segments are also recorded in
+/// `mem.regions`, so code can't be fetched from a segment without PF_X.
+/// Segments must lie in RAM and may not overlap each other or an MMIO window.
+///
+/// A position-independent executable (ET_DYN) is placed so its lowest segment
+/// lands at `mem.base`; use `load_elf_at` to choose the bias instead.
 pub fn load_elf_into_memory(
     path: &str,
     mem: &mut Memory,
-) -> Result<u64, Box<dyn std::error::Error>> {
+) -> Result<LoadedImage, Box<dyn std::error::Error>> {
+    load_elf_at(path, mem, None)
+}
+
+/// Like `load_elf_into_memory`, but an ET_DYN image is shifted by `bias` when
+/// given. The bias is applied to every segment and the entry point, and the
+/// image's R_RISCV_RELATIVE relocations are resolved against it. ET_EXEC
+/// images always load at their linked addresses.
+pub fn load_elf_at(
+    path: &str,
+    mem: &mut Memory,
+    bias: Option<u64>,
+) -> Result<LoadedImage, Box<dyn std::error::Error>> {
     let bytes = fs::read(path)?;
     let elf = Elf::parse(&bytes)?;
 
@@ -27,6 +76,23 @@ pub fn load_elf_into_memory(
     }
 
     let ram_end = mem.end_addr();
+    let mut segments = Vec::new();
+    let mut contents = Vec::new();
+
+    let bias = if elf.header.e_type == ET_DYN {
+        bias.unwrap_or_else(|| {
+            let lowest = elf
+                .program_headers
+                .iter()
+                .filter(|ph| ph.p_type == goblin::elf::program_header::PT_LOAD)
+                .map(|ph| ph.p_vaddr)
+                .min()
+                .unwrap_or(0);
+            mem.base.wrapping_sub(lowest)
+        })
+    } else {
+        0
+    };
 
     // Load PT_LOAD program headers
     for ph in &elf.program_headers {
@@ -35,7 +101,7 @@ pub fn load_elf_into_memory(
         }
         let file_off = ph.p_offset as usize;
         let file_sz = ph.p_filesz as usize;
-        let vaddr = ph.p_vaddr as u64;
+        let vaddr = ph.p_vaddr.wrapping_add(bias);
 
         let end = file_off
             .checked_add(file_sz)
@@ -62,20 +128,315 @@ pub fn load_elf_into_memory(
             .into());
         }
 
-        let seg = &bytes[file_off..file_off + file_sz];
-        mem.write_bytes_phys(vaddr, seg)
+        segments.push(Region {
+            start: vaddr,
+            end: seg_end,
+            perms: Perms {
+                read: ph.p_flags & PF_R != 0,
+                write: ph.p_flags & PF_W != 0,
+                execute: ph.p_flags & PF_X != 0,
+            },
+        });
+        contents.push(&bytes[file_off..end]);
+    }
+
+    // Nothing is written until every segment is known to have a place of its own
+    check_overlaps(&segments, mem)?;
+
+    for (seg, data) in segments.iter().zip(contents) {
+        mem.write_bytes_phys(seg.start, data)
             .map_err(|e: MemError| format!("mem write failed: {e}"))?;
 
         // Zero-fill bss (p_memsz may be larger than p_filesz)
-        let mem_sz = ph.p_memsz as usize;
-        if mem_sz > file_sz {
-            let zeros = vec![0u8; mem_sz - file_sz];
-            mem.write_bytes_phys(vaddr + file_sz as u64, &zeros)
+        let bss = (seg.end - seg.start) as usize - data.len();
+        if bss > 0 {
+            let zeros = vec![0u8; bss];
+            mem.write_bytes_phys(seg.start + data.len() as u64, &zeros)
                 .map_err(|e: MemError| format!("bss write failed: {e}"))?;
         }
     }
 
-    Ok(elf.entry)
+    // Where the program headers land in memory, for AT_PHDR
+    let phoff = elf.header.e_phoff;
+    let mut phdr = None;
+    for ph in &elf.program_headers {
+        if ph.p_type != goblin::elf::program_header::PT_LOAD {
+            continue;
+        }
+        let file_end = ph
+            .p_offset
+            .checked_add(ph.p_filesz)
+            .ok_or("program header file range overflow")?;
+        if (ph.p_offset..file_end).contains(&phoff) {
+            phdr = Some(ph.p_vaddr.wrapping_add(bias) + (phoff - ph.p_offset));
+            break;
+        }
+    }
+
+    // A static PIE relocates itself through R_RISCV_RELATIVE entries only
+    for rela in elf.dynrelas.iter().chain(elf.dynrels.iter()) {
+        match rela.r_type {
+            R_RISCV_NONE => {}
+            R_RISCV_RELATIVE => {
+                let addr = rela.r_offset.wrapping_add(bias);
+                let outside = || format!("relocation outside the image at 0x{addr:x}");
+                let last = addr.checked_add(7).ok_or_else(outside)?;
+                if !segments
+                    .iter()
+                    .any(|s| s.contains(addr) && s.contains(last))
+                {
+                    return Err(outside().into());
+                }
+                let addend = match rela.r_addend {
+                    Some(addend) => addend as u64,
+                    // REL entries keep the addend in place
+                    None => mem.read_u64_phys(addr)?,
+                };
+                mem.write_u64_phys(addr, addend.wrapping_add(bias))?;
+            }
+            other => {
+                return Err(format!("unsupported dynamic relocation type {other}").into());
+            }
+        }
+    }
+
+    mem.regions.extend_from_slice(&segments);
+    Ok(LoadedImage {
+        entry: elf.entry.wrapping_add(bias),
+        bias,
+        segments,
+        phdr,
+        phnum: elf.header.e_phnum,
+    })
+}
+
+/// Copy a raw image (e.g. from `objcopy -O binary`) verbatim into RAM at
+/// `load_addr`, which becomes the entry point. The whole file is recorded as
+/// one RWX segment, since a flat binary carries no permissions.
+pub fn load_flat_binary(
+    path: &str,
+    load_addr: u64,
+    mem: &mut Memory,
+) -> Result<LoadedImage, Box<dyn std::error::Error>> {
+    let bytes = fs::read(path)?;
+    if bytes.is_empty() {
+        return Err("flat binary is empty".into());
+    }
+    let end = load_addr
+        .checked_add(bytes.len() as u64)
+        .ok_or("flat binary runs past the end of the address space")?;
+    let segment = Region {
+        start: load_addr,
+        end,
+        perms: Perms {
+            read: true,
+            write: true,
+            execute: true,
+        },
+    };
+    check_overlaps(std::slice::from_ref(&segment), mem)?;
+    mem.write_bytes_phys(load_addr, &bytes)
+        .map_err(|e: MemError| {
+            format!(
+                "flat binary [0x{load_addr:x},0x{end:x}) not within RAM [0x{:x},0x{:x}): {e}",
+                mem.base,
+                mem.end_addr()
+            )
+        })?;
+
+    mem.regions.push(segment);
+    Ok(LoadedImage {
+        entry: load_addr,
+        bias: 0,
+        segments: vec![segment],
+        phdr: None,
+        phnum: 0,
+    })
+}
+
+/// Reject PT_LOAD segments that share bytes with each other or with an MMIO
+/// window. Loading writes RAM directly, so a later segment would silently
+/// overwrite an earlier one, and bytes under a device window could never be
+/// fetched back. Empty segments occupy nothing and are ignored.
+fn check_overlaps(segments: &[Region], mem: &Memory) -> Result<(), String> {
+    let mut sorted: Vec<_> = segments.iter().filter(|s| s.start < s.end).collect();
+    sorted.sort_by_key(|s| s.start);
+    for pair in sorted.windows(2) {
+        if pair[1].start < pair[0].end {
+            return Err(format!(
+                "segments [0x{:x},0x{:x}) and [0x{:x},0x{:x}) overlap",
+                pair[0].start, pair[0].end, pair[1].start, pair[1].end
+            ));
+        }
+    }
+    for seg in sorted {
+        if let Some((name, base, size)) = mem
+            .mmio_windows()
+            .find(|&(_, base, size)| base < seg.end && seg.start < base + size)
+        {
+            return Err(format!(
+                "segment [0x{:x},0x{:x}) overlaps the {name} at [0x{base:x},0x{:x})",
+                seg.start,
+                seg.end,
+                base + size
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Reserve `USER_STACK_SIZE` bytes at the top of RAM as a non-executable stack
+/// and lay out the initial process stack of the RISC-V Linux ABI on it:
+///
+/// ```text
+/// sp -> argc
+///       argv[0..argc], NULL
+///       envp[..], NULL
+///       auxv (key, value) pairs, AT_NULL
+///       ...
+///       argument and environment strings, AT_RANDOM bytes
+/// ```
+///
+/// Returns the initial sp, 16-byte aligned, for x2, and the stack's bottom.
+pub fn setup_user_stack(
+    mem: &mut Memory,
+    image: &LoadedImage,
+    argv: &[String],
+    envp: &[String],
+) -> Result<UserStack, Box<dyn std::error::Error>> {
+    let top = mem.end_addr() & !0xf;
+    let bottom = top
+        .checked_sub(USER_STACK_SIZE)
+        .filter(|&b| b >= mem.base)
+        .ok_or("RAM too small for the user stack")?;
+    if let Some(seg) = image.segments.iter().find(|s| s.end > bottom) {
+        return Err(format!(
+            "user stack [0x{bottom:x},0x{top:x}) overlaps segment [0x{:x},0x{:x})",
+            seg.start, seg.end
+        )
+        .into());
+    }
+
+    // Strings go at the very top, preceded by 16 bytes for AT_RANDOM. They're
+    // fixed so runs stay reproducible.
+    let random = top - 16;
+    let mut strings = Vec::new();
+    let mut string_offsets = Vec::new();
+    for s in argv.iter().chain(envp) {
+        string_offsets.push(strings.len() as u64);
+        strings.extend_from_slice(s.as_bytes());
+        strings.push(0);
+    }
+    let strings_addr = random
+        .checked_sub(strings.len() as u64)
+        .filter(|&a| a >= bottom)
+        .ok_or("guest arguments don't fit on the user stack")?;
+    let ptrs: Vec<u64> = string_offsets
+        .iter()
+        .map(|off| strings_addr + off)
+        .collect();
+    let (argv_ptrs, envp_ptrs) = ptrs.split_at(argv.len());
+
+    let mut words = vec![argv.len() as u64];
+    words.extend_from_slice(argv_ptrs);
+    words.push(0);
+    words.extend_from_slice(envp_ptrs);
+    words.push(0);
+    if let Some(phdr) = image.phdr {
+        words.extend_from_slice(&[AT_PHDR, phdr, AT_PHENT, 56, AT_PHNUM, image.phnum as u64]);
+    }
+    words.extend_from_slice(&[AT_PAGESZ, 4096, AT_ENTRY, image.entry, AT_RANDOM, random]);
+    words.extend_from_slice(&[AT_NULL, 0]);
+
+    let sp = (strings_addr & !0xf)
+        .checked_sub(8 * words.len() as u64)
+        .map(|sp| sp & !0xf)
+        .filter(|&sp| sp >= bottom)
+        .ok_or("guest arguments don't fit on the user stack")?;
+    let block: Vec<u8> = words.iter().flat_map(|w| w.to_le_bytes()).collect();
+    mem.write_bytes_phys(sp, &block)?;
+    mem.write_bytes_phys(strings_addr, &strings)?;
+    mem.write_bytes_phys(random, &[0x5a; 16])?;
+
+    mem.regions.push(Region {
+        start: bottom,
+        end: top,
+        perms: Perms {
+            read: true,
+            write: true,
+            execute: false,
+        },
+    });
+    Ok(UserStack { sp, bottom })
+}
+
+/// Where `setup_user_stack` put the stack
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UserStack {
+    /// Initial sp, pointing at argc
+    pub sp: u64,
+    /// Lowest address of the stack; a heap must stay below it
+    pub bottom: u64,
+}
+
+/// Code symbols of an ELF, sorted by address, for naming pcs in diagnostics.
+#[derive(Debug, Clone, Default)]
+pub struct SymbolTable {
+    /// (address, size, name); size 0 means unknown
+    symbols: Vec<(u64, u64, String)>,
+}
+
+impl SymbolTable {
+    /// The symbol at or nearest below `addr`, with `addr`'s offset into it.
+    /// A symbol with a known size doesn't claim addresses past its end.
+    pub fn lookup(&self, addr: u64) -> Option<(&str, u64)> {
+        let idx = self.symbols.partition_point(|&(start, _, _)| start <= addr);
+        let (start, size, name) = self.symbols.get(idx.checked_sub(1)?)?;
+        let offset = addr - start;
+        if *size != 0 && offset >= *size {
+            return None;
+        }
+        Some((name, offset))
+    }
+
+    /// `pc=0x...` followed by ` (<symbol>+0x...)` when a symbol covers `pc`.
+    pub fn describe(&self, pc: u64) -> String {
+        match self.lookup(pc) {
+            Some((name, offset)) => format!("pc=0x{pc:016x} ({name}+0x{offset:x})"),
+            None => format!("pc=0x{pc:016x}"),
+        }
+    }
+}
+
+/// Read the function symbols of an ELF, plus untyped labels such as the
+/// `_start` of hand-written assembly, shifted by the image's load `bias`.
+pub fn load_symbols(path: &str, bias: u64) -> Result<SymbolTable, Box<dyn std::error::Error>> {
+    let bytes = fs::read(path)?;
+    let elf = Elf::parse(&bytes)?;
+
+    let mut symbols: Vec<(u64, u64, String)> = elf
+        .syms
+        .iter()
+        .filter(|sym| {
+            matches!(sym.st_type(), sym::STT_FUNC | sym::STT_NOTYPE)
+                && sym.st_shndx != section_header::SHN_UNDEF as usize
+                && sym.st_shndx != section_header::SHN_ABS as usize
+        })
+        .filter_map(|sym| {
+            let name = elf.strtab.get_at(sym.st_name)?;
+            // Skip mapping symbols ($x, $d) and assembler-local labels
+            if name.is_empty() || name.starts_with('$') || name.starts_with(".L") {
+                return None;
+            }
+            Some((
+                sym.st_value.wrapping_add(bias),
+                sym.st_size,
+                name.to_string(),
+            ))
+        })
+        .collect();
+    symbols.sort();
+    Ok(SymbolTable { symbols })
 }
 
 /// Find the address of the "tohost" symbol in an ELF file.
@@ -85,12 +446,331 @@ pub fn find_tohost_symbol(path: &str) -> Result<Option<u64>, Box<dyn std::error:
     let elf = Elf::parse(&bytes)?;
 
     for sym in elf.syms.iter() {
-        if let Some(name) = elf.strtab.get_at(sym.st_name) {
-            if name == "tohost" {
-                return Ok(Some(sym.st_value));
-            }
+        if elf.strtab.get_at(sym.st_name) == Some("tohost") {
+            return Ok(Some(sym.st_value));
         }
     }
 
     Ok(None)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture(name: &str) -> String {
+        format!("{}/tests/{name}", env!("CARGO_MANIFEST_DIR"))
+    }
+
+    #[test]
+    fn test_loaded_image_records_segment_permissions() {
+        let mut mem = Memory::new(0x1000);
+        let image = load_elf_into_memory(&fixture("min.elf"), &mut mem).unwrap();
+
+        let text = Region {
+            start: 0x8000_0000,
+            end: 0x8000_0018,
+            perms: Perms {
+                read: true,
+                write: false,
+                execute: true,
+            },
+        };
+        assert_eq!(image.entry, 0x8000_0000);
+        assert_eq!(image.segments, [text]);
+        assert_eq!(mem.regions, [text]);
+    }
+
+    /// Minimal static PIE linked at 0: one RWX segment holding the headers, a
+    /// dynamic section with a single R_RISCV_RELATIVE, a nop at the entry
+    /// point and the 8-byte slot the relocation fills in.
+    fn pie_elf() -> Vec<u8> {
+        const DYNAMIC: u64 = 0xb0;
+        const RELA: u64 = 0x100;
+        const ENTRY: u64 = 0x118;
+        const SLOT: u64 = 0x120;
+        const SIZE: u64 = 0x128;
+
+        let mut elf = Vec::new();
+        // ELF header
+        elf.extend_from_slice(b"\x7fELF\x02\x01\x01");
+        elf.resize(16, 0);
+        elf.extend_from_slice(&ET_DYN.to_le_bytes());
+        elf.extend_from_slice(&EM_RISCV.to_le_bytes());
+        elf.extend_from_slice(&1u32.to_le_bytes());
+        for v in [ENTRY, 0x40, 0] {
+            elf.extend_from_slice(&v.to_le_bytes()); // e_entry, e_phoff, e_shoff
+        }
+        elf.extend_from_slice(&0u32.to_le_bytes());
+        for v in [64u16, 56, 2, 64, 0, 0] {
+            elf.extend_from_slice(&v.to_le_bytes()); // ehsize, phentsize, phnum, ...
+        }
+        // PT_LOAD and PT_DYNAMIC
+        for (p_type, flags, off, size) in [(1u32, 7u32, 0, SIZE), (2, 6, DYNAMIC, 0x40)] {
+            elf.extend_from_slice(&p_type.to_le_bytes());
+            elf.extend_from_slice(&flags.to_le_bytes());
+            for v in [off, off, off, size, size, 8] {
+                elf.extend_from_slice(&v.to_le_bytes());
+            }
+        }
+        // DT_RELA, DT_RELASZ, DT_RELAENT, DT_NULL
+        for v in [7, RELA, 8, 24, 9, 24, 0, 0] {
+            elf.extend_from_slice(&v.to_le_bytes());
+        }
+        elf.resize(RELA as usize, 0);
+        for v in [SLOT, R_RISCV_RELATIVE as u64, ENTRY] {
+            elf.extend_from_slice(&v.to_le_bytes());
+        }
+        elf.extend_from_slice(&0x0000_0013u32.to_le_bytes()); // nop
+        elf.resize(SIZE as usize, 0);
+        elf
+    }
+
+    fn write_fixture(name: &str, bytes: &[u8]) -> String {
+        let path = std::env::temp_dir().join(format!("riscv-emu-{}-{name}", std::process::id()));
+        fs::write(&path, bytes).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_pie_is_biased_and_relocated() {
+        let path = write_fixture("pie.elf", &pie_elf());
+
+        let mut mem = Memory::new(0x1000);
+        let image = load_elf_into_memory(&path, &mut mem).unwrap();
+        assert_eq!(image.bias, 0x8000_0000);
+        assert_eq!(image.entry, 0x8000_0118);
+        assert_eq!(image.phdr, Some(0x8000_0040));
+        assert_eq!(
+            (image.segments[0].start, image.segments[0].end),
+            (0x8000_0000, 0x8000_0128)
+        );
+        assert_eq!(mem.read_u32_phys(0x8000_0118).unwrap(), 0x0000_0013);
+        assert_eq!(mem.read_u64_phys(0x8000_0120).unwrap(), 0x8000_0118);
+
+        let mut mem = Memory::new(0x10000);
+        let image = load_elf_at(&path, &mut mem, Some(0x8000_4000)).unwrap();
+        assert_eq!(image.entry, 0x8000_4118);
+        assert_eq!(mem.read_u64_phys(0x8000_4120).unwrap(), 0x8000_4118);
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_relocation_at_the_top_of_the_address_space_is_rejected() {
+        let mut elf = pie_elf();
+        // r_offset such that the biased slot's last byte wraps past u64::MAX
+        let r_offset = (u64::MAX - 3).wrapping_sub(0x8000_0000);
+        elf[0x100..0x108].copy_from_slice(&r_offset.to_le_bytes());
+        let path = write_fixture("wrapping-rela.elf", &elf);
+
+        let mut mem = Memory::new(0x1000);
+        let err = load_elf_into_memory(&path, &mut mem).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "relocation outside the image at 0xfffffffffffffffc"
+        );
+
+        fs::remove_file(path).unwrap();
+    }
+
+    /// ET_EXEC with one R+X PT_LOAD per `(vaddr, size)`, segment `i` filled
+    /// with the byte `i + 1`.
+    fn exec_elf(segments: &[(u64, u64)]) -> Vec<u8> {
+        let phnum = segments.len() as u16;
+        let mut elf = Vec::new();
+        elf.extend_from_slice(b"\x7fELF\x02\x01\x01");
+        elf.resize(16, 0);
+        elf.extend_from_slice(&ET_EXEC.to_le_bytes());
+        elf.extend_from_slice(&EM_RISCV.to_le_bytes());
+        elf.extend_from_slice(&1u32.to_le_bytes());
+        for v in [segments[0].0, 0x40, 0] {
+            elf.extend_from_slice(&v.to_le_bytes()); // e_entry, e_phoff, e_shoff
+        }
+        elf.extend_from_slice(&0u32.to_le_bytes());
+        for v in [64u16, 56, phnum, 64, 0, 0] {
+            elf.extend_from_slice(&v.to_le_bytes());
+        }
+        let mut off = 0x40 + 56 * segments.len() as u64;
+        for &(vaddr, size) in segments {
+            elf.extend_from_slice(&1u32.to_le_bytes());
+            elf.extend_from_slice(&(PF_R | PF_X).to_le_bytes());
+            for v in [off, vaddr, vaddr, size, size, 4] {
+                elf.extend_from_slice(&v.to_le_bytes());
+            }
+            off += size;
+        }
+        for (i, &(_, size)) in segments.iter().enumerate() {
+            elf.extend(std::iter::repeat_n(i as u8 + 1, size as usize));
+        }
+        elf
+    }
+
+    #[test]
+    fn test_overlapping_segments_are_rejected_before_loading() {
+        let path = write_fixture(
+            "overlap.elf",
+            &exec_elf(&[(0x8000_0000, 0x100), (0x8000_0080, 0x100)]),
+        );
+        let mut mem = Memory::new(0x1000);
+        let err = load_elf_into_memory(&path, &mut mem).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "segments [0x80000000,0x80000100) and [0x80000080,0x80000180) overlap"
+        );
+        assert_eq!(mem.read_u8_phys(0x8000_0000).unwrap(), 0);
+        assert!(mem.regions.is_empty());
+        fs::remove_file(path).unwrap();
+
+        // Touching end to start is fine
+        let path = write_fixture(
+            "adjacent.elf",
+            &exec_elf(&[(0x8000_0100, 0x100), (0x8000_0000, 0x100)]),
+        );
+        let image = load_elf_into_memory(&path, &mut mem).unwrap();
+        assert_eq!(image.segments.len(), 2);
+        assert_eq!(mem.read_u8_phys(0x8000_00ff).unwrap(), 2);
+        assert_eq!(mem.read_u8_phys(0x8000_0100).unwrap(), 1);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_segment_over_a_device_window_is_rejected() {
+        use crate::clint::{CLINT_BASE, CLINT_SIZE};
+
+        // RAM underneath the CLINT: the loader could write it, but every
+        // access would decode to the device instead
+        let end = CLINT_BASE + CLINT_SIZE;
+        let path = write_fixture("over-clint.elf", &exec_elf(&[(end - 0x80, 0x100)]));
+        let mut mem = Memory::with_base(0x2_0000, CLINT_BASE);
+        let err = load_elf_into_memory(&path, &mut mem).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "segment [0x{:x},0x{:x}) overlaps the CLINT at [0x{CLINT_BASE:x},0x{end:x})",
+                end - 0x80,
+                end + 0x80
+            )
+        );
+
+        fs::remove_file(path).unwrap();
+
+        // Just past the window it loads
+        let path = write_fixture("after-clint.elf", &exec_elf(&[(end, 0x100)]));
+        load_elf_into_memory(&path, &mut mem).unwrap();
+        assert_eq!(mem.read_u8_phys(end).unwrap(), 1);
+        fs::remove_file(path).unwrap();
+
+        // With the CLINT detached its addresses are plain RAM again
+        mem.devices.clint = false;
+        let path = write_fixture("over-clint.elf", &exec_elf(&[(end - 0x80, 0x100)]));
+        load_elf_into_memory(&path, &mut mem).unwrap();
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_flat_binary_is_copied_verbatim() {
+        let path = write_fixture("flat.bin", &[0x13, 0, 0, 0, 0x73, 0, 0x10, 0]);
+        let mut mem = Memory::new(0x2000);
+        let image = load_flat_binary(&path, 0x8000_1000, &mut mem).unwrap();
+        assert_eq!(image.entry, 0x8000_1000);
+        assert_eq!(
+            (image.segments[0].start, image.segments[0].end),
+            (0x8000_1000, 0x8000_1008)
+        );
+        assert_eq!(
+            mem.read_u64_phys(0x8000_1000).unwrap(),
+            0x0010_0073_0000_0013
+        );
+        assert_eq!(mem.regions, image.segments);
+
+        // Must fit entirely within RAM
+        let mut mem = Memory::new(0x2000);
+        assert!(load_flat_binary(&path, 0x8000_1ffc, &mut mem).is_err());
+        assert!(load_flat_binary(&path, 0x7fff_fffc, &mut mem).is_err());
+        assert!(mem.regions.is_empty());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_symbols_name_code_addresses() {
+        let symbols = load_symbols(&fixture("min.elf"), 0).unwrap();
+
+        assert_eq!(symbols.lookup(0x8000_0004), Some(("_start", 4)));
+        assert_eq!(symbols.lookup(0x8000_0008), Some(("loop", 0)));
+        assert_eq!(symbols.lookup(0x7fff_fffc), None);
+        assert_eq!(
+            symbols.describe(0x8000_0010),
+            "pc=0x0000000080000010 (loop+0x8)"
+        );
+        assert_eq!(symbols.describe(0x1000), "pc=0x0000000000001000");
+
+        let biased = load_symbols(&fixture("min.elf"), 0x1000).unwrap();
+        assert_eq!(biased.lookup(0x8000_1014), Some(("done", 0)));
+    }
+
+    fn read_str(mem: &Memory, mut addr: u64) -> String {
+        let mut s = Vec::new();
+        while let Ok(b @ 1..) = mem.read_u8_phys(addr) {
+            s.push(b);
+            addr += 1;
+        }
+        String::from_utf8(s).unwrap()
+    }
+
+    #[test]
+    fn test_user_stack_follows_linux_abi() {
+        let mut mem = Memory::new(2 * USER_STACK_SIZE as usize);
+        let image = LoadedImage {
+            entry: 0x8000_0000,
+            bias: 0,
+            segments: Vec::new(),
+            phdr: Some(0x8000_0040),
+            phnum: 2,
+        };
+        let argv = ["prog".to_string(), "-v".to_string()];
+        let envp = ["HOME=/".to_string()];
+        let sp = setup_user_stack(&mut mem, &image, &argv, &envp).unwrap().sp;
+
+        assert_eq!(sp % 16, 0);
+        let word = |i: u64| mem.read_u64_phys(sp + 8 * i).unwrap();
+        assert_eq!(word(0), 2);
+        assert_eq!(read_str(&mem, word(1)), "prog");
+        assert_eq!(read_str(&mem, word(2)), "-v");
+        assert_eq!(word(3), 0);
+        assert_eq!(read_str(&mem, word(4)), "HOME=/");
+        assert_eq!(word(5), 0);
+
+        let auxv: Vec<(u64, u64)> = (6..)
+            .step_by(2)
+            .map(|i| (word(i), word(i + 1)))
+            .take_while(|&(k, _)| k != AT_NULL)
+            .collect();
+        assert_eq!(
+            &auxv[..5],
+            [
+                (AT_PHDR, 0x8000_0040),
+                (AT_PHENT, 56),
+                (AT_PHNUM, 2),
+                (AT_PAGESZ, 4096),
+                (AT_ENTRY, 0x8000_0000)
+            ]
+        );
+        assert_eq!(auxv[5].0, AT_RANDOM);
+        assert_eq!(auxv.len(), 6);
+
+        let stack = mem.regions.last().unwrap();
+        assert_eq!(stack.end, mem.end_addr());
+        assert!(stack.contains(sp) && !stack.perms.execute);
+    }
+
+    #[test]
+    fn test_user_stack_must_not_overlap_segments() {
+        let mut mem = Memory::new(USER_STACK_SIZE as usize + 0x1000);
+        let mut image = load_elf_into_memory(&fixture("min.elf"), &mut mem).unwrap();
+        assert!(setup_user_stack(&mut mem, &image, &[], &[]).is_ok());
+
+        image.segments[0].end = 0x8000_2000;
+        assert!(setup_user_stack(&mut mem, &image, &[], &[]).is_err());
+    }
+}
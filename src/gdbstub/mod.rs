@@ -0,0 +1,570 @@
+use std::collections::HashSet;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use crate::cpu::{CpuStepResult, HaltReason, Machine};
+use crate::debug::ABI_NAMES;
+
+const INTERRUPT: u8 = 0x03;
+/// Steps between checks for a ^C from gdb while continuing
+const INTERRUPT_POLL: u64 = 4096;
+/// gdb's register number for pc, following x0-x31
+const PC_REGNUM: usize = 32;
+
+const STOP_TRAP: &str = "S05";
+const STOP_INTERRUPT: &str = "S02";
+/// An exception with no handler installed; pc stays at the faulting instruction
+const STOP_FAULT: &str = "S0b";
+const ERR_INVALID: &str = "E01";
+const ERR_MEMORY: &str = "E14";
+
+/// A debugger connection that can be polled for a ^C without blocking.
+pub trait Connection: Read + Write {
+    fn interrupt_pending(&mut self) -> io::Result<bool>;
+}
+
+impl Connection for TcpStream {
+    fn interrupt_pending(&mut self) -> io::Result<bool> {
+        self.set_nonblocking(true)?;
+        let mut byte = [0u8; 1];
+        let peeked = self.peek(&mut byte);
+        self.set_nonblocking(false)?;
+        match peeked {
+            Ok(1) if byte[0] == INTERRUPT => {
+                self.read_exact(&mut byte)?;
+                Ok(true)
+            }
+            Ok(_) => Ok(false),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Block until gdb connects to `port` on localhost.
+pub fn wait_for_gdb(port: u16) -> io::Result<TcpStream> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    let (stream, _) = listener.accept()?;
+    stream.set_nodelay(true)?;
+    Ok(stream)
+}
+
+/// Why a debug session ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionEnd {
+    /// gdb detached; the machine should keep running on its own
+    Detached,
+    /// gdb killed the program or dropped the connection
+    Killed,
+    /// The machine halted while running under gdb
+    Halted(HaltReason),
+}
+
+/// Remote serial protocol stub, so gdb can drive a `Machine` over TCP:
+///
+///   $ riscv-emu --elf prog --gdb 1234
+///   (gdb) target remote :1234
+///
+/// Supports register access (x0-x31 and pc), memory access through the
+/// physical access methods, single-step, continue (interruptible with ^C) and
+/// software breakpoints, which are kept here and checked against pc rather
+/// than patched into guest memory.
+pub struct GdbStub<C> {
+    conn: C,
+    breakpoints: HashSet<u64>,
+}
+
+impl<C: Connection> GdbStub<C> {
+    pub fn new(conn: C) -> Self {
+        Self {
+            conn,
+            breakpoints: HashSet::new(),
+        }
+    }
+
+    /// Serve requests until gdb detaches or kills the session, or the machine
+    /// halts. The machine only runs when gdb asks it to step or continue.
+    pub fn run(&mut self, machine: &mut Machine) -> io::Result<SessionEnd> {
+        while let Some(packet) = self.read_packet()? {
+            if let Some(end) = self.handle(machine, &packet)? {
+                return Ok(end);
+            }
+        }
+        Ok(SessionEnd::Killed)
+    }
+
+    fn handle(&mut self, machine: &mut Machine, packet: &str) -> io::Result<Option<SessionEnd>> {
+        let Some(cmd) = packet.chars().next() else {
+            self.send("")?;
+            return Ok(None);
+        };
+        let args = &packet[cmd.len_utf8()..];
+        let reply = match cmd {
+            '?' => STOP_TRAP.to_string(),
+            'g' => (0..=PC_REGNUM)
+                .map(|n| encode_hex(&read_register(machine, n).to_le_bytes()))
+                .collect(),
+            'G' => match decode_hex(args) {
+                Some(bytes) if bytes.len() == 8 * (PC_REGNUM + 1) => {
+                    for (n, chunk) in bytes.chunks_exact(8).enumerate() {
+                        write_register(machine, n, u64::from_le_bytes(chunk.try_into().unwrap()));
+                    }
+                    "OK".to_string()
+                }
+                _ => ERR_INVALID.to_string(),
+            },
+            'p' => match parse_hex(args) {
+                Some(n) if n as usize <= PC_REGNUM => {
+                    encode_hex(&read_register(machine, n as usize).to_le_bytes())
+                }
+                _ => ERR_INVALID.to_string(),
+            },
+            'P' => match args
+                .split_once('=')
+                .and_then(|(n, v)| Some((parse_hex(n)?, decode_hex(v)?)))
+            {
+                Some((n, v)) if n as usize <= PC_REGNUM && v.len() == 8 => {
+                    write_register(
+                        machine,
+                        n as usize,
+                        u64::from_le_bytes(v.try_into().unwrap()),
+                    );
+                    "OK".to_string()
+                }
+                _ => ERR_INVALID.to_string(),
+            },
+            'm' => match parse_addr_len(args) {
+                Some((addr, len)) => {
+                    let bytes: Result<Vec<u8>, _> = (0..len)
+                        .map(|i| machine.mem.read_u8_phys(addr.wrapping_add(i)))
+                        .collect();
+                    match bytes {
+                        Ok(bytes) => encode_hex(&bytes),
+                        Err(_) => ERR_MEMORY.to_string(),
+                    }
+                }
+                None => ERR_INVALID.to_string(),
+            },
+            'M' => {
+                let parsed = args
+                    .split_once(':')
+                    .and_then(|(range, data)| Some((parse_addr_len(range)?, decode_hex(data)?)));
+                match parsed {
+                    Some(((addr, len), data)) if data.len() as u64 == len => {
+                        match machine.mem.write_bytes_phys(addr, &data) {
+                            Ok(()) => "OK".to_string(),
+                            Err(_) => ERR_MEMORY.to_string(),
+                        }
+                    }
+                    _ => ERR_INVALID.to_string(),
+                }
+            }
+            's' | 'c' => {
+                if !args.is_empty() {
+                    match parse_hex(args) {
+                        Some(pc) => machine.cpu.pc = pc,
+                        None => {
+                            self.send(ERR_INVALID)?;
+                            return Ok(None);
+                        }
+                    }
+                }
+                return self.resume(machine, cmd == 's');
+            }
+            'Z' | 'z' => match args.strip_prefix("0,").and_then(parse_addr_len) {
+                Some((addr, _kind)) => {
+                    if cmd == 'Z' {
+                        self.breakpoints.insert(addr);
+                    } else {
+                        self.breakpoints.remove(&addr);
+                    }
+                    "OK".to_string()
+                }
+                // Only software breakpoints; gdb falls back for the rest
+                None => String::new(),
+            },
+            'q' => self.query(args),
+            'H' => "OK".to_string(),
+            'D' => {
+                self.send("OK")?;
+                return Ok(Some(SessionEnd::Detached));
+            }
+            'k' => return Ok(Some(SessionEnd::Killed)),
+            _ => String::new(),
+        };
+        self.send(&reply)?;
+        Ok(None)
+    }
+
+    fn query(&self, args: &str) -> String {
+        if args.starts_with("Supported") {
+            "PacketSize=1000;qXfer:features:read+".to_string()
+        } else if args == "Attached" {
+            "1".to_string()
+        } else if let Some(range) = args.strip_prefix("Xfer:features:read:target.xml:") {
+            match parse_addr_len(range) {
+                Some((offset, len)) => {
+                    let xml = target_xml();
+                    let start = (offset as usize).min(xml.len());
+                    let end = start.saturating_add(len as usize).min(xml.len());
+                    let more = if end < xml.len() { 'm' } else { 'l' };
+                    format!("{}{}", more, &xml[start..end])
+                }
+                None => ERR_INVALID.to_string(),
+            }
+        } else {
+            String::new()
+        }
+    }
+
+    /// Run one step, or until a breakpoint, a halt, an unhandled trap or a ^C,
+    /// and report the stop to gdb.
+    fn resume(
+        &mut self,
+        machine: &mut Machine,
+        single_step: bool,
+    ) -> io::Result<Option<SessionEnd>> {
+        let mut steps = 0u64;
+        loop {
+            match machine.step() {
+                Ok(()) => {}
+                // The guest asked to restart; keep debugging the new run
+                Err(CpuStepResult::Halt(HaltReason::Reset)) => machine.reset(),
+                // Stop like a breakpoint; the session carries on, and a trap
+                // stopped at is taken by the next step
+                Err(CpuStepResult::Halt(
+                    HaltReason::Watchpoint { .. } | HaltReason::TrapHalt { .. },
+                )) => {
+                    self.send(STOP_TRAP)?;
+                    return Ok(None);
+                }
+                Err(CpuStepResult::Halt(reason)) => {
+                    let reply = match reason {
+                        HaltReason::HostExit { code, .. }
+                        | HaltReason::Poweroff { code }
+                        | HaltReason::Exit { code } => {
+                            format!("W{:02x}", code.min(255))
+                        }
+                        HaltReason::MaxInsns
+                        | HaltReason::Reset
+                        | HaltReason::WfiDeadlock
+                        | HaltReason::Deadlock { .. }
+                        | HaltReason::Watchpoint { .. }
+                        | HaltReason::TrapHalt { .. }
+                        | HaltReason::Trap(_) => "X09".to_string(),
+                    };
+                    self.send(&reply)?;
+                    return Ok(Some(SessionEnd::Halted(reason)));
+                }
+                Err(_) => {
+                    self.send(STOP_FAULT)?;
+                    return Ok(None);
+                }
+            }
+            if single_step || self.breakpoints.contains(&machine.cpu.pc) {
+                self.send(STOP_TRAP)?;
+                return Ok(None);
+            }
+            steps += 1;
+            if steps.is_multiple_of(INTERRUPT_POLL) && self.conn.interrupt_pending()? {
+                self.send(STOP_INTERRUPT)?;
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Next `$payload#checksum` packet, acknowledging it. Acks from gdb and
+    /// stray ^Cs while stopped are skipped. None once the connection closes.
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        loop {
+            match self.read_byte()? {
+                None => return Ok(None),
+                Some(b'$') => {}
+                Some(_) => continue,
+            }
+            let mut payload = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    None => return Ok(None),
+                    Some(b'#') => break,
+                    Some(byte) => payload.push(byte),
+                }
+            }
+            let mut checksum = [0u8; 2];
+            self.conn.read_exact(&mut checksum)?;
+            let expected = std::str::from_utf8(&checksum)
+                .ok()
+                .and_then(|s| u8::from_str_radix(s, 16).ok());
+            if expected != Some(checksum_of(&payload)) {
+                self.conn.write_all(b"-")?;
+                continue;
+            }
+            self.conn.write_all(b"+")?;
+            return Ok(Some(String::from_utf8_lossy(&payload).into_owned()));
+        }
+    }
+
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let mut byte = [0u8; 1];
+        match self.conn.read(&mut byte)? {
+            0 => Ok(None),
+            _ => Ok(Some(byte[0])),
+        }
+    }
+
+    fn send(&mut self, payload: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", payload, checksum_of(payload.as_bytes()));
+        self.conn.write_all(packet.as_bytes())?;
+        self.conn.flush()
+    }
+}
+
+fn read_register(machine: &Machine, n: usize) -> u64 {
+    match n {
+        PC_REGNUM => machine.cpu.pc,
+        _ => machine.cpu.reg(n as u8),
+    }
+}
+
+fn write_register(machine: &mut Machine, n: usize, value: u64) {
+    match n {
+        PC_REGNUM => machine.cpu.pc = value,
+        _ => machine.cpu.set_reg(n as u8, value),
+    }
+}
+
+/// Target description naming the registers in the order `g` reports them.
+fn target_xml() -> String {
+    let mut xml = String::from(
+        "<?xml version=\"1.0\"?>\
+         <!DOCTYPE target SYSTEM \"gdb-target.dtd\">\
+         <target version=\"1.0\">\
+         <architecture>riscv:rv64</architecture>\
+         <feature name=\"org.gnu.gdb.riscv.cpu\">",
+    );
+    for (n, name) in ABI_NAMES.iter().enumerate() {
+        let ty = match n {
+            1 => "code_ptr",
+            2 | 8 => "data_ptr",
+            _ => "int",
+        };
+        xml += &format!(
+            "<reg name=\"{}\" bitsize=\"64\" type=\"{}\" regnum=\"{}\"/>",
+            name, ty, n
+        );
+    }
+    xml += "<reg name=\"pc\" bitsize=\"64\" type=\"code_ptr\" regnum=\"32\"/></feature></target>";
+    xml
+}
+
+fn checksum_of(payload: &[u8]) -> u8 {
+    payload.iter().fold(0u8, |sum, &b| sum.wrapping_add(b))
+}
+
+fn parse_hex(s: &str) -> Option<u64> {
+    u64::from_str_radix(s, 16).ok()
+}
+
+/// `addr,len` as used by memory and breakpoint packets.
+fn parse_addr_len(s: &str) -> Option<(u64, u64)> {
+    let (addr, len) = s.split_once(',')?;
+    Some((parse_hex(addr)?, parse_hex(len)?))
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const RAM: u64 = 0x8000_0000;
+
+    /// gdb's side of the conversation, scripted up front
+    struct Script {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Script {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Script {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Connection for Script {
+        fn interrupt_pending(&mut self) -> io::Result<bool> {
+            Ok(false)
+        }
+    }
+
+    fn packet(payload: &str) -> String {
+        format!("${}#{:02x}", payload, checksum_of(payload.as_bytes()))
+    }
+
+    /// Run a session over `requests` and return the packets the stub replied with.
+    fn session(machine: &mut Machine, requests: &[&str]) -> (SessionEnd, Vec<String>) {
+        let input: String = requests.iter().map(|r| format!("+{}", packet(r))).collect();
+        let mut stub = GdbStub::new(Script {
+            input: Cursor::new(input.into_bytes()),
+            output: Vec::new(),
+        });
+        let end = stub.run(machine).unwrap();
+        let output = String::from_utf8(stub.conn.output).unwrap();
+        let replies = output
+            .split('$')
+            .skip(1)
+            .map(|p| {
+                let (payload, checksum) = p.split_once('#').unwrap();
+                assert_eq!(
+                    &checksum[..2],
+                    format!("{:02x}", checksum_of(payload.as_bytes()))
+                );
+                payload.to_string()
+            })
+            .collect();
+        (end, replies)
+    }
+
+    fn counting_machine() -> Machine {
+        let mut m = Machine::new(0x1000);
+        m.mem.write_u32_phys(RAM, 0x0015_0513).unwrap(); // addi a0, a0, 1
+        m.mem.write_u32_phys(RAM + 4, 0x0015_0513).unwrap(); // addi a0, a0, 1
+        m.mem.write_u32_phys(RAM + 8, 0x0015_0513).unwrap(); // addi a0, a0, 1
+        m.mem.write_u32_phys(RAM + 12, 0xff5f_f06f).unwrap(); // j 0x80000000
+        m
+    }
+
+    #[test]
+    fn test_step_continue_and_breakpoints() {
+        let mut m = counting_machine();
+        let (end, replies) = session(
+            &mut m,
+            &[
+                "?",
+                "s",
+                "p20",
+                "Z0,80000008,4",
+                "c",
+                "pa",
+                "c",
+                "z0,80000008,4",
+                "D",
+            ],
+        );
+        assert_eq!(end, SessionEnd::Detached);
+        assert_eq!(
+            replies,
+            [
+                "S05",
+                "S05",
+                "0400008000000000", // pc after one step, little-endian
+                "OK",
+                "S05",
+                "0200000000000000", // a0
+                "S05",              // around the loop to the breakpoint again
+                "OK",
+                "OK",
+            ]
+        );
+        assert_eq!(m.cpu.a0(), 5);
+        assert_eq!(m.cpu.pc, RAM + 8);
+    }
+
+    #[test]
+    fn test_register_and_memory_access() {
+        let mut m = counting_machine();
+        m.cpu.set_reg(2, 0x8000_0800);
+        let (_, replies) = session(
+            &mut m,
+            &[
+                "g",
+                "P5=efbeadde00000000",
+                "m80000000,4",
+                "M80000100,2:3412",
+                "m80000100,2",
+                "m0,4",
+                "qXfer:features:read:target.xml:0,20",
+                "k",
+            ],
+        );
+        let regs = &replies[0];
+        assert_eq!(regs.len(), 33 * 16);
+        assert_eq!(&regs[2 * 16..3 * 16], "0008008000000000", "sp");
+        assert_eq!(&regs[32 * 16..], "0000008000000000", "pc");
+        assert_eq!(replies[1], "OK");
+        assert_eq!(m.cpu.reg(5), 0xdead_beef);
+        assert_eq!(replies[2], "13051500");
+        assert_eq!(replies[3], "OK");
+        assert_eq!(replies[4], "3412");
+        assert_eq!(m.mem.read_u16_phys(RAM + 0x100).unwrap(), 0x1234);
+        assert_eq!(replies[5], ERR_MEMORY, "nothing mapped at 0");
+        assert_eq!(replies[6], "m<?xml version=\"1.0\"?><!DOCTYPE t");
+    }
+
+    #[test]
+    fn test_trap_halt_stops_and_continues_into_the_handler() {
+        let mut m = Machine::new(0x1000);
+        m.halt_on_trap = 1 << 11; // ECALL from M-mode
+        m.cpu.csr.mtvec = RAM + 0x100;
+        m.mem.write_u32_phys(RAM, 0x0000_0073).unwrap(); // ecall
+        m.mem.write_u32_phys(RAM + 0x100, 0x0000_0013).unwrap(); // nop
+        let (end, replies) = session(&mut m, &["c", "p20", "Z0,80000100,4", "c", "p20", "k"]);
+        assert_eq!(end, SessionEnd::Killed);
+        assert_eq!(
+            replies,
+            [
+                "S05",
+                "0000008000000000", // still at the ecall
+                "OK",
+                "S05",
+                "0001008000000000", // in the handler
+            ]
+        );
+        assert_eq!(m.cpu.csr.mepc, RAM);
+    }
+
+    #[test]
+    fn test_non_ascii_command_is_unsupported() {
+        let mut m = counting_machine();
+        let (_, replies) = session(&mut m, &["\u{fffd}x", "é", "k"]);
+        assert_eq!(replies, ["", ""]);
+    }
+
+    #[test]
+    fn test_host_exit_ends_the_session() {
+        let mut m = Machine::new(0x1000);
+        m.host_exit_addr = Some(RAM + 0x104);
+        m.mem.write_u32_phys(RAM, 0x00f0_0293).unwrap(); // li t0, 15
+        m.mem.write_u32_phys(RAM + 4, 0x0000_0317).unwrap(); // auipc t1, 0
+        m.mem.write_u32_phys(RAM + 8, 0x1053_2023).unwrap(); // sw t0, 0x100(t1)
+        let (end, replies) = session(&mut m, &["c"]);
+        assert_eq!(
+            end,
+            SessionEnd::Halted(HaltReason::HostExit { code: 7, gp: 0 })
+        );
+        assert_eq!(replies, ["W07"]);
+    }
+}
@@ -1,6 +1,17 @@
+pub mod bus;
+pub mod clint;
 pub mod cpu;
 pub mod csr;
 pub mod debug;
+pub mod dtb;
 pub mod elf;
+pub mod gdbstub;
 pub mod mem;
 pub mod mmu;
+pub mod plic;
+pub mod pmp;
+pub mod proxy;
+pub mod snapshot;
+pub mod syscon;
+pub mod uart;
+pub mod virtio;
@@ -1,15 +1,50 @@
-use clap::Parser;
+use clap::{ArgGroup, Parser, ValueEnum};
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum TraceMode {
+    /// One line per instruction with a few key registers
+    Compact,
+    /// All 32 registers plus the trap CSRs; much slower on long runs
+    Full,
+}
 
 #[derive(Parser, Debug)]
+#[command(group(ArgGroup::new("image").required(true).args(["elf", "bin"])))]
+#[command(
+    after_help = "Exit status: the guest's own exit code (0-123), 124 when \
+--max-insns runs out, 125 when the guest crashes or the run fails, 126 when the \
+program can't be loaded."
+)]
 struct Args {
     /// Path to a RISC-V ELF to load (statically linked is easiest at first)
     #[arg(long)]
-    elf: String,
+    elf: Option<String>,
+
+    /// Raw binary image (e.g. from `objcopy -O binary`) to load instead of an ELF
+    #[arg(long)]
+    bin: Option<String>,
+
+    /// Where to load the --bin image and start executing (default: the RAM base)
+    #[arg(long, value_parser = parse_addr, conflicts_with = "elf")]
+    load_addr: Option<u64>,
 
     /// RAM size in MiB
     #[arg(long, default_value_t = 256)]
     ram_mib: usize,
 
+    /// Extensions the hart implements, e.g. rv64gc or rv64imac
+    #[arg(long, default_value = "rv64gc", value_parser = parse_isa)]
+    isa: u64,
+
+    /// Implement Sv48 four-level paging as well as Sv39
+    #[arg(long, default_value_t = false)]
+    sv48: bool,
+
+    /// Raise a page fault on a PTE whose A bit (or D bit, on a store) is clear
+    /// instead of setting the bit
+    #[arg(long, default_value_t = false)]
+    pte_ad_fault: bool,
+
     /// Stop after N instructions (0 = run forever)
     #[arg(long, default_value_t = 0)]
     max_insns: u64,
@@ -17,58 +52,457 @@ struct Args {
     /// Enable instruction trace
     #[arg(long, default_value_t = false)]
     trace: bool,
+
+    /// Trace format
+    #[arg(long, value_enum, default_value_t = TraceMode::Compact)]
+    trace_mode: TraceMode,
+
+    /// Write one JSON object per retired instruction to this file
+    #[arg(long)]
+    trace_json: Option<String>,
+
+    /// Check every retired instruction against a reference trace (Spike's
+    /// --log-commits output, or lines of `pc reg=value...`) and stop at the
+    /// first divergence
+    #[arg(long)]
+    ref_trace: Option<String>,
+
+    /// Cache decoded instructions by pc (experimental)
+    #[arg(long, default_value_t = false)]
+    decode_cache: bool,
+
+    /// Execute cached straight-line blocks of decoded instructions; interrupts
+    /// are only taken between blocks (experimental)
+    #[arg(long, default_value_t = false)]
+    blocks: bool,
+
+    /// Count executed instructions by kind and print a histogram at exit
+    #[arg(long, default_value_t = false)]
+    profile: bool,
+
+    /// Count traps taken by cause and print a summary at exit
+    #[arg(long, default_value_t = false)]
+    trap_stats: bool,
+
+    /// Argument passed to the guest program after its name (repeatable)
+    #[arg(long = "arg", allow_hyphen_values = true)]
+    guest_args: Vec<String>,
+
+    /// Wait for gdb on this TCP port and start halted under its control
+    #[arg(long)]
+    gdb: Option<u16>,
+
+    /// Sleep while the guest idles in WFI instead of spinning; mtime then
+    /// follows the host clock, so runs aren't deterministic
+    #[arg(long, default_value_t = false)]
+    sleep_on_wfi: bool,
+
+    /// Stop with an error once an instruction has jumped to itself N times in
+    /// a row, as in `j .` (0 = off; spin-waits on an interrupt look the same)
+    #[arg(long, default_value_t = 0)]
+    deadlock_after: u64,
+
+    /// Stop as soon as an exception of this kind is raised, before the guest's
+    /// handler runs (repeatable): illegal, breakpoint, ecall, misaligned,
+    /// access-fault, page-fault, or a cause number. Under gdb, the stop is a
+    /// SIGTRAP and continuing enters the handler.
+    #[arg(long, value_parser = parse_trap_causes)]
+    halt_on_trap: Vec<u64>,
+
+    /// Same as `--halt-on-trap ecall`
+    #[arg(long)]
+    halt_on_ecall: bool,
+
+    /// Flattened device tree to load for firmware; a0 gets the hartid and a1
+    /// its address at entry. Without one, a tree describing this machine is
+    /// generated.
+    #[arg(long)]
+    dtb: Option<String>,
+
+    /// Where to load the DTB (default: just under the user stack at the top of RAM)
+    #[arg(long, value_parser = parse_addr)]
+    dtb_addr: Option<u64>,
+
+    /// Run the program in U-mode and serve its Linux syscalls (read, write,
+    /// close, fstat, brk, exit) on the host instead of trapping. Bypasses the
+    /// guest's own privilege and trap handling, so it suits newlib programs
+    /// rather than kernels.
+    #[arg(long, default_value_t = false, conflicts_with_all = ["dtb", "dtb_addr", "gdb"])]
+    proxy: bool,
+
+    /// Disk image to expose read-only as a virtio-blk device at 0x1000_1000
+    #[arg(long)]
+    disk: Option<String>,
+}
+
+/// Parse an address given in hex with a 0x prefix, or in decimal
+fn parse_addr(s: &str) -> Result<u64, String> {
+    let parsed = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(&hex.replace('_', ""), 16),
+        None => s.parse(),
+    };
+    parsed.map_err(|e| format!("invalid address '{}': {}", s, e))
+}
+
+fn parse_isa(s: &str) -> Result<u64, String> {
+    riscv_emu::csr::parse_isa(s).map_err(|e| e.to_string())
+}
+
+/// Parse a `--halt-on-trap` kind into a mask of exception causes.
+fn parse_trap_causes(s: &str) -> Result<u64, String> {
+    use riscv_emu::cpu::trap::causes::*;
+    let causes: &[u64] = match s {
+        "illegal" => &[ILLEGAL_INSTRUCTION],
+        "breakpoint" => &[BREAKPOINT],
+        "ecall" => &[ECALL_U, ECALL_S, ECALL_M],
+        "misaligned" => &[
+            INSTRUCTION_ADDRESS_MISALIGNED,
+            LOAD_ADDRESS_MISALIGNED,
+            STORE_ADDRESS_MISALIGNED,
+        ],
+        "access-fault" => &[
+            INSTRUCTION_ACCESS_FAULT,
+            LOAD_ACCESS_FAULT,
+            STORE_ACCESS_FAULT,
+        ],
+        "page-fault" => &[INSTRUCTION_PAGE_FAULT, LOAD_PAGE_FAULT, STORE_PAGE_FAULT],
+        _ => match s.parse::<u64>() {
+            Ok(cause) if cause < 64 => return Ok(1 << cause),
+            _ => return Err(format!("unknown trap '{}'", s)),
+        },
+    };
+    Ok(causes.iter().fold(0, |mask, &cause| mask | (1 << cause)))
+}
+
+/// Exit status when `--max-insns` runs out before the guest exits
+const EXIT_TIMEOUT: i32 = 124;
+/// Exit status when the guest crashes on an unhandled trap or the run fails
+const EXIT_ERROR: i32 = 125;
+/// Exit status when the --elf or --bin image can't be read or loaded
+const EXIT_LOAD: i32 = 126;
+
+/// Report an image that couldn't be loaded and exit with `EXIT_LOAD`.
+fn exit_load_failed(path: &str, e: impl std::fmt::Display) -> ! {
+    eprintln!("{}: {}", path, e);
+    std::process::exit(EXIT_LOAD);
 }
 
+/// Exit status: 0 when the guest passes through HTIF or the syscon, else its
+/// failure code (clamped below `EXIT_TIMEOUT`), `EXIT_TIMEOUT`, `EXIT_ERROR`
+/// or `EXIT_LOAD`.
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = Args::parse();
 
     let ram_bytes = args.ram_mib * 1024 * 1024;
-    let mut machine = riscv_emu::cpu::Machine::new(ram_bytes);
+    let mut machine = riscv_emu::cpu::Machine::builder()
+        .ram_size(ram_bytes)
+        .isa(args.isa)
+        .sv48(args.sv48)
+        .pte_ad_mode(if args.pte_ad_fault {
+            riscv_emu::mmu::PteAdMode::Fault
+        } else {
+            riscv_emu::mmu::PteAdMode::Update
+        })
+        .build()?;
     machine.max_insns = args.max_insns;
+    machine.sleep_on_wfi = args.sleep_on_wfi;
+    machine.deadlock_after = args.deadlock_after;
+    machine.halt_on_trap = args
+        .halt_on_trap
+        .iter()
+        .fold(0, |mask, &causes| mask | causes);
+    if args.halt_on_ecall {
+        machine.halt_on_trap |= parse_trap_causes("ecall")?;
+    }
+    if args.decode_cache {
+        machine.decode_cache = Some(riscv_emu::cpu::decode_cache::DecodeCache::default());
+    }
+    if args.blocks {
+        machine.block_cache = Some(riscv_emu::cpu::block_cache::BlockCache::new());
+    }
+    if args.profile {
+        machine.profile = Some(riscv_emu::debug::profile::Profile::new());
+    }
+    if args.trap_stats {
+        machine.trap_stats = Some(riscv_emu::debug::trap_stats::TrapStats::new());
+    }
+    // The proxy kernel reads stdin itself
+    if !args.proxy {
+        machine.mem.uart.attach_stdin();
+    }
+    if let Some(path) = &args.disk {
+        use riscv_emu::virtio::{VIRTIO_BASE, VIRTIO_SIZE, VirtioBlk};
+
+        let disk = VirtioBlk::open(path).map_err(|e| format!("{}: {}", path, e))?;
+        machine
+            .mem
+            .attach_device(VIRTIO_BASE, VIRTIO_SIZE, Box::new(disk))?;
+    }
+
+    let (path, loaded) = match (&args.elf, &args.bin) {
+        (Some(path), _) => (
+            path,
+            riscv_emu::elf::load_elf_into_memory(path, &mut machine.mem),
+        ),
+        (None, Some(path)) => {
+            let load_addr = args.load_addr.unwrap_or(machine.mem.base);
+            (
+                path,
+                riscv_emu::elf::load_flat_binary(path, load_addr, &mut machine.mem),
+            )
+        }
+        (None, None) => unreachable!("clap requires --elf or --bin"),
+    };
+    let image = loaded.unwrap_or_else(|e| exit_load_failed(path, e));
+    machine.reset_vector = image.entry;
+    machine.cpu.pc = image.entry;
+
+    // argc/argv/envp for user programs. Bare-metal code sets its own sp and
+    // keeps all of RAM, so the stack is only built when something will use it.
+    let stack = if args.proxy || !args.guest_args.is_empty() {
+        let argv: Vec<String> = std::iter::once(path.clone())
+            .chain(args.guest_args.iter().cloned())
+            .collect();
+        match riscv_emu::elf::setup_user_stack(&mut machine.mem, &image, &argv, &[]) {
+            Ok(stack) => {
+                machine.reset_sp = Some(stack.sp);
+                machine.cpu.set_reg(2, stack.sp);
+                Some(stack)
+            }
+            Err(e) => {
+                eprintln!("warning: no user stack: {}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
 
-    let entry = riscv_emu::elf::load_elf_into_memory(&args.elf, &mut machine.mem)?;
-    machine.cpu.pc = entry;
+    if args.proxy {
+        use riscv_emu::csr::PrivMode;
 
-    // Check for tohost symbol (used by RISC-V tests)
-    if let Some(tohost) = riscv_emu::elf::find_tohost_symbol(&args.elf)? {
-        machine.host_exit_addr = Some(tohost);
-        println!("Found tohost at 0x{:016x}", tohost);
+        // Like a proxy kernel would: U-mode with the FPU and counters usable,
+        // and a heap from the end of the image up to the stack
+        machine.reset_priv = PrivMode::User;
+        machine.cpu.csr.priv_mode = PrivMode::User;
+        machine.cpu.csr.mstatus |= 1 << 13; // FS = Initial
+        machine.cpu.csr.mcounteren = 0b111;
+        machine.cpu.csr.scounteren = 0b111;
+        let brk = image
+            .segments
+            .iter()
+            .map(|s| s.end)
+            .max()
+            .unwrap_or(image.entry);
+        machine.proxy = Some(riscv_emu::proxy::Proxy::new(
+            brk.next_multiple_of(4096),
+            stack.map_or(machine.mem.end_addr(), |stack| stack.bottom),
+        ));
+    } else {
+        // Without --dtb, describe the machine as configured
+        let blob = match &args.dtb {
+            Some(path) => std::fs::read(path).map_err(|e| format!("{}: {}", path, e))?,
+            None => riscv_emu::dtb::generate_dtb(&machine),
+        };
+        let dtb_addr = args
+            .dtb_addr
+            .unwrap_or_else(|| riscv_emu::dtb::default_addr(&machine, blob.len() as u64));
+        riscv_emu::dtb::load_dtb(&mut machine, &blob, dtb_addr)?;
     }
 
-    // sanity check
-    println!("Loaded ELF entry point at 0x{:016x}", entry);
+    // A flat binary has no symbols, so no tohost either
+    let symbols = match &args.elf {
+        Some(elf) => {
+            // Check for tohost symbol (used by RISC-V tests)
+            let tohost = riscv_emu::elf::find_tohost_symbol(elf)
+                .unwrap_or_else(|e| exit_load_failed(elf, e));
+            if let Some(tohost) = tohost {
+                machine.host_exit_addr = Some(tohost);
+                println!("Found tohost at 0x{:016x}", tohost);
+            }
+
+            // sanity check
+            println!("Loaded ELF entry point at 0x{:016x}", image.entry);
+            riscv_emu::elf::load_symbols(elf, image.bias)
+                .unwrap_or_else(|e| exit_load_failed(elf, e))
+        }
+        None => {
+            println!("Loaded binary at 0x{:016x}", image.entry);
+            riscv_emu::elf::SymbolTable::default()
+        }
+    };
+    let mut json_trace = match &args.trace_json {
+        Some(path) => Some(riscv_emu::debug::json_trace::JsonTrace::new(
+            std::fs::File::create(path).map_err(|e| format!("{}: {}", path, e))?,
+        )),
+        None => None,
+    };
+    let mut ref_trace = match &args.ref_trace {
+        Some(path) => Some(riscv_emu::debug::ref_trace::RefChecker::new(
+            std::io::BufReader::new(
+                std::fs::File::open(path).map_err(|e| format!("{}: {}", path, e))?,
+            ),
+        )),
+        None => None,
+    };
+    let halted = run(
+        &mut machine,
+        &args,
+        &symbols,
+        json_trace.as_mut(),
+        ref_trace.as_mut(),
+    );
+
+    // process::exit skips destructors, so flush the trace here
+    if let (Some(trace), Some(path)) = (json_trace, &args.trace_json)
+        && let Err(e) = trace.finish()
+    {
+        eprintln!("{}: {}", path, e);
+    }
+
+    if let Some(profile) = &machine.profile {
+        eprint!("{}", profile.report());
+    }
+    if let Some(stats) = &machine.trap_stats {
+        eprint!("{}", stats.report());
+    }
+    std::process::exit(match halted {
+        Ok(Some(reason)) => report_halt(reason),
+        Ok(None) => 0,
+        Err(e) => {
+            eprintln!("{}", e);
+            EXIT_ERROR
+        }
+    });
+}
+
+/// Run the loaded program, under gdb if requested, until it halts (returning
+/// why), hits an error such as an unhandled trap, or the debugger kills it.
+fn run(
+    machine: &mut riscv_emu::cpu::Machine,
+    args: &Args,
+    symbols: &riscv_emu::elf::SymbolTable,
+    mut json_trace: Option<&mut riscv_emu::debug::json_trace::JsonTrace<std::fs::File>>,
+    mut ref_trace: Option<
+        &mut riscv_emu::debug::ref_trace::RefChecker<std::io::BufReader<std::fs::File>>,
+    >,
+) -> Result<Option<riscv_emu::cpu::HaltReason>, Box<dyn std::error::Error>> {
+    if let Some(port) = args.gdb {
+        println!("Waiting for gdb on localhost:{}", port);
+        let stream = riscv_emu::gdbstub::wait_for_gdb(port)?;
+        match riscv_emu::gdbstub::GdbStub::new(stream).run(machine)? {
+            // Carry on without the debugger
+            riscv_emu::gdbstub::SessionEnd::Detached => {}
+            riscv_emu::gdbstub::SessionEnd::Killed => return Ok(None),
+            riscv_emu::gdbstub::SessionEnd::Halted(reason) => return Ok(Some(reason)),
+        }
+    }
 
-    // Minimal convention: x0 hardwired, others start 0.
-    // You can also set up a stack pointer later if you want for your own test programs.
+    // Minimal convention: x0 hardwired, sp at the user stack, others start 0.
     loop {
         if args.trace {
-            riscv_emu::debug::trace(&machine.cpu, machine.executed);
+            match args.trace_mode {
+                TraceMode::Compact => {
+                    let instr = machine.peek_instr();
+                    riscv_emu::debug::trace(&machine.cpu, machine.executed, instr.as_ref());
+                }
+                TraceMode::Full => riscv_emu::debug::trace_full(&machine.cpu, machine.executed),
+            }
+        }
+
+        if let Some(trace) = json_trace.as_deref_mut() {
+            trace.before(machine);
+        }
+        if let Some(checker) = ref_trace.as_deref_mut() {
+            checker.before(&machine.cpu);
         }
 
-        // fetch-decode-execute
-        // handle halting conditions
-        match machine.step() {
-            Err(riscv_emu::cpu::CpuStepResult::Halt(reason)) => {
+        // Tracing needs control back after every instruction
+        let budget = if args.trace || json_trace.is_some() || ref_trace.is_some() {
+            1
+        } else {
+            0
+        };
+        let outcome = machine.run(budget);
+        if let (Some(trace), Some(path)) = (json_trace.as_deref_mut(), &args.trace_json) {
+            trace
+                .after(&machine.cpu)
+                .map_err(|e| format!("{}: {}", path, e))?;
+        }
+        if let Some(checker) = ref_trace.as_deref_mut() {
+            checker.after(&machine.cpu)?;
+            if checker.finished() {
+                eprintln!(
+                    "Reference trace ended; {} instructions matched",
+                    checker.retired()
+                );
+                ref_trace = None;
+            }
+        }
+        match outcome {
+            riscv_emu::cpu::StepOutcome::Continued => {}
+            riscv_emu::cpu::StepOutcome::Halted(riscv_emu::cpu::HaltReason::Trap(trap)) => {
+                return Err(format!(
+                    "CPU error: {}\nAt {}, gp(x3)=0x{:x}",
+                    trap,
+                    symbols.describe(trap.pc()),
+                    machine.cpu.gp()
+                )
+                .into());
+            }
+            riscv_emu::cpu::StepOutcome::Halted(riscv_emu::cpu::HaltReason::Deadlock { pc }) => {
+                return Err(format!(
+                    "CPU stuck in a jump-to-self loop\nAt {}",
+                    symbols.describe(pc)
+                )
+                .into());
+            }
+            riscv_emu::cpu::StepOutcome::Halted(
+                reason @ riscv_emu::cpu::HaltReason::TrapHalt { pc, .. },
+            ) => {
+                eprintln!(
+                    "Stopped before the trap handler; state at {}:",
+                    symbols.describe(pc)
+                );
+                riscv_emu::debug::trace_full(&machine.cpu, machine.executed);
+                return Ok(Some(reason));
+            }
+            riscv_emu::cpu::StepOutcome::Halted(riscv_emu::cpu::HaltReason::Reset) => {
+                println!("CPU reset");
+                machine.reset();
+            }
+            riscv_emu::cpu::StepOutcome::Halted(reason) => {
                 if args.trace {
                     eprintln!(
                         "Final state: gp(x3)=0x{:x} ({})",
-                        machine.cpu.regs[3], machine.cpu.regs[3]
+                        machine.cpu.gp(),
+                        machine.cpu.gp()
                     );
                 }
-                println!("CPU halted: {}", reason);
-                break;
-            }
-            Err(e) => {
-                eprintln!("CPU error: {}", e);
-                eprintln!(
-                    "At PC: 0x{:016x}, gp(x3)=0x{:x}",
-                    machine.cpu.pc, machine.cpu.regs[3]
-                );
-                break;
+                return Ok(Some(reason));
             }
-            Ok(()) => {}
         }
     }
+}
 
-    Ok(())
+/// Print why the machine halted and pick the process exit status for it.
+fn report_halt(reason: riscv_emu::cpu::HaltReason) -> i32 {
+    println!("CPU halted: {}", reason);
+    match reason {
+        // Exit statuses are 8 bits; keep any failure nonzero and apart from
+        // the emulator's own statuses
+        riscv_emu::cpu::HaltReason::HostExit { code, .. }
+        | riscv_emu::cpu::HaltReason::Poweroff { code }
+        | riscv_emu::cpu::HaltReason::Exit { code } => code.min(EXIT_TIMEOUT as u64 - 1) as i32,
+        // run() restarts the machine instead of stopping
+        riscv_emu::cpu::HaltReason::Reset => 0,
+        // Neither will ever finish
+        riscv_emu::cpu::HaltReason::MaxInsns | riscv_emu::cpu::HaltReason::WfiDeadlock => {
+            EXIT_TIMEOUT
+        }
+        riscv_emu::cpu::HaltReason::Trap(_)
+        | riscv_emu::cpu::HaltReason::Deadlock { .. }
+        | riscv_emu::cpu::HaltReason::Watchpoint { .. }
+        | riscv_emu::cpu::HaltReason::TrapHalt { .. } => EXIT_ERROR,
+    }
 }
@@ -1,6 +1,17 @@
+pub mod ram;
+
+use crate::bus::{Bus, BusError, Device, GuestRam};
+use crate::clint::{CLINT_BASE, CLINT_SIZE, Clint};
+use crate::plic::{PLIC_BASE, PLIC_SIZE, Plic};
+use crate::pmp::Pmp;
+use crate::syscon::{SYSCON_BASE, SYSCON_SIZE, Syscon};
+use crate::uart::{UART_BASE, UART_SIZE, Uart};
+use ram::Ram;
+use std::cell::Cell;
+use std::collections::HashSet;
 use thiserror::Error;
 
-#[derive(Error, Debug)]
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum MemError {
     #[error("address out of range: 0x{0:x}")]
     Oob(u64),
@@ -10,24 +21,205 @@ pub enum MemError {
     LoadPageFault(u64),
     #[error("store page fault at address: 0x{0:x}")]
     StorePageFault(u64),
+    #[error("instruction access fault at address: 0x{0:x}")]
+    InstructionAccessFault(u64),
+    #[error("load access fault at address: 0x{0:x}")]
+    LoadAccessFault(u64),
+    #[error("store access fault at address: 0x{0:x}")]
+    StoreAccessFault(u64),
+    #[error("load address misaligned: 0x{0:x}")]
+    LoadMisaligned(u64),
+    #[error("store address misaligned: 0x{0:x}")]
+    StoreMisaligned(u64),
+}
+
+impl MemError {
+    /// Access fault matching the access type, reporting `vaddr`.
+    pub fn access_fault(vaddr: u64, is_fetch: bool, is_write: bool) -> Self {
+        if is_fetch {
+            MemError::InstructionAccessFault(vaddr)
+        } else if is_write {
+            MemError::StoreAccessFault(vaddr)
+        } else {
+            MemError::LoadAccessFault(vaddr)
+        }
+    }
+
+    /// The address the error is about.
+    pub fn addr(&self) -> u64 {
+        match *self {
+            MemError::Oob(addr)
+            | MemError::InstructionPageFault(addr)
+            | MemError::LoadPageFault(addr)
+            | MemError::StorePageFault(addr)
+            | MemError::InstructionAccessFault(addr)
+            | MemError::LoadAccessFault(addr)
+            | MemError::StoreAccessFault(addr)
+            | MemError::LoadMisaligned(addr)
+            | MemError::StoreMisaligned(addr) => addr,
+        }
+    }
+
+    /// A physical access that no RAM or device claims becomes an access fault
+    /// for the original access, reported at its virtual address.
+    pub fn into_access_fault(self, vaddr: u64, is_fetch: bool, is_write: bool) -> Self {
+        match self {
+            MemError::Oob(_) => Self::access_fault(vaddr, is_fetch, is_write),
+            other => other,
+        }
+    }
+}
+
+/// Which memory-mapped devices respond on the bus. A detached device keeps its
+/// state, but its address window no longer decodes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Devices {
+    pub clint: bool,
+    pub plic: bool,
+    pub uart: bool,
+    pub syscon: bool,
+}
+
+impl Default for Devices {
+    fn default() -> Self {
+        Self {
+            clint: true,
+            plic: true,
+            uart: true,
+            syscon: true,
+        }
+    }
+}
+
+const PAGE_SIZE: u64 = 4096;
+
+/// Whether a `size`-byte access at `vaddr` runs into the next 4 KiB page, so
+/// its two parts must be translated separately. Only misaligned accesses can.
+pub fn crosses_page(vaddr: u64, size: u64) -> bool {
+    (vaddr & (PAGE_SIZE - 1)) + size > PAGE_SIZE
+}
+
+/// Whether data accesses made at `priv_mode` are big-endian, per mstatus.MBE,
+/// SBE or UBE. Instruction fetches are always little-endian.
+pub fn big_endian(priv_mode: crate::csr::PrivMode, mstatus: u64) -> bool {
+    use crate::csr::{CsrFile, PrivMode};
+    let bit = match priv_mode {
+        PrivMode::Machine => CsrFile::MSTATUS_MBE,
+        PrivMode::Supervisor => CsrFile::MSTATUS_SBE,
+        PrivMode::User => CsrFile::MSTATUS_UBE,
+    };
+    mstatus & bit != 0
+}
+
+/// Access permissions of a region of physical memory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Perms {
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool,
+}
+
+/// Physical address range `[start, end)` with the permissions it was loaded with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Region {
+    pub start: u64,
+    pub end: u64,
+    pub perms: Perms,
+}
+
+impl Region {
+    pub fn contains(&self, paddr: u64) -> bool {
+        (self.start..self.end).contains(&paddr)
+    }
+}
+
+/// Physical range `[addr, addr + len)` to watch for RAM reads and/or writes.
+/// Instruction fetches and the `*_bytes_phys` loader paths aren't watched.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub addr: u64,
+    pub len: u64,
+    pub on_write: bool,
+    pub on_read: bool,
+}
+
+/// An access that matched a watchpoint: its physical address and the bytes
+/// there before and after it, little-endian. A page-crossing access reports
+/// the part on one page; values are truncated to 8 bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WatchHit {
+    pub addr: u64,
+    pub old: u64,
+    pub new: u64,
+}
+
+/// Up to the first 8 of `bytes` as a little-endian value
+fn le_prefix(bytes: &[u8]) -> u64 {
+    bytes
+        .iter()
+        .take(8)
+        .rev()
+        .fold(0, |acc, &b| (acc << 8) | b as u64)
 }
 
 pub struct Memory {
-    data: Vec<u8>,
+    data: Ram,
     pub base: u64,
+    pub clint: Clint,
+    pub plic: Plic,
+    pub uart: Uart,
+    pub syscon: Syscon,
+    pub devices: Devices,
+    /// Further memory-mapped devices, added with `attach_device`
+    pub bus: Bus,
+    /// Emulate misaligned loads/stores instead of raising address-misaligned traps
+    pub allow_misaligned: bool,
+    /// Regions with recorded permissions, e.g. the segments of a loaded ELF.
+    /// Fetching from one without execute permission is an access fault, with
+    /// or without paging; addresses outside every region are unrestricted.
+    pub regions: Vec<Region>,
+    /// Copy of the hart's PMP configuration, refreshed by `Machine::step`
+    pub pmp: Pmp,
+    /// Physical ranges whose RAM accesses are reported through `take_watch_hit`
+    pub watchpoints: Vec<Watchpoint>,
+    watch_hit: Cell<Option<WatchHit>>,
+    /// Physical pages holding instructions in the decode cache. A store to
+    /// one sets `code_written` so the cache can be flushed.
+    code_pages: HashSet<u64>,
+    code_written: bool,
 }
 
 impl Memory {
     pub fn new(bytes: usize) -> Self {
+        // around the typical RISC-V physical memory base
+        Self::with_base(bytes, 0x8000_0000)
+    }
+
+    /// RAM of `bytes` bytes starting at physical address `base`.
+    pub fn with_base(bytes: usize, base: u64) -> Self {
         Self {
-            data: vec![0; bytes],
-            base: 0x8000_0000, // around the typical RISC-V physical memory base
+            data: Ram::new(bytes),
+            base,
+            clint: Clint::new(),
+            plic: Plic::new(),
+            uart: Uart::new(),
+            syscon: Syscon::new(),
+            devices: Devices::default(),
+            bus: Bus::new(),
+            allow_misaligned: false,
+            regions: Vec::new(),
+            pmp: Pmp::default(),
+            watchpoints: Vec::new(),
+            watch_hit: Cell::new(None),
+            code_pages: HashSet::new(),
+            code_written: false,
         }
     }
 
     /// Translate a virtual address to physical address.
     /// With Sv39 enabled, performs page table walk through MMU.
     /// Otherwise returns identity mapping (bare mode).
+    #[allow(clippy::too_many_arguments)]
     pub fn translate_addr(
         &mut self,
         vaddr: u64,
@@ -35,19 +227,23 @@ impl Memory {
         is_fetch: bool,
         is_write: bool,
         priv_mode: crate::csr::PrivMode,
+        mstatus: u64,
         mmu: &mut crate::mmu::Mmu,
     ) -> Result<u64, MemError> {
-        mmu.translate(vaddr, satp, is_fetch, is_write, priv_mode, self)
-            .map_err(|trap| match trap {
-                crate::cpu::trap::Trap::InstructionPageFault { addr, .. } => {
-                    MemError::InstructionPageFault(addr)
-                }
-                crate::cpu::trap::Trap::LoadPageFault { addr, .. } => MemError::LoadPageFault(addr),
-                crate::cpu::trap::Trap::StorePageFault { addr, .. } => {
-                    MemError::StorePageFault(addr)
-                }
-                _ => MemError::Oob(vaddr),
-            })
+        mmu.translate(vaddr, satp, is_fetch, is_write, priv_mode, mstatus, self)
+    }
+
+    /// Reject accesses that aren't naturally aligned, unless misaligned
+    /// accesses are being emulated.
+    pub fn check_alignment(&self, vaddr: u64, size: u64, is_write: bool) -> Result<(), MemError> {
+        if self.allow_misaligned || vaddr.is_multiple_of(size) {
+            return Ok(());
+        }
+        if is_write {
+            Err(MemError::StoreMisaligned(vaddr))
+        } else {
+            Err(MemError::LoadMisaligned(vaddr))
+        }
     }
 
     fn check_oob(&self, addr: u64, size: u64) -> Result<usize, MemError> {
@@ -59,56 +255,245 @@ impl Memory {
         Ok(a as usize)
     }
 
+    /// Apply PMP to a `size`-byte access at `paddr`, made at `priv_mode` for the
+    /// access at `vaddr`. A denied access is an access fault.
+    pub fn check_pmp(
+        &self,
+        paddr: u64,
+        size: u64,
+        is_fetch: bool,
+        is_write: bool,
+        priv_mode: crate::csr::PrivMode,
+        vaddr: u64,
+    ) -> Result<(), MemError> {
+        if self.pmp.check(paddr, size, is_fetch, is_write, priv_mode) {
+            Ok(())
+        } else {
+            Err(MemError::access_fault(vaddr, is_fetch, is_write))
+        }
+    }
+
+    /// Deny instruction fetches from a region that isn't executable.
+    fn check_exec(&self, paddr: u64, vaddr: u64) -> Result<(), MemError> {
+        match self.regions.iter().find(|r| r.contains(paddr)) {
+            Some(r) if !r.perms.execute => Err(MemError::InstructionAccessFault(vaddr)),
+            _ => Ok(()),
+        }
+    }
+
+    /// The device decoding `paddr`, with the offset into its window. The
+    /// built-in devices come first, then anything attached to `bus`.
+    fn device_at(&self, paddr: u64) -> Option<(&dyn Device, u64)> {
+        if self.devices.clint && Clint::contains(paddr) {
+            return Some((&self.clint, paddr - CLINT_BASE));
+        }
+        if self.devices.plic && Plic::contains(paddr) {
+            return Some((&self.plic, paddr - PLIC_BASE));
+        }
+        if self.devices.uart && Uart::contains(paddr) {
+            return Some((&self.uart, paddr - UART_BASE));
+        }
+        if self.devices.syscon && Syscon::contains(paddr) {
+            return Some((&self.syscon, paddr - SYSCON_BASE));
+        }
+        self.bus.find(paddr)
+    }
+
+    fn device_at_mut(&mut self, paddr: u64) -> Option<(&mut dyn Device, u64)> {
+        if self.devices.clint && Clint::contains(paddr) {
+            return Some((&mut self.clint, paddr - CLINT_BASE));
+        }
+        if self.devices.plic && Plic::contains(paddr) {
+            return Some((&mut self.plic, paddr - PLIC_BASE));
+        }
+        if self.devices.uart && Uart::contains(paddr) {
+            return Some((&mut self.uart, paddr - UART_BASE));
+        }
+        if self.devices.syscon && Syscon::contains(paddr) {
+            return Some((&mut self.syscon, paddr - SYSCON_BASE));
+        }
+        self.bus.find_mut(paddr)
+    }
+
+    /// Map `device` at `[base, base + size)` on the bus. The window may not
+    /// overlap RAM, an attached built-in device or another bus device.
+    pub fn attach_device(
+        &mut self,
+        base: u64,
+        size: u64,
+        device: Box<dyn Device>,
+    ) -> Result<(), BusError> {
+        let end = base.saturating_add(size);
+        let taken = self
+            .builtin_windows()
+            .chain([("RAM", self.base, self.data.len() as u64)])
+            .any(|(_, start, len)| start < end && base < start + len);
+        if taken && size != 0 {
+            return Err(BusError::Overlap { base, end });
+        }
+        self.bus.attach(base, size, device)
+    }
+
+    /// `(name, base, size)` of the attached built-in devices.
+    fn builtin_windows(&self) -> impl Iterator<Item = (&'static str, u64, u64)> + use<> {
+        [
+            ("CLINT", self.devices.clint, CLINT_BASE, CLINT_SIZE),
+            ("PLIC", self.devices.plic, PLIC_BASE, PLIC_SIZE),
+            ("UART", self.devices.uart, UART_BASE, UART_SIZE),
+            ("SYSCON", self.devices.syscon, SYSCON_BASE, SYSCON_SIZE),
+        ]
+        .into_iter()
+        .filter(|&(_, attached, _, _)| attached)
+        .map(|(name, _, base, size)| (name, base, size))
+    }
+
+    /// `(name, base, size)` of every window that decodes as MMIO rather than
+    /// RAM: the attached built-in devices, then the bus devices in address order.
+    pub fn mmio_windows(&self) -> impl Iterator<Item = (&'static str, u64, u64)> + '_ {
+        self.builtin_windows().chain(
+            self.bus
+                .ranges()
+                .map(|(base, size)| ("bus device", base, size)),
+        )
+    }
+
+    /// Run the deferred work of devices on the bus, giving them RAM for DMA.
+    pub fn service_devices(&mut self) {
+        let mut ram = GuestRam::new(self.base, &mut self.data);
+        self.bus.service(&mut ram);
+    }
+
     // ========== Physical Address Access (internal use) ==========
-    // These methods bypass translation and access physical memory directly
+    // These methods bypass translation and access physical memory (or MMIO) directly
+
+    /// `N`-byte little-endian physical read. Fetches don't trigger read
+    /// watchpoints.
+    fn read_phys<const N: usize>(&self, paddr: u64, is_fetch: bool) -> Result<u64, MemError> {
+        if let Some((device, offset)) = self.device_at(paddr) {
+            return Ok(device.read(offset, N as u64));
+        }
+        let off = self.check_oob(paddr, N as u64)?;
+        let mut b = [0u8; 8];
+        self.data.read(off, &mut b[..N]);
+        let v = u64::from_le_bytes(b);
+        if !is_fetch {
+            self.note_watch(paddr, N as u64, false, v, v);
+        }
+        Ok(v)
+    }
+
+    /// `N`-byte little-endian physical write of the low bytes of `v`.
+    fn write_phys<const N: usize>(&mut self, paddr: u64, v: u64) -> Result<(), MemError> {
+        if let Some((device, offset)) = self.device_at_mut(paddr) {
+            device.write(offset, N as u64, v);
+            return Ok(());
+        }
+        let off = self.check_oob(paddr, N as u64)?;
+        if !self.watchpoints.is_empty() {
+            let mut old = [0u8; 8];
+            self.data.read(off, &mut old[..N]);
+            self.note_watch(paddr, N as u64, true, u64::from_le_bytes(old), v);
+        }
+        self.note_code_write(paddr, N as u64);
+        self.data.write(off, &v.to_le_bytes()[..N]);
+        Ok(())
+    }
+
+    /// `N`-byte little-endian read of RAM for a debugger or tracer: devices
+    /// aren't decoded, since reading one can change its state, and watchpoints
+    /// don't fire.
+    fn peek_phys<const N: usize>(&self, paddr: u64) -> Result<u64, MemError> {
+        let off = self.check_oob(paddr, N as u64)?;
+        let mut b = [0u8; 8];
+        self.data.read(off, &mut b[..N]);
+        Ok(u64::from_le_bytes(b))
+    }
+
+    pub fn peek_u64_phys(&self, paddr: u64) -> Result<u64, MemError> {
+        self.peek_phys::<8>(paddr)
+    }
 
     pub fn read_u32_phys(&self, paddr: u64) -> Result<u32, MemError> {
-        let off = self.check_oob(paddr, 4)?;
-        let b = &self.data[off..off + 4];
-        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        self.read_phys::<4>(paddr, false).map(|v| v as u32)
     }
 
     pub fn read_u64_phys(&self, paddr: u64) -> Result<u64, MemError> {
-        let off = self.check_oob(paddr, 8)?;
-        let b = &self.data[off..off + 8];
-        Ok(u64::from_le_bytes([
-            b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7],
-        ]))
+        self.read_phys::<8>(paddr, false)
     }
 
     pub fn write_u32_phys(&mut self, paddr: u64, v: u32) -> Result<(), MemError> {
-        let off = self.check_oob(paddr, 4)?;
-        self.data[off..off + 4].copy_from_slice(&v.to_le_bytes());
-        Ok(())
+        self.write_phys::<4>(paddr, v as u64)
     }
 
     pub fn write_u64_phys(&mut self, paddr: u64, v: u64) -> Result<(), MemError> {
-        let off = self.check_oob(paddr, 8)?;
-        self.data[off..off + 8].copy_from_slice(&v.to_le_bytes());
-        Ok(())
+        self.write_phys::<8>(paddr, v)
     }
 
     pub fn read_u8_phys(&self, paddr: u64) -> Result<u8, MemError> {
-        let off = self.check_oob(paddr, 1)?;
-        Ok(self.data[off])
+        self.read_phys::<1>(paddr, false).map(|v| v as u8)
     }
 
     pub fn write_u8_phys(&mut self, paddr: u64, v: u8) -> Result<(), MemError> {
-        let off = self.check_oob(paddr, 1)?;
-        self.data[off] = v;
-        Ok(())
+        self.write_phys::<1>(paddr, v as u64)
     }
 
     pub fn write_u16_phys(&mut self, paddr: u64, v: u16) -> Result<(), MemError> {
-        let off = self.check_oob(paddr, 2)?;
-        self.data[off..off + 2].copy_from_slice(&v.to_le_bytes());
-        Ok(())
+        self.write_phys::<2>(paddr, v as u64)
     }
 
     pub fn read_u16_phys(&self, paddr: u64) -> Result<u16, MemError> {
-        let off = self.check_oob(paddr, 2)?;
-        let b = &self.data[off..off + 2];
-        Ok(u16::from_le_bytes([b[0], b[1]]))
+        self.read_phys::<2>(paddr, false).map(|v| v as u16)
+    }
+
+    /// Record the first access this step that matches a watchpoint. `old` and
+    /// `new` are the accessed bytes before and after, little-endian (equal for
+    /// a read).
+    fn note_watch(&self, paddr: u64, size: u64, is_write: bool, old: u64, new: u64) {
+        if self.watch_hit.get().is_some() {
+            return;
+        }
+        let hit = self.watchpoints.iter().any(|w| {
+            (if is_write { w.on_write } else { w.on_read })
+                && paddr < w.addr.saturating_add(w.len)
+                && w.addr < paddr + size
+        });
+        if hit {
+            self.watch_hit.set(Some(WatchHit {
+                addr: paddr,
+                old,
+                new,
+            }));
+        }
+    }
+
+    /// Watch the page containing `paddr` for stores; see `take_code_written`.
+    pub fn track_code_page(&mut self, paddr: u64) {
+        self.code_pages.insert(paddr / PAGE_SIZE);
+    }
+
+    pub fn clear_code_pages(&mut self) {
+        self.code_pages.clear();
+        self.code_written = false;
+    }
+
+    /// Whether a store has hit a tracked code page since the last call.
+    pub fn take_code_written(&mut self) -> bool {
+        std::mem::take(&mut self.code_written)
+    }
+
+    fn note_code_write(&mut self, paddr: u64, size: u64) {
+        if self.code_pages.is_empty() || size == 0 {
+            return;
+        }
+        let last = paddr.saturating_add(size - 1) / PAGE_SIZE;
+        if (paddr / PAGE_SIZE..=last).any(|page| self.code_pages.contains(&page)) {
+            self.code_written = true;
+        }
+    }
+
+    /// The watchpoint hit recorded since the last call, if any.
+    pub fn take_watch_hit(&self) -> Option<WatchHit> {
+        self.watch_hit.take()
     }
 
     // ========== Virtual Address Access (public API) ==========
@@ -119,23 +504,76 @@ impl Memory {
         vaddr: u64,
         satp: u64,
         priv_mode: crate::csr::PrivMode,
+        mstatus: u64,
         mmu: &mut crate::mmu::Mmu,
     ) -> Result<u32, MemError> {
-        let paddr = self.translate_addr(vaddr, satp, false, false, priv_mode, mmu)?;
-        self.read_u32_phys(paddr)
+        self.check_alignment(vaddr, 4, false)?;
+        let v = if crosses_page(vaddr, 4) {
+            self.read_split(vaddr, 4, satp, priv_mode, mstatus, mmu)? as u32
+        } else {
+            let paddr = self.translate_addr(vaddr, satp, false, false, priv_mode, mstatus, mmu)?;
+            self.check_pmp(paddr, 4, false, false, priv_mode, vaddr)?;
+            self.read_u32_phys(paddr)
+                .map_err(|err| err.into_access_fault(vaddr, false, false))?
+        };
+        Ok(if big_endian(priv_mode, mstatus) {
+            v.swap_bytes()
+        } else {
+            v
+        })
     }
 
     /// Instruction fetch path: translation must enforce execute permission (X bit),
     /// not data load permission (R bit).
-    pub fn read_u32_exec(
+    pub fn fetch_u32(
         &mut self,
         vaddr: u64,
         satp: u64,
         priv_mode: crate::csr::PrivMode,
+        mstatus: u64,
         mmu: &mut crate::mmu::Mmu,
     ) -> Result<u32, MemError> {
-        let paddr = self.translate_addr(vaddr, satp, true, false, priv_mode, mmu)?;
-        self.read_u32_phys(paddr)
+        let paddr = self.translate_addr(vaddr, satp, true, false, priv_mode, mstatus, mmu)?;
+        self.check_pmp(paddr, 4, true, false, priv_mode, vaddr)?;
+        self.check_exec(paddr, vaddr)?;
+        self.read_phys::<4>(paddr, true)
+            .map(|v| v as u32)
+            .map_err(|err| err.into_access_fault(vaddr, true, false))
+    }
+
+    /// Fetch one 16-bit instruction parcel; RVC only guarantees 2-byte pc alignment.
+    pub fn fetch_u16(
+        &mut self,
+        vaddr: u64,
+        satp: u64,
+        priv_mode: crate::csr::PrivMode,
+        mstatus: u64,
+        mmu: &mut crate::mmu::Mmu,
+    ) -> Result<u16, MemError> {
+        let paddr = self.translate_addr(vaddr, satp, true, false, priv_mode, mstatus, mmu)?;
+        self.check_pmp(paddr, 2, true, false, priv_mode, vaddr)?;
+        self.check_exec(paddr, vaddr)?;
+        self.read_phys::<2>(paddr, true)
+            .map(|v| v as u16)
+            .map_err(|err| err.into_access_fault(vaddr, true, false))
+    }
+
+    /// Like `fetch_u16`, but without side effects: translation goes through
+    /// `Mmu::probe` and only RAM is read (see `peek_phys`).
+    pub fn peek_fetch_u16(
+        &self,
+        vaddr: u64,
+        satp: u64,
+        priv_mode: crate::csr::PrivMode,
+        mstatus: u64,
+        mmu: &crate::mmu::Mmu,
+    ) -> Result<u16, MemError> {
+        let paddr = mmu.probe(vaddr, satp, true, false, priv_mode, mstatus, self)?;
+        self.check_pmp(paddr, 2, true, false, priv_mode, vaddr)?;
+        self.check_exec(paddr, vaddr)?;
+        self.peek_phys::<2>(paddr)
+            .map(|v| v as u16)
+            .map_err(|err| err.into_access_fault(vaddr, true, false))
     }
 
     pub fn read_u64(
@@ -143,10 +581,23 @@ impl Memory {
         vaddr: u64,
         satp: u64,
         priv_mode: crate::csr::PrivMode,
+        mstatus: u64,
         mmu: &mut crate::mmu::Mmu,
     ) -> Result<u64, MemError> {
-        let paddr = self.translate_addr(vaddr, satp, false, false, priv_mode, mmu)?;
-        self.read_u64_phys(paddr)
+        self.check_alignment(vaddr, 8, false)?;
+        let v = if crosses_page(vaddr, 8) {
+            self.read_split(vaddr, 8, satp, priv_mode, mstatus, mmu)?
+        } else {
+            let paddr = self.translate_addr(vaddr, satp, false, false, priv_mode, mstatus, mmu)?;
+            self.check_pmp(paddr, 8, false, false, priv_mode, vaddr)?;
+            self.read_u64_phys(paddr)
+                .map_err(|err| err.into_access_fault(vaddr, false, false))?
+        };
+        Ok(if big_endian(priv_mode, mstatus) {
+            v.swap_bytes()
+        } else {
+            v
+        })
     }
 
     pub fn write_u32(
@@ -155,10 +606,23 @@ impl Memory {
         v: u32,
         satp: u64,
         priv_mode: crate::csr::PrivMode,
+        mstatus: u64,
         mmu: &mut crate::mmu::Mmu,
     ) -> Result<(), MemError> {
-        let paddr = self.translate_addr(vaddr, satp, false, true, priv_mode, mmu)?;
+        self.check_alignment(vaddr, 4, true)?;
+        let v = if big_endian(priv_mode, mstatus) {
+            v.swap_bytes()
+        } else {
+            v
+        };
+        if crosses_page(vaddr, 4) {
+            let bytes = v.to_le_bytes();
+            return self.write_bytes(vaddr, &bytes, satp, priv_mode, mstatus, mmu);
+        }
+        let paddr = self.translate_addr(vaddr, satp, false, true, priv_mode, mstatus, mmu)?;
+        self.check_pmp(paddr, 4, false, true, priv_mode, vaddr)?;
         self.write_u32_phys(paddr, v)
+            .map_err(|err| err.into_access_fault(vaddr, false, true))
     }
 
     pub fn write_u64(
@@ -167,10 +631,23 @@ impl Memory {
         v: u64,
         satp: u64,
         priv_mode: crate::csr::PrivMode,
+        mstatus: u64,
         mmu: &mut crate::mmu::Mmu,
     ) -> Result<(), MemError> {
-        let paddr = self.translate_addr(vaddr, satp, false, true, priv_mode, mmu)?;
+        self.check_alignment(vaddr, 8, true)?;
+        let v = if big_endian(priv_mode, mstatus) {
+            v.swap_bytes()
+        } else {
+            v
+        };
+        if crosses_page(vaddr, 8) {
+            let bytes = v.to_le_bytes();
+            return self.write_bytes(vaddr, &bytes, satp, priv_mode, mstatus, mmu);
+        }
+        let paddr = self.translate_addr(vaddr, satp, false, true, priv_mode, mstatus, mmu)?;
+        self.check_pmp(paddr, 8, false, true, priv_mode, vaddr)?;
         self.write_u64_phys(paddr, v)
+            .map_err(|err| err.into_access_fault(vaddr, false, true))
     }
 
     pub fn read_u8(
@@ -178,10 +655,13 @@ impl Memory {
         vaddr: u64,
         satp: u64,
         priv_mode: crate::csr::PrivMode,
+        mstatus: u64,
         mmu: &mut crate::mmu::Mmu,
     ) -> Result<u8, MemError> {
-        let paddr = self.translate_addr(vaddr, satp, false, false, priv_mode, mmu)?;
+        let paddr = self.translate_addr(vaddr, satp, false, false, priv_mode, mstatus, mmu)?;
+        self.check_pmp(paddr, 1, false, false, priv_mode, vaddr)?;
         self.read_u8_phys(paddr)
+            .map_err(|err| err.into_access_fault(vaddr, false, false))
     }
 
     pub fn write_u8(
@@ -190,10 +670,13 @@ impl Memory {
         v: u8,
         satp: u64,
         priv_mode: crate::csr::PrivMode,
+        mstatus: u64,
         mmu: &mut crate::mmu::Mmu,
     ) -> Result<(), MemError> {
-        let paddr = self.translate_addr(vaddr, satp, false, true, priv_mode, mmu)?;
+        let paddr = self.translate_addr(vaddr, satp, false, true, priv_mode, mstatus, mmu)?;
+        self.check_pmp(paddr, 1, false, true, priv_mode, vaddr)?;
         self.write_u8_phys(paddr, v)
+            .map_err(|err| err.into_access_fault(vaddr, false, true))
     }
 
     pub fn write_u16(
@@ -202,10 +685,23 @@ impl Memory {
         v: u16,
         satp: u64,
         priv_mode: crate::csr::PrivMode,
+        mstatus: u64,
         mmu: &mut crate::mmu::Mmu,
     ) -> Result<(), MemError> {
-        let paddr = self.translate_addr(vaddr, satp, false, true, priv_mode, mmu)?;
+        self.check_alignment(vaddr, 2, true)?;
+        let v = if big_endian(priv_mode, mstatus) {
+            v.swap_bytes()
+        } else {
+            v
+        };
+        if crosses_page(vaddr, 2) {
+            let bytes = v.to_le_bytes();
+            return self.write_bytes(vaddr, &bytes, satp, priv_mode, mstatus, mmu);
+        }
+        let paddr = self.translate_addr(vaddr, satp, false, true, priv_mode, mstatus, mmu)?;
+        self.check_pmp(paddr, 2, false, true, priv_mode, vaddr)?;
         self.write_u16_phys(paddr, v)
+            .map_err(|err| err.into_access_fault(vaddr, false, true))
     }
 
     pub fn read_u16(
@@ -213,10 +709,71 @@ impl Memory {
         vaddr: u64,
         satp: u64,
         priv_mode: crate::csr::PrivMode,
+        mstatus: u64,
         mmu: &mut crate::mmu::Mmu,
     ) -> Result<u16, MemError> {
-        let paddr = self.translate_addr(vaddr, satp, false, false, priv_mode, mmu)?;
-        self.read_u16_phys(paddr)
+        self.check_alignment(vaddr, 2, false)?;
+        let v = if crosses_page(vaddr, 2) {
+            self.read_split(vaddr, 2, satp, priv_mode, mstatus, mmu)? as u16
+        } else {
+            let paddr = self.translate_addr(vaddr, satp, false, false, priv_mode, mstatus, mmu)?;
+            self.check_pmp(paddr, 2, false, false, priv_mode, vaddr)?;
+            self.read_u16_phys(paddr)
+                .map_err(|err| err.into_access_fault(vaddr, false, false))?
+        };
+        Ok(if big_endian(priv_mode, mstatus) {
+            v.swap_bytes()
+        } else {
+            v
+        })
+    }
+
+    /// Write `bytes` starting at `vaddr`, translating each page it touches
+    /// separately. A fault is reported at the first address of the failing
+    /// page's part of the range, and nothing is written.
+    /// Check that `write_bytes` of `len` bytes at `vaddr` would succeed,
+    /// without writing anything, so a caller can fault before producing the
+    /// data. Translation fills the TLB and sets A/D bits as the store would.
+    #[allow(clippy::too_many_arguments)]
+    pub fn probe_write(
+        &mut self,
+        vaddr: u64,
+        len: usize,
+        satp: u64,
+        priv_mode: crate::csr::PrivMode,
+        mstatus: u64,
+        mmu: &mut crate::mmu::Mmu,
+    ) -> Result<(), MemError> {
+        self.translate_store(vaddr, len, satp, priv_mode, mstatus, mmu)
+            .map(|_| ())
+    }
+
+    /// Translate a `len`-byte store at `vaddr` page by page, as
+    /// `(paddr, RAM offset, offset into the data, length)` chunks.
+    fn translate_store(
+        &mut self,
+        vaddr: u64,
+        len: usize,
+        satp: u64,
+        priv_mode: crate::csr::PrivMode,
+        mstatus: u64,
+        mmu: &mut crate::mmu::Mmu,
+    ) -> Result<Vec<(u64, usize, usize, usize)>, MemError> {
+        let mut chunks = Vec::new();
+        let mut done = 0;
+        while done < len {
+            let addr = vaddr.wrapping_add(done as u64);
+            let in_page = PAGE_SIZE - (addr & (PAGE_SIZE - 1));
+            let chunk = (len - done).min(in_page as usize);
+            let paddr = self.translate_addr(addr, satp, false, true, priv_mode, mstatus, mmu)?;
+            self.check_pmp(paddr, chunk as u64, false, true, priv_mode, addr)?;
+            let off = self
+                .check_oob(paddr, chunk as u64)
+                .map_err(|err| err.into_access_fault(addr, false, true))?;
+            chunks.push((paddr, off, done, chunk));
+            done += chunk;
+        }
+        Ok(chunks)
     }
 
     pub fn write_bytes(
@@ -225,23 +782,287 @@ impl Memory {
         bytes: &[u8],
         satp: u64,
         priv_mode: crate::csr::PrivMode,
+        mstatus: u64,
         mmu: &mut crate::mmu::Mmu,
     ) -> Result<(), MemError> {
-        // For multi-byte writes, translate the start address only
-        let paddr = self.translate_addr(vaddr, satp, false, true, priv_mode, mmu)?;
-        let off = self.check_oob(paddr, bytes.len() as u64)?;
-        self.data[off..off + bytes.len()].copy_from_slice(bytes);
+        // Translate every page before writing any, so a fault leaves memory untouched
+        let chunks = self.translate_store(vaddr, bytes.len(), satp, priv_mode, mstatus, mmu)?;
+        for (paddr, off, start, len) in chunks {
+            let new = &bytes[start..start + len];
+            let mut old = [0u8; 8];
+            self.data.read(off, &mut old[..len.min(8)]);
+            self.note_watch(
+                paddr,
+                len as u64,
+                true,
+                u64::from_le_bytes(old),
+                le_prefix(new),
+            );
+            self.note_code_write(paddr, len as u64);
+            self.data.write(off, new);
+        }
         Ok(())
     }
 
+    /// Load of `size` bytes that crosses a page boundary, assembled little-endian
+    /// from the parts on each page.
+    #[allow(clippy::too_many_arguments)]
+    fn read_split(
+        &mut self,
+        vaddr: u64,
+        size: u64,
+        satp: u64,
+        priv_mode: crate::csr::PrivMode,
+        mstatus: u64,
+        mmu: &mut crate::mmu::Mmu,
+    ) -> Result<u64, MemError> {
+        let bytes = self.read_bytes(vaddr, size as usize, satp, priv_mode, mstatus, mmu)?;
+        Ok(bytes.iter().rev().fold(0, |acc, &b| (acc << 8) | b as u64))
+    }
+
+    /// Read `len` bytes starting at `vaddr`. Like `write_bytes`, each page is
+    /// translated on its own, so a read crossing into a page that maps to a
+    /// different frame (or faults) is handled correctly. A fault is reported at
+    /// the first address of the failing page's part of the range.
+    pub fn read_bytes(
+        &mut self,
+        vaddr: u64,
+        len: usize,
+        satp: u64,
+        priv_mode: crate::csr::PrivMode,
+        mstatus: u64,
+        mmu: &mut crate::mmu::Mmu,
+    ) -> Result<Vec<u8>, MemError> {
+        let mut out = Vec::with_capacity(len);
+        let mut addr = vaddr;
+        while out.len() < len {
+            let in_page = PAGE_SIZE - (addr & (PAGE_SIZE - 1));
+            let chunk = (len - out.len()).min(in_page as usize);
+            let paddr = self.translate_addr(addr, satp, false, false, priv_mode, mstatus, mmu)?;
+            self.check_pmp(paddr, chunk as u64, false, false, priv_mode, addr)?;
+            let off = self
+                .check_oob(paddr, chunk as u64)
+                .map_err(|err| err.into_access_fault(addr, false, false))?;
+            let start = out.len();
+            out.resize(start + chunk, 0);
+            let part = &mut out[start..];
+            self.data.read(off, part);
+            let v = le_prefix(part);
+            self.note_watch(paddr, chunk as u64, false, v, v);
+            addr = addr.wrapping_add(chunk as u64);
+        }
+        Ok(out)
+    }
+
+    /// Direct physical read of RAM; like `write_bytes_phys`, devices aren't decoded.
+    pub fn read_bytes_phys(&self, paddr: u64, len: usize) -> Result<Vec<u8>, MemError> {
+        let off = self.check_oob(paddr, len as u64)?;
+        let mut out = vec![0; len];
+        self.data.read(off, &mut out);
+        Ok(out)
+    }
+
     pub fn write_bytes_phys(&mut self, paddr: u64, bytes: &[u8]) -> Result<(), MemError> {
         // Direct physical write (for ELF loading and boot)
         let off = self.check_oob(paddr, bytes.len() as u64)?;
-        self.data[off..off + bytes.len()].copy_from_slice(bytes);
+        if !bytes.is_empty() {
+            self.note_code_write(paddr, bytes.len() as u64);
+        }
+        self.data.write(off, bytes);
         Ok(())
     }
 
     pub fn end_addr(&self) -> u64 {
         self.base + self.data.len() as u64
     }
+
+    /// Bytes of RAM backed by host memory; untouched pages cost nothing.
+    pub fn resident_bytes(&self) -> usize {
+        self.data.resident_bytes()
+    }
+
+    /// Contents of all of RAM
+    pub(crate) fn ram(&self) -> Vec<u8> {
+        self.data.to_vec()
+    }
+
+    /// Replace RAM with a copy of `data` starting at `base`.
+    pub(crate) fn load_ram(&mut self, base: u64, data: &[u8]) {
+        self.base = base;
+        self.data = Ram::from_bytes(data);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::csr::PrivMode;
+    use crate::mmu::Mmu;
+
+    #[test]
+    fn test_read_bytes_stops_at_the_end_of_ram() {
+        let mut mem = Memory::new(0x2000);
+        let mut mmu = Mmu::new();
+        mem.write_bytes_phys(0x8000_1ffc, &[1, 2, 3, 4]).unwrap();
+
+        assert_eq!(mem.read_bytes_phys(0x8000_1ffc, 4).unwrap(), [1, 2, 3, 4]);
+        assert!(matches!(
+            mem.read_bytes_phys(0x8000_1ffc, 5),
+            Err(MemError::Oob(0x8000_1ffc))
+        ));
+
+        let read = |mem: &mut Memory, mmu: &mut Mmu, vaddr, len| {
+            mem.read_bytes(vaddr, len, 0, PrivMode::Machine, 0, mmu)
+        };
+        assert_eq!(read(&mut mem, &mut mmu, 0x8000_1ffe, 2).unwrap(), [3, 4]);
+        // The fault lands on the first address past RAM's last page
+        assert!(matches!(
+            read(&mut mem, &mut mmu, 0x8000_0ffc, 0x1008),
+            Err(MemError::LoadAccessFault(0x8000_2000))
+        ));
+    }
+
+    #[test]
+    fn test_write_spanning_pages_notes_a_tracked_middle_page() {
+        let mut mem = Memory::new(0x4000);
+        mem.track_code_page(0x8000_2000);
+
+        mem.write_bytes_phys(0x8000_0ff0, &[0; 0x20]).unwrap();
+        assert!(
+            !mem.take_code_written(),
+            "no byte lands in the tracked page"
+        );
+        mem.write_bytes_phys(0x8000_0ff0, &[0; 0x2020]).unwrap();
+        assert!(mem.take_code_written());
+    }
+
+    /// Eight u64 registers, one per 8-byte offset
+    struct Scratch([u64; 8]);
+
+    impl Device for Scratch {
+        fn read(&self, offset: u64, _size: u64) -> u64 {
+            self.0[offset as usize / 8]
+        }
+
+        fn write(&mut self, offset: u64, _size: u64, value: u64) {
+            self.0[offset as usize / 8] = value;
+        }
+    }
+
+    #[test]
+    fn test_attached_device_decodes_its_window() {
+        let mut mem = Memory::new(0x1000);
+        let mut mmu = Mmu::new();
+        let scratch = Box::new(Scratch([0, 1, 2, 3, 4, 5, 6, 7]));
+        mem.attach_device(0x4000_0000, 0x40, scratch).unwrap();
+
+        mem.write_u64(0x4000_0008, 0xabcd, 0, PrivMode::Machine, 0, &mut mmu)
+            .unwrap();
+        assert_eq!(mem.read_u64_phys(0x4000_0008).unwrap(), 0xabcd);
+        assert_eq!(mem.read_u32_phys(0x4000_0038).unwrap(), 7);
+        // Past the window nothing answers, and the access faults
+        assert!(matches!(
+            mem.read_u32(0x4000_0040, 0, PrivMode::Machine, 0, &mut mmu),
+            Err(MemError::LoadAccessFault(0x4000_0040))
+        ));
+
+        // Windows can't overlap RAM, a built-in device or each other
+        let overlapping = [(0x8000_0ff8, 0x10), (UART_BASE, 8), (0x4000_0020, 8)];
+        for (base, size) in overlapping {
+            assert!(matches!(
+                mem.attach_device(base, size, Box::new(Scratch([0; 8]))),
+                Err(BusError::Overlap { .. })
+            ));
+        }
+        mem.devices.uart = false;
+        mem.attach_device(UART_BASE, 0x40, Box::new(Scratch([9; 8])))
+            .unwrap();
+        assert_eq!(mem.read_u8_phys(UART_BASE).unwrap(), 9);
+    }
+
+    /// Sv39 with VA 0x4000_0000 -> PA 0x8000_5000 and VA 0x4000_1000 -> PA
+    /// 0x8000_3000; the page after them is unmapped. Returns the satp value.
+    fn map_two_pages(mem: &mut Memory) -> u64 {
+        const ROOT: u64 = 0x8000_0000;
+        const L1: u64 = 0x8000_1000;
+        const L0: u64 = 0x8000_2000;
+        const LEAF: u64 = 0xcf; // V|R|W|X|A|D
+        let pte = |paddr: u64, flags: u64| ((paddr >> 12) << 10) | flags;
+        mem.write_u64_phys(ROOT + 8, pte(L1, 1)).unwrap();
+        mem.write_u64_phys(L1, pte(L0, 1)).unwrap();
+        mem.write_u64_phys(L0, pte(0x8000_5000, LEAF)).unwrap();
+        mem.write_u64_phys(L0 + 8, pte(0x8000_3000, LEAF)).unwrap();
+        (8 << 60) | (ROOT >> 12)
+    }
+
+    #[test]
+    fn test_read_bytes_translates_each_page() {
+        let mut mem = Memory::new(0x8000);
+        let mut mmu = Mmu::new();
+        let satp = map_two_pages(&mut mem);
+        mem.write_bytes_phys(0x8000_5ffe, &[1, 2]).unwrap();
+        mem.write_bytes_phys(0x8000_3000, &[3, 4]).unwrap();
+
+        let bytes = mem
+            .read_bytes(0x4000_0ffe, 4, satp, PrivMode::Supervisor, 0, &mut mmu)
+            .unwrap();
+        assert_eq!(bytes, [1, 2, 3, 4]);
+
+        // The next page isn't mapped
+        assert!(matches!(
+            mem.read_bytes(0x4000_1ffe, 4, satp, PrivMode::Supervisor, 0, &mut mmu),
+            Err(MemError::LoadPageFault(0x4000_2000))
+        ));
+    }
+
+    #[test]
+    fn test_misaligned_accesses_split_across_pages() {
+        let mut mem = Memory::new(0x8000);
+        let mut mmu = Mmu::new();
+        mem.allow_misaligned = true;
+        let satp = map_two_pages(&mut mem);
+        let s_mode = PrivMode::Supervisor;
+
+        mem.write_u64(
+            0x4000_0ffc,
+            0x0807_0605_0403_0201,
+            satp,
+            s_mode,
+            0,
+            &mut mmu,
+        )
+        .unwrap();
+        assert_eq!(mem.read_bytes_phys(0x8000_5ffc, 4).unwrap(), [1, 2, 3, 4]);
+        assert_eq!(mem.read_bytes_phys(0x8000_3000, 4).unwrap(), [5, 6, 7, 8]);
+        assert_eq!(
+            mem.read_u32(0x4000_0ffe, satp, s_mode, 0, &mut mmu)
+                .unwrap(),
+            0x0605_0403
+        );
+
+        // The second half faults, and the first isn't written either
+        assert!(matches!(
+            mem.write_u32(0x4000_1ffe, 0xffff_ffff, satp, s_mode, 0, &mut mmu),
+            Err(MemError::StorePageFault(0x4000_2000))
+        ));
+        assert_eq!(mem.read_bytes_phys(0x8000_3ffe, 2).unwrap(), [0, 0]);
+        assert!(matches!(
+            mem.read_u16(0x4000_1fff, satp, s_mode, 0, &mut mmu),
+            Err(MemError::LoadPageFault(0x4000_2000))
+        ));
+    }
+
+    #[test]
+    fn test_huge_ram_is_only_resident_where_touched() {
+        let mut m = crate::cpu::Machine::new(4 << 30);
+        assert_eq!(m.mem.end_addr(), 0x8000_0000 + (4 << 30));
+        assert_eq!(m.mem.resident_bytes(), 0);
+
+        let top = m.mem.end_addr() - 8;
+        assert_eq!(m.mem.read_u64_phys(top).unwrap(), 0);
+        m.mem.write_u64_phys(top, 0x1234_5678).unwrap();
+        m.mem.write_u32_phys(top - 0x100, 7).unwrap();
+        assert_eq!(m.mem.read_u64_phys(top).unwrap(), 0x1234_5678);
+        assert_eq!(m.mem.resident_bytes(), 4096);
+    }
 }
@@ -0,0 +1,103 @@
+const PAGE_SIZE: usize = 4096;
+
+type Page = Box<[u8; PAGE_SIZE]>;
+
+/// Sparse RAM: 4 KiB pages allocated on first write. Unallocated pages read
+/// as zero, so a large RAM costs little until the guest touches it. Offsets
+/// are from the start of RAM; callers bounds-check against `len`.
+pub struct Ram {
+    len: usize,
+    pages: Vec<Option<Page>>,
+}
+
+impl Ram {
+    pub fn new(len: usize) -> Self {
+        Self {
+            len,
+            pages: std::iter::repeat_with(|| None)
+                .take(len.div_ceil(PAGE_SIZE))
+                .collect(),
+        }
+    }
+
+    /// RAM holding a copy of `data`; all-zero pages stay unallocated.
+    pub fn from_bytes(data: &[u8]) -> Self {
+        let mut ram = Self::new(data.len());
+        for (i, chunk) in data.chunks(PAGE_SIZE).enumerate() {
+            if chunk.iter().any(|&b| b != 0) {
+                ram.write(i * PAGE_SIZE, chunk);
+            }
+        }
+        ram
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Bytes of RAM actually allocated
+    pub fn resident_bytes(&self) -> usize {
+        self.pages.iter().flatten().count() * PAGE_SIZE
+    }
+
+    /// Fill `buf` from offset `off`.
+    pub fn read(&self, mut off: usize, buf: &mut [u8]) {
+        let mut done = 0;
+        while done < buf.len() {
+            let in_page = off % PAGE_SIZE;
+            let chunk = (buf.len() - done).min(PAGE_SIZE - in_page);
+            let dst = &mut buf[done..done + chunk];
+            match &self.pages[off / PAGE_SIZE] {
+                Some(page) => dst.copy_from_slice(&page[in_page..in_page + chunk]),
+                None => dst.fill(0),
+            }
+            done += chunk;
+            off += chunk;
+        }
+    }
+
+    /// Copy `bytes` in at offset `off`, allocating pages as needed.
+    pub fn write(&mut self, mut off: usize, bytes: &[u8]) {
+        let mut done = 0;
+        while done < bytes.len() {
+            let in_page = off % PAGE_SIZE;
+            let chunk = (bytes.len() - done).min(PAGE_SIZE - in_page);
+            let page = self.pages[off / PAGE_SIZE].get_or_insert_with(|| Box::new([0; PAGE_SIZE]));
+            page[in_page..in_page + chunk].copy_from_slice(&bytes[done..done + chunk]);
+            done += chunk;
+            off += chunk;
+        }
+    }
+
+    /// The whole of RAM as one buffer
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut out = vec![0; self.len];
+        self.read(0, &mut out);
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pages_are_allocated_on_first_write() {
+        let mut ram = Ram::new(3 * PAGE_SIZE);
+        let mut buf = [0xff; 8];
+        ram.read(PAGE_SIZE - 4, &mut buf);
+        assert_eq!(buf, [0; 8]);
+        assert_eq!(ram.resident_bytes(), 0);
+
+        // A write straddling two pages allocates both
+        ram.write(PAGE_SIZE - 2, &[1, 2, 3, 4]);
+        assert_eq!(ram.resident_bytes(), 2 * PAGE_SIZE);
+        ram.read(PAGE_SIZE - 4, &mut buf);
+        assert_eq!(buf, [0, 0, 1, 2, 3, 4, 0, 0]);
+        assert_eq!(ram.to_vec()[PAGE_SIZE..PAGE_SIZE + 2], [3, 4]);
+    }
+}
@@ -0,0 +1,916 @@
+use crate::csr::{CsrFile, PrivMode};
+use crate::mem::{MemError, Memory};
+use crate::snapshot::{Reader, SnapshotError, Writer};
+
+/// satp.MODE encodings
+const SATP_MODE_BARE: u64 = 0;
+const SATP_MODE_SV39: u64 = 8;
+const SATP_MODE_SV48: u64 = 9;
+
+/// satp.ASID occupies bits 59:44
+const SATP_ASID_SHIFT: u64 = 44;
+const SATP_ASID_MASK: u64 = 0xffff;
+
+/// Sv39/Sv48 geometry
+const PAGE_SHIFT: u64 = 12;
+const PTE_SIZE: u64 = 8;
+const SV39_LEVELS: usize = 3;
+const SV48_LEVELS: usize = 4;
+const VPN_BITS: u64 = 9;
+
+/// PTE flag bits
+const PTE_V: u64 = 1 << 0;
+const PTE_R: u64 = 1 << 1;
+const PTE_W: u64 = 1 << 2;
+const PTE_X: u64 = 1 << 3;
+const PTE_U: u64 = 1 << 4;
+const PTE_G: u64 = 1 << 5;
+const PTE_A: u64 = 1 << 6;
+const PTE_D: u64 = 1 << 7;
+
+/// PTE.PPN occupies bits 53:10; bits 63:54 are reserved and must be zero.
+const PTE_PPN_MASK: u64 = (1 << 44) - 1;
+const PTE_RESERVED_MASK: u64 = !((1 << 54) - 1);
+
+/// TLB geometry: separate I/D TLBs, fully associative with LRU replacement.
+const TLB_ENTRIES: usize = 32;
+
+/// A leaf PTE found by a walk, together with where it lives and at what level.
+#[derive(Clone, Copy, Default)]
+struct TlbEntry {
+    valid: bool,
+    /// Virtual page number with the VPN fields below `level` cleared
+    vpn: u64,
+    asid: u64,
+    level: usize,
+    pte: u64,
+    pte_addr: u64,
+    last_used: u64,
+}
+
+impl TlbEntry {
+    fn matches(&self, vaddr: u64, asid: u64) -> bool {
+        self.valid
+            && self.vpn == vpn_for_level(vaddr, self.level)
+            && (self.asid == asid || self.pte & PTE_G != 0)
+    }
+
+    fn paddr(&self, vaddr: u64) -> u64 {
+        let ppn = (self.pte >> 10) & PTE_PPN_MASK;
+        let offset_mask = (1u64 << (PAGE_SHIFT + VPN_BITS * self.level as u64)) - 1;
+        ((ppn << PAGE_SHIFT) & !offset_mask) | (vaddr & offset_mask)
+    }
+}
+
+/// Hit/miss counters for one TLB.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TlbStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+#[derive(Clone)]
+struct Tlb {
+    entries: [TlbEntry; TLB_ENTRIES],
+    clock: u64,
+    stats: TlbStats,
+}
+
+impl Tlb {
+    fn new() -> Self {
+        Self {
+            entries: [TlbEntry::default(); TLB_ENTRIES],
+            clock: 0,
+            stats: TlbStats::default(),
+        }
+    }
+
+    fn lookup(&mut self, vaddr: u64, asid: u64) -> Option<TlbEntry> {
+        self.clock += 1;
+        match self.entries.iter_mut().find(|e| e.matches(vaddr, asid)) {
+            Some(entry) => {
+                entry.last_used = self.clock;
+                self.stats.hits += 1;
+                Some(*entry)
+            }
+            None => {
+                self.stats.misses += 1;
+                None
+            }
+        }
+    }
+
+    fn insert(&mut self, mut entry: TlbEntry) {
+        entry.valid = true;
+        entry.last_used = self.clock;
+        // Replace an existing entry for the same page, else the least recently used
+        let slot = match self
+            .entries
+            .iter()
+            .position(|e| e.valid && e.vpn == entry.vpn && e.asid == entry.asid)
+        {
+            Some(i) => i,
+            None => (0..TLB_ENTRIES)
+                .min_by_key(|&i| (self.entries[i].valid, self.entries[i].last_used))
+                .unwrap(),
+        };
+        self.entries[slot] = entry;
+    }
+
+    /// Invalidate entries matching `vaddr` and `asid`; `None` matches everything.
+    /// Global mappings survive an ASID-specific flush.
+    fn flush(&mut self, vaddr: Option<u64>, asid: Option<u64>) {
+        for entry in self.entries.iter_mut() {
+            let va_match = vaddr.is_none_or(|va| entry.vpn == vpn_for_level(va, entry.level));
+            let asid_match = asid.is_none_or(|asid| entry.asid == asid && entry.pte & PTE_G == 0);
+            if va_match && asid_match {
+                entry.valid = false;
+            }
+        }
+    }
+
+    fn save(&self, w: &mut Writer) {
+        for entry in &self.entries {
+            w.bool(entry.valid);
+            w.u64(entry.vpn);
+            w.u64(entry.asid);
+            w.u8(entry.level as u8);
+            w.u64(entry.pte);
+            w.u64(entry.pte_addr);
+            w.u64(entry.last_used);
+        }
+        w.u64(self.clock);
+        w.u64(self.stats.hits);
+        w.u64(self.stats.misses);
+    }
+
+    fn load(r: &mut Reader) -> Result<Self, SnapshotError> {
+        let mut tlb = Self::new();
+        for entry in tlb.entries.iter_mut() {
+            *entry = TlbEntry {
+                valid: r.bool()?,
+                vpn: r.u64()?,
+                asid: r.u64()?,
+                level: match r.u8()? as usize {
+                    level if level < SV48_LEVELS => level,
+                    _ => return Err(SnapshotError::Invalid("TLB entry level")),
+                },
+                pte: r.u64()?,
+                pte_addr: r.u64()?,
+                last_used: r.u64()?,
+            };
+        }
+        tlb.clock = r.u64()?;
+        tlb.stats.hits = r.u64()?;
+        tlb.stats.misses = r.u64()?;
+        Ok(tlb)
+    }
+}
+
+/// What a walk does with a leaf whose A bit is clear, or whose D bit is clear
+/// on a store. The privileged spec allows either.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PteAdMode {
+    /// Raise a page fault and leave it to the guest's handler to set the bit
+    Fault,
+    /// Set the bit in the PTE in memory and carry on
+    #[default]
+    Update,
+}
+
+#[derive(Clone)]
+pub struct Mmu {
+    itlb: Tlb,
+    dtlb: Tlb,
+    /// How A/D bits get set. Copied from `MachineConfig::ad_mode`.
+    pub(crate) ad_mode: PteAdMode,
+}
+
+impl Default for Mmu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Mmu {
+    pub fn new() -> Self {
+        Self {
+            itlb: Tlb::new(),
+            dtlb: Tlb::new(),
+            ad_mode: PteAdMode::default(),
+        }
+    }
+
+    /// Translate a virtual address to a physical address.
+    /// M-mode and satp.MODE=Bare use identity mapping; Sv39 and Sv48 consult
+    /// the TLB and on a miss walk the page table rooted at satp.PPN, raising
+    /// the page fault matching the access type. A non-canonical address faults
+    /// before any TLB lookup or walk.
+    #[allow(clippy::too_many_arguments)]
+    pub fn translate(
+        &mut self,
+        vaddr: u64,
+        satp: u64,
+        is_fetch: bool,
+        is_write: bool,
+        priv_mode: PrivMode,
+        mstatus: u64,
+        mem: &mut Memory,
+    ) -> Result<u64, MemError> {
+        match Self::paging_levels(vaddr, satp, is_fetch, is_write, priv_mode)? {
+            None => Ok(vaddr),
+            Some(levels) => self.translate_paged(
+                vaddr, satp, levels, is_fetch, is_write, priv_mode, mstatus, mem,
+            ),
+        }
+    }
+
+    /// Translate as `translate` would, for a debugger or tracer looking at the
+    /// guest: the TLB is neither consulted nor filled, A/D bits are left as
+    /// they are, and page tables are read without tripping watchpoints.
+    /// Fails wherever the real access would fault.
+    #[allow(clippy::too_many_arguments)]
+    pub fn probe(
+        &self,
+        vaddr: u64,
+        satp: u64,
+        is_fetch: bool,
+        is_write: bool,
+        priv_mode: PrivMode,
+        mstatus: u64,
+        mem: &Memory,
+    ) -> Result<u64, MemError> {
+        let Some(levels) = Self::paging_levels(vaddr, satp, is_fetch, is_write, priv_mode)? else {
+            return Ok(vaddr);
+        };
+        let entry = Self::walk(vaddr, satp, levels, is_fetch, is_write, mem, true)?;
+        Self::check_leaf(entry.pte, vaddr, is_fetch, is_write, priv_mode, mstatus)?;
+        if entry.pte & PTE_A == 0 || (is_write && entry.pte & PTE_D == 0) {
+            if self.ad_mode == PteAdMode::Fault {
+                return Err(Self::page_fault(vaddr, is_fetch, is_write));
+            }
+            if !mem
+                .pmp
+                .check(entry.pte_addr, PTE_SIZE, false, true, PrivMode::Supervisor)
+            {
+                return Err(MemError::access_fault(vaddr, is_fetch, is_write));
+            }
+        }
+        Ok(entry.paddr(vaddr))
+    }
+
+    /// Page table depth for an access, or None when it isn't translated
+    /// (M-mode or satp.MODE=Bare).
+    fn paging_levels(
+        vaddr: u64,
+        satp: u64,
+        is_fetch: bool,
+        is_write: bool,
+        priv_mode: PrivMode,
+    ) -> Result<Option<usize>, MemError> {
+        if priv_mode == PrivMode::Machine {
+            return Ok(None);
+        }
+
+        let levels = match satp >> 60 {
+            SATP_MODE_BARE => return Ok(None),
+            SATP_MODE_SV39 => SV39_LEVELS,
+            SATP_MODE_SV48 => SV48_LEVELS,
+            _ => return Err(Self::page_fault(vaddr, is_fetch, is_write)),
+        };
+
+        // Bits above the top VPN field must all copy its highest bit
+        // (bit 38 for Sv39, 47 for Sv48)
+        let unused_bits = 64 - (PAGE_SHIFT + VPN_BITS * levels as u64);
+        if ((vaddr as i64) << unused_bits >> unused_bits) as u64 != vaddr {
+            return Err(Self::page_fault(vaddr, is_fetch, is_write));
+        }
+        Ok(Some(levels))
+    }
+
+    /// Drop cached translations, as SFENCE.VMA does. `vaddr` selects a single
+    /// page and `asid` a single address space; `None` matches all of them.
+    pub fn flush_tlb(&mut self, vaddr: Option<u64>, asid: Option<u64>) {
+        let asid = asid.map(|asid| asid & SATP_ASID_MASK);
+        self.itlb.flush(vaddr, asid);
+        self.dtlb.flush(vaddr, asid);
+    }
+
+    /// Instruction and data TLB hit/miss counters.
+    pub fn tlb_stats(&self) -> (TlbStats, TlbStats) {
+        (self.itlb.stats, self.dtlb.stats)
+    }
+
+    pub(crate) fn save(&self, w: &mut Writer) {
+        self.itlb.save(w);
+        self.dtlb.save(w);
+    }
+
+    pub(crate) fn load(r: &mut Reader) -> Result<Self, SnapshotError> {
+        Ok(Self {
+            itlb: Tlb::load(r)?,
+            dtlb: Tlb::load(r)?,
+            ad_mode: PteAdMode::default(),
+        })
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn translate_paged(
+        &mut self,
+        vaddr: u64,
+        satp: u64,
+        levels: usize,
+        is_fetch: bool,
+        is_write: bool,
+        priv_mode: PrivMode,
+        mstatus: u64,
+        mem: &mut Memory,
+    ) -> Result<u64, MemError> {
+        let asid = (satp >> SATP_ASID_SHIFT) & SATP_ASID_MASK;
+        let tlb = if is_fetch {
+            &mut self.itlb
+        } else {
+            &mut self.dtlb
+        };
+
+        // Permissions are checked on every hit, so one entry serves all privilege levels.
+        // A store through a clean entry re-walks so the D bit gets set in memory.
+        if let Some(entry) = tlb.lookup(vaddr, asid) {
+            Self::check_leaf(entry.pte, vaddr, is_fetch, is_write, priv_mode, mstatus)?;
+            if !is_write || entry.pte & PTE_D != 0 {
+                return Ok(entry.paddr(vaddr));
+            }
+        }
+
+        let mut entry = Self::walk(vaddr, satp, levels, is_fetch, is_write, mem, false)?;
+        Self::check_leaf(entry.pte, vaddr, is_fetch, is_write, priv_mode, mstatus)?;
+
+        // A/D bits: set by hardware, or left to the guest's page fault handler
+        let mut updated = entry.pte | PTE_A;
+        if is_write {
+            updated |= PTE_D;
+        }
+        if updated != entry.pte {
+            if self.ad_mode == PteAdMode::Fault {
+                return Err(Self::page_fault(vaddr, is_fetch, is_write));
+            }
+            // A PTE the walker can't reach is an access fault for the original access
+            if !mem
+                .pmp
+                .check(entry.pte_addr, PTE_SIZE, false, true, PrivMode::Supervisor)
+            {
+                return Err(MemError::access_fault(vaddr, is_fetch, is_write));
+            }
+            mem.write_u64_phys(entry.pte_addr, updated)
+                .map_err(|_| MemError::access_fault(vaddr, is_fetch, is_write))?;
+            entry.pte = updated;
+        }
+
+        entry.asid = asid;
+        tlb.insert(entry);
+        Ok(entry.paddr(vaddr))
+    }
+
+    /// Walk a `levels`-deep page table (3 for Sv39, 4 for Sv48) and return
+    /// the leaf PTE mapping `vaddr`. A `peek` walk reads PTEs with
+    /// `Memory::peek_u64_phys`.
+    fn walk(
+        vaddr: u64,
+        satp: u64,
+        levels: usize,
+        is_fetch: bool,
+        is_write: bool,
+        mem: &Memory,
+        peek: bool,
+    ) -> Result<TlbEntry, MemError> {
+        let fault = || Self::page_fault(vaddr, is_fetch, is_write);
+
+        let mut table = (satp & PTE_PPN_MASK) << PAGE_SHIFT;
+        let mut level = levels - 1;
+
+        loop {
+            let vpn = (vaddr >> (PAGE_SHIFT + VPN_BITS * level as u64)) & ((1 << VPN_BITS) - 1);
+            let pte_addr = table + vpn * PTE_SIZE;
+            // The walk's accesses are checked by PMP as S-mode reads
+            if !mem
+                .pmp
+                .check(pte_addr, PTE_SIZE, false, false, PrivMode::Supervisor)
+            {
+                return Err(MemError::access_fault(vaddr, is_fetch, is_write));
+            }
+            let pte = if peek {
+                mem.peek_u64_phys(pte_addr)
+            } else {
+                mem.read_u64_phys(pte_addr)
+            }
+            .map_err(|_| MemError::access_fault(vaddr, is_fetch, is_write))?;
+
+            if pte & PTE_V == 0 || (pte & PTE_R == 0 && pte & PTE_W != 0) {
+                return Err(fault());
+            }
+            if pte & PTE_RESERVED_MASK != 0 {
+                return Err(fault());
+            }
+
+            let ppn = (pte >> 10) & PTE_PPN_MASK;
+
+            // Non-leaf: descend to the next level
+            if pte & (PTE_R | PTE_X) == 0 {
+                if level == 0 {
+                    return Err(fault());
+                }
+                level -= 1;
+                table = ppn << PAGE_SHIFT;
+                continue;
+            }
+
+            // Superpages must be aligned: the low PPN fields must be zero
+            let low_ppn_mask = (1u64 << (VPN_BITS * level as u64)) - 1;
+            if ppn & low_ppn_mask != 0 {
+                return Err(fault());
+            }
+
+            return Ok(TlbEntry {
+                vpn: vpn_for_level(vaddr, level),
+                level,
+                pte,
+                pte_addr,
+                ..TlbEntry::default()
+            });
+        }
+    }
+
+    /// Check a leaf PTE's permissions for the access type and privilege.
+    /// mstatus.MXR lets loads read execute-only pages, and mstatus.SUM lets
+    /// S-mode load/store (but never fetch) from U pages.
+    fn check_leaf(
+        pte: u64,
+        vaddr: u64,
+        is_fetch: bool,
+        is_write: bool,
+        priv_mode: PrivMode,
+        mstatus: u64,
+    ) -> Result<(), MemError> {
+        let mxr = mstatus & CsrFile::MSTATUS_MXR != 0;
+        let sum = mstatus & CsrFile::MSTATUS_SUM != 0;
+
+        let permitted = if is_fetch {
+            pte & PTE_X != 0
+        } else if is_write {
+            pte & PTE_W != 0
+        } else {
+            pte & PTE_R != 0 || (mxr && pte & PTE_X != 0)
+        };
+
+        let user_page = pte & PTE_U != 0;
+        let priv_ok = match priv_mode {
+            PrivMode::User => user_page,
+            PrivMode::Supervisor => !user_page || (sum && !is_fetch),
+            PrivMode::Machine => true,
+        };
+
+        if permitted && priv_ok {
+            Ok(())
+        } else {
+            Err(Self::page_fault(vaddr, is_fetch, is_write))
+        }
+    }
+
+    fn page_fault(vaddr: u64, is_fetch: bool, is_write: bool) -> MemError {
+        if is_fetch {
+            MemError::InstructionPageFault(vaddr)
+        } else if is_write {
+            MemError::StorePageFault(vaddr)
+        } else {
+            MemError::LoadPageFault(vaddr)
+        }
+    }
+}
+
+/// Virtual page number of `vaddr` with the VPN fields below `level` cleared,
+/// so every address inside a superpage maps to the same tag. Tags are Sv48
+/// wide; an Sv39 walk ignores the top field, and writing satp flushes the TLB.
+fn vpn_for_level(vaddr: u64, level: usize) -> u64 {
+    let vpn = (vaddr >> PAGE_SHIFT) & ((1 << (VPN_BITS * SV48_LEVELS as u64)) - 1);
+    vpn & !((1u64 << (VPN_BITS * level as u64)) - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROOT: u64 = 0x8000_1000;
+    const L1: u64 = 0x8000_2000;
+    const L0: u64 = 0x8000_3000;
+    const SATP: u64 = (SATP_MODE_SV39 << 60) | (ROOT >> PAGE_SHIFT);
+
+    fn pte(paddr: u64, flags: u64) -> u64 {
+        ((paddr >> PAGE_SHIFT) << 10) | flags
+    }
+
+    /// Map VA 0x4000_5000 -> PA 0x8000_8000 through all three levels.
+    fn map_4k(mem: &mut Memory, flags: u64) {
+        mem.write_u64_phys(ROOT + 8, pte(L1, PTE_V)).unwrap();
+        mem.write_u64_phys(L1, pte(L0, PTE_V)).unwrap();
+        mem.write_u64_phys(L0 + 5 * PTE_SIZE, pte(0x8000_8000, flags))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_machine_mode_and_bare_are_identity() {
+        let mut mem = Memory::new(0x10000);
+        let mut mmu = Mmu::new();
+
+        let pa = mmu.translate(0x1234, SATP, false, false, PrivMode::Machine, 0, &mut mem);
+        assert_eq!(pa.unwrap(), 0x1234);
+        let pa = mmu.translate(0x1234, 0, false, false, PrivMode::User, 0, &mut mem);
+        assert_eq!(pa.unwrap(), 0x1234);
+    }
+
+    #[test]
+    fn test_4k_walk_sets_accessed_and_dirty() {
+        let mut mem = Memory::new(0x10000);
+        let mut mmu = Mmu::new();
+        map_4k(&mut mem, PTE_V | PTE_R | PTE_W);
+        let leaf = L0 + 5 * PTE_SIZE;
+
+        let pa = mmu.translate(
+            0x4000_5abc,
+            SATP,
+            false,
+            false,
+            PrivMode::Supervisor,
+            0,
+            &mut mem,
+        );
+        assert_eq!(pa.unwrap(), 0x8000_8abc);
+        let flags = mem.read_u64_phys(leaf).unwrap();
+        assert_ne!(flags & PTE_A, 0, "load should set A");
+        assert_eq!(flags & PTE_D, 0, "load must not set D");
+
+        mmu.translate(
+            0x4000_5abc,
+            SATP,
+            false,
+            true,
+            PrivMode::Supervisor,
+            0,
+            &mut mem,
+        )
+        .unwrap();
+        assert_ne!(
+            mem.read_u64_phys(leaf).unwrap() & PTE_D,
+            0,
+            "store should set D"
+        );
+    }
+
+    #[test]
+    fn test_ad_fault_mode_leaves_ptes_to_the_guest() {
+        let mut mem = Memory::new(0x10000);
+        let mut mmu = Mmu::new();
+        mmu.ad_mode = PteAdMode::Fault;
+        let leaf = L0 + 5 * PTE_SIZE;
+        let va = 0x4000_5abc;
+        let s = PrivMode::Supervisor;
+
+        map_4k(&mut mem, PTE_V | PTE_R | PTE_W);
+        let err = mmu
+            .translate(va, SATP, false, false, s, 0, &mut mem)
+            .unwrap_err();
+        assert!(matches!(err, MemError::LoadPageFault(addr) if addr == va));
+        let err = mmu
+            .translate(va, SATP, false, true, s, 0, &mut mem)
+            .unwrap_err();
+        assert!(matches!(err, MemError::StorePageFault(addr) if addr == va));
+        assert_eq!(
+            mem.read_u64_phys(leaf).unwrap(),
+            pte(0x8000_8000, PTE_V | PTE_R | PTE_W),
+            "the PTE must not be written"
+        );
+
+        // Once the handler sets A, loads go through but a store still needs D
+        map_4k(&mut mem, PTE_V | PTE_R | PTE_W | PTE_A);
+        let pa = mmu.translate(va, SATP, false, false, s, 0, &mut mem);
+        assert_eq!(pa.unwrap(), 0x8000_8abc);
+        let err = mmu
+            .translate(va, SATP, false, true, s, 0, &mut mem)
+            .unwrap_err();
+        assert!(matches!(err, MemError::StorePageFault(_)));
+
+        map_4k(&mut mem, PTE_V | PTE_R | PTE_W | PTE_A | PTE_D);
+        let pa = mmu.translate(va, SATP, false, true, s, 0, &mut mem);
+        assert_eq!(pa.unwrap(), 0x8000_8abc);
+    }
+
+    #[test]
+    fn test_faults_match_access_type() {
+        let mut mem = Memory::new(0x10000);
+        let mut mmu = Mmu::new();
+        map_4k(&mut mem, PTE_V | PTE_R);
+        let va = 0x4000_5000;
+        let s = PrivMode::Supervisor;
+
+        // Read-only, non-executable page
+        let err = mmu
+            .translate(va, SATP, false, true, s, 0, &mut mem)
+            .unwrap_err();
+        assert!(matches!(err, MemError::StorePageFault(addr) if addr == va));
+        let err = mmu
+            .translate(va, SATP, true, false, s, 0, &mut mem)
+            .unwrap_err();
+        assert!(matches!(err, MemError::InstructionPageFault(addr) if addr == va));
+
+        // Unmapped (V=0) root entry
+        let err = mmu
+            .translate(0x8000, SATP, false, false, s, 0, &mut mem)
+            .unwrap_err();
+        assert!(matches!(err, MemError::LoadPageFault(0x8000)));
+    }
+
+    #[test]
+    fn test_user_bit_checks() {
+        let mut mem = Memory::new(0x10000);
+        let mut mmu = Mmu::new();
+        let va = 0x4000_5000;
+
+        map_4k(&mut mem, PTE_V | PTE_R);
+        assert!(
+            mmu.translate(va, SATP, false, false, PrivMode::Supervisor, 0, &mut mem)
+                .is_ok()
+        );
+        assert!(
+            mmu.translate(va, SATP, false, false, PrivMode::User, 0, &mut mem)
+                .is_err()
+        );
+
+        map_4k(&mut mem, PTE_V | PTE_R | PTE_U);
+        mmu.flush_tlb(None, None);
+        assert!(
+            mmu.translate(va, SATP, false, false, PrivMode::User, 0, &mut mem)
+                .is_ok()
+        );
+        assert!(
+            mmu.translate(va, SATP, false, false, PrivMode::Supervisor, 0, &mut mem)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_superpages_and_alignment() {
+        let mut mem = Memory::new(0x10000);
+        let mut mmu = Mmu::new();
+        let s = PrivMode::Supervisor;
+
+        // 1 GiB gigapage at root index 2: VA 0x8000_0000.. -> PA 0x8000_0000..
+        mem.write_u64_phys(ROOT + 2 * PTE_SIZE, pte(0x8000_0000, PTE_V | PTE_R))
+            .unwrap();
+        let pa = mmu.translate(0x8012_3456, SATP, false, false, s, 0, &mut mem);
+        assert_eq!(pa.unwrap(), 0x8012_3456);
+
+        // 2 MiB megapage at level 1 with a misaligned PPN must fault
+        mem.write_u64_phys(ROOT + 8, pte(L1, PTE_V)).unwrap();
+        mem.write_u64_phys(L1, pte(0x8000_1000, PTE_V | PTE_R))
+            .unwrap();
+        let err = mmu
+            .translate(0x4000_0000, SATP, false, false, s, 0, &mut mem)
+            .unwrap_err();
+        assert!(matches!(err, MemError::LoadPageFault(_)));
+
+        // Aligned megapage translates with a 21-bit offset
+        mem.write_u64_phys(L1, pte(0x8020_0000, PTE_V | PTE_R))
+            .unwrap();
+        let pa = mmu.translate(0x4012_3456, SATP, false, false, s, 0, &mut mem);
+        assert_eq!(pa.unwrap(), 0x8032_3456);
+    }
+
+    #[test]
+    fn test_sv48_walks_four_levels() {
+        const L2: u64 = 0x8000_4000;
+        const L1: u64 = 0x8000_5000;
+        const L0: u64 = 0x8000_6000;
+        const SATP48: u64 = (SATP_MODE_SV48 << 60) | (ROOT >> PAGE_SHIFT);
+        let mut mem = Memory::new(0x10000);
+        let mut mmu = Mmu::new();
+        let s = PrivMode::Supervisor;
+
+        // VPN[3..0] = 1, 1, 0, 5
+        mem.write_u64_phys(ROOT + 8, pte(L2, PTE_V)).unwrap();
+        mem.write_u64_phys(L2 + 8, pte(L1, PTE_V)).unwrap();
+        mem.write_u64_phys(L1, pte(L0, PTE_V)).unwrap();
+        mem.write_u64_phys(L0 + 5 * PTE_SIZE, pte(0x8000_8000, PTE_V | PTE_R))
+            .unwrap();
+        let pa = mmu.translate(0x80_4000_5abc, SATP48, false, false, s, 0, &mut mem);
+        assert_eq!(pa.unwrap(), 0x8000_8abc);
+
+        // Superpages at every level above the leaf
+        mem.write_u64_phys(L1 + 8, pte(0x8020_0000, PTE_V | PTE_R))
+            .unwrap();
+        let pa = mmu.translate(0x80_4020_1234, SATP48, false, false, s, 0, &mut mem);
+        assert_eq!(pa.unwrap(), 0x8020_1234);
+        mem.write_u64_phys(L2 + 2 * PTE_SIZE, pte(0x8000_0000, PTE_V | PTE_R))
+            .unwrap();
+        let pa = mmu.translate(0x80_8012_3456, SATP48, false, false, s, 0, &mut mem);
+        assert_eq!(pa.unwrap(), 0x8012_3456);
+        mem.write_u64_phys(ROOT + 2 * PTE_SIZE, pte(0, PTE_V | PTE_R))
+            .unwrap();
+        let pa = mmu.translate(0x100_8000_1234, SATP48, false, false, s, 0, &mut mem);
+        assert_eq!(pa.unwrap(), 0x8000_1234);
+
+        // A gigapage whose PPN isn't 1 GiB aligned faults
+        mem.write_u64_phys(L2 + 3 * PTE_SIZE, pte(0x8020_0000, PTE_V | PTE_R))
+            .unwrap();
+        let err = mmu
+            .translate(0x80_c000_0000, SATP48, false, false, s, 0, &mut mem)
+            .unwrap_err();
+        assert!(matches!(err, MemError::LoadPageFault(0x80_c000_0000)));
+    }
+
+    #[test]
+    fn test_non_canonical_addresses_fault_before_the_walk() {
+        let mut mem = Memory::new(0x10000);
+        let s = PrivMode::Supervisor;
+        // A superpage at root index 0, so the low VPN bits alone would map
+        mem.write_u64_phys(ROOT, pte(0, PTE_V | PTE_R | PTE_W | PTE_X))
+            .unwrap();
+        let sv48 = (SATP_MODE_SV48 << 60) | (ROOT >> PAGE_SHIFT);
+
+        // Sv39 sign-extends bit 38, Sv48 bit 47
+        for (satp, va) in [(SATP, 0x80_0000_1000), (sv48, 0x0001_0000_0000_1000)] {
+            let mut mmu = Mmu::new();
+            assert!(
+                mmu.translate(0x1000, satp, false, false, s, 0, &mut mem)
+                    .is_ok()
+            );
+            let err = mmu
+                .translate(va, satp, false, false, s, 0, &mut mem)
+                .unwrap_err();
+            assert!(matches!(err, MemError::LoadPageFault(addr) if addr == va));
+            let err = mmu
+                .translate(va, satp, false, true, s, 0, &mut mem)
+                .unwrap_err();
+            assert!(matches!(err, MemError::StorePageFault(addr) if addr == va));
+            let err = mmu
+                .translate(va, satp, true, false, s, 0, &mut mem)
+                .unwrap_err();
+            assert!(matches!(err, MemError::InstructionPageFault(addr) if addr == va));
+        }
+
+        // Canonical upper-half addresses walk normally: Sv39 root index 256
+        let mut mmu = Mmu::new();
+        mem.write_u64_phys(ROOT + 256 * PTE_SIZE, pte(0x8000_0000, PTE_V | PTE_R))
+            .unwrap();
+        let pa = mmu.translate(0xffff_ffc0_0000_1234, SATP, false, false, s, 0, &mut mem);
+        assert_eq!(pa.unwrap(), 0x8000_1234);
+    }
+
+    #[test]
+    fn test_fetch_loop_is_served_from_itlb() {
+        let mut mem = Memory::new(0x10000);
+        let mut mmu = Mmu::new();
+        map_4k(&mut mem, PTE_V | PTE_X | PTE_A);
+        let s = PrivMode::Supervisor;
+
+        // A three-instruction loop body fetched 1000 times walks the table once
+        for i in 0..3000u64 {
+            let va = 0x4000_5000 + (i % 3) * 4;
+            let pa = mmu
+                .translate(va, SATP, true, false, s, 0, &mut mem)
+                .unwrap();
+            assert_eq!(pa, 0x8000_8000 + (i % 3) * 4);
+        }
+        let (itlb, dtlb) = mmu.tlb_stats();
+        assert_eq!(
+            itlb,
+            TlbStats {
+                hits: 2999,
+                misses: 1
+            }
+        );
+        assert_eq!(dtlb, TlbStats::default());
+
+        // Clobbering the PTE isn't observed until the TLB is flushed
+        mem.write_u64_phys(L0 + 5 * PTE_SIZE, 0).unwrap();
+        assert!(
+            mmu.translate(0x4000_5000, SATP, true, false, s, 0, &mut mem)
+                .is_ok()
+        );
+        mmu.flush_tlb(Some(0x4000_5000), None);
+        let err = mmu
+            .translate(0x4000_5000, SATP, true, false, s, 0, &mut mem)
+            .unwrap_err();
+        assert!(matches!(err, MemError::InstructionPageFault(_)));
+    }
+
+    #[test]
+    fn test_tlb_evicts_least_recently_used() {
+        let mut mem = Memory::new(0x10000);
+        let mut mmu = Mmu::new();
+        let s = PrivMode::Supervisor;
+
+        // Identity-map 33 pages at VA 0x4000_0000.. through one L0 table
+        mem.write_u64_phys(ROOT + 8, pte(L1, PTE_V)).unwrap();
+        mem.write_u64_phys(L1, pte(L0, PTE_V)).unwrap();
+        for i in 0..=TLB_ENTRIES as u64 {
+            let leaf = pte(0x8000_0000 + (i << PAGE_SHIFT), PTE_V | PTE_R | PTE_A);
+            mem.write_u64_phys(L0 + i * PTE_SIZE, leaf).unwrap();
+        }
+        let va = |i: u64| 0x4000_0000 + (i << PAGE_SHIFT);
+
+        for i in 0..TLB_ENTRIES as u64 {
+            mmu.translate(va(i), SATP, false, false, s, 0, &mut mem)
+                .unwrap();
+        }
+        // Touch page 0 so page 1 becomes the LRU victim
+        mmu.translate(va(0), SATP, false, false, s, 0, &mut mem)
+            .unwrap();
+        mmu.translate(va(TLB_ENTRIES as u64), SATP, false, false, s, 0, &mut mem)
+            .unwrap();
+
+        let before = mmu.tlb_stats().1.misses;
+        mmu.translate(va(0), SATP, false, false, s, 0, &mut mem)
+            .unwrap();
+        assert_eq!(mmu.tlb_stats().1.misses, before, "page 0 should still hit");
+        mmu.translate(va(1), SATP, false, false, s, 0, &mut mem)
+            .unwrap();
+        assert_eq!(mmu.tlb_stats().1.misses, before + 1, "page 1 was evicted");
+    }
+
+    #[test]
+    fn test_unreachable_pte_is_access_fault_not_page_fault() {
+        let mut mem = Memory::new(0x10000);
+        let mut mmu = Mmu::new();
+        let s = PrivMode::Supervisor;
+
+        // Non-leaf root entry pointing at a table outside RAM
+        mem.write_u64_phys(ROOT + 8, pte(0x1000_0000_0000, PTE_V))
+            .unwrap();
+
+        let err = mmu
+            .translate(0x4000_0000, SATP, false, true, s, 0, &mut mem)
+            .unwrap_err();
+        assert!(matches!(err, MemError::StoreAccessFault(0x4000_0000)));
+        let err = mmu
+            .translate(0x4000_0000, SATP, true, false, s, 0, &mut mem)
+            .unwrap_err();
+        assert!(matches!(err, MemError::InstructionAccessFault(0x4000_0000)));
+    }
+
+    #[test]
+    fn test_sum_permits_supervisor_data_access_to_user_pages() {
+        let mut mem = Memory::new(0x10000);
+        let mut mmu = Mmu::new();
+        map_4k(&mut mem, PTE_V | PTE_R | PTE_W | PTE_X | PTE_U);
+        let (va, s) = (0x4000_5000, PrivMode::Supervisor);
+        let sum = CsrFile::MSTATUS_SUM;
+
+        assert!(
+            mmu.translate(va, SATP, false, false, s, 0, &mut mem)
+                .is_err()
+        );
+        assert!(
+            mmu.translate(va, SATP, false, false, s, sum, &mut mem)
+                .is_ok()
+        );
+        assert!(
+            mmu.translate(va, SATP, false, true, s, sum, &mut mem)
+                .is_ok()
+        );
+
+        // SUM never allows S-mode to execute user code
+        let err = mmu
+            .translate(va, SATP, true, false, s, sum, &mut mem)
+            .unwrap_err();
+        assert!(matches!(err, MemError::InstructionPageFault(_)));
+
+        // The cached entry is rechecked when SUM is cleared again
+        let err = mmu
+            .translate(va, SATP, false, false, s, 0, &mut mem)
+            .unwrap_err();
+        assert!(matches!(err, MemError::LoadPageFault(_)));
+    }
+
+    #[test]
+    fn test_mxr_makes_execute_only_pages_readable() {
+        let mut mem = Memory::new(0x10000);
+        let mut mmu = Mmu::new();
+        map_4k(&mut mem, PTE_V | PTE_X);
+        let (va, s) = (0x4000_5000, PrivMode::Supervisor);
+        let mxr = CsrFile::MSTATUS_MXR;
+
+        let err = mmu
+            .translate(va, SATP, false, false, s, 0, &mut mem)
+            .unwrap_err();
+        assert!(matches!(err, MemError::LoadPageFault(_)));
+        assert!(
+            mmu.translate(va, SATP, false, false, s, mxr, &mut mem)
+                .is_ok()
+        );
+
+        // MXR only affects loads
+        let err = mmu
+            .translate(va, SATP, false, true, s, mxr, &mut mem)
+            .unwrap_err();
+        assert!(matches!(err, MemError::StorePageFault(_)));
+    }
+}
@@ -0,0 +1,325 @@
+use std::cell::Cell;
+
+use crate::bus::Device;
+use crate::snapshot::{Reader, SnapshotError, Writer};
+
+/// Platform-Level Interrupt Controller (PLIC) at the QEMU virt base address.
+///
+/// Routes device interrupt lines (sources 1..NUM_SOURCES) to the two contexts
+/// of the single hart: context 0 drives mip.MEIP and context 1 drives mip.SEIP.
+///
+/// Register layout:
+///   0x000000  priority      (32-bit per source; source 0 is reserved)
+///   0x001000  pending       (one bit per source)
+///   0x002000  enable        (one bit per source, 0x80 per context)
+///   0x200000  threshold     (0x1000 per context)
+///   0x200004  claim/complete
+pub const PLIC_BASE: u64 = 0x0C00_0000;
+pub const PLIC_SIZE: u64 = 0x400_0000;
+
+pub const NUM_SOURCES: usize = 64;
+pub const CONTEXT_M: usize = 0;
+pub const CONTEXT_S: usize = 1;
+const NUM_CONTEXTS: usize = 2;
+
+const PRIORITY: u64 = 0x00_0000;
+const PENDING: u64 = 0x00_1000;
+const ENABLE: u64 = 0x00_2000;
+const ENABLE_STRIDE: u64 = 0x80;
+const CONTEXT: u64 = 0x20_0000;
+const CONTEXT_STRIDE: u64 = 0x1000;
+
+// Bitmaps are exposed as 32-bit words, NUM_SOURCES / 32 of them
+const BITMAP_BYTES: u64 = NUM_SOURCES as u64 / 8;
+// Priorities are 3 bits wide, as on QEMU virt
+const PRIORITY_MASK: u32 = 0x7;
+
+#[derive(Clone)]
+pub struct Plic {
+    priority: [u32; NUM_SOURCES],
+    enable: [u64; NUM_CONTEXTS],
+    threshold: [u32; NUM_CONTEXTS],
+    /// Current level of each source's interrupt line
+    level: u64,
+
+    // Claim reads have a side effect, so the pending and in-service (claimed
+    // but not yet completed) sets live in Cells to keep reads &self.
+    pending: Cell<u64>,
+    claimed: Cell<u64>,
+}
+
+impl Default for Plic {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Plic {
+    pub fn new() -> Self {
+        Self {
+            priority: [0; NUM_SOURCES],
+            enable: [0; NUM_CONTEXTS],
+            threshold: [0; NUM_CONTEXTS],
+            level: 0,
+            pending: Cell::new(0),
+            claimed: Cell::new(0),
+        }
+    }
+
+    pub fn contains(paddr: u64) -> bool {
+        paddr >= PLIC_BASE && paddr - PLIC_BASE < PLIC_SIZE
+    }
+
+    /// Drive a source's interrupt line. Lines are level-triggered: pending
+    /// follows the level, except that a source being serviced stays quiet
+    /// until its completion.
+    pub fn set_irq(&mut self, id: u32, level: bool) {
+        let Some(bit) = source_bit(id) else {
+            return;
+        };
+        if level {
+            self.level |= bit;
+            if self.claimed.get() & bit == 0 {
+                self.pending.set(self.pending.get() | bit);
+            }
+        } else {
+            self.level &= !bit;
+            self.pending.set(self.pending.get() & !bit);
+        }
+    }
+
+    /// Output line for `context`: some enabled source is pending above its threshold.
+    pub fn irq_pending(&self, context: usize) -> bool {
+        self.best_pending(context).is_some()
+    }
+
+    /// Read the 32-bit register containing `offset`. Unmapped offsets read as zero.
+    pub fn read(&self, offset: u64, _size: u64) -> u64 {
+        let offset = offset & !0x3;
+        let value = match offset {
+            PRIORITY..PENDING => self.priority.get(offset as usize / 4).copied().unwrap_or(0),
+            PENDING..ENABLE => bitmap_word(self.pending.get(), offset - PENDING),
+            ENABLE..CONTEXT => match enable_slot(offset) {
+                Some((context, word_off)) => bitmap_word(self.enable[context], word_off),
+                None => 0,
+            },
+            _ => match context_slot(offset) {
+                Some((context, 0)) => self.threshold[context],
+                Some((context, 4)) => self.claim(context),
+                _ => 0,
+            },
+        };
+        value as u64
+    }
+
+    /// Write the 32-bit register containing `offset`. Unmapped offsets are ignored.
+    pub fn write(&mut self, offset: u64, _size: u64, value: u64) {
+        let offset = offset & !0x3;
+        let value = value as u32;
+        match offset {
+            PRIORITY..PENDING => {
+                let id = offset as usize / 4;
+                if id != 0 && id < NUM_SOURCES {
+                    self.priority[id] = value & PRIORITY_MASK;
+                }
+            }
+            // Pending bits are read-only
+            PENDING..ENABLE => {}
+            ENABLE..CONTEXT => {
+                if let Some((context, word_off)) = enable_slot(offset) {
+                    let shift = word_off * 8;
+                    let word = (0xffff_ffffu64 << shift) & !1; // source 0 doesn't exist
+                    let enable = &mut self.enable[context];
+                    *enable = (*enable & !word) | (((value as u64) << shift) & word);
+                }
+            }
+            _ => match context_slot(offset) {
+                Some((context, 0)) => self.threshold[context] = value & PRIORITY_MASK,
+                Some((context, 4)) => self.complete(context, value),
+                _ => {}
+            },
+        }
+    }
+
+    /// Highest-priority enabled pending source above the context's threshold;
+    /// ties go to the lowest id.
+    fn best_pending(&self, context: usize) -> Option<u32> {
+        let candidates = self.pending.get() & self.enable[context];
+        (1..NUM_SOURCES)
+            .filter(|&id| candidates & (1 << id) != 0)
+            .filter(|&id| self.priority[id] > self.threshold[context])
+            .max_by_key(|&id| (self.priority[id], std::cmp::Reverse(id)))
+            .map(|id| id as u32)
+    }
+
+    /// Claim the best pending source for `context`, or 0 if there is none.
+    fn claim(&self, context: usize) -> u32 {
+        let Some(id) = self.best_pending(context) else {
+            return 0;
+        };
+        let bit = 1u64 << id;
+        self.pending.set(self.pending.get() & !bit);
+        self.claimed.set(self.claimed.get() | bit);
+        id
+    }
+
+    /// Finish servicing `id`. A line that is still asserted becomes pending again.
+    fn complete(&mut self, context: usize, id: u32) {
+        let Some(bit) = source_bit(id) else {
+            return;
+        };
+        // Completions for sources not enabled in this context are ignored
+        if self.enable[context] & bit == 0 {
+            return;
+        }
+        self.claimed.set(self.claimed.get() & !bit);
+        if self.level & bit != 0 {
+            self.pending.set(self.pending.get() | bit);
+        }
+    }
+
+    pub(crate) fn save(&self, w: &mut Writer) {
+        for priority in self.priority {
+            w.u32(priority);
+        }
+        for context in 0..NUM_CONTEXTS {
+            w.u64(self.enable[context]);
+            w.u32(self.threshold[context]);
+        }
+        w.u64(self.level);
+        w.u64(self.pending.get());
+        w.u64(self.claimed.get());
+    }
+
+    pub(crate) fn load(r: &mut Reader) -> Result<Self, SnapshotError> {
+        let mut plic = Self::new();
+        for priority in plic.priority.iter_mut() {
+            *priority = r.u32()?;
+        }
+        for context in 0..NUM_CONTEXTS {
+            plic.enable[context] = r.u64()?;
+            plic.threshold[context] = r.u32()?;
+        }
+        plic.level = r.u64()?;
+        plic.pending.set(r.u64()?);
+        plic.claimed.set(r.u64()?);
+        Ok(plic)
+    }
+}
+
+impl Device for Plic {
+    fn read(&self, offset: u64, size: u64) -> u64 {
+        Plic::read(self, offset, size)
+    }
+
+    fn write(&mut self, offset: u64, size: u64, value: u64) {
+        Plic::write(self, offset, size, value)
+    }
+}
+
+fn source_bit(id: u32) -> Option<u64> {
+    (id != 0 && (id as usize) < NUM_SOURCES).then(|| 1u64 << id)
+}
+
+/// The 32-bit word `byte_off` bytes into a bitmap.
+fn bitmap_word(bitmap: u64, byte_off: u64) -> u32 {
+    if byte_off >= BITMAP_BYTES {
+        return 0;
+    }
+    (bitmap >> (byte_off * 8)) as u32
+}
+
+/// Context and word offset of an address in the enable block.
+fn enable_slot(offset: u64) -> Option<(usize, u64)> {
+    let context = ((offset - ENABLE) / ENABLE_STRIDE) as usize;
+    let word_off = (offset - ENABLE) % ENABLE_STRIDE;
+    (context < NUM_CONTEXTS && word_off < BITMAP_BYTES).then_some((context, word_off))
+}
+
+/// Context and register offset (0 = threshold, 4 = claim/complete) of an
+/// address in the per-context block.
+fn context_slot(offset: u64) -> Option<(usize, u64)> {
+    let rel = offset.checked_sub(CONTEXT)?;
+    let context = (rel / CONTEXT_STRIDE) as usize;
+    (context < NUM_CONTEXTS).then_some((context, rel % CONTEXT_STRIDE))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn threshold(context: usize) -> u64 {
+        CONTEXT + CONTEXT_STRIDE * context as u64
+    }
+
+    fn claim(context: usize) -> u64 {
+        threshold(context) + 4
+    }
+
+    #[test]
+    fn test_claim_picks_highest_priority_and_clears_pending() {
+        let mut plic = Plic::new();
+        plic.write(PRIORITY + 4 * 3, 4, 1);
+        plic.write(PRIORITY + 4 * 10, 4, 5);
+        plic.write(PRIORITY + 4 * 12, 4, 5);
+        plic.write(ENABLE, 4, (1 << 3) | (1 << 10) | (1 << 12));
+
+        plic.set_irq(3, true);
+        plic.set_irq(10, true);
+        plic.set_irq(12, true);
+        assert_eq!(plic.read(PENDING, 4), (1 << 3) | (1 << 10) | (1 << 12));
+        assert!(plic.irq_pending(CONTEXT_M));
+        assert!(!plic.irq_pending(CONTEXT_S), "nothing enabled for S");
+
+        // Equal priorities go to the lower id
+        assert_eq!(plic.read(claim(CONTEXT_M), 4), 10);
+        assert_eq!(plic.read(PENDING, 4), (1 << 3) | (1 << 12));
+        assert_eq!(plic.read(claim(CONTEXT_M), 4), 12);
+        assert_eq!(plic.read(claim(CONTEXT_M), 4), 3);
+        assert_eq!(plic.read(claim(CONTEXT_M), 4), 0);
+        assert!(!plic.irq_pending(CONTEXT_M));
+    }
+
+    #[test]
+    fn test_complete_rearms_an_asserted_line() {
+        let mut plic = Plic::new();
+        plic.write(PRIORITY + 4 * 10, 4, 1);
+        plic.write(ENABLE + ENABLE_STRIDE, 4, 1 << 10);
+        plic.set_irq(10, true);
+
+        assert_eq!(plic.read(claim(CONTEXT_S), 4), 10);
+        // Still asserted, but in service until completed
+        plic.set_irq(10, true);
+        assert!(!plic.irq_pending(CONTEXT_S));
+
+        plic.write(claim(CONTEXT_S), 4, 10);
+        assert!(plic.irq_pending(CONTEXT_S));
+
+        // Once the device drops the line, completion leaves it idle
+        assert_eq!(plic.read(claim(CONTEXT_S), 4), 10);
+        plic.set_irq(10, false);
+        plic.write(claim(CONTEXT_S), 4, 10);
+        assert!(!plic.irq_pending(CONTEXT_S));
+    }
+
+    #[test]
+    fn test_threshold_and_priority_zero_mask_sources() {
+        let mut plic = Plic::new();
+        plic.write(ENABLE, 4, 1 << 5);
+        plic.set_irq(5, true);
+        assert!(!plic.irq_pending(CONTEXT_M), "priority 0 never interrupts");
+
+        plic.write(PRIORITY + 4 * 5, 4, 2);
+        assert!(plic.irq_pending(CONTEXT_M));
+        plic.write(threshold(CONTEXT_M), 4, 2);
+        assert!(!plic.irq_pending(CONTEXT_M), "must exceed the threshold");
+        assert_eq!(plic.read(claim(CONTEXT_M), 4), 0);
+        assert_eq!(plic.read(threshold(CONTEXT_M), 4), 2);
+
+        // Source 0 is reserved: no priority, no enable bit
+        plic.write(PRIORITY, 4, 7);
+        plic.write(ENABLE, 4, 0xffff_ffff);
+        assert_eq!(plic.read(PRIORITY, 4), 0);
+        assert_eq!(plic.read(ENABLE, 4), 0xffff_fffe);
+    }
+}
@@ -0,0 +1,251 @@
+use crate::csr::PrivMode;
+use crate::snapshot::{Reader, SnapshotError, Writer};
+
+/// Physical Memory Protection: 16 entries, each a pmpcfg byte and a pmpaddr.
+///
+/// pmpcfg byte layout:
+///   bit 0  R
+///   bit 1  W
+///   bit 2  X
+///   3..4   A (address matching: OFF, TOR, NA4, NAPOT)
+///   bit 7  L (lock; also applies the entry to M-mode)
+///
+/// pmpaddr holds bits 55:2 of a physical address.
+///
+/// A locked entry's pmpcfg byte and pmpaddr can't be changed until reset,
+/// and neither can the pmpaddr below a locked TOR entry, since it's that
+/// entry's base.
+pub const PMP_ENTRIES: usize = 16;
+
+pub const PMP_R: u8 = 1 << 0;
+pub const PMP_W: u8 = 1 << 1;
+pub const PMP_X: u8 = 1 << 2;
+pub const PMP_L: u8 = 1 << 7;
+const PMP_A_SHIFT: u8 = 3;
+const PMP_A_MASK: u8 = 0b11 << PMP_A_SHIFT;
+/// Bits 5 and 6 are reserved and read as zero
+const PMP_CFG_WRITABLE: u8 = PMP_R | PMP_W | PMP_X | PMP_A_MASK | PMP_L;
+
+pub const PMP_A_OFF: u8 = 0;
+pub const PMP_A_TOR: u8 = 1 << PMP_A_SHIFT;
+pub const PMP_A_NA4: u8 = 2 << PMP_A_SHIFT;
+pub const PMP_A_NAPOT: u8 = 3 << PMP_A_SHIFT;
+
+/// Writable bits of pmpaddr on RV64
+pub const PMPADDR_MASK: u64 = (1 << 54) - 1;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Pmp {
+    /// pmpcfg0 and pmpcfg2, each packing eight entries' config bytes
+    pub cfg: [u64; 2],
+    pub addr: [u64; PMP_ENTRIES],
+}
+
+impl Pmp {
+    /// Config byte of entry `i`
+    pub fn cfg(&self, i: usize) -> u8 {
+        (self.cfg[i / 8] >> (8 * (i % 8))) as u8
+    }
+
+    fn locked(&self, i: usize) -> bool {
+        self.cfg(i) & PMP_L != 0
+    }
+
+    /// Write pmpcfg0 (`word` 0) or pmpcfg2 (`word` 1), leaving the bytes of
+    /// locked entries as they were.
+    pub fn write_cfg(&mut self, word: usize, value: u64) {
+        let mut cfg = self.cfg[word];
+        for byte in 0..8 {
+            if self.locked(word * 8 + byte) {
+                continue;
+            }
+            let shift = 8 * byte;
+            let new = (value >> shift) as u8 & PMP_CFG_WRITABLE;
+            cfg = (cfg & !(0xff << shift)) | (new as u64) << shift;
+        }
+        self.cfg[word] = cfg;
+    }
+
+    /// Write pmpaddr `i`, unless it's locked by its own entry or by the TOR
+    /// entry above it.
+    pub fn write_addr(&mut self, i: usize, value: u64) {
+        let base_of_locked_tor =
+            i + 1 < PMP_ENTRIES && self.locked(i + 1) && self.cfg(i + 1) & PMP_A_MASK == PMP_A_TOR;
+        if !self.locked(i) && !base_of_locked_tor {
+            self.addr[i] = value & PMPADDR_MASK;
+        }
+    }
+
+    /// Byte range `[lo, hi)` entry `i` matches, or None while it's off. Kept
+    /// in u128 so a NAPOT entry covering the whole address space fits.
+    fn range(&self, i: usize) -> Option<(u128, u128)> {
+        let addr = self.addr[i] as u128;
+        match self.cfg(i) & PMP_A_MASK {
+            PMP_A_TOR => {
+                let lo = if i == 0 {
+                    0
+                } else {
+                    (self.addr[i - 1] as u128) << 2
+                };
+                Some((lo, addr << 2))
+            }
+            PMP_A_NA4 => Some((addr << 2, (addr << 2) + 4)),
+            PMP_A_NAPOT => {
+                // Trailing ones encode the size: 2^(ones + 3) bytes
+                let ones = self.addr[i].trailing_ones();
+                let base = (addr & !((1u128 << ones) - 1)) << 2;
+                Some((base, base + (1u128 << (ones + 3))))
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether a `size`-byte access at `paddr` from `priv_mode` is allowed.
+    ///
+    /// The lowest-numbered entry matching any byte decides; an access only
+    /// partly inside it fails. Unlocked entries don't apply to M-mode, and an
+    /// S/U-mode access no entry matches fails. While every entry is off, PMP
+    /// isn't in use and all accesses are allowed.
+    pub fn check(
+        &self,
+        paddr: u64,
+        size: u64,
+        is_fetch: bool,
+        is_write: bool,
+        priv_mode: PrivMode,
+    ) -> bool {
+        if (0..PMP_ENTRIES).all(|i| self.cfg(i) & PMP_A_MASK == PMP_A_OFF) {
+            return true;
+        }
+
+        let start = paddr as u128;
+        let end = start + size as u128;
+        for i in 0..PMP_ENTRIES {
+            let Some((lo, hi)) = self.range(i) else {
+                continue;
+            };
+            if end <= lo || start >= hi {
+                continue;
+            }
+            if start < lo || end > hi {
+                return false;
+            }
+            let cfg = self.cfg(i);
+            if priv_mode == PrivMode::Machine && cfg & PMP_L == 0 {
+                return true;
+            }
+            let needed = if is_fetch {
+                PMP_X
+            } else if is_write {
+                PMP_W
+            } else {
+                PMP_R
+            };
+            return cfg & needed != 0;
+        }
+        priv_mode == PrivMode::Machine
+    }
+
+    pub(crate) fn save(&self, w: &mut Writer) {
+        for addr in self.addr {
+            w.u64(addr);
+        }
+        for cfg in self.cfg {
+            w.u64(cfg);
+        }
+    }
+
+    pub(crate) fn load(r: &mut Reader) -> Result<Self, SnapshotError> {
+        let mut pmp = Self::default();
+        for addr in pmp.addr.iter_mut() {
+            *addr = r.u64()?;
+        }
+        for cfg in pmp.cfg.iter_mut() {
+            *cfg = r.u64()?;
+        }
+        Ok(pmp)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RWX: u8 = PMP_R | PMP_W | PMP_X;
+
+    fn set(pmp: &mut Pmp, i: usize, cfg: u8, addr: u64) {
+        pmp.addr[i] = addr;
+        let shift = 8 * (i % 8);
+        pmp.cfg[i / 8] = (pmp.cfg[i / 8] & !(0xff << shift)) | (cfg as u64) << shift;
+    }
+
+    #[test]
+    fn test_address_matching_modes() {
+        let mut pmp = Pmp::default();
+        // 0: TOR [0, 0x8000_0000) read-only
+        set(&mut pmp, 0, PMP_A_TOR | PMP_R, 0x8000_0000 >> 2);
+        // 1: NA4 [0x8000_0000, 0x8000_0004) no access
+        set(&mut pmp, 1, PMP_A_NA4, 0x8000_0000 >> 2);
+        // 2: NAPOT 4 KiB at 0x8000_0000, RWX (shadowed by 1 for its first word)
+        set(&mut pmp, 2, PMP_A_NAPOT | RWX, (0x8000_0000 >> 2) | 0x1ff);
+
+        let s = PrivMode::Supervisor;
+        assert!(pmp.check(0x1000, 8, false, false, s));
+        assert!(!pmp.check(0x1000, 8, false, true, s));
+        assert!(!pmp.check(0x8000_0000, 4, false, false, s));
+        assert!(pmp.check(0x8000_0004, 4, true, false, s));
+        assert!(pmp.check(0x8000_0ff8, 8, false, true, s));
+        // Partly in the NA4 entry, partly in the NAPOT one
+        assert!(!pmp.check(0x7fff_fffc, 8, false, false, s));
+        // Past every entry
+        assert!(!pmp.check(0x8000_1000, 4, false, false, s));
+        assert!(pmp.check(0x8000_1000, 4, false, false, PrivMode::Machine));
+    }
+
+    #[test]
+    fn test_lock_applies_entries_to_machine_mode() {
+        let mut pmp = Pmp::default();
+        let napot = (0x8000_0000 >> 2) | 0x1ff;
+        set(&mut pmp, 0, PMP_A_NAPOT | PMP_R, napot);
+
+        let m = PrivMode::Machine;
+        assert!(pmp.check(0x8000_0000, 4, false, true, m));
+        set(&mut pmp, 0, PMP_A_NAPOT | PMP_R | PMP_L, napot);
+        assert!(!pmp.check(0x8000_0000, 4, false, true, m));
+        assert!(pmp.check(0x8000_0000, 4, false, false, m));
+
+        // NAPOT with every bit set covers the whole address space
+        let mut all = Pmp::default();
+        set(&mut all, 0, PMP_A_NAPOT | RWX, PMPADDR_MASK);
+        assert!(all.check((1 << 56) - 8, 8, false, true, PrivMode::User));
+    }
+
+    #[test]
+    fn test_locked_entries_ignore_writes() {
+        let mut pmp = Pmp::default();
+        // Entry 1 locked TOR, entry 9 locked NA4 (second word)
+        pmp.write_cfg(0, ((PMP_L | PMP_A_TOR | PMP_R) as u64) << 8 | RWX as u64);
+        pmp.write_cfg(1, ((PMP_L | PMP_A_NA4) as u64) << 8);
+        // Reserved bits 5 and 6 are dropped
+        assert_eq!(pmp.cfg(0), RWX);
+
+        pmp.write_cfg(0, 0x7f7f_7f7f_7f7f_7f7f);
+        assert_eq!(pmp.cfg(0), PMP_CFG_WRITABLE & !PMP_L);
+        assert_eq!(pmp.cfg(1), PMP_L | PMP_A_TOR | PMP_R);
+        assert_eq!(pmp.cfg(2), PMP_CFG_WRITABLE & !PMP_L);
+        pmp.write_cfg(1, 0);
+        assert_eq!(pmp.cfg(9), PMP_L | PMP_A_NA4);
+        assert_eq!(pmp.cfg(8), 0);
+
+        for i in 0..PMP_ENTRIES {
+            pmp.write_addr(i, 0x1234);
+        }
+        // 0 is entry 1's TOR base, 1 and 9 are locked themselves
+        assert_eq!(pmp.addr[0], 0);
+        assert_eq!(pmp.addr[1], 0);
+        assert_eq!(pmp.addr[9], 0);
+        assert_eq!(pmp.addr[2], 0x1234);
+        // Entry 9 is NA4, so the address below it stays writable
+        assert_eq!(pmp.addr[8], 0x1234);
+    }
+}
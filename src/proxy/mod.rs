@@ -0,0 +1,397 @@
+use std::io::{Read, Write};
+
+use crate::cpu::Cpu;
+use crate::mem::Memory;
+use crate::mmu::Mmu;
+
+// Linux syscall numbers (asm-generic, as used by RISC-V)
+const SYS_CLOSE: u64 = 57;
+const SYS_READ: u64 = 63;
+const SYS_WRITE: u64 = 64;
+const SYS_FSTAT: u64 = 80;
+const SYS_EXIT: u64 = 93;
+const SYS_EXIT_GROUP: u64 = 94;
+const SYS_BRK: u64 = 214;
+
+// errno values, returned negated in a0
+const EIO: i64 = 5;
+const EBADF: i64 = 9;
+const EFAULT: i64 = 14;
+const ENOSYS: i64 = 38;
+
+/// Most bytes one read or write moves. Larger requests get a short count, as
+/// Linux may return, so a guest can't make the host allocate without bound.
+const MAX_IO: u64 = 1 << 16;
+
+/// Size of `struct stat` on RV64 Linux, and the offsets `fstat` fills in
+const STAT_SIZE: usize = 128;
+const STAT_MODE: usize = 16;
+const STAT_BLKSIZE: usize = 56;
+/// S_IFCHR | 0620: the standard streams look like a terminal, so newlib
+/// line-buffers stdout
+const STAT_MODE_TTY: u32 = 0o020620;
+
+/// A tiny proxy kernel: ECALLs from U-mode are served on the host instead of
+/// trapping, for newlib programs that only need the standard streams, a heap
+/// and exit. Arguments are in a0-a5 with the syscall number in a7, and the
+/// result (or a negated errno) goes back in a0.
+///
+/// Supported: read, write, close and fstat on fds 0-2, brk, exit and
+/// exit_group. Anything else fails with ENOSYS.
+pub struct Proxy {
+    brk_start: u64,
+    brk: u64,
+    brk_limit: u64,
+    stdin: Box<dyn Read>,
+    stdout: Box<dyn Write>,
+    stderr: Box<dyn Write>,
+}
+
+impl Proxy {
+    /// Proxy on the host's standard streams. The program break starts at
+    /// `brk_start` (the end of the loaded image) and can't grow past `brk_limit`.
+    pub fn new(brk_start: u64, brk_limit: u64) -> Self {
+        Self::with_io(
+            brk_start,
+            brk_limit,
+            Box::new(std::io::stdin()),
+            Box::new(std::io::stdout()),
+            Box::new(std::io::stderr()),
+        )
+    }
+
+    pub fn with_io(
+        brk_start: u64,
+        brk_limit: u64,
+        stdin: Box<dyn Read>,
+        stdout: Box<dyn Write>,
+        stderr: Box<dyn Write>,
+    ) -> Self {
+        Self {
+            brk_start,
+            brk: brk_start,
+            brk_limit,
+            stdin,
+            stdout,
+            stderr,
+        }
+    }
+
+    /// Carry out the syscall the hart's registers describe, as if the ECALL
+    /// had returned. Returns the exit status if the program exited.
+    pub fn syscall(&mut self, cpu: &mut Cpu, mem: &mut Memory, mmu: &mut Mmu) -> Option<u64> {
+        let (a0, a1, a2) = (cpu.a0(), cpu.a1(), cpu.a2());
+        let ret = match cpu.a7() {
+            SYS_EXIT | SYS_EXIT_GROUP => return Some(a0 & 0xff),
+            SYS_READ => self.read(cpu, mem, mmu, a0, a1, a2),
+            SYS_WRITE => self.write(cpu, mem, mmu, a0, a1, a2),
+            SYS_CLOSE => {
+                if a0 <= 2 {
+                    // Keep the host's streams open for the rest of the run
+                    0
+                } else {
+                    -EBADF
+                }
+            }
+            SYS_FSTAT => fstat(cpu, mem, mmu, a0, a1),
+            SYS_BRK => {
+                // Linux leaves the break alone on a request it can't satisfy
+                if (self.brk_start..=self.brk_limit).contains(&a0) {
+                    self.brk = a0;
+                }
+                self.brk as i64
+            }
+            _ => -ENOSYS,
+        };
+        cpu.set_reg(10, ret as u64);
+        None
+    }
+
+    fn read(
+        &mut self,
+        cpu: &Cpu,
+        mem: &mut Memory,
+        mmu: &mut Mmu,
+        fd: u64,
+        buf: u64,
+        len: u64,
+    ) -> i64 {
+        if fd != 0 {
+            return -EBADF;
+        }
+        let mut bytes = vec![0; len.min(MAX_IO) as usize];
+        // Fault before taking anything from stdin, or the input would be lost
+        let c = &cpu.csr;
+        if mem
+            .probe_write(buf, bytes.len(), c.satp, c.priv_mode, c.mstatus, mmu)
+            .is_err()
+        {
+            return -EFAULT;
+        }
+        let n = match self.stdin.read(&mut bytes) {
+            Ok(n) => n,
+            Err(_) => return -EIO,
+        };
+        match write_guest(cpu, mem, mmu, buf, &bytes[..n]) {
+            Ok(()) => n as i64,
+            Err(errno) => errno,
+        }
+    }
+
+    fn write(
+        &mut self,
+        cpu: &Cpu,
+        mem: &mut Memory,
+        mmu: &mut Mmu,
+        fd: u64,
+        buf: u64,
+        len: u64,
+    ) -> i64 {
+        let out = match fd {
+            1 => &mut self.stdout,
+            2 => &mut self.stderr,
+            _ => return -EBADF,
+        };
+        let len = len.min(MAX_IO);
+        let c = &cpu.csr;
+        let Ok(bytes) = mem.read_bytes(buf, len as usize, c.satp, c.priv_mode, c.mstatus, mmu)
+        else {
+            return -EFAULT;
+        };
+        match out.write_all(&bytes).and_then(|()| out.flush()) {
+            Ok(()) => len as i64,
+            Err(_) => -EIO,
+        }
+    }
+}
+
+fn fstat(cpu: &Cpu, mem: &mut Memory, mmu: &mut Mmu, fd: u64, statbuf: u64) -> i64 {
+    if fd > 2 {
+        return -EBADF;
+    }
+    let mut stat = [0u8; STAT_SIZE];
+    stat[STAT_MODE..STAT_MODE + 4].copy_from_slice(&STAT_MODE_TTY.to_le_bytes());
+    stat[STAT_BLKSIZE..STAT_BLKSIZE + 4].copy_from_slice(&1024u32.to_le_bytes());
+    match write_guest(cpu, mem, mmu, statbuf, &stat) {
+        Ok(()) => 0,
+        Err(errno) => errno,
+    }
+}
+
+/// Store `bytes` at the guest virtual address `addr`, translated as the hart
+/// would; a fault is EFAULT.
+fn write_guest(
+    cpu: &Cpu,
+    mem: &mut Memory,
+    mmu: &mut Mmu,
+    addr: u64,
+    bytes: &[u8],
+) -> Result<(), i64> {
+    let c = &cpu.csr;
+    mem.write_bytes(addr, bytes, c.satp, c.priv_mode, c.mstatus, mmu)
+        .map_err(|_| -EFAULT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::{HaltReason, Machine, StepOutcome};
+    use crate::csr::PrivMode;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[derive(Clone, Default)]
+    struct SharedBuf(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    const HEAP: u64 = 0x8000_4000;
+    const STACK: u64 = 0x8000_8000;
+
+    fn machine(stdin: &'static [u8], stdout: &SharedBuf) -> Machine {
+        let mut m = Machine::new(0x10000);
+        m.cpu.csr.priv_mode = PrivMode::User;
+        m.proxy = Some(Proxy::with_io(
+            HEAP,
+            STACK,
+            Box::new(stdin),
+            Box::new(stdout.clone()),
+            Box::new(std::io::sink()),
+        ));
+        m
+    }
+
+    /// Set a7 and a0-a2, then serve the call; the result is in a0.
+    fn call(m: &mut Machine, nr: u64, args: [u64; 3]) -> Option<u64> {
+        m.cpu.set_reg(17, nr);
+        for (i, arg) in args.into_iter().enumerate() {
+            m.cpu.set_reg(10 + i as u8, arg);
+        }
+        let proxy = m.proxy.as_mut().unwrap();
+        proxy.syscall(&mut m.cpu, &mut m.mem, &mut m.mmu)
+    }
+
+    #[test]
+    fn test_user_ecalls_write_and_exit_on_the_host() {
+        let out = SharedBuf::default();
+        let mut m = machine(b"", &out);
+        let program = [
+            0x0000_0597, // auipc a1, 0
+            0x0285_8593, // addi a1, a1, 40
+            0x0010_0513, // li a0, 1
+            0x0030_0613, // li a2, 3
+            0x0400_0893, // li a7, 64 (write)
+            0x0000_0073, // ecall
+            0x0070_0513, // li a0, 7
+            0x05d0_0893, // li a7, 93 (exit)
+            0x0000_0073, // ecall
+            0x0000_006f, // j .
+        ];
+        for (i, inst) in program.into_iter().enumerate() {
+            m.mem
+                .write_u32_phys(0x8000_0000 + 4 * i as u64, inst)
+                .unwrap();
+        }
+        m.mem.write_bytes_phys(0x8000_0028, b"hi\n").unwrap();
+
+        assert_eq!(
+            m.run(100),
+            StepOutcome::Halted(HaltReason::Exit { code: 7 })
+        );
+        assert_eq!(out.0.borrow().as_slice(), b"hi\n");
+        assert_eq!(m.cpu.pc, 0x8000_0024, "pc moves past the ECALL");
+        assert_eq!(m.cpu.csr.instret, 9);
+    }
+
+    #[test]
+    fn test_supervisor_ecall_still_traps() {
+        let mut m = machine(b"", &SharedBuf::default());
+        m.cpu.csr.priv_mode = PrivMode::Supervisor;
+        m.cpu.csr.mtvec = 0x8000_0100;
+        m.mem.write_u32_phys(0x8000_0000, 0x0000_0073).unwrap(); // ecall
+        m.step().unwrap();
+        assert_eq!(m.cpu.pc, 0x8000_0100);
+        assert_eq!(m.cpu.csr.mcause, 9);
+    }
+
+    #[test]
+    fn test_read_and_fstat_fill_guest_buffers() {
+        let mut m = machine(b"abc", &SharedBuf::default());
+        assert_eq!(call(&mut m, SYS_READ, [0, 0x8000_1000, 16]), None);
+        assert_eq!(m.cpu.a0(), 3);
+        assert_eq!(m.mem.read_bytes_phys(0x8000_1000, 3).unwrap(), b"abc");
+
+        assert_eq!(call(&mut m, SYS_FSTAT, [1, 0x8000_2000, 0]), None);
+        assert_eq!(m.cpu.a0(), 0);
+        let mode = m.mem.read_u32_phys(0x8000_2000 + STAT_MODE as u64).unwrap();
+        assert_eq!(mode, STAT_MODE_TTY);
+
+        // Only the standard streams exist, and unknown calls fail cleanly
+        call(&mut m, SYS_WRITE, [5, 0x8000_1000, 3]);
+        assert_eq!(m.cpu.a0() as i64, -EBADF);
+        call(&mut m, SYS_FSTAT, [1, 0x10, 0]);
+        assert_eq!(m.cpu.a0() as i64, -EFAULT);
+        call(&mut m, 1024, [0; 3]);
+        assert_eq!(m.cpu.a0() as i64, -ENOSYS);
+    }
+
+    #[test]
+    fn test_huge_lengths_are_clamped_not_allocated() {
+        let out = SharedBuf::default();
+        let mut m = machine(b"abc", &out);
+        // The clamped buffer fills all of RAM
+        call(&mut m, SYS_READ, [0, 0x8000_0000, u64::MAX]);
+        assert_eq!(m.cpu.a0(), 3);
+
+        // Still past the end of RAM after clamping
+        call(&mut m, SYS_WRITE, [1, 0x8000_1000, u64::MAX]);
+        assert_eq!(m.cpu.a0() as i64, -EFAULT);
+        call(&mut m, SYS_WRITE, [1, 0x8000_1000, u64::MAX >> 1]);
+        assert_eq!(m.cpu.a0() as i64, -EFAULT);
+        assert!(out.0.borrow().is_empty());
+
+        // A full-sized write in range comes back short
+        let mut m = Machine::new(0x20000);
+        m.proxy = Some(Proxy::with_io(
+            HEAP,
+            STACK,
+            Box::new(std::io::empty()),
+            Box::new(out.clone()),
+            Box::new(std::io::sink()),
+        ));
+        call(&mut m, SYS_WRITE, [1, 0x8000_0000, MAX_IO + 1]);
+        assert_eq!(m.cpu.a0(), MAX_IO);
+    }
+
+    /// A host stream whose every operation fails
+    struct Broken;
+
+    impl Read for Broken {
+        fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+            Err(std::io::ErrorKind::BrokenPipe.into())
+        }
+    }
+
+    impl Write for Broken {
+        fn write(&mut self, _buf: &[u8]) -> std::io::Result<usize> {
+            Err(std::io::ErrorKind::BrokenPipe.into())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_read_faults_before_consuming_stdin() {
+        let mut m = machine(b"abc", &SharedBuf::default());
+        // The buffer's last byte is past the end of RAM
+        call(&mut m, SYS_READ, [0, 0x8000_fffe, 3]);
+        assert_eq!(m.cpu.a0() as i64, -EFAULT);
+        call(&mut m, SYS_READ, [0, 0x8000_1000, 3]);
+        assert_eq!(m.cpu.a0(), 3);
+        assert_eq!(m.mem.read_bytes_phys(0x8000_1000, 3).unwrap(), b"abc");
+    }
+
+    #[test]
+    fn test_host_stream_errors_are_eio() {
+        let mut m = Machine::new(0x10000);
+        m.proxy = Some(Proxy::with_io(
+            HEAP,
+            STACK,
+            Box::new(Broken),
+            Box::new(Broken),
+            Box::new(Broken),
+        ));
+        call(&mut m, SYS_READ, [0, 0x8000_1000, 3]);
+        assert_eq!(m.cpu.a0() as i64, -EIO);
+        call(&mut m, SYS_WRITE, [2, 0x8000_1000, 3]);
+        assert_eq!(m.cpu.a0() as i64, -EIO);
+    }
+
+    #[test]
+    fn test_brk_grows_the_heap_within_its_limits() {
+        let mut m = machine(b"", &SharedBuf::default());
+        call(&mut m, SYS_BRK, [0; 3]);
+        assert_eq!(m.cpu.a0(), HEAP);
+        call(&mut m, SYS_BRK, [HEAP + 0x1000, 0, 0]);
+        assert_eq!(m.cpu.a0(), HEAP + 0x1000);
+
+        // Requests past the stack or below the image leave the break alone
+        call(&mut m, SYS_BRK, [STACK + 1, 0, 0]);
+        assert_eq!(m.cpu.a0(), HEAP + 0x1000);
+        call(&mut m, SYS_BRK, [HEAP - 8, 0, 0]);
+        assert_eq!(m.cpu.a0(), HEAP + 0x1000);
+
+        assert_eq!(call(&mut m, SYS_EXIT_GROUP, [0x1ff, 0, 0]), Some(0xff));
+    }
+}
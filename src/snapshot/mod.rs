@@ -0,0 +1,275 @@
+use thiserror::Error;
+
+use crate::clint::Clint;
+use crate::cpu::{Cpu, Machine};
+use crate::mmu::Mmu;
+use crate::plic::Plic;
+
+/// First bytes of every serialized snapshot
+const MAGIC: &[u8; 8] = b"RVEMUSNP";
+/// Layout of what follows the magic. Bump it whenever a component's `save`
+/// changes; `from_bytes` rejects any other version.
+const VERSION: u32 = 3;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum SnapshotError {
+    #[error("not a machine snapshot")]
+    BadMagic,
+    #[error("unsupported snapshot version {0}")]
+    UnsupportedVersion(u32),
+    #[error("snapshot is truncated")]
+    Truncated,
+    #[error("invalid snapshot: {0}")]
+    Invalid(&'static str),
+}
+
+/// Complete architectural and device state of a `Machine`: integer and float
+/// registers, pc, CSRs, the LR reservation, RAM, both TLBs, the CLINT and the
+/// PLIC, and the retired instruction count.
+///
+/// The UART isn't captured, since its state is mostly the host terminal's, nor
+/// are devices on the bus.
+/// Neither is configuration (`config`, `host_exit_addr`, `max_insns`, the
+/// reset state and which devices are attached); `restore` leaves it as it is.
+///
+/// `to_bytes` and `from_bytes` convert to and from a versioned little-endian
+/// format, so a snapshot can be written to disk and resumed in another run:
+/// the 8-byte `MAGIC`, a u32 `VERSION`, then the CPU, RAM base, length and
+/// contents, MMU, CLINT, PLIC and retired count in that order.
+#[derive(Clone)]
+pub struct MachineSnapshot {
+    pub(crate) cpu: Cpu,
+    pub(crate) ram_base: u64,
+    pub(crate) ram: Vec<u8>,
+    pub(crate) mmu: Mmu,
+    pub(crate) clint: Clint,
+    pub(crate) plic: Plic,
+    pub(crate) executed: u64,
+}
+
+impl MachineSnapshot {
+    pub fn pc(&self) -> u64 {
+        self.cpu.pc
+    }
+
+    pub fn executed(&self) -> u64 {
+        self.executed
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut w = Writer::default();
+        w.bytes(MAGIC);
+        w.u32(VERSION);
+        self.cpu.save(&mut w);
+        w.u64(self.ram_base);
+        w.u64(self.ram.len() as u64);
+        w.bytes(&self.ram);
+        self.mmu.save(&mut w);
+        self.clint.save(&mut w);
+        self.plic.save(&mut w);
+        w.u64(self.executed);
+        w.0
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, SnapshotError> {
+        let mut r = Reader(bytes);
+        if r.bytes(MAGIC.len()).ok() != Some(MAGIC.as_slice()) {
+            return Err(SnapshotError::BadMagic);
+        }
+        let version = r.u32()?;
+        if version != VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+        let cpu = Cpu::load(&mut r)?;
+        let ram_base = r.u64()?;
+        let ram_len = r.u64()?;
+        let ram = r.bytes(usize::try_from(ram_len).map_err(|_| SnapshotError::Truncated)?)?;
+        let snapshot = Self {
+            cpu,
+            ram_base,
+            ram: ram.to_vec(),
+            mmu: Mmu::load(&mut r)?,
+            clint: Clint::load(&mut r)?,
+            plic: Plic::load(&mut r)?,
+            executed: r.u64()?,
+        };
+        if !r.0.is_empty() {
+            return Err(SnapshotError::Invalid("trailing data"));
+        }
+        Ok(snapshot)
+    }
+}
+
+impl Machine {
+    pub fn snapshot(&self) -> MachineSnapshot {
+        MachineSnapshot {
+            cpu: self.cpu.clone(),
+            ram_base: self.mem.base,
+            ram: self.mem.ram(),
+            mmu: self.mmu.clone(),
+            clint: self.mem.clint.clone(),
+            plic: self.mem.plic.clone(),
+            executed: self.executed,
+        }
+    }
+
+    /// Put the machine back in the state captured by `snapshot`. RAM takes the
+    /// snapshot's base and size.
+    pub fn restore(&mut self, snapshot: &MachineSnapshot) {
+        self.cpu = snapshot.cpu.clone();
+        self.mem.load_ram(snapshot.ram_base, &snapshot.ram);
+        self.mmu = snapshot.mmu.clone();
+        self.apply_config();
+        self.mem.clint = snapshot.clint.clone();
+        self.mem.plic = snapshot.plic.clone();
+        self.executed = snapshot.executed;
+        self.held_trap = None;
+        self.flush_decode_cache();
+    }
+}
+
+/// Little-endian encoder used by each component's `save`.
+#[derive(Default)]
+pub(crate) struct Writer(Vec<u8>);
+
+impl Writer {
+    pub(crate) fn bytes(&mut self, bytes: &[u8]) {
+        self.0.extend_from_slice(bytes);
+    }
+
+    pub(crate) fn u8(&mut self, v: u8) {
+        self.0.push(v);
+    }
+
+    pub(crate) fn bool(&mut self, v: bool) {
+        self.u8(v as u8);
+    }
+
+    pub(crate) fn u32(&mut self, v: u32) {
+        self.bytes(&v.to_le_bytes());
+    }
+
+    pub(crate) fn u64(&mut self, v: u64) {
+        self.bytes(&v.to_le_bytes());
+    }
+}
+
+/// Decoder matching `Writer`, used by each component's `load`.
+pub(crate) struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    pub(crate) fn bytes(&mut self, len: usize) -> Result<&'a [u8], SnapshotError> {
+        if self.0.len() < len {
+            return Err(SnapshotError::Truncated);
+        }
+        let (head, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(head)
+    }
+
+    pub(crate) fn array<const N: usize>(&mut self) -> Result<[u8; N], SnapshotError> {
+        Ok(self.bytes(N)?.try_into().unwrap())
+    }
+
+    pub(crate) fn u8(&mut self) -> Result<u8, SnapshotError> {
+        Ok(self.array::<1>()?[0])
+    }
+
+    pub(crate) fn bool(&mut self) -> Result<bool, SnapshotError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(SnapshotError::Invalid("bool out of range")),
+        }
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32, SnapshotError> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    pub(crate) fn u64(&mut self) -> Result<u64, SnapshotError> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RAM: u64 = 0x8000_0000;
+
+    /// Loop mixing ALU ops, stores, LR/SC and a CSR write
+    fn looping_machine() -> Machine {
+        let program = [
+            0x0000_1297, // auipc t0, 1
+            0x0015_0513, // loop: addi a0, a0, 1
+            0x00a5_85b3, // add a1, a1, a0
+            0x00b2_b023, // sd a1, 0(t0)
+            0x1002_b32f, // lr.d t1, (t0)
+            0x18a2_b3af, // sc.d t2, a0, (t0)
+            0x3405_9073, // csrw mscratch, a1
+            0xfe9f_f06f, // j loop
+        ];
+        let mut m = Machine::new(0x4000);
+        for (i, inst) in program.iter().enumerate() {
+            m.mem.write_u32_phys(RAM + 4 * i as u64, *inst).unwrap();
+        }
+        m
+    }
+
+    fn run(m: &mut Machine, steps: usize) {
+        for _ in 0..steps {
+            m.step().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_restore_replays_identically() {
+        let mut m = looping_machine();
+        run(&mut m, 50);
+        let saved = m.snapshot();
+        assert_eq!(saved.executed(), 50);
+
+        run(&mut m, 33);
+        let expected = m.snapshot().to_bytes();
+
+        m.restore(&saved);
+        assert_eq!(m.cpu.pc, saved.pc());
+        run(&mut m, 33);
+        assert_eq!(m.snapshot().to_bytes(), expected);
+
+        // Through the serialized form, into a fresh machine
+        let reloaded = MachineSnapshot::from_bytes(&saved.to_bytes()).unwrap();
+        let mut fresh = Machine::new(0x1000);
+        fresh.restore(&reloaded);
+        run(&mut fresh, 33);
+        assert_eq!(fresh.snapshot().to_bytes(), expected);
+        assert_eq!(fresh.cpu.csr.mscratch, m.cpu.csr.mscratch);
+    }
+
+    #[test]
+    fn test_from_bytes_rejects_malformed_input() {
+        let bytes = looping_machine().snapshot().to_bytes();
+
+        assert_eq!(
+            MachineSnapshot::from_bytes(b"not a snapshot").err(),
+            Some(SnapshotError::BadMagic)
+        );
+        let mut newer = bytes.clone();
+        newer[8] = VERSION as u8 + 1;
+        assert_eq!(
+            MachineSnapshot::from_bytes(&newer).err(),
+            Some(SnapshotError::UnsupportedVersion(VERSION + 1))
+        );
+        assert_eq!(
+            MachineSnapshot::from_bytes(&bytes[..bytes.len() - 1]).err(),
+            Some(SnapshotError::Truncated)
+        );
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert!(matches!(
+            MachineSnapshot::from_bytes(&trailing),
+            Err(SnapshotError::Invalid(_))
+        ));
+    }
+}
@@ -0,0 +1,104 @@
+use crate::bus::Device;
+
+/// SiFive test finisher ("syscon") at the QEMU virt base address.
+///
+/// A 32-bit write to offset 0 requests a power state change; the low 16 bits
+/// pick the command and, for a failing exit, the high 16 bits carry the code:
+///   0x5555              poweroff, exit code 0
+///   (code << 16)|0x3333 poweroff, exit code `code`
+///   0x7777              reset
+/// Other values are ignored, and the register reads as zero.
+pub const SYSCON_BASE: u64 = 0x10_0000;
+pub const SYSCON_SIZE: u64 = 0x1000;
+
+const FINISHER_FAIL: u64 = 0x3333;
+const FINISHER_PASS: u64 = 0x5555;
+const FINISHER_RESET: u64 = 0x7777;
+
+/// Power state change requested by the guest, for `Machine::step` to act on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SysconRequest {
+    Poweroff { code: u64 },
+    Reset,
+}
+
+#[derive(Clone, Default)]
+pub struct Syscon {
+    request: Option<SysconRequest>,
+}
+
+impl Syscon {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn contains(paddr: u64) -> bool {
+        paddr >= SYSCON_BASE && paddr - SYSCON_BASE < SYSCON_SIZE
+    }
+
+    pub fn read(&self, _offset: u64, _size: u64) -> u64 {
+        0
+    }
+
+    /// Write `size` bytes at `offset` from the syscon base.
+    pub fn write(&mut self, offset: u64, size: u64, value: u64) {
+        if offset != 0 || size < 4 {
+            return;
+        }
+        let value = value & 0xffff_ffff;
+        self.request = match value & 0xffff {
+            FINISHER_PASS => Some(SysconRequest::Poweroff { code: 0 }),
+            FINISHER_FAIL => Some(SysconRequest::Poweroff { code: value >> 16 }),
+            FINISHER_RESET => Some(SysconRequest::Reset),
+            _ => return,
+        };
+    }
+
+    /// The request written since the last call, if any.
+    pub fn take_request(&mut self) -> Option<SysconRequest> {
+        self.request.take()
+    }
+}
+
+impl Device for Syscon {
+    fn read(&self, offset: u64, size: u64) -> u64 {
+        Syscon::read(self, offset, size)
+    }
+
+    fn write(&mut self, offset: u64, size: u64, value: u64) {
+        Syscon::write(self, offset, size, value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_finisher_commands() {
+        let mut syscon = Syscon::new();
+
+        syscon.write(0, 4, 0x5555);
+        assert_eq!(
+            syscon.take_request(),
+            Some(SysconRequest::Poweroff { code: 0 })
+        );
+        assert_eq!(syscon.take_request(), None);
+
+        syscon.write(0, 4, (3 << 16) | 0x3333);
+        assert_eq!(
+            syscon.take_request(),
+            Some(SysconRequest::Poweroff { code: 3 })
+        );
+
+        syscon.write(0, 8, 0x7777);
+        assert_eq!(syscon.take_request(), Some(SysconRequest::Reset));
+
+        // Unknown commands, other offsets and narrow writes do nothing
+        syscon.write(0, 4, 0x1234);
+        syscon.write(4, 4, 0x5555);
+        syscon.write(0, 2, 0x5555);
+        assert_eq!(syscon.take_request(), None);
+        assert_eq!(syscon.read(0, 4), 0);
+    }
+}
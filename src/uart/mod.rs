@@ -0,0 +1,311 @@
+use std::cell::Cell;
+use std::io::{Read, Write};
+use std::sync::mpsc::{self, Receiver};
+use std::time::Duration;
+
+use crate::bus::Device;
+
+/// NS16550-compatible UART at the QEMU virt base address.
+///
+/// Only the subset a console needs is modeled: THR/RBR data, IER, IIR/FCR, LCR
+/// (with the DLAB divisor latch), MCR, LSR, MSR and SCR. Baud rate is ignored.
+pub const UART_BASE: u64 = 0x1000_0000;
+pub const UART_SIZE: u64 = 0x100;
+/// PLIC source the UART's interrupt line is wired to
+pub const UART_IRQ: u32 = 10;
+
+const RBR_THR: u64 = 0; // DLL when LCR.DLAB=1
+const IER: u64 = 1; // DLM when LCR.DLAB=1
+const IIR_FCR: u64 = 2;
+const LCR: u64 = 3;
+const MCR: u64 = 4;
+const LSR: u64 = 5;
+const MSR: u64 = 6;
+const SCR: u64 = 7;
+
+const LCR_DLAB: u8 = 1 << 7;
+
+const IER_RX_AVAILABLE: u8 = 1 << 0;
+const IER_THR_EMPTY: u8 = 1 << 1;
+
+const IIR_NO_INTERRUPT: u8 = 0x01;
+const IIR_THR_EMPTY: u8 = 0x02;
+const IIR_RX_AVAILABLE: u8 = 0x04;
+const IIR_FIFO_ENABLED: u8 = 0xc0;
+
+const LSR_DATA_READY: u8 = 1 << 0;
+const LSR_THR_EMPTY: u8 = 1 << 5;
+const LSR_TX_IDLE: u8 = 1 << 6;
+
+pub struct Uart {
+    ier: u8,
+    fcr: u8,
+    lcr: u8,
+    mcr: u8,
+    scr: u8,
+    dll: u8,
+    dlm: u8,
+
+    // Receive path: bytes arrive from a host reader thread. RBR reads have a
+    // side effect (pop), so the staged byte lives in a Cell to keep reads &self.
+    rx: Option<Receiver<u8>>,
+    rx_staged: Cell<Option<u8>>,
+
+    output: Box<dyn Write>,
+}
+
+impl Default for Uart {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Uart {
+    /// UART whose transmitter writes to host stdout and with no receive source.
+    pub fn new() -> Self {
+        Self::with_output(Box::new(std::io::stdout()))
+    }
+
+    pub fn with_output(output: Box<dyn Write>) -> Self {
+        Self {
+            ier: 0,
+            fcr: 0,
+            lcr: 0,
+            mcr: 0,
+            scr: 0,
+            dll: 0,
+            dlm: 0,
+            rx: None,
+            rx_staged: Cell::new(None),
+            output,
+        }
+    }
+
+    /// Feed the receiver from host stdin via a background reader thread.
+    pub fn attach_stdin(&mut self) {
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            let mut byte = [0u8; 1];
+            let mut stdin = std::io::stdin();
+            while let Ok(1) = stdin.read(&mut byte) {
+                if tx.send(byte[0]).is_err() {
+                    break;
+                }
+            }
+        });
+        self.attach_input(rx);
+    }
+
+    /// Feed the receiver from an arbitrary byte channel.
+    pub fn attach_input(&mut self, rx: Receiver<u8>) {
+        self.rx = Some(rx);
+    }
+
+    /// Block until a received byte is available or `timeout` passes, returning
+    /// whether one is. Without an input source this just sleeps.
+    pub fn wait_rx(&self, timeout: Duration) -> bool {
+        if self.rx_staged.get().is_some() {
+            return true;
+        }
+        let Some(rx) = &self.rx else {
+            std::thread::sleep(timeout);
+            return false;
+        };
+        match rx.recv_timeout(timeout) {
+            Ok(byte) => {
+                self.rx_staged.set(Some(byte));
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    pub fn contains(paddr: u64) -> bool {
+        paddr >= UART_BASE && paddr - UART_BASE < UART_SIZE
+    }
+
+    /// Read the byte register at `offset`. Wider accesses return the low byte.
+    pub fn read(&self, offset: u64) -> u8 {
+        let dlab = self.lcr & LCR_DLAB != 0;
+        match offset {
+            RBR_THR if dlab => self.dll,
+            RBR_THR => self
+                .rx_staged
+                .take()
+                .or_else(|| self.poll_rx())
+                .unwrap_or(0),
+            IER if dlab => self.dlm,
+            IER => self.ier,
+            IIR_FCR => self.iir(),
+            LCR => self.lcr,
+            MCR => self.mcr,
+            LSR => {
+                let mut lsr = LSR_THR_EMPTY | LSR_TX_IDLE;
+                if self.rx_ready() {
+                    lsr |= LSR_DATA_READY;
+                }
+                lsr
+            }
+            MSR => 0,
+            SCR => self.scr,
+            _ => 0,
+        }
+    }
+
+    /// Write the byte register at `offset`.
+    pub fn write(&mut self, offset: u64, value: u8) {
+        let dlab = self.lcr & LCR_DLAB != 0;
+        match offset {
+            RBR_THR if dlab => self.dll = value,
+            RBR_THR => {
+                // Console output is best-effort; a closed stdout shouldn't stop the guest
+                let _ = self.output.write_all(&[value]);
+                let _ = self.output.flush();
+            }
+            IER if dlab => self.dlm = value,
+            IER => self.ier = value & 0x0f,
+            IIR_FCR => self.fcr = value,
+            LCR => self.lcr = value,
+            MCR => self.mcr = value,
+            SCR => self.scr = value,
+            _ => {}
+        }
+    }
+
+    /// Interrupt line level, for routing to an interrupt controller.
+    pub fn irq_pending(&self) -> bool {
+        self.iir() & IIR_NO_INTERRUPT == 0
+    }
+
+    fn iir(&self) -> u8 {
+        let fifo = if self.fcr & 1 != 0 {
+            IIR_FIFO_ENABLED
+        } else {
+            0
+        };
+        let id = if self.ier & IER_RX_AVAILABLE != 0 && self.rx_ready() {
+            IIR_RX_AVAILABLE
+        } else if self.ier & IER_THR_EMPTY != 0 {
+            // The transmitter drains instantly, so THR is always empty
+            IIR_THR_EMPTY
+        } else {
+            IIR_NO_INTERRUPT
+        };
+        fifo | id
+    }
+
+    fn rx_ready(&self) -> bool {
+        if self.rx_staged.get().is_some() {
+            return true;
+        }
+        match self.poll_rx() {
+            Some(byte) => {
+                self.rx_staged.set(Some(byte));
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn poll_rx(&self) -> Option<u8> {
+        self.rx.as_ref().and_then(|rx| rx.try_recv().ok())
+    }
+}
+
+/// 16550 registers are byte-wide; wider accesses only touch the addressed one.
+impl Device for Uart {
+    fn read(&self, offset: u64, _size: u64) -> u64 {
+        Uart::read(self, offset) as u64
+    }
+
+    fn write(&mut self, offset: u64, _size: u64, value: u64) {
+        Uart::write(self, offset, value as u8)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[derive(Clone, Default)]
+    struct SharedBuf(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_thr_write_emits_byte_and_dlab_redirects() {
+        let out = SharedBuf::default();
+        let mut uart = Uart::with_output(Box::new(out.clone()));
+
+        uart.write(RBR_THR, b'h');
+        uart.write(RBR_THR, b'i');
+        assert_eq!(out.0.borrow().as_slice(), b"hi");
+
+        // With DLAB set, offset 0 is the divisor latch, not THR
+        uart.write(LCR, LCR_DLAB);
+        uart.write(RBR_THR, 0x03);
+        assert_eq!(uart.read(RBR_THR), 0x03);
+        assert_eq!(out.0.borrow().as_slice(), b"hi");
+    }
+
+    #[test]
+    fn test_rbr_delivers_input_and_lsr_reports_ready() {
+        let mut uart = Uart::with_output(Box::new(std::io::sink()));
+        let (tx, rx) = mpsc::channel();
+        uart.attach_input(rx);
+
+        assert_eq!(uart.read(LSR) & LSR_DATA_READY, 0);
+        assert_ne!(uart.read(LSR) & LSR_THR_EMPTY, 0);
+
+        tx.send(b'x').unwrap();
+        assert_ne!(uart.read(LSR) & LSR_DATA_READY, 0);
+        assert_eq!(uart.read(RBR_THR), b'x');
+        assert_eq!(uart.read(LSR) & LSR_DATA_READY, 0);
+    }
+
+    #[test]
+    fn test_ier_gates_rx_interrupt() {
+        let mut uart = Uart::with_output(Box::new(std::io::sink()));
+        let (tx, rx) = mpsc::channel();
+        uart.attach_input(rx);
+        tx.send(b'a').unwrap();
+
+        assert!(!uart.irq_pending(), "no interrupt while IER is clear");
+        uart.write(IER, IER_RX_AVAILABLE);
+        assert!(uart.irq_pending());
+        assert_eq!(uart.read(IIR_FCR), IIR_RX_AVAILABLE);
+
+        uart.read(RBR_THR);
+        assert!(!uart.irq_pending());
+    }
+
+    #[test]
+    fn test_wait_rx_stages_the_byte_it_waited_for() {
+        let uart = Uart::with_output(Box::new(std::io::sink()));
+        assert!(!uart.wait_rx(Duration::from_millis(1)), "no input source");
+
+        let mut uart = Uart::with_output(Box::new(std::io::sink()));
+        let (tx, rx) = mpsc::channel();
+        uart.attach_input(rx);
+        assert!(!uart.wait_rx(Duration::from_millis(1)));
+
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(5));
+            tx.send(b'z').unwrap();
+        });
+        assert!(uart.wait_rx(Duration::from_secs(10)));
+        assert_ne!(uart.read(LSR) & LSR_DATA_READY, 0);
+        assert_eq!(uart.read(RBR_THR), b'z');
+    }
+}
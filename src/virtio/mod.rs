@@ -0,0 +1,468 @@
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use crate::bus::{Device, GuestRam};
+use crate::mem::MemError;
+
+/// virtio-blk over the virtio-mmio transport (version 2), in the first virtio
+/// slot of the QEMU virt machine.
+///
+/// There is a single request queue. Requests are handled when the driver
+/// notifies the queue: the device reads the descriptor chain from guest RAM,
+/// performs the transfer against the disk image and writes back the status
+/// byte, then raises its PLIC line. The disk is read-only for now; writes
+/// complete with an I/O error.
+pub const VIRTIO_BASE: u64 = 0x1000_1000;
+pub const VIRTIO_SIZE: u64 = 0x1000;
+pub const VIRTIO_IRQ: u32 = 1;
+
+pub const SECTOR_SIZE: u64 = 512;
+
+// Register offsets
+const MAGIC_VALUE: u64 = 0x000;
+const VERSION: u64 = 0x004;
+const DEVICE_ID: u64 = 0x008;
+const VENDOR_ID: u64 = 0x00c;
+const DEVICE_FEATURES: u64 = 0x010;
+const DEVICE_FEATURES_SEL: u64 = 0x014;
+const DRIVER_FEATURES: u64 = 0x020;
+const DRIVER_FEATURES_SEL: u64 = 0x024;
+const QUEUE_SEL: u64 = 0x030;
+const QUEUE_NUM_MAX: u64 = 0x034;
+const QUEUE_NUM: u64 = 0x038;
+const QUEUE_READY: u64 = 0x044;
+const QUEUE_NOTIFY: u64 = 0x050;
+const INTERRUPT_STATUS: u64 = 0x060;
+const INTERRUPT_ACK: u64 = 0x064;
+const STATUS: u64 = 0x070;
+const QUEUE_DESC_LOW: u64 = 0x080;
+const QUEUE_DESC_HIGH: u64 = 0x084;
+const QUEUE_DRIVER_LOW: u64 = 0x090;
+const QUEUE_DRIVER_HIGH: u64 = 0x094;
+const QUEUE_DEVICE_LOW: u64 = 0x0a0;
+const QUEUE_DEVICE_HIGH: u64 = 0x0a4;
+const CONFIG: u64 = 0x100;
+
+const MAGIC: u32 = 0x7472_6976; // "virt"
+const DEVICE_ID_BLOCK: u32 = 2;
+const VENDOR_QEMU: u32 = 0x554d_4551;
+const QUEUE_SIZE_MAX: u32 = 256;
+
+const F_BLK_RO: u64 = 1 << 5;
+const F_VERSION_1: u64 = 1 << 32;
+const FEATURES: u64 = F_BLK_RO | F_VERSION_1;
+
+const STATUS_NEEDS_RESET: u32 = 0x40;
+const INT_USED_BUFFER: u32 = 1;
+
+const DESC_F_NEXT: u16 = 1;
+const DESC_F_WRITE: u16 = 2;
+
+const REQ_IN: u32 = 0;
+const REQ_OUT: u32 = 1;
+const BLK_S_OK: u8 = 0;
+const BLK_S_IOERR: u8 = 1;
+const BLK_S_UNSUPP: u8 = 2;
+
+/// Backing store for a virtio-blk device: a host file, or any seekable reader.
+pub trait Disk: Read + Seek {}
+
+impl<T: Read + Seek> Disk for T {}
+
+/// Addresses and progress of the request queue, as set up by the driver
+#[derive(Default)]
+struct Queue {
+    num: u32,
+    ready: bool,
+    desc: u64,
+    driver: u64,
+    device: u64,
+    /// Next available-ring entry the device hasn't consumed
+    last_avail: u16,
+}
+
+struct Desc {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+pub struct VirtioBlk {
+    disk: Box<dyn Disk>,
+    /// Capacity in 512-byte sectors
+    capacity: u64,
+    status: u32,
+    device_features_sel: u32,
+    driver_features_sel: u32,
+    driver_features: u64,
+    queue_sel: u32,
+    queue: Queue,
+    interrupt_status: u32,
+    notified: bool,
+}
+
+/// Replace the low or (`high`) upper 32 bits of `word` with `value`
+fn set_half(word: &mut u64, high: bool, value: u64) {
+    let shift = if high { 32 } else { 0 };
+    *word = (*word & !(0xffff_ffff << shift)) | ((value & 0xffff_ffff) << shift);
+}
+
+fn read_u16(ram: &GuestRam, paddr: u64) -> Result<u16, MemError> {
+    let mut b = [0; 2];
+    ram.read(paddr, &mut b)?;
+    Ok(u16::from_le_bytes(b))
+}
+
+impl VirtioBlk {
+    /// A device serving `disk`; a partial sector at the end is ignored.
+    pub fn new(mut disk: impl Disk + 'static) -> io::Result<Self> {
+        let capacity = disk.seek(SeekFrom::End(0))? / SECTOR_SIZE;
+        Ok(Self {
+            disk: Box::new(disk),
+            capacity,
+            status: 0,
+            device_features_sel: 0,
+            driver_features_sel: 0,
+            driver_features: 0,
+            queue_sel: 0,
+            queue: Queue::default(),
+            interrupt_status: 0,
+            notified: false,
+        })
+    }
+
+    /// A device serving the image at `path`, opened read-only.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::new(File::open(path)?)
+    }
+
+    pub fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    /// Writing 0 to the status register resets the device.
+    fn reset(&mut self) {
+        self.status = 0;
+        self.driver_features = 0;
+        self.queue = Queue::default();
+        self.interrupt_status = 0;
+        self.notified = false;
+    }
+
+    fn config_byte(&self, offset: u64) -> u8 {
+        // Only `capacity` is valid without further feature bits
+        match offset {
+            0..8 => self.capacity.to_le_bytes()[offset as usize],
+            _ => 0,
+        }
+    }
+
+    fn read_desc(&self, ram: &GuestRam, idx: u16) -> Result<Desc, MemError> {
+        let mut b = [0; 16];
+        ram.read(self.queue.desc + 16 * idx as u64, &mut b)?;
+        Ok(Desc {
+            addr: u64::from_le_bytes(b[0..8].try_into().unwrap()),
+            len: u32::from_le_bytes(b[8..12].try_into().unwrap()),
+            flags: u16::from_le_bytes(b[12..14].try_into().unwrap()),
+            next: u16::from_le_bytes(b[14..16].try_into().unwrap()),
+        })
+    }
+
+    /// Consume every available request and post it to the used ring.
+    fn process_queue(&mut self, ram: &mut GuestRam) -> Result<(), MemError> {
+        let num = self.queue.num as u64;
+        if !self.queue.ready || num == 0 {
+            return Ok(());
+        }
+        let avail_idx = read_u16(ram, self.queue.driver + 2)?;
+        while self.queue.last_avail != avail_idx {
+            let slot = self.queue.last_avail as u64 % num;
+            let head = read_u16(ram, self.queue.driver + 4 + 2 * slot)?;
+            let written = self.handle_request(ram, head)?;
+
+            let used_idx = read_u16(ram, self.queue.device + 2)?;
+            let elem = self.queue.device + 4 + 8 * (used_idx as u64 % num);
+            ram.write(elem, &(head as u32).to_le_bytes())?;
+            ram.write(elem + 4, &written.to_le_bytes())?;
+            ram.write(
+                self.queue.device + 2,
+                &used_idx.wrapping_add(1).to_le_bytes(),
+            )?;
+
+            self.queue.last_avail = self.queue.last_avail.wrapping_add(1);
+            self.interrupt_status |= INT_USED_BUFFER;
+        }
+        Ok(())
+    }
+
+    /// Carry out the request whose chain starts at descriptor `head`: a
+    /// 16-byte header, data buffers, then a status byte. Returns how many
+    /// bytes were written into the chain.
+    fn handle_request(&mut self, ram: &mut GuestRam, head: u16) -> Result<u32, MemError> {
+        let mut chain = vec![self.read_desc(ram, head)?];
+        while chain.last().unwrap().flags & DESC_F_NEXT != 0 {
+            if chain.len() > self.queue.num as usize {
+                return Err(MemError::Oob(self.queue.desc)); // looping chain
+            }
+            chain.push(self.read_desc(ram, chain.last().unwrap().next)?);
+        }
+        let (header, status) = (&chain[0], chain.last().unwrap());
+        if chain.len() < 2 || header.len < 16 || status.flags & DESC_F_WRITE == 0 {
+            return Err(MemError::Oob(header.addr));
+        }
+        let mut hdr = [0; 16];
+        ram.read(header.addr, &mut hdr)?;
+        let kind = u32::from_le_bytes(hdr[0..4].try_into().unwrap());
+        let sector = u64::from_le_bytes(hdr[8..16].try_into().unwrap());
+
+        let data = &chain[1..chain.len() - 1];
+        let mut written = 0;
+        let result = if kind == REQ_IN {
+            let mut pos = sector.saturating_mul(SECTOR_SIZE);
+            let mut result = BLK_S_OK;
+            for d in data {
+                let end = pos.saturating_add(d.len as u64);
+                if d.flags & DESC_F_WRITE == 0 || end > self.capacity * SECTOR_SIZE {
+                    result = BLK_S_IOERR;
+                    break;
+                }
+                let mut buf = vec![0; d.len as usize];
+                if self.read_disk(pos, &mut buf).is_err() {
+                    result = BLK_S_IOERR;
+                    break;
+                }
+                ram.write(d.addr, &buf)?;
+                written += d.len;
+                pos = end;
+            }
+            result
+        } else if kind == REQ_OUT {
+            // The disk is read-only
+            BLK_S_IOERR
+        } else {
+            BLK_S_UNSUPP
+        };
+        ram.write(status.addr, &[result])?;
+        Ok(written + 1)
+    }
+
+    fn read_disk(&mut self, pos: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.disk.seek(SeekFrom::Start(pos))?;
+        self.disk.read_exact(buf)
+    }
+}
+
+impl Device for VirtioBlk {
+    fn read(&self, offset: u64, size: u64) -> u64 {
+        if offset >= CONFIG {
+            return (0..size).fold(0, |acc, i| {
+                acc | (self.config_byte(offset - CONFIG + i) as u64) << (8 * i)
+            });
+        }
+        let features_half = |word: u64, sel: u32| match sel {
+            0 => word & 0xffff_ffff,
+            1 => word >> 32,
+            _ => 0,
+        };
+        let value = match offset {
+            MAGIC_VALUE => MAGIC,
+            VERSION => 2,
+            DEVICE_ID => DEVICE_ID_BLOCK,
+            VENDOR_ID => VENDOR_QEMU,
+            DEVICE_FEATURES => features_half(FEATURES, self.device_features_sel) as u32,
+            QUEUE_NUM_MAX if self.queue_sel == 0 => QUEUE_SIZE_MAX,
+            QUEUE_READY if self.queue_sel == 0 => self.queue.ready as u32,
+            INTERRUPT_STATUS => self.interrupt_status,
+            STATUS => self.status,
+            // Including ConfigGeneration, since the config never changes
+            _ => 0,
+        };
+        value as u64
+    }
+
+    fn write(&mut self, offset: u64, _size: u64, value: u64) {
+        let value = value & 0xffff_ffff;
+        let queue = self.queue_sel == 0;
+        match offset {
+            DEVICE_FEATURES_SEL => self.device_features_sel = value as u32,
+            DRIVER_FEATURES_SEL => self.driver_features_sel = value as u32,
+            DRIVER_FEATURES if self.driver_features_sel < 2 => {
+                set_half(
+                    &mut self.driver_features,
+                    self.driver_features_sel == 1,
+                    value,
+                );
+                self.driver_features &= FEATURES;
+            }
+            QUEUE_SEL => self.queue_sel = value as u32,
+            QUEUE_NUM if queue => self.queue.num = (value as u32).min(QUEUE_SIZE_MAX),
+            QUEUE_READY if queue => self.queue.ready = value & 1 != 0,
+            QUEUE_DESC_LOW | QUEUE_DESC_HIGH if queue => {
+                set_half(&mut self.queue.desc, offset == QUEUE_DESC_HIGH, value)
+            }
+            QUEUE_DRIVER_LOW | QUEUE_DRIVER_HIGH if queue => {
+                set_half(&mut self.queue.driver, offset == QUEUE_DRIVER_HIGH, value)
+            }
+            QUEUE_DEVICE_LOW | QUEUE_DEVICE_HIGH if queue => {
+                set_half(&mut self.queue.device, offset == QUEUE_DEVICE_HIGH, value)
+            }
+            QUEUE_NOTIFY if value == 0 => self.notified = true,
+            INTERRUPT_ACK => self.interrupt_status &= !(value as u32),
+            STATUS if value == 0 => self.reset(),
+            STATUS => self.status = value as u32,
+            _ => {}
+        }
+    }
+
+    fn irq_line(&self) -> Option<u32> {
+        Some(VIRTIO_IRQ)
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.interrupt_status != 0
+    }
+
+    fn service(&mut self, ram: &mut GuestRam) {
+        if !std::mem::take(&mut self.notified) || self.status & STATUS_NEEDS_RESET != 0 {
+            return;
+        }
+        // A chain the device can't follow is a driver bug it can only report
+        // by asking for a reset
+        if self.process_queue(ram).is_err() {
+            self.status |= STATUS_NEEDS_RESET;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::ram::Ram;
+    use std::io::Cursor;
+
+    const RAM_BASE: u64 = 0x8000_0000;
+    const DESC: u64 = RAM_BASE;
+    const AVAIL: u64 = RAM_BASE + 0x100;
+    const USED: u64 = RAM_BASE + 0x200;
+    const HEADER: u64 = RAM_BASE + 0x1000;
+    const DATA: u64 = RAM_BASE + 0x2000;
+    const STATUS_BYTE: u64 = RAM_BASE + 0x3000;
+
+    /// Four sectors, each byte holding its offset's low 8 bits plus the sector
+    fn disk() -> Cursor<Vec<u8>> {
+        Cursor::new(
+            (0..4 * SECTOR_SIZE)
+                .map(|i| (i as u8).wrapping_add((i / SECTOR_SIZE) as u8))
+                .collect(),
+        )
+    }
+
+    /// Go through the driver's initialization sequence with an 8-entry queue
+    fn set_up(blk: &mut VirtioBlk) {
+        blk.write(STATUS, 4, 0x1 | 0x2); // ACKNOWLEDGE | DRIVER
+        blk.write(DRIVER_FEATURES_SEL, 4, 1);
+        blk.write(DRIVER_FEATURES, 4, 1); // VIRTIO_F_VERSION_1
+        blk.write(STATUS, 4, 0x1 | 0x2 | 0x8); // FEATURES_OK
+        blk.write(QUEUE_SEL, 4, 0);
+        blk.write(QUEUE_NUM, 4, 8);
+        blk.write(QUEUE_DESC_LOW, 4, DESC & 0xffff_ffff);
+        blk.write(QUEUE_DESC_HIGH, 4, DESC >> 32);
+        blk.write(QUEUE_DRIVER_LOW, 4, AVAIL);
+        blk.write(QUEUE_DEVICE_LOW, 4, USED);
+        blk.write(QUEUE_READY, 4, 1);
+        blk.write(STATUS, 4, 0x1 | 0x2 | 0x8 | 0x4); // DRIVER_OK
+    }
+
+    /// Queue a request of `kind` for `len` bytes at `sector` as chain 0-1-2
+    fn submit(ram: &mut GuestRam, kind: u32, sector: u64, len: u32) {
+        let mut header = kind.to_le_bytes().to_vec();
+        header.extend_from_slice(&[0; 4]);
+        header.extend_from_slice(&sector.to_le_bytes());
+        ram.write(HEADER, &header).unwrap();
+
+        let descs = [
+            (HEADER, 16, DESC_F_NEXT, 1),
+            (DATA, len, DESC_F_NEXT | DESC_F_WRITE, 2),
+            (STATUS_BYTE, 1, DESC_F_WRITE, 0),
+        ];
+        for (i, (addr, len, flags, next)) in descs.into_iter().enumerate() {
+            let mut d = addr.to_le_bytes().to_vec();
+            d.extend_from_slice(&u32::to_le_bytes(len));
+            d.extend_from_slice(&u16::to_le_bytes(flags));
+            d.extend_from_slice(&u16::to_le_bytes(next));
+            ram.write(DESC + 16 * i as u64, &d).unwrap();
+        }
+        let idx = read_u16(ram, AVAIL + 2).unwrap();
+        ram.write(AVAIL + 4 + 2 * (idx as u64 % 8), &[0, 0])
+            .unwrap();
+        ram.write(AVAIL + 2, &idx.wrapping_add(1).to_le_bytes())
+            .unwrap();
+    }
+
+    #[test]
+    fn test_identifies_as_a_read_only_block_device() {
+        let mut blk = VirtioBlk::new(disk()).unwrap();
+        assert_eq!(blk.read(MAGIC_VALUE, 4), 0x7472_6976);
+        assert_eq!(blk.read(VERSION, 4), 2);
+        assert_eq!(blk.read(DEVICE_ID, 4), 2);
+        assert_eq!(blk.read(DEVICE_FEATURES, 4), F_BLK_RO);
+        blk.write(DEVICE_FEATURES_SEL, 4, 1);
+        assert_eq!(blk.read(DEVICE_FEATURES, 4), 1, "VIRTIO_F_VERSION_1");
+        assert_eq!(blk.read(CONFIG, 8), 4, "capacity in sectors");
+        assert_eq!(blk.read(CONFIG, 4), 4);
+        assert_eq!(blk.read(CONFIG + 4, 4), 0);
+    }
+
+    #[test]
+    fn test_reads_sector_0() {
+        let mut blk = VirtioBlk::new(disk()).unwrap();
+        let mut data = Ram::new(0x4000);
+        let mut ram = GuestRam::new(RAM_BASE, &mut data);
+        set_up(&mut blk);
+
+        submit(&mut ram, REQ_IN, 0, 512);
+        blk.write(QUEUE_NOTIFY, 4, 0);
+        assert!(!blk.irq_pending(), "requests wait for the device's turn");
+        blk.service(&mut ram);
+
+        let mut sector = vec![0; 512];
+        ram.read(DATA, &mut sector).unwrap();
+        assert_eq!(sector, disk().into_inner()[..512]);
+        let mut status = [0xff];
+        ram.read(STATUS_BYTE, &mut status).unwrap();
+        assert_eq!(status, [BLK_S_OK]);
+        // Used ring: idx 1, element {id 0, len 512 data + 1 status}
+        let mut used = [0; 12];
+        ram.read(USED, &mut used).unwrap();
+        assert_eq!(used, [0, 0, 1, 0, 0, 0, 0, 0, 0x01, 0x02, 0, 0]);
+
+        assert!(blk.irq_pending());
+        assert_eq!(blk.read(INTERRUPT_STATUS, 4), 1);
+        blk.write(INTERRUPT_ACK, 4, 1);
+        assert!(!blk.irq_pending());
+    }
+
+    #[test]
+    fn test_rejects_writes_and_reads_past_the_end() {
+        let mut blk = VirtioBlk::new(disk()).unwrap();
+        let mut data = Ram::new(0x4000);
+        let mut ram = GuestRam::new(RAM_BASE, &mut data);
+        set_up(&mut blk);
+
+        let mut status = [0xff];
+        submit(&mut ram, REQ_OUT, 0, 512);
+        blk.write(QUEUE_NOTIFY, 4, 0);
+        blk.service(&mut ram);
+        ram.read(STATUS_BYTE, &mut status).unwrap();
+        assert_eq!(status, [BLK_S_IOERR]);
+
+        submit(&mut ram, REQ_IN, 3, 1024);
+        blk.write(QUEUE_NOTIFY, 4, 0);
+        blk.service(&mut ram);
+        ram.read(STATUS_BYTE, &mut status).unwrap();
+        assert_eq!(status, [BLK_S_IOERR]);
+        assert_eq!(read_u16(&ram, USED + 2).unwrap(), 2);
+    }
+}
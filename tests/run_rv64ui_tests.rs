@@ -8,44 +8,62 @@ enum TestResult {
     Skipped(String),
 }
 
+const MAX_INSNS: &str = "100000"; // 100K instructions per test
+// A test stuck on `j .` fails at once rather than at MAX_INSNS
+const DEADLOCK_AFTER: &str = "1000";
+
+// Exit statuses of the emulator besides the guest's own
+const EXIT_TIMEOUT: i32 = 124;
+const EXIT_ERROR: i32 = 125;
+const EXIT_LOAD: i32 = 126;
+
 /// run a single rv64ui test binary and verify it passes
 fn run_test_binary(test_path: &str) -> TestResult {
+    // Without tohost the test has no way to report a result
+    match riscv_emu::elf::find_tohost_symbol(test_path) {
+        Ok(Some(_)) => {}
+        Ok(None) => return TestResult::Skipped("no tohost symbol".to_string()),
+        Err(e) => return TestResult::Fail(format!("Failed to read ELF: {}", e)),
+    }
+
     let output = match Command::new(env!("CARGO_BIN_EXE_riscv-emu"))
         .arg("--elf")
         .arg(test_path)
         .arg("--max-insns")
-        .arg("100000") // 100K instructions per test
+        .arg(MAX_INSNS)
+        .arg("--deadlock-after")
+        .arg(DEADLOCK_AFTER)
         .output()
     {
         Ok(out) => out,
         Err(e) => return TestResult::Fail(format!("Failed to run test: {}", e)),
     };
 
-    let stdout = String::from_utf8_lossy(&output.stdout);
-    let stderr = String::from_utf8_lossy(&output.stderr);
-    let combined = format!("{}{}", stdout, stderr);
-
-    // Check for [PASS] or [FAIL] markers in the output
-    if combined.contains("[PASS]")
-        || combined.contains("CPU halted") && !combined.contains("[FAIL]")
-    {
-        TestResult::Pass
-    } else if combined.contains("[FAIL]") {
-        TestResult::Fail(
-            combined
-                .lines()
-                .find(|l| l.contains("[FAIL]"))
-                .unwrap_or("Test failed")
-                .to_string(),
-        )
-    } else if !output.status.success() {
-        TestResult::Fail(format!(
-            "exited with code: {}",
-            output.status.code().unwrap_or(-1)
-        ))
-    } else {
-        // Assume success if status is 0 and no [FAIL] marker
-        TestResult::Pass
+    // The exit status carries the HTIF tohost result
+    match output.status.code() {
+        Some(0) => TestResult::Pass,
+        Some(EXIT_TIMEOUT) => {
+            TestResult::Fail(format!("timed out after {} instructions", MAX_INSNS))
+        }
+        Some(EXIT_ERROR) => {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            TestResult::Fail(
+                stderr
+                    .lines()
+                    .next()
+                    .unwrap_or("emulator error")
+                    .to_string(),
+            )
+        }
+        Some(EXIT_LOAD) => {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            TestResult::Fail(format!(
+                "load error: {}",
+                stderr.lines().next().unwrap_or("emulator error")
+            ))
+        }
+        Some(test) => TestResult::Fail(format!("failed test #{}", test)),
+        None => TestResult::Fail("emulator killed by a signal".to_string()),
     }
 }
 